@@ -98,16 +98,20 @@ extern "C" {
 }
 /// Identity map range
 /// Takes a contiguous allocation of memory and maps it using PAGE_SIZE
-/// This assumes that start <= end
+/// A backwards (start > end) or empty range maps nothing. A caller that
+/// passes one has almost certainly computed its addresses wrong.
 pub fn id_map_range(root: &mut page::Table,
                     start: usize,
                     end: usize,
                     bits: i64)
 {
+	debug_assert!(start <= end, "id_map_range: 0x{:x} > 0x{:x}", start, end);
+	if end <= start {
+		return;
+	}
 	let mut memaddr = start & !(page::PAGE_SIZE - 1);
-	let num_kb_pages = (page::align_val(end, 12)
-	                 - memaddr)
-	                / page::PAGE_SIZE;
+	let num_kb_pages = page::align_val(end, 12).saturating_sub(memaddr)
+		/ page::PAGE_SIZE;
 
 	// I named this num_kb_pages for future expansion when
 	// I decide to allow for GiB (2^30) and 2MiB (2^21) page
@@ -155,11 +159,12 @@ extern "C" fn kinit() -> usize {
 	             page::EntryBits::ReadWrite.val(),
 	);
 	unsafe {
-		// Map heap descriptors
+		// Map heap descriptors. There is one Page descriptor per page,
+		// so the end is in bytes, not pages.
 		let num_pages = HEAP_SIZE / page::PAGE_SIZE;
 		id_map_range(&mut root,
 					 HEAP_START,
-					 HEAP_START + num_pages,
+					 HEAP_START + num_pages * core::mem::size_of::<page::Page>(),
 					 page::EntryBits::ReadWrite.val()
 		);
 		// Map executable section
@@ -304,8 +309,44 @@ extern "C" fn kmain() {
 	// Usually we can use #[test] modules in Rust, but it would convolute
 	// the task at hand, and it requires us to create the testing harness
 	// since the embedded testing system is part of the "std" library.
+	let mut decoder = ansi::AnsiDecoder::new();
 	loop {
 		if let Some(c) = my_uart.get() {
+			// Arrow keys come in as several bytes, which might not all be
+			// here yet. The decoder remembers where we are in the sequence
+			// between polls and only hands us a key when it's complete.
+			let c = match decoder.feed(c) {
+				Some(ansi::Key::Char(c)) => c,
+				Some(ansi::Key::Up) => {
+					println!("That's the up arrow!");
+					continue;
+				},
+				Some(ansi::Key::Down) => {
+					println!("That's the down arrow!");
+					continue;
+				},
+				Some(ansi::Key::Left) => {
+					println!("That's the left arrow!");
+					continue;
+				},
+				Some(ansi::Key::Right) => {
+					println!("That's the right arrow!");
+					continue;
+				},
+				Some(ansi::Key::Home) => {
+					println!("That's the home key!");
+					continue;
+				},
+				Some(ansi::Key::End) => {
+					println!("That's the end key!");
+					continue;
+				},
+				Some(_) => {
+					println!("That's something else.....");
+					continue;
+				},
+				None => continue,
+			};
 			match c {
 				8 => {
 					// This is a backspace, so we
@@ -329,6 +370,9 @@ extern "C" fn kmain() {
 // / RUST MODULES
 // ///////////////////////////////////
 
+// The escape sequence decoder is shared with the full kernel.
+#[path = "../../../src/ansi.rs"]
+pub mod ansi;
 pub mod kmem;
 pub mod page;
 pub mod uart;
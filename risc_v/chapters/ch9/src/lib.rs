@@ -97,15 +97,21 @@ extern "C" fn abort() -> ! {
 // }
 /// Identity map range
 /// Takes a contiguous allocation of memory and maps it using PAGE_SIZE
-/// This assumes that start <= end
+/// A backwards (start > end) or empty range maps nothing. A caller that
+/// passes one has almost certainly computed its addresses wrong.
 pub fn id_map_range(root: &mut page::Table,
                     start: usize,
                     end: usize,
                     bits: i64)
 {
+	debug_assert!(start <= end, "id_map_range: 0x{:x} > 0x{:x}", start, end);
+	if end <= start {
+		return;
+	}
 	let mut memaddr = start & !(page::PAGE_SIZE - 1);
 	let num_kb_pages =
-		(page::align_val(end, 12) - memaddr) / page::PAGE_SIZE;
+		page::align_val(end, 12).saturating_sub(memaddr)
+		/ page::PAGE_SIZE;
 
 	// I named this num_kb_pages for future expansion when
 	// I decide to allow for GiB (2^30) and 2MiB (2^21) page
@@ -0,0 +1,111 @@
+// ansi.rs
+// Decoding the escape sequences a terminal sends for its special keys
+// Stephen Marz
+
+// This doesn't touch the hardware or anything else in the kernel, so ch3
+// pulls in this same file with #[path] instead of keeping its own copy.
+
+/// A key from the terminal. Most keys are just the byte they send, but
+/// the arrows and friends send an escape sequence, which AnsiDecoder turns
+/// into one of these.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Key {
+	Up,
+	Down,
+	Left,
+	Right,
+	Home,
+	End,
+	PageUp,
+	PageDown,
+	Insert,
+	Delete,
+	Char(u8),
+}
+
+#[derive(Clone, Copy)]
+enum AnsiState {
+	// Not in the middle of anything.
+	Ground,
+	// We saw ESC.
+	Escape,
+	// We saw ESC [. We keep the first number (ESC [ 5 ~ is page up). After
+	// a ;, the rest are modifiers (ctrl, shift), which we don't care
+	// about, so the bool says to stop collecting digits.
+	Csi(u8, bool),
+	// We saw ESC O, which some terminals use for the arrows, home and end.
+	Ss3,
+}
+
+/// Turn the bytes a terminal sends into Keys, one byte at a time. feed()
+/// gives back None while it's in the middle of an escape sequence, so it
+/// doesn't matter how the bytes were split up when they came in. Sequences
+/// we don't know are swallowed.
+pub struct AnsiDecoder {
+	state: AnsiState,
+}
+
+impl AnsiDecoder {
+	pub const fn new() -> Self {
+		Self { state: AnsiState::Ground }
+	}
+
+	pub fn feed(&mut self, c: u8) -> Option<Key> {
+		let (state, key) = match self.state {
+			AnsiState::Ground => match c {
+				0x1b => (AnsiState::Escape, None),
+				_ => (AnsiState::Ground, Some(Key::Char(c))),
+			},
+			AnsiState::Escape => match c {
+				b'[' => (AnsiState::Csi(0, false), None),
+				b'O' => (AnsiState::Ss3, None),
+				// ESC ESC. The first one was a lone escape, so we
+				// start over with this one.
+				0x1b => (AnsiState::Escape, None),
+				// ESC by itself followed by something else. We can't
+				// give back two keys, so we drop the ESC.
+				_ => (AnsiState::Ground, Some(Key::Char(c))),
+			},
+			AnsiState::Csi(param, modifiers) => match c {
+				b'0'..=b'9' if !modifiers => {
+					(AnsiState::Csi(param.saturating_mul(10).saturating_add(c - b'0'), false), None)
+				},
+				b'0'..=b'9' => (AnsiState::Csi(param, true), None),
+				b';' => (AnsiState::Csi(param, true), None),
+				b'~' => (AnsiState::Ground, Self::tilde_key(param)),
+				// Anything from @ to ~ ends the sequence.
+				0x40..=0x7e => (AnsiState::Ground, Self::final_key(c)),
+				_ => (AnsiState::Csi(param, modifiers), None),
+			},
+			AnsiState::Ss3 => (AnsiState::Ground, Self::final_key(c)),
+		};
+		self.state = state;
+		key
+	}
+
+	// ESC [ A, ESC O A, and so on.
+	fn final_key(c: u8) -> Option<Key> {
+		match c {
+			b'A' => Some(Key::Up),
+			b'B' => Some(Key::Down),
+			b'C' => Some(Key::Right),
+			b'D' => Some(Key::Left),
+			b'H' => Some(Key::Home),
+			b'F' => Some(Key::End),
+			_ => None,
+		}
+	}
+
+	// ESC [ n ~. Terminals don't agree on home and end, so we take both.
+	fn tilde_key(param: u8) -> Option<Key> {
+		match param {
+			1 | 7 => Some(Key::Home),
+			2 => Some(Key::Insert),
+			3 => Some(Key::Delete),
+			4 | 8 => Some(Key::End),
+			5 => Some(Key::PageUp),
+			6 => Some(Key::PageDown),
+			_ => None,
+		}
+	}
+}
@@ -3,20 +3,25 @@
 // Stephen Marz
 // 10 March 2020
 
-use crate::{kmem::{kfree, kmalloc},
-            page::{zalloc, PAGE_SIZE},
+use crate::{cpu::Registers,
+            kmem::{kfree, kmalloc, talloc, tfree},
+            lock::SpinLock,
             process::{add_kernel_process_args,
-                      get_by_pid,
-                      set_running,
-                      set_waiting},
+                      set_waiting,
+                      try_with_process,
+                      ProcessState,
+                      DEFAULT_PRIORITY},
+            syscall::SysError,
             virtio,
-            virtio::{Descriptor,
+            virtio::{init_queue,
+                     DeviceTypes,
+                     Descriptor,
                      MmioOffsets,
                      Queue,
                      StatusField,
                      VIRTIO_RING_SIZE}};
-use core::mem::size_of;
-use alloc::boxed::Box;
+use core::{mem::size_of, ptr::{copy_nonoverlapping, null_mut}};
+use alloc::{collections::VecDeque, vec, vec::Vec};
 
 #[repr(C)]
 pub struct Geometry {
@@ -95,21 +100,71 @@ pub struct Request {
 	// before we get here. If we used a pointer, we
 	// may dereference invalid memory.
 	watcher: u16,
+	// When the caller doesn't want whole sectors, the device reads into
+	// bounce instead, and pending() copies the size bytes they asked for,
+	// starting skip bytes in, into their buffer.
+	bounce:  *mut u8,
+	buffer:  *mut u8,
+	skip:    usize,
+	size:    usize,
+	// Requests for a /dev/block file give the watcher the number of bytes
+	// instead of the device's status, like read() and write() do. Their
+	// buffer (owned) is a kernel copy that we free when they're done.
+	file:    bool,
+	owned:   *mut u8,
+	// How many bytes the device moves, which is size rounded out to whole
+	// sectors, and the priority of the process that asked for it.
+	dev_size: usize,
+	priority: u8,
+}
+
+/// An open /dev/blockN. The offset is in bytes, and it's up to the caller
+/// to keep requests inside of the device. See capacity().
+pub struct BlockFile {
+	pub dev:    usize,
+	pub offset: u64,
+}
+
+/// Which device does a /dev/blockN path name? We look it up in the virtio
+/// registry every time, so there's a node for every block device probe()
+/// set up, and nothing else.
+pub fn device_path(path: &str) -> Option<usize> {
+	let dev = path.strip_prefix("/dev/block")?.parse::<usize>().ok()?;
+	if virtio::is_ready(dev, DeviceTypes::Block) {
+		Some(dev)
+	}
+	else {
+		None
+	}
 }
 
 // Internal block device structure
-// We keep our own used_idx and idx for
-// descriptors. There is a shared index, but that
-// tells us or the device if we've kept up with where
-// we are for the available (us) or used (device) ring.
+// We keep our own used_idx. There is a shared index,
+// but that tells us or the device if we've kept up with
+// where we are for the available (us) or used (device) ring.
+// The queue keeps track of which descriptor is next.
 pub struct BlockDevice {
-	queue:        *mut Queue,
+	queue:        Queue,
 	dev:          *mut u32,
-	idx:          u16,
 	ack_used_idx: u16,
 	read_only:    bool,
+	// The requests the device still has, indexed by their head
+	// descriptor. When a process dies, we go through these and
+	// take it off as the watcher. There's one for every descriptor in
+	// the queue.
+	outstanding:  Vec<*mut Request>,
+	// Requests that haven't gone to the device yet, highest priority
+	// first. Each request takes REQUEST_DESCRIPTORS descriptors, so only
+	// so many of them fit in the ring at once. in_flight is how many the
+	// device has right now.
+	waiting:      VecDeque<*mut Request>,
+	in_flight:    usize,
 }
 
+// A request is a header, the data, and a status, each in its own
+// descriptor.
+const REQUEST_DESCRIPTORS: usize = 3;
+
 // Type values
 pub const VIRTIO_BLK_T_IN: u32 = 0;
 pub const VIRTIO_BLK_T_OUT: u32 = 1;
@@ -140,15 +195,26 @@ pub enum BlockErrors {
 	BlockDeviceNotFound,
 	InvalidArgument,
 	ReadOnly,
+	// We couldn't get the memory for the request or its bounce buffer.
+	OutOfMemory,
 }
 
 // Much like with processes, Rust requires some initialization
 // when we declare a static. In this case, we use the Option
 // value type to signal that the variable exists, but not the
 // queue itself. We will replace this with an actual queue when
-// we initialize the block system.
-static mut BLOCK_DEVICES: [Option<BlockDevice>; 8] =
-	[None, None, None, None, None, None, None, None];
+// we initialize the block system. Each device has its own lock, since
+// block_op() and the interrupt handler both move its ring indices.
+static BLOCK_DEVICES: [SpinLock<Option<BlockDevice>>; 8] = [
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
+];
 
 pub fn setup_block_device(ptr: *mut u32) -> bool {
 	unsafe {
@@ -192,67 +258,29 @@ pub fn setup_block_device(ptr: *mut u32) -> bool {
 		// that means that the device couldn't accept
 		// the features that we request. Therefore, this is
 		// considered a "failed" state.
-		if false == StatusField::features_ok(status_ok) {
+		if !StatusField::features_ok(status_ok) {
 			print!("features fail...");
 			ptr.add(MmioOffsets::Status.scale32())
 			   .write_volatile(StatusField::Failed.val32());
 			return false;
 		}
 		// 7. Perform device-specific setup.
-		// Set the queue num. We have to make sure that the
-		// queue size is valid because the device can only take
-		// a certain size.
-		let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32())
-		               .read_volatile();
-		ptr.add(MmioOffsets::QueueNum.scale32())
-		   .write_volatile(VIRTIO_RING_SIZE as u32);
-		if VIRTIO_RING_SIZE as u32 > qnmax {
-			print!("queue size fail...");
-			return false;
-		}
-		// First, if the block device array is empty, create it!
-		// We add 4095 to round this up and then do an integer
-		// divide to truncate the decimal. We don't add 4096,
-		// because if it is exactly 4096 bytes, we would get two
-		// pages, not one.
-		let num_pages =
-			(size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
-		// println!("np = {}", num_pages);
-		// We allocate a page for each device. This will the the
-		// descriptor where we can communicate with the block
-		// device. We will still use an MMIO register (in
-		// particular, QueueNotify) to actually tell the device
-		// we put something in memory. We also have to be
-		// careful with memory ordering. We don't want to
-		// issue a notify before all memory writes have
-		// finished. We will look at that later, but we need
-		// what is called a memory "fence" or barrier.
-		ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(0);
-		// Alignment is very important here. This is the memory address
-		// alignment between the available and used rings. If this is
-		// wrong, then we and the device will refer to different memory
-		// addresses and hence get the wrong data in the used ring.
-		// ptr.add(MmioOffsets::QueueAlign.scale32()).write_volatile(2);
-		let queue_ptr = zalloc(num_pages) as *mut Queue;
-		let queue_pfn = queue_ptr as u32;
-		ptr.add(MmioOffsets::GuestPageSize.scale32())
-		   .write_volatile(PAGE_SIZE as u32);
-		// QueuePFN is a physical page number, however it
-		// appears for QEMU we have to write the entire memory
-		// address. This is a physical memory address where we
-		// (the OS) and the block device have in common for
-		// making and receiving requests.
-		ptr.add(MmioOffsets::QueuePfn.scale32())
-		   .write_volatile(queue_pfn / PAGE_SIZE as u32);
+		// The request queue is the only queue a block device has.
+		let queue = match init_queue(ptr, 0, VIRTIO_RING_SIZE as u16) {
+			Some(queue) => queue,
+			None => return false,
+		};
 		// We need to store all of this data as a "BlockDevice"
 		// structure We will be referring to this structure when
 		// making block requests AND when handling responses.
-		let bd = BlockDevice { queue:        queue_ptr,
+		let bd = BlockDevice { queue,
 		                       dev:          ptr,
-		                       idx:          0,
 		                       ack_used_idx: 0,
-		                       read_only:    ro, };
-		BLOCK_DEVICES[idx] = Some(bd);
+		                       read_only:    ro,
+		                       outstanding:  vec![null_mut(); queue.size() as usize],
+		                       waiting:      VecDeque::new(),
+		                       in_flight:    0, };
+		*BLOCK_DEVICES[idx].lock() = Some(bd);
 
 		// 8. Set the DRIVER_OK status bit. Device is now "live"
 		status_bits |= StatusField::DriverOk.val32();
@@ -263,54 +291,132 @@ pub fn setup_block_device(ptr: *mut u32) -> bool {
 	}
 }
 
-pub fn fill_next_descriptor(bd: &mut BlockDevice, desc: Descriptor) -> u16 {
+/// Was this device set up as read-only (VIRTIO_BLK_F_RO)? A device that
+/// doesn't exist can't be written to either, so that's read-only too.
+pub fn is_read_only(dev: usize) -> bool {
+	if dev == 0 || dev > 8 {
+		return true;
+	}
+	match BLOCK_DEVICES[dev - 1].lock().as_ref() {
+		Some(bdev) => bdev.read_only,
+		None => true,
+	}
+}
+
+/// How big is the device in bytes? This comes from the configuration
+/// space, which gives us the capacity in 512-byte sectors.
+pub fn capacity(dev: usize) -> Option<u64> {
+	if dev == 0 || dev > 8 {
+		return None;
+	}
+	unsafe {
+		match BLOCK_DEVICES[dev - 1].lock().as_ref() {
+			Some(bdev) => {
+				let config = bdev.dev.add(0x100 / 4) as *const Config;
+				Some((*config).capacity * 512)
+			},
+			None => None,
+		}
+	}
+}
+
+/// A process is going away, so nobody should wake it up when its
+/// requests finish. The requests themselves still have to complete,
+/// since the device owns their memory until then.
+pub fn clear_watcher(pid: u16) {
 	unsafe {
-		// The ring structure increments here first. This allows us to
-		// skip index 0, which then in the used ring will show that .id
-		// > 0. This is one way to error check. We will eventually get
-		// back to 0 as this index is cyclical. However, it shows if the
-		// first read/write actually works.
-		bd.idx = (bd.idx + 1) % VIRTIO_RING_SIZE as u16;
-		(*bd.queue).desc[bd.idx as usize] = desc;
-		if (*bd.queue).desc[bd.idx as usize].flags
-		   & virtio::VIRTIO_DESC_F_NEXT
-		   != 0
-		{
-			// If the next flag is set, we need another descriptor.
-			(*bd.queue).desc[bd.idx as usize].next =
-				(bd.idx + 1) % VIRTIO_RING_SIZE as u16;
+		for bdev in BLOCK_DEVICES.iter() {
+			if let Some(bdev) = bdev.lock().as_mut() {
+				for rq in bdev.outstanding.iter().chain(bdev.waiting.iter()) {
+					if !rq.is_null() && (**rq).watcher == pid {
+						(**rq).watcher = 0;
+					}
+				}
+			}
 		}
-		bd.idx
 	}
+	// Its PID could be handed out again before a wakeup we put off gets
+	// retried, and that wakeup isn't for the new process.
+	DEFERRED_WAKEUPS.lock().retain(|&(watcher, _)| watcher != pid);
 }
+
 /// This is now a common block operation for both reads and writes. Therefore,
 /// when one thing needs to change, we can change it for both reads and writes.
 /// There is a lot of error checking that I haven't done. The block device reads
-/// sectors at a time, which are 512 bytes. Therefore, our buffer must be
-/// capable of storing multiples of 512 bytes depending on the size. The size is
-/// also a multiple of 512, but we don't really check that.
+/// sectors at a time, which are 512 bytes. A read can start and end anywhere:
+/// we read the whole sectors into a bounce buffer and copy out just the part
+/// that was asked for when the request finishes. A write still has to be
+/// whole sectors, since writing part of one would mean reading it first.
+/// Asking for 0 bytes does nothing and succeeds.
 /// We DO however, check that we aren't writing to an R/O device. This would
 /// cause a I/O error if we tried to write to a R/O device.
+/// Requests don't always go to the device right away. If the ring is full,
+/// they wait in line, and a request with a higher priority (a process'
+/// priority) gets in line ahead of the ones with a lower priority.
 pub fn block_op(dev: usize,
                 buffer: *mut u8,
                 size: u32,
                 offset: u64,
                 write: bool,
-                watcher: u16)
+                watcher: u16,
+                priority: u8)
                 -> Result<u32, BlockErrors>
 {
+	submit(dev, buffer, size, offset, write, watcher, priority, false, null_mut())
+}
+
+/// block_op() for a /dev/block file. When the request is done, the watcher
+/// gets the number of bytes in A0, or -EIO. If owned isn't null, it's a
+/// kmalloc()ed buffer (usually buffer itself) that we kfree() then.
+pub fn file_op(dev: usize,
+               buffer: *mut u8,
+               size: u32,
+               offset: u64,
+               write: bool,
+               watcher: u16,
+               priority: u8,
+               owned: *mut u8)
+               -> Result<u32, BlockErrors>
+{
+	submit(dev, buffer, size, offset, write, watcher, priority, true, owned)
+}
+
+fn submit(dev: usize,
+          buffer: *mut u8,
+          size: u32,
+          offset: u64,
+          write: bool,
+          watcher: u16,
+          priority: u8,
+          file: bool,
+          owned: *mut u8)
+          -> Result<u32, BlockErrors>
+{
+	if size == 0 {
+		return Ok(0);
+	}
+	// Don't put a request into a ring the device has given up on.
+	virtio::reset_if_needed(dev);
 	unsafe {
-		if let Some(bdev) = BLOCK_DEVICES[dev - 1].as_mut() {
+		if let Some(bdev) = BLOCK_DEVICES[dev - 1].lock().as_mut() {
 			// Check to see if we are trying to write to a read only
 			// device.
 			if bdev.read_only && write {
 				println!("Trying to write to read/only!");
 				return Err(BlockErrors::ReadOnly);
 			}
-			if size % 512 != 0 {
+			let skip = (offset % 512) as usize;
+			let aligned = skip == 0 && size.is_multiple_of(512);
+			if write && !aligned {
 				return Err(BlockErrors::InvalidArgument);
 			}
 			let sector = offset / 512;
+			// The device only deals in whole sectors.
+			let dev_size = (skip + size as usize + 511) & !511;
+			let bounce = if aligned { null_mut() } else { kmalloc(dev_size) };
+			if !aligned && bounce.is_null() {
+				return Err(BlockErrors::OutOfMemory);
+			}
 			// TODO: Before we get here, we are NOT allowed to
 			// schedule a read or write OUTSIDE of the disk's size.
 			// So, we can read capacity from the configuration space
@@ -318,14 +424,11 @@ pub fn block_op(dev: usize,
 			let blk_request_size = size_of::<Request>();
 			let blk_request =
 				kmalloc(blk_request_size) as *mut Request;
-			let desc =
-				Descriptor { addr:  &(*blk_request).header
-				                    as *const Header
-				                    as u64,
-				             len:   size_of::<Header>() as u32,
-				             flags: virtio::VIRTIO_DESC_F_NEXT,
-				             next:  0, };
-			let head_idx = fill_next_descriptor(bdev, desc);
+			if blk_request.is_null() {
+				// kfree() doesn't mind a null bounce.
+				kfree(bounce);
+				return Err(BlockErrors::OutOfMemory);
+			}
 			(*blk_request).header.sector = sector;
 			// A write is an "out" direction, whereas a read is an
 			// "in" direction.
@@ -343,36 +446,16 @@ pub fn block_op(dev: usize,
 			(*blk_request).header.reserved = 0;
 			(*blk_request).status.status = 111;
 			(*blk_request).watcher = watcher;
-			let desc =
-				Descriptor { addr:  buffer as u64,
-				             len:   size,
-				             flags: virtio::VIRTIO_DESC_F_NEXT
-				                    | if !write {
-					                    virtio::VIRTIO_DESC_F_WRITE
-				                    }
-				                    else {
-					                    0
-				                    },
-				             next:  0, };
-			let _data_idx = fill_next_descriptor(bdev, desc);
-			let desc =
-				Descriptor { addr:  &(*blk_request).status
-				                    as *const Status
-				                    as u64,
-				             len:   size_of::<Status>() as u32,
-				             flags: virtio::VIRTIO_DESC_F_WRITE,
-				             next:  0, };
-			let _status_idx = fill_next_descriptor(bdev, desc);
-			(*bdev.queue).avail.ring[(*bdev.queue).avail.idx
-			                         as usize
-			                         % virtio::VIRTIO_RING_SIZE] = head_idx;
-			(*bdev.queue).avail.idx =
-				(*bdev.queue).avail.idx.wrapping_add(1);
-			// The only queue a block device has is 0, which is the
-			// request queue.
-			bdev.dev
-			    .add(MmioOffsets::QueueNotify.scale32())
-			    .write_volatile(0);
+			(*blk_request).bounce = bounce;
+			(*blk_request).buffer = buffer;
+			(*blk_request).skip = skip;
+			(*blk_request).size = size as usize;
+			(*blk_request).file = file;
+			(*blk_request).owned = owned;
+			(*blk_request).dev_size = dev_size;
+			(*blk_request).priority = priority;
+			enqueue(bdev, blk_request);
+			drain(bdev);
 			Ok(size)
 		}
 		else {
@@ -381,13 +464,76 @@ pub fn block_op(dev: usize,
 	}
 }
 
+/// Get in line behind every request with the same or a higher priority.
+fn enqueue(bd: &mut BlockDevice, rq: *mut Request) {
+	unsafe {
+		let pos = bd.waiting
+		            .iter()
+		            .position(|r| (**r).priority < (*rq).priority)
+		            .unwrap_or(bd.waiting.len());
+		bd.waiting.insert(pos, rq);
+	}
+}
+
+/// Hand waiting requests to the device, best first, until the ring is full.
+fn drain(bd: &mut BlockDevice) {
+	let capacity = bd.queue.size() as usize / REQUEST_DESCRIPTORS;
+	while bd.in_flight < capacity {
+		match bd.waiting.pop_front() {
+			Some(rq) => issue(bd, rq),
+			None => break,
+		}
+	}
+}
+
+/// Put rq into the ring. The data goes to or from the bounce buffer if
+/// there is one, otherwise straight to or from the caller's buffer.
+fn issue(bd: &mut BlockDevice, rq: *mut Request) {
+	unsafe {
+		let write = (*rq).header.blktype == VIRTIO_BLK_T_OUT;
+		let data = if (*rq).bounce.is_null() { (*rq).buffer } else { (*rq).bounce };
+		// We're holding the device's lock, so pending() can't see this
+		// finish before we know where it went.
+		let head_idx =
+			virtio::submit(&mut bd.queue,
+			               &[Descriptor { addr:  &(*rq).header as *const Header as u64,
+			                              len:   size_of::<Header>() as u32,
+			                              flags: 0,
+			                              next:  0, },
+			                 Descriptor { addr:  data as u64,
+			                              len:   (*rq).dev_size as u32,
+			                              flags: if !write {
+				                              virtio::VIRTIO_DESC_F_WRITE
+			                              }
+			                              else {
+				                              0
+			                              },
+			                              next:  0, },
+			                 Descriptor { addr:  &(*rq).status as *const Status as u64,
+			                              len:   size_of::<Status>() as u32,
+			                              flags: virtio::VIRTIO_DESC_F_WRITE,
+			                              next:  0, }]);
+		bd.outstanding[head_idx as usize] = rq;
+		bd.in_flight += 1;
+	}
+}
+
+/// How many requests for device number dev are waiting for room in the
+/// ring, and how many the device has. None if there's no such device.
+pub fn queue_depth(dev: usize) -> Option<(usize, usize)> {
+	if dev == 0 || dev > 8 {
+		return None;
+	}
+	BLOCK_DEVICES[dev - 1].lock().as_ref().map(|bdev| (bdev.waiting.len(), bdev.in_flight))
+}
+
 pub fn read(dev: usize,
             buffer: *mut u8,
             size: u32,
             offset: u64)
             -> Result<u32, BlockErrors>
 {
-	block_op(dev, buffer, size, offset, false, 0)
+	block_op(dev, buffer, size, offset, false, 0, DEFAULT_PRIORITY)
 }
 
 pub fn write(dev: usize,
@@ -396,7 +542,7 @@ pub fn write(dev: usize,
              offset: u64)
              -> Result<u32, BlockErrors>
 {
-	block_op(dev, buffer, size, offset, true, 0)
+	block_op(dev, buffer, size, offset, true, 0, DEFAULT_PRIORITY)
 }
 
 /// Here we handle block specific interrupts. Here, we need to check
@@ -406,62 +552,166 @@ pub fn pending(bd: &mut BlockDevice) {
 	// Here we need to check the used ring and then free the resources
 	// given by the descriptor id.
 	unsafe {
-		let ref queue = *bd.queue;
-		while bd.ack_used_idx != queue.used.idx {
-			let ref elem = queue.used.ring
-				[bd.ack_used_idx as usize % VIRTIO_RING_SIZE];
-			bd.ack_used_idx = bd.ack_used_idx.wrapping_add(1);
+		let queue = bd.queue;
+		while let Some(elem) = virtio::next_used(&queue, &mut bd.ack_used_idx) {
 			// Requests stay resident on the heap until this
 			// function, so we can recapture the address here
-			let rq = queue.desc[elem.id as usize].addr
+			let rq = queue.desc(elem.id as u16).addr
 			         as *const Request;
+			bd.outstanding[elem.id as usize] = null_mut();
+			bd.in_flight -= 1;
+			// A partial read went into a bounce buffer, so now the
+			// caller gets their part of it.
+			if !(*rq).bounce.is_null() {
+				if (*rq).status.status == VIRTIO_BLK_S_OK {
+					copy_nonoverlapping((*rq).bounce.add((*rq).skip), (*rq).buffer, (*rq).size);
+				}
+				kfree((*rq).bounce);
+			}
+			if !(*rq).owned.is_null() {
+				kfree((*rq).owned);
+			}
 
 			// A process might be waiting for this interrupt. Awaken
 			// the process attached here.
 			let pid_of_watcher = (*rq).watcher;
 			// A PID of 0 means that we don't have a watcher.
+			// delete_process() clears the watcher.
 			if pid_of_watcher > 0 {
-				set_running(pid_of_watcher);
-				let proc = get_by_pid(pid_of_watcher);
-				(*(*proc).frame).regs[10] = (*rq).status.status as usize;
-				// TODO: Set GpA0 to the value of the return
-				// status.
+				wake_watcher(pid_of_watcher, if !(*rq).file {
+					(*rq).status.status as usize
+				}
+				else if (*rq).status.status == VIRTIO_BLK_S_OK {
+					(*rq).size
+				}
+				else {
+					SysError::EIO.to_ret()
+				});
 			}
 			kfree(rq as *mut u8);
 		}
 	}
+	// That made room for whoever is waiting.
+	drain(bd);
 }
 
-/// The trap code will route PLIC interrupts 1..=8 for virtio devices. When
-/// virtio determines that this is a block device, it sends it here.
-pub fn handle_interrupt(idx: usize) {
+/// The device at idx is about to be reset, so none of its outstanding
+/// requests are ever going to finish, and the ones waiting for room in the
+/// ring will never get there. Fail them so that nobody waits on them
+/// forever.
+pub fn fail_outstanding(idx: usize) {
+	if let Some(bdev) = BLOCK_DEVICES[idx].lock().as_mut() {
+		for rq in bdev.outstanding.iter_mut() {
+			if !rq.is_null() {
+				fail(*rq);
+				*rq = null_mut();
+			}
+		}
+		bdev.in_flight = 0;
+		for rq in bdev.waiting.drain(..) {
+			fail(rq);
+		}
+	}
+}
+
+/// Give rq's watcher an I/O error and free everything that goes with rq.
+fn fail(rq: *mut Request) {
 	unsafe {
-		if let Some(bdev) = BLOCK_DEVICES[idx].as_mut() {
-			pending(bdev);
+		let pid_of_watcher = (*rq).watcher;
+		if pid_of_watcher > 0 {
+			wake_watcher(pid_of_watcher, if (*rq).file {
+				SysError::EIO.to_ret()
+			}
+			else {
+				VIRTIO_BLK_S_IOERR as usize
+			});
 		}
-		else {
-			println!(
-			         "Invalid block device for interrupt {}",
-			         idx + 1
-			);
+		if !(*rq).bounce.is_null() {
+			kfree((*rq).bounce);
 		}
+		if !(*rq).owned.is_null() {
+			kfree((*rq).owned);
+		}
+		kfree(rq as *mut u8);
+	}
+}
+
+// Wakeups that pending() or fail() couldn't hand out, because a kernel
+// process had the process list. Each is a PID and what goes in its A0.
+static DEFERRED_WAKEUPS: SpinLock<Vec<(u16, usize)>> = SpinLock::new(Vec::new());
+
+/// Put value in pid's A0 and let it run. We're usually in an interrupt, so
+/// we can't wait for the process list. If it's busy, the process could
+/// well still be there, so we hang onto the wakeup for retry_wakeups().
+/// If the process is really gone, there's nobody to tell.
+fn wake_watcher(pid: u16, value: usize) {
+	if !deliver_wakeup(pid, value) {
+		DEFERRED_WAKEUPS.lock().push((pid, value));
+	}
+}
+
+/// Returns false if we couldn't get the process list to find out.
+fn deliver_wakeup(pid: u16, value: usize) -> bool {
+	// We have the list, so this is set_running() without going back
+	// for it.
+	try_with_process(pid, |process| unsafe {
+		(*process.frame).regs[Registers::A0 as usize] = value;
+		process.state = ProcessState::Running;
+	}).is_some()
+}
+
+/// Give the wakeups the process list was too busy for another go. The
+/// timer calls this on every tick.
+pub fn retry_wakeups() {
+	DEFERRED_WAKEUPS.lock().retain(|&(pid, value)| !deliver_wakeup(pid, value));
+}
+
+/// The trap code will route PLIC interrupts 1..=8 for virtio devices. When
+/// virtio determines that this is a block device, it sends it here.
+pub fn handle_interrupt(idx: usize) {
+	if let Some(bdev) = BLOCK_DEVICES[idx].lock().as_mut() {
+		pending(bdev);
+	}
+	else {
+		println!(
+		         "Invalid block device for interrupt {}",
+		         idx + 1
+		);
 	}
 }
 
 // ///////////////////////////////////////////////
 // //  BLOCK PROCESSES (KERNEL PROCESSES)
 // ///////////////////////////////////////////////
+#[derive(Clone, Copy)]
 struct ProcArgs {
-	pub pid:    u16,
-	pub dev:    usize,
-	pub buffer: *mut u8,
-	pub size:   u32,
-	pub offset: u64,
+	pub pid:      u16,
+	pub dev:      usize,
+	pub buffer:   *mut u8,
+	pub size:     u32,
+	pub offset:   u64,
+	pub priority: u8,
+}
+
+/// Put args on the heap for the kernel process we're about to start. It
+/// gets them back with take_args(). Returns None if there's no room.
+fn give_args(args: ProcArgs) -> Option<usize> {
+	let heap_args = talloc::<ProcArgs>()?;
+	*heap_args = args;
+	Some(heap_args as *mut ProcArgs as usize)
+}
+
+/// Copy the arguments give_args() made out of the heap and free them.
+fn take_args(args_addr: usize) -> ProcArgs {
+	let heap_args = unsafe { &mut *(args_addr as *mut ProcArgs) };
+	let args = *heap_args;
+	tfree(heap_args);
+	args
 }
 
 /// This will be a
 fn read_proc(args_addr: usize) {
-	let args = unsafe { Box::from_raw(args_addr as *mut ProcArgs) };
+	let args = take_args(args_addr);
 	let _ = block_op(
 	                 args.dev,
 	                 args.buffer,
@@ -469,16 +719,22 @@ fn read_proc(args_addr: usize) {
 	                 args.offset,
 	                 false,
 	                 args.pid,
+	                 args.priority,
 	);
 	// This should be handled by the RA now.
 	// syscall_exit();
 }
 
+/// Read in a kernel process on behalf of pid, whose priority is priority.
+/// If we can't even get the arguments onto the heap, pid is left alone and
+/// we return OutOfMemory.
 pub fn process_read(pid: u16,
                     dev: usize,
                     buffer: *mut u8,
                     size: u32,
-                    offset: u64)
+                    offset: u64,
+                    priority: u8)
+                    -> Result<(), BlockErrors>
 {
 	// println!("Block read {}, {}, 0x{:x}, {}, {}", pid, dev, buffer as
 	// usize, size, offset);
@@ -488,17 +744,20 @@ pub fn process_read(pid: u16,
 		buffer,
 		size,
 		offset,
+		priority,
 	};
-	let boxed_args = Box::new(args);
+	let args_addr = give_args(args).ok_or(BlockErrors::OutOfMemory)?;
 	set_waiting(pid);
 	let _ = add_kernel_process_args(
 	                                read_proc,
-	                                Box::into_raw(boxed_args) as usize,
+	                                args_addr,
+	                                "block_read",
 	);
+	Ok(())
 }
 
 fn write_proc(args_addr: usize) {
-	let args = unsafe { Box::from_raw(args_addr as *mut ProcArgs) };
+	let args = take_args(args_addr);
 
 	let _ = block_op(
 	                 args.dev,
@@ -507,15 +766,19 @@ fn write_proc(args_addr: usize) {
 	                 args.offset,
 	                 true,
 	                 args.pid,
+	                 args.priority,
 	);
 	// syscall_exit();
 }
 
+/// The same as process_read(), but for a write.
 pub fn process_write(pid: u16,
                      dev: usize,
                      buffer: *mut u8,
                      size: u32,
-                     offset: u64)
+                     offset: u64,
+                     priority: u8)
+                     -> Result<(), BlockErrors>
 {
 	let args = ProcArgs {
 		pid,
@@ -523,11 +786,14 @@ pub fn process_write(pid: u16,
 		buffer,
 		size,
 		offset,
+		priority,
 	};
-	let boxed_args = Box::new(args);
+	let args_addr = give_args(args).ok_or(BlockErrors::OutOfMemory)?;
 	set_waiting(pid);
 	let _ = add_kernel_process_args(
 	                                write_proc,
-	                                Box::into_raw(boxed_args) as usize,
+	                                args_addr,
+	                                "block_write",
 	);
+	Ok(())
 }
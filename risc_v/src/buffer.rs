@@ -4,7 +4,7 @@
 // Stephen Marz
 
 use crate::{cpu::memcpy, kmem::{kmalloc, kfree}};
-use core::{ptr::null_mut, ops::{Index, IndexMut}};
+use core::{ptr::{copy, null_mut}, ops::{Deref, DerefMut}, slice};
 // We need a Buffer that can automatically be created and destroyed
 // in the lifetime of our read and write functions. In C, this would entail
 // goto statements that "unravel" all of the allocations that we made. Take
@@ -30,9 +30,26 @@ impl Buffer {
 		self.buffer
 	}
 
+	// A Buffer is never empty, so there's no is_empty().
+	#[allow(clippy::len_without_is_empty)]
 	pub fn len(&self) -> usize {
 		self.len
 	}
+
+	/// Copy src into this buffer starting at offset. This uses ptr::copy,
+	/// which is a memmove, so it is safe even if src came from this buffer.
+	/// If src doesn't fit, nothing is copied and we return false.
+	pub fn copy_from(&mut self, offset: usize, src: &[u8]) -> bool {
+		match offset.checked_add(src.len()) {
+			Some(end) if end <= self.len => {
+				unsafe {
+					copy(src.as_ptr(), self.buffer.add(offset), src.len());
+				}
+				true
+			}
+			_ => false,
+		}
+	}
 }
 
 impl Default for Buffer {
@@ -41,22 +58,30 @@ impl Default for Buffer {
 	}
 }
 
-impl Index<usize> for Buffer {
-	type Output = u8;
-	fn index(&self, idx: usize) -> &Self::Output {
-		unsafe {
-			self.get().add(idx).as_ref().unwrap()
+// Deref lets us treat a Buffer as a [u8], so we get the slice methods and
+// bounds-checked indexing for free. If kmalloc failed, we hand back an empty
+// slice rather than a slice over a null pointer.
+impl Deref for Buffer {
+	type Target = [u8];
+	fn deref(&self) -> &[u8] {
+		if self.buffer.is_null() {
+			&[]
+		}
+		else {
+			unsafe { slice::from_raw_parts(self.buffer, self.len) }
 		}
 	}
 }
 
-impl IndexMut<usize> for Buffer {
-	fn index_mut(&mut self, idx: usize) -> &mut Self::Output {
-		unsafe {
-			self.get_mut().add(idx).as_mut().unwrap()
+impl DerefMut for Buffer {
+	fn deref_mut(&mut self) -> &mut [u8] {
+		if self.buffer.is_null() {
+			&mut []
+		}
+		else {
+			unsafe { slice::from_raw_parts_mut(self.buffer, self.len) }
 		}
 	}
-
 }
 
 impl Clone for Buffer {
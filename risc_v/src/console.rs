@@ -3,9 +3,12 @@
 // Stephen Marz
 // 4 June 2020
 
-use alloc::collections::VecDeque;
+use alloc::{collections::VecDeque, vec::Vec};
 use crate::lock::Mutex;
-use crate::process::{get_by_pid, set_running};
+use crate::cpu::Registers;
+use crate::process::{try_with_process, ProcessState};
+use crate::syscall::{copy_to_user, syscall_read, user_table};
+use crate::uart::Uart;
 
 pub static mut IN_BUFFER: Option<VecDeque<u8>> = None;
 pub static mut OUT_BUFFER: Option<VecDeque<u8>> = None;
@@ -16,12 +19,23 @@ pub static mut OUT_LOCK: Mutex = Mutex::new();
 pub const DEFAULT_OUT_BUFFER_SIZE: usize = 10_000;
 pub const DEFAULT_IN_BUFFER_SIZE: usize = 1_000;
 
-pub static mut CONSOLE_QUEUE: Option<VecDeque<u16>> = None;
+/// A process blocked in read() on stdin. We remember where its buffer is so
+/// that we can hand it a line directly instead of waking it up to go look.
+pub struct StdinWaiter {
+    pub pid:  u16,
+    pub buf:  *mut u8,
+    pub size: usize,
+}
+
+// Readers waiting on stdin, first come, first served. This is protected by
+// IN_LOCK, just like IN_BUFFER.
+pub static mut CONSOLE_QUEUE: Option<VecDeque<StdinWaiter>> = None;
 
 pub fn init() {
     unsafe {
         IN_BUFFER.replace(VecDeque::with_capacity(DEFAULT_IN_BUFFER_SIZE));
         OUT_BUFFER.replace(VecDeque::with_capacity(DEFAULT_OUT_BUFFER_SIZE));
+        CONSOLE_QUEUE.replace(VecDeque::new());
     }
 }
 
@@ -60,13 +74,7 @@ pub fn push_stdin(c: u8) {
             if buf.len() < DEFAULT_IN_BUFFER_SIZE {
                 buf.push_back(c);
                 if c == 10 || c == 11 {
-                    if let Some(mut q) = CONSOLE_QUEUE.take() {
-                        for i in q.drain(..) {
-                            set_running(i);
-                            // We also need to put stuff in here.
-                        }
-                        CONSOLE_QUEUE.replace(q);
-                    }
+                    deliver_stdin(&mut buf);
                 }
             }
             IN_BUFFER.replace(buf);
@@ -88,11 +96,105 @@ pub fn pop_stdin() -> u8 {
     ret.unwrap_or(0)
 }
 
-pub fn push_queue(pid: u16) {
+/// Hand whole lines to the processes waiting on stdin, one line for each
+/// process in the order they started waiting. Each one gets its line copied
+/// right into its buffer and is the only one we wake up. Whatever's left,
+/// either because nobody is waiting or it isn't a whole line yet, stays in
+/// the buffer for the next read(). The caller holds IN_LOCK.
+unsafe fn deliver_stdin(buf: &mut VecDeque<u8>) {
+    if let Some(mut q) = CONSOLE_QUEUE.take() {
+        while let Some(end) = buf.iter().position(|c| *c == 10 || *c == 11) {
+            let waiter = match q.pop_front() {
+                Some(waiter) => waiter,
+                None => break,
+            };
+            // If the line won't fit, the rest of it goes to the next read().
+            let num_elements = (end + 1).min(waiter.size);
+            let delivered = try_with_process(waiter.pid, |process| {
+                let line: Vec<u8> = buf.drain(0..num_elements).collect();
+                let table = user_table(process.frame, process);
+                let bytes = copy_to_user(table, waiter.buf, &line);
+                (*process.frame).regs[Registers::A0 as usize] = bytes;
+                // We already have the list, so wake it up here rather
+                // than going back for it with set_running().
+                process.state = ProcessState::Running;
+            });
+            match delivered {
+                Some(Some(())) => {}
+                // It was killed while it was waiting.
+                Some(None) => continue,
+                // Someone has the process list, so we can't tell. The
+                // waiter keeps its place, and retry_stdin() tries again.
+                None => {
+                    q.push_front(waiter);
+                    break;
+                }
+            }
+        }
+        CONSOLE_QUEUE.replace(q);
+    }
+}
+
+/// Give deliver_stdin() another go. It stops when it can't get at the
+/// process list, and otherwise it wouldn't run again until the next line
+/// comes in, so the timer calls this on every tick. We're in a trap, so if
+/// someone has IN_LOCK, we'll get them next time.
+pub fn retry_stdin() {
+    unsafe {
+        if !IN_LOCK.try_lock() {
+            return;
+        }
+        if let Some(mut buf) = IN_BUFFER.take() {
+            if stdin_waiting() {
+                deliver_stdin(&mut buf);
+            }
+            IN_BUFFER.replace(buf);
+        }
+        IN_LOCK.unlock();
+    }
+}
+
+/// Is anyone waiting on stdin? The caller holds IN_LOCK.
+pub fn stdin_waiting() -> bool {
+    unsafe {
+        match CONSOLE_QUEUE.as_ref() {
+            Some(q) => !q.is_empty(),
+            None => false,
+        }
+    }
+}
+
+/// Put pid at the end of the line for stdin. When a line comes in,
+/// push_stdin() copies it into buf (at most size bytes). The caller holds
+/// IN_LOCK.
+pub fn push_queue(pid: u16, buf: *mut u8, size: usize) {
     unsafe {
         if let Some(mut q) = CONSOLE_QUEUE.take() {
-            q.push_back(pid);
+            q.push_back(StdinWaiter { pid, buf, size });
             CONSOLE_QUEUE.replace(q);
         }
     }
 }
+
+/// Get one character from the console in a kernel process. Input is line
+/// buffered, so if there's nothing left from the last line, we wait in
+/// line with everyone else reading stdin until the next one comes in. We
+/// go through read() so that the process sleeps instead of spinning.
+pub fn getc_blocking() -> u8 {
+    let mut c = 0u8;
+    loop {
+        if syscall_read(0, &mut c, 1) == 1 {
+            return c;
+        }
+    }
+}
+
+/// Put one character out on the console. Like println!, a newline goes out
+/// as a carriage return and a line feed.
+pub fn put(c: u8) {
+    let mut uart = Uart::default();
+    if c == 10 {
+        uart.put(13);
+    }
+    uart.put(c);
+}
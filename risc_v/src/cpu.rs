@@ -4,11 +4,44 @@
 // Stephen Marz
 // 14 October 2019
 
+use core::{arch::asm, sync::atomic::{AtomicU64, Ordering}};
+
 // The frequency of QEMU is 10 MHz
 pub const FREQ: u64 = 10_000_000;
-// Let's do this 250 times per second for switching
+// Let's do this 500 times per second for switching
 pub const CONTEXT_SWITCH_TIME: u64 = FREQ / 500;
 
+// This is what the timer actually uses. It starts at CONTEXT_SWITCH_TIME,
+// but set_context_switch_hz() can change it while we're running, which is
+// handy for watching the scheduler in slow motion.
+static CONTEXT_SWITCH_INTERVAL: AtomicU64 = AtomicU64::new(CONTEXT_SWITCH_TIME);
+
+/// Switch contexts hz times per second. Anything faster than FREQ is
+/// clamped to one mtime tick. A hz of 0 would mean never switching, so
+/// we refuse it and return false.
+pub fn set_context_switch_hz(hz: u32) -> bool {
+	if hz == 0 {
+		return false;
+	}
+	let interval = (FREQ / hz as u64).max(1);
+	CONTEXT_SWITCH_INTERVAL.store(interval, Ordering::Relaxed);
+	true
+}
+
+/// The number of mtime ticks between context switches right now.
+pub fn context_switch_time() -> u64 {
+	CONTEXT_SWITCH_INTERVAL.load(Ordering::Relaxed)
+}
+
+/// A process' quantum is measured in context switch periods. This
+/// converts a quantum into the number of mtime ticks that the timer
+/// needs to be armed for. A quantum of 0 is treated as 1 so that we
+/// never arm a timer that has already expired.
+pub fn quantum_to_mtime(qm: u16) -> u64 {
+	let qm = if qm == 0 { 1 } else { qm };
+	context_switch_time() * qm as u64
+}
+
 /// In 64-bit mode, we're given three different modes for the MMU:
 /// 0 - The MMU is off -- no protection and no translation PA = VA
 /// 8 - This is Sv39 mode -- 39-bit virtual addresses
@@ -141,6 +174,13 @@ impl TrapFrame {
 		            pid:    0,
 		            mode:   0, }
 	}
+
+	/// Does this frame's SATP turn translation on? User processes run
+	/// with their own page table, and kernel processes run with the MMU
+	/// off (SatpMode::Off), so their addresses are already physical.
+	pub fn is_translated(&self) -> bool {
+		self.satp >> 60 != SatpMode::Off as usize
+	}
 }
 
 /// The SATP register contains three fields: mode, address space id, and
@@ -155,135 +195,154 @@ pub const fn build_satp(mode: SatpMode, asid: usize, addr: usize) -> usize {
 
 pub fn mhartid_read() -> usize {
 	unsafe {
-		let rval;
-		llvm_asm!("csrr $0, mhartid" :"=r"(rval));
+		let rval: usize;
+		asm!("csrr {}, mhartid", out(reg) rval, options(nomem, nostack));
 		rval
 	}
 }
 pub fn mie_read() -> usize {
 	unsafe {
-		let rval;
-		llvm_asm!("csrr $0, mie" :"=r"(rval));
+		let rval: usize;
+		asm!("csrr {}, mie", out(reg) rval, options(nomem, nostack));
 		rval
 	}
 }
 
 pub fn mie_write(val: usize) {
 	unsafe {
-		llvm_asm!("csrw mie, $0" :: "r"(val));
+		asm!("csrw mie, {}", in(reg) val, options(nostack));
 	}
 }
 
 pub fn mstatus_write(val: usize) {
 	unsafe {
-		llvm_asm!("csrw	mstatus, $0" ::"r"(val));
+		asm!("csrw	mstatus, {}", in(reg) val, options(nostack));
 	}
 }
 
 pub fn mstatus_read() -> usize {
 	unsafe {
-		let rval;
-		llvm_asm!("csrr	$0, mstatus":"=r"(rval));
+		let rval: usize;
+		asm!("csrr	{}, mstatus", out(reg) rval, options(nomem, nostack));
 		rval
 	}
 }
 
+/// Turn off machine-mode interrupts on this hart. Returns whether they
+/// were on, which is what interrupts_restore() wants.
+pub fn interrupts_disable() -> bool {
+	unsafe {
+		let old: usize;
+		asm!("csrrci	{}, mstatus, 8", out(reg) old, options(nostack));
+		old & 8 != 0
+	}
+}
+
+/// Undo interrupts_disable().
+pub fn interrupts_restore(were_on: bool) {
+	if were_on {
+		unsafe {
+			asm!("csrsi	mstatus, 8", options(nostack));
+		}
+	}
+}
+
 pub fn stvec_write(val: usize) {
 	unsafe {
-		llvm_asm!("csrw	stvec, $0" ::"r"(val));
+		asm!("csrw	stvec, {}", in(reg) val, options(nostack));
 	}
 }
 
 pub fn stvec_read() -> usize {
 	unsafe {
-		let rval;
-		llvm_asm!("csrr	$0, stvec" :"=r"(rval));
+		let rval: usize;
+		asm!("csrr	{}, stvec", out(reg) rval, options(nomem, nostack));
 		rval
 	}
 }
 
 pub fn mscratch_write(val: usize) {
 	unsafe {
-		llvm_asm!("csrw	mscratch, $0" ::"r"(val));
+		asm!("csrw	mscratch, {}", in(reg) val, options(nostack));
 	}
 }
 
 pub fn mscratch_read() -> usize {
 	unsafe {
-		let rval;
-		llvm_asm!("csrr	$0, mscratch" : "=r"(rval));
+		let rval: usize;
+		asm!("csrr	{}, mscratch", out(reg) rval, options(nomem, nostack));
 		rval
 	}
 }
 
 pub fn mscratch_swap(to: usize) -> usize {
 	unsafe {
-		let from;
-		llvm_asm!("csrrw	$0, mscratch, $1" : "=r"(from) : "r"(to));
+		let from: usize;
+		asm!("csrrw	{}, mscratch, {}", out(reg) from, in(reg) to, options(nostack));
 		from
 	}
 }
 
 pub fn sscratch_write(val: usize) {
 	unsafe {
-		llvm_asm!("csrw	sscratch, $0" ::"r"(val));
+		asm!("csrw	sscratch, {}", in(reg) val, options(nostack));
 	}
 }
 
 pub fn sscratch_read() -> usize {
 	unsafe {
-		let rval;
-		llvm_asm!("csrr	$0, sscratch" : "=r"(rval));
+		let rval: usize;
+		asm!("csrr	{}, sscratch", out(reg) rval, options(nomem, nostack));
 		rval
 	}
 }
 
 pub fn sscratch_swap(to: usize) -> usize {
 	unsafe {
-		let from;
-		llvm_asm!("csrrw	$0, sscratch, $1" : "=r"(from) : "r"(to));
+		let from: usize;
+		asm!("csrrw	{}, sscratch, {}", out(reg) from, in(reg) to, options(nostack));
 		from
 	}
 }
 
 pub fn mepc_write(val: usize) {
 	unsafe {
-		llvm_asm!("csrw mepc, $0" :: "r"(val));
+		asm!("csrw mepc, {}", in(reg) val, options(nostack));
 	}
 }
 
 pub fn mepc_read() -> usize {
 	unsafe {
-		let rval;
-		llvm_asm!("csrr $0, mepc" :"=r"(rval));
+		let rval: usize;
+		asm!("csrr {}, mepc", out(reg) rval, options(nomem, nostack));
 		rval
 	}
 }
 
 pub fn sepc_write(val: usize) {
 	unsafe {
-		llvm_asm!("csrw sepc, $0" :: "r"(val));
+		asm!("csrw sepc, {}", in(reg) val, options(nostack));
 	}
 }
 
 pub fn sepc_read() -> usize {
 	unsafe {
-		let rval;
-		llvm_asm!("csrr $0, sepc" :"=r"(rval));
+		let rval: usize;
+		asm!("csrr {}, sepc", out(reg) rval, options(nomem, nostack));
 		rval
 	}
 }
 
 pub fn satp_write(val: usize) {
 	unsafe {
-		llvm_asm!("csrw satp, $0" :: "r"(val));
+		asm!("csrw satp, {}", in(reg) val, options(nostack));
 	}
 }
 
 pub fn satp_read() -> usize {
 	unsafe {
-		let rval;
-		llvm_asm!("csrr $0, satp" :"=r"(rval));
+		let rval: usize;
+		asm!("csrr {}, satp", out(reg) rval, options(nomem, nostack));
 		rval
 	}
 }
@@ -293,7 +352,7 @@ pub fn satp_read() -> usize {
 /// TLB.
 pub fn satp_fence(vaddr: usize, asid: usize) {
 	unsafe {
-		llvm_asm!("sfence.vma $0, $1" :: "r"(vaddr), "r"(asid));
+		asm!("sfence.vma {}, {}", in(reg) vaddr, in(reg) asid, options(nostack));
 	}
 }
 
@@ -306,7 +365,85 @@ pub fn satp_fence(vaddr: usize, asid: usize) {
 /// Intel/AMD's invtlb [] instruction.
 pub fn satp_fence_asid(asid: usize) {
 	unsafe {
-		llvm_asm!("sfence.vma zero, $0" :: "r"(asid));
+		asm!("sfence.vma zero, {}", in(reg) asid, options(nostack));
+	}
+}
+
+/// Flush the translation for one virtual address, in every address space.
+/// This is for when we've changed one page and don't know which ASID (if
+/// any) has it cached.
+pub fn satp_fence_vaddr(vaddr: usize) {
+	unsafe {
+		asm!("sfence.vma {}, zero", in(reg) vaddr, options(nostack));
+	}
+}
+
+/// Flush every translation for every address space. We only need this
+/// when we're about to reuse ASIDs. See asid_changed().
+pub fn satp_fence_all() {
+	unsafe {
+		asm!("sfence.vma zero, zero", options(nostack));
+	}
+}
+
+/// Make the instructions we've stored visible to instruction fetch on
+/// this hart. The instruction cache isn't kept coherent with stores, so
+/// anything that writes code and then runs it (the ELF loader, for one)
+/// has to call this in between. It only covers the hart we're on; another
+/// hart that might run the code needs its own fence.i.
+pub fn fence_i() {
+	unsafe {
+		asm!("fence.i", options(nostack));
+	}
+}
+
+// Each process' PID is its ASID, so the TLB can hold translations for
+// several processes at once and a context switch doesn't have to flush
+// anything. The TLB only goes stale when a process' page table changes, so
+// we remember which ASIDs have changed and fence just those the next time
+// we switch to them. This only works if an ASID is never handed to a
+// different address space without a fence. A PID wrapping around does a
+// global fence (satp_fence_all), and execve marks its ASID as changed.
+// There are 65536 ASIDs, so this is a bitmap of 1024 u64s.
+static mut ASID_STALE: [u64; 1024] = [0; 1024];
+
+/// Call this after changing the page table of the process using asid.
+pub fn asid_changed(asid: usize) {
+	let asid = asid & 0xffff;
+	unsafe {
+		ASID_STALE[asid / 64] |= 1 << (asid % 64);
+	}
+}
+
+/// Call this after changing a page table when we don't know whose it is.
+/// Every ASID gets fenced the next time it runs.
+pub fn asid_all_changed() {
+	unsafe {
+		for stale in ASID_STALE.iter_mut() {
+			*stale = !0;
+		}
+	}
+}
+
+/// Called on the way to running a process. If its page table changed since
+/// the last time, fence its ASID and only its ASID.
+pub fn fence_asid_if_stale(asid: usize) {
+	let asid = asid & 0xffff;
+	unsafe {
+		if ASID_STALE[asid / 64] & 1 << (asid % 64) != 0 {
+			ASID_STALE[asid / 64] &= !(1 << (asid % 64));
+			satp_fence_asid(asid);
+		}
+	}
+}
+
+/// Read the frame pointer (s0/fp). This only means something if we
+/// compile with -C force-frame-pointers, which .cargo/config sets.
+pub fn fp_read() -> usize {
+	unsafe {
+		let rval: usize;
+		asm!("mv {}, s0", out(reg) rval, options(nomem, nostack));
+		rval
 	}
 }
 
@@ -335,6 +472,8 @@ pub unsafe fn memcpy(dest: *mut u8, src: *const u8, bytes: usize) {
 /// Dumps the registers of a given trap frame. This is NOT the
 /// current CPU registers!
 pub fn dump_registers(frame: *const TrapFrame) {
+	let pid = unsafe { (*frame).pid as u16 };
+	println!("Registers of PID {} ({}):", pid, crate::process::name_of(pid));
 	print!("   ");
 	for i in 1..32 {
 		if i % 4 == 0 {
@@ -345,3 +484,38 @@ pub fn dump_registers(frame: *const TrapFrame) {
 	}
 	println!();
 }
+
+// We bound the backtrace so that a corrupt stack can't loop us forever.
+pub const MAX_BACKTRACE_DEPTH: usize = 32;
+// Any frame pointer outside of RAM is garbage. This is the 128M that
+// virt.lds gives us.
+const RAM_START: usize = 0x8000_0000;
+const RAM_END: usize = 0x8800_0000;
+
+/// Walk the frame pointer chain and print each return address. With frame
+/// pointers, the RISC-V calling convention puts the return address at
+/// fp - 8 and the caller's frame pointer at fp - 16. These are raw code
+/// addresses, so resolve them offline with
+/// addr2line -e target/riscv64gc-unknown-none-elf/debug/sos <addr>
+pub fn backtrace() {
+	println!("Backtrace:");
+	let mut fp = fp_read();
+	for depth in 0..MAX_BACKTRACE_DEPTH {
+		// Stop if the frame pointer can't possibly be a stack address.
+		if !fp.is_multiple_of(8) || !(RAM_START + 16..=RAM_END).contains(&fp) {
+			break;
+		}
+		let ra = unsafe { ((fp - 8) as *const usize).read() };
+		let prev_fp = unsafe { ((fp - 16) as *const usize).read() };
+		if ra == 0 {
+			break;
+		}
+		println!("  #{:<2} 0x{:016x}", depth, ra);
+		// The stack grows down, so each caller's frame must be above ours.
+		// If it isn't, the chain is corrupt (or we've hit the top).
+		if prev_fp <= fp {
+			break;
+		}
+		fp = prev_fp;
+	}
+}
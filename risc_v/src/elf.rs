@@ -5,10 +5,11 @@
 // Stephen Marz
 
 use crate::{buffer::Buffer,
-            cpu::{build_satp, memcpy, satp_fence_asid, CpuMode, Registers, SatpMode, TrapFrame},
-            page::{map, zalloc, EntryBits, Table, PAGE_SIZE},
-            process::{Process, ProcessData, ProcessState, NEXT_PID, STACK_ADDR, STACK_PAGES}};
-use alloc::collections::VecDeque;
+            cpu::{build_satp, fence_i, memcpy, satp_fence_asid, CpuMode, Registers, TrapFrame},
+            page::{dealloc, incref, map, zalloc, EntryBits, Table, PAGE_SIZE, PAGING_MODE},
+            process::{new_pid, Process, ProcessData, ProcessState, DEFAULT_PRIORITY, STACK_ADDR, STACK_PAGES}};
+use alloc::{collections::VecDeque, string::String};
+use core::mem::size_of;
 // Every ELF file starts with ELF "magic", which is a sequence of four bytes 0x7f followed by capital ELF, which is 0x45, 0x4c, and 0x46 respectively.
 pub const MAGIC: u32 = 0x464c_457f;
 
@@ -64,6 +65,11 @@ pub const PH_SEG_TYPE_DYNAMIC: u32 = 2;
 pub const PH_SEG_TYPE_INTERP: u32 = 3;
 pub const PH_SEG_TYPE_NOTE: u32 = 4;
 
+// The most memory one segment can ask for. Each segment is copied into a
+// kernel buffer before it's mapped, so memsz is really asking the kernel
+// heap for that much.
+pub const MAX_SEGMENT_SIZE: usize = 16 * 1024 * 1024;
+
 pub struct Program {
 	pub header: ProgramHeader,
 	pub data:   Buffer
@@ -73,7 +79,11 @@ pub enum LoadErrors {
 	Magic,
 	Machine,
 	TypeExec,
-	FileRead
+	FileRead,
+	// We ran out of memory for the program or the process.
+	OutOfMemory,
+	// We couldn't get a PID for the process.
+	NoPid
 }
 
 pub struct File {
@@ -83,6 +93,11 @@ pub struct File {
 
 impl File {
 	pub fn load(buffer: &Buffer) -> Result<Self, LoadErrors> {
+		// Everything in here comes from a file, so we check that it's all
+		// inside of the buffer before we read any of it.
+		if buffer.len() < size_of::<Header>() {
+			return Err(LoadErrors::FileRead);
+		}
 		let elf_hdr;
 		unsafe {
 			// Load the ELF
@@ -101,14 +116,25 @@ impl File {
 		if elf_hdr.obj_type != TYPE_EXEC {
 			return Err(LoadErrors::TypeExec);
 		}
-		let ph_tab = unsafe { buffer.get().add(elf_hdr.phoff) } as *const ProgramHeader;
+		// The program headers are phentsize bytes apart, which can be more
+		// than we know about, but not less.
+		let phentsize = elf_hdr.phentsize as usize;
+		if elf_hdr.phnum > 0 && phentsize < size_of::<ProgramHeader>() {
+			return Err(LoadErrors::FileRead);
+		}
+		match elf_hdr.phoff.checked_add(elf_hdr.phnum as usize * phentsize) {
+			Some(end) if end <= buffer.len() => {},
+			_ => return Err(LoadErrors::FileRead),
+		}
+		let ph_tab = unsafe { buffer.get().add(elf_hdr.phoff) };
 		// There are phnum number of program headers. We need to go through
 		// each one and load it into memory, if necessary.
 		let mut ret = Self { header:   *elf_hdr,
 		                     programs: VecDeque::new() };
 		for i in 0..elf_hdr.phnum as usize {
 			unsafe {
-				let ph = ph_tab.add(i).as_ref().unwrap();
+				// Nothing says phoff is aligned.
+				let ph = (ph_tab.add(i * phentsize) as *const ProgramHeader).read_unaligned();
 				// If the segment isn't marked as LOAD (loaded into memory),
 				// then there is no point to this. Most executables use a LOAD
 				// type for their program headers.
@@ -119,10 +145,28 @@ impl File {
 				if ph.memsz == 0 {
 					continue;
 				}
+				// The segment has to fit below the stack, and it can't be
+				// bigger than we're willing to allocate for it.
+				match ph.vaddr.checked_add(ph.memsz) {
+					Some(end) if ph.memsz <= MAX_SEGMENT_SIZE && end <= STACK_ADDR => {},
+					_ => return Err(LoadErrors::FileRead),
+				}
+				// Only filesz bytes are in the file. The rest of memsz (usually
+				// the .bss) needs to be zeroed. Reading memsz bytes out of the
+				// file would walk right off of the end of the buffer.
+				let file_end = match ph.off.checked_add(ph.filesz) {
+					Some(end) if ph.filesz <= ph.memsz && end <= buffer.len() => end,
+					_ => return Err(LoadErrors::FileRead),
+				};
 				let mut ph_buffer = Buffer::new(ph.memsz);
-
-				memcpy(ph_buffer.get_mut(), buffer.get().add(ph.off), ph.memsz);
-				ret.programs.push_back(Program { header: *ph,
+				if ph_buffer.get().is_null() {
+					return Err(LoadErrors::OutOfMemory);
+				}
+				for b in ph_buffer.iter_mut() {
+					*b = 0;
+				}
+				ph_buffer.copy_from(0, &buffer[ph.off..file_end]);
+				ret.programs.push_back(Program { header: ph,
 				                                 data:   ph_buffer });
 			}
 		}
@@ -130,54 +174,82 @@ impl File {
 	}
 
 	// load
-	pub fn load_proc(buffer: &Buffer) -> Result<Process, LoadErrors> {
-		let elf_fl = Self::load(&buffer);
+	pub fn load_proc(buffer: &Buffer, path: &str) -> Result<Process, LoadErrors> {
+		let my_pid = new_pid();
+		if my_pid == 0 {
+			return Err(LoadErrors::NoPid);
+		}
+		Self::load_image(buffer, my_pid, path)
+	}
+
+	/// Build a process for the ELF file in buffer, but give it the PID we're
+	/// told instead of a new one. execve uses this to build the new image of
+	/// a process that already exists, and then it swaps the image in.
+	/// The program is copied in with stores, so we fence.i after each
+	/// segment. That only covers this hart, which is fine while hart 0 is
+	/// the only one running processes. The process is named after the
+	/// last component of path.
+	pub fn load_image(buffer: &Buffer, my_pid: u16, path: &str) -> Result<Process, LoadErrors> {
+		let elf_fl = Self::load(buffer);
 		if elf_fl.is_err() {
 			return Err(elf_fl.err().unwrap());
 		}
 		let elf_fl = elf_fl.ok().unwrap();
-		let mut sz = 0usize;
-		// Get the size, in memory, that we're going to need for the program storage.
-		for p in elf_fl.programs.iter() {
-			sz += p.header.memsz;
+		// Dropping a Process frees these, but it expects all of them to be
+		// there, so if any is missing, we give back the rest ourselves.
+		let frame = zalloc(1);
+		let stack = zalloc(STACK_PAGES);
+		let mmu_table = zalloc(1);
+		if frame.is_null() || stack.is_null() || mmu_table.is_null() {
+			for page in [frame, stack, mmu_table].iter() {
+				if !page.is_null() {
+					dealloc(*page);
+				}
+			}
+			return Err(LoadErrors::OutOfMemory);
 		}
-		// We add two pages since we could possibly split the front and back pages, hence
-		// necessitating the need for two extra pages. This can get wasteful, but for now
-		// if we don't do this, we could end up mapping into the MMU table!
-		let program_pages = (sz + PAGE_SIZE * 2) / PAGE_SIZE;
-		// I did this to demonstrate the expressive nature of Rust. Kinda cool, no?
-		let my_pid = unsafe {
-			let p = NEXT_PID + 1;
-			NEXT_PID += 1;
-			p
-		};
-		let mut my_proc = Process { frame:       zalloc(1) as *mut TrapFrame,
-		                            stack:       zalloc(STACK_PAGES),
+		let mut my_proc = Process { frame:       frame as *mut TrapFrame,
+		                            stack,
 		                            pid:         my_pid,
-		                            mmu_table:        zalloc(1) as *mut Table,
+		                            mmu_table:        mmu_table as *mut Table,
 		                            state:       ProcessState::Running,
 		                            data:        ProcessData::new(),
 		                            sleep_until: 0,
-									program:     zalloc(program_pages),
 									brk:         0,
+									heap:        0,
+									priority:    DEFAULT_PRIORITY,
+									name:        String::from(path.rsplit('/').next().unwrap_or(path)),
 								 };
 
-		let program_mem = my_proc.program;
 		let table = unsafe { my_proc.mmu_table.as_mut().unwrap() };
 		// The ELF has several "program headers". This usually mimics the .text,
 		// .rodata, .data, and .bss sections, but not necessarily.
 		// What we do here is map the program headers into the process' page
 		// table.
 		for p in elf_fl.programs.iter() {
-			// The program header table starts where the ELF header says it is
-			// given by the field phoff (program header offset).
+			// Each segment gets its own memory, sized for the pages its
+			// memsz bytes touch at its virtual address. The vaddr doesn't
+			// have to start on a page, so the data starts that far into the
+			// first page. Once it's mapped, the mapping owns it, and
+			// Process::drop() frees it with the rest of the page table.
+			let page_offset = p.header.vaddr % PAGE_SIZE;
+			let pages = (page_offset + p.header.memsz).div_ceil(PAGE_SIZE);
+			let segment_mem = zalloc(pages);
+			// my_proc frees whatever we've mapped so far when it's dropped.
+			if segment_mem.is_null() {
+				return Err(LoadErrors::OutOfMemory);
+			}
 			// Copy the buffer we got from the filesystem into the program
 			// memory we're going to map to the user. The memsz field in the
-			// program header tells us how many bytes will need to be loaded.
-			// The ph.off is the offset to load this into.
+			// program header tells us how many bytes will need to be loaded,
+			// and load() already zeroed the part that isn't in the file.
 			unsafe {
-				memcpy(program_mem.add(p.header.off), p.data.get(), p.header.memsz);
+				memcpy(segment_mem.add(page_offset), p.data.get(), p.header.memsz);
 			}
+			// We just wrote instructions with ordinary stores. The
+			// process will fetch them through the instruction cache,
+			// which might still hold whatever used to be in these pages.
+			fence_i();
 			// We start off with the user bit set.
 			let mut bits = EntryBits::User.val();
 			// This sucks, but we check each bit in the flags to see
@@ -192,35 +264,32 @@ impl File {
 				bits |= EntryBits::Write.val();
 			}
 			// Now we map the program counter. The virtual address
-			// is provided in the ELF program header.
-			let pages = (p.header.memsz + PAGE_SIZE) / PAGE_SIZE;
+			// is provided in the ELF program header. We only map the
+			// pages we just allocated, so we can't run off the end.
+			let first_page = p.header.vaddr - page_offset;
 			for i in 0..pages {
-				let vaddr = p.header.vaddr + i * PAGE_SIZE;
-				// The ELF specifies a paddr, but not when we
-				// use the vaddr!
-				let paddr = program_mem as usize + p.header.off + i * PAGE_SIZE;
-				// There is no checking here! This is very dangerous, and I have already
-				// been bitten by it. I mapped too far and mapped userspace into the MMU
-				// table, which is AWFUL!
+				let vaddr = first_page + i * PAGE_SIZE;
+				let paddr = segment_mem as usize + i * PAGE_SIZE;
 				map(table, vaddr, paddr, bits, 0);
-				if vaddr > my_proc.brk {
-					my_proc.brk = vaddr;
-				}
 				// println!("DEBUG: Map 0x{:08x} to 0x{:08x} {:02x}", vaddr, paddr, bits);
 			}
-			my_proc.brk += 0x1000;
+			my_proc.brk = my_proc.brk.max(first_page + pages * PAGE_SIZE);
 		}
+		// The heap starts out empty, right after the program.
+		my_proc.heap = my_proc.brk;
 		// This will map all of the program pages. Notice that in linker.lds in
 		// userspace we set the entry point address to 0x2000_0000. This is the
 		// same address as PROCESS_STARTING_ADDR, and they must match.
 		// Map the stack
-		let ptr = my_proc.stack as *mut u8;
+		let ptr = my_proc.stack;
 		for i in 0..STACK_PAGES {
 			let vaddr = STACK_ADDR + i * PAGE_SIZE;
 			let paddr = ptr as usize + i * PAGE_SIZE;
 			// We create the stack. We don't load a stack from the disk.
 			// This is why I don't need to make the stack executable.
 			map(table, vaddr, paddr, EntryBits::UserReadWrite.val(), 0);
+			// my_proc.stack is one owner and the mapping is another.
+			incref(paddr as *mut u8);
 		}
 		// Set everything up in the trap frame
 		unsafe {
@@ -229,7 +298,7 @@ impl File {
 			(*my_proc.frame).pc = elf_fl.header.entry_addr;
 			// Stack pointer. The stack starts at the bottom and works its
 			// way up, so we have to set the stack pointer to the bottom.
-			(*my_proc.frame).regs[Registers::Sp as usize] = STACK_ADDR as usize + STACK_PAGES * PAGE_SIZE - 0x1000;
+			(*my_proc.frame).regs[Registers::Sp as usize] = STACK_ADDR + STACK_PAGES * PAGE_SIZE - 0x1000;
 			// USER MODE! This is how we set what'll go into mstatus when we
 			// run the process.
 			(*my_proc.frame).mode = CpuMode::User as usize;
@@ -238,7 +307,7 @@ impl File {
 			// map our table into that register. The switch_to_user
 			// function will load .satp into the actual register
 			// when the time comes.
-			(*my_proc.frame).satp = build_satp(SatpMode::Sv39, my_proc.pid as usize, my_proc.mmu_table as usize);
+			(*my_proc.frame).satp = build_satp(PAGING_MODE.satp_mode(), my_proc.pid as usize, my_proc.mmu_table as usize);
 		}
 		// The ASID field of the SATP register is only 16-bits, and we reserved
 		// 0 for the kernel, even though we run the kernel in machine mode for
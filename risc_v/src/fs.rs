@@ -3,19 +3,27 @@
 // Stephen Marz
 // 16 March 2020
 
-use crate::{cpu::Registers,
-            process::{add_kernel_process_args, get_by_pid, set_running, set_waiting},
-            syscall::syscall_block_read};
+use crate::{block::{capacity, is_read_only, VIRTIO_BLK_S_OK},
+            kmem::{talloc, tfree},
+            process::{add_kernel_worker, set_waiting, Completion},
+            sched::PreemptGuard,
+            syscall::{syscall_block_read, syscall_block_write}};
 
 use crate::{buffer::Buffer, cpu::memcpy};
-use alloc::{boxed::Box, collections::BTreeMap, string::String};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
 use core::mem::size_of;
 
 pub const MAGIC: u16 = 0x4d5a;
+// The smallest block size, and where the superblock always sits no matter
+// what the block size is. The real block size comes out of the superblock,
+// see Geometry.
 pub const BLOCK_SIZE: u32 = 1024;
-pub const NUM_IPTRS: usize = BLOCK_SIZE as usize / 4;
+// The file type is the top four bits of the mode.
+pub const S_IFMT: u16 = 0o170_000;
 pub const S_IFDIR: u16 = 0o040_000;
 pub const S_IFREG: u16 = 0o100_000;
+// The rwx bits for user, group, and other plus setuid, setgid, and sticky.
+pub const S_IPERM: u16 = 0o7777;
 /// The superblock describes the file system on the disk. It gives
 /// us all the information we need to read the file system and navigate
 /// the file system, including where to find the inodes and zones (blocks).
@@ -65,77 +73,150 @@ pub struct DirEntry {
 	pub name:  [u8; 60]
 }
 
+/// An open file on a Minix file system, which is what goes into a
+/// process' file descriptor. The offset is in bytes.
+pub struct MinixFile {
+	pub bdev:   usize,
+	pub inode:  Inode,
+	pub offset: u32,
+}
+
 /// The MinixFileSystem implements the FileSystem trait for the VFS.
 pub struct MinixFileSystem;
 // The plan for this in the future is to have a single inode cache. What we
 // will do is have a cache of Node structures which will combine the Inode
-// with the block drive.
-static mut MFS_INODE_CACHE: [Option<BTreeMap<String, Inode>>; 8] = [None, None, None, None, None, None, None, None];
+// with the block drive. For now, each path maps to its inode number, which
+// is what put_inode() needs, and a copy of the inode.
+static mut MFS_INODE_CACHE: [Option<BTreeMap<String, (u32, Inode)>>; 8] = [None, None, None, None, None, None, None, None];
+
+// Indirect zones are just blocks full of zone pointers. A sequential read of
+// a big file walks the same pointer blocks over and over, once per read()
+// call, so we keep the last few we've loaded for each block device. This is
+// keyed by zone number. Nothing writes pointer blocks yet, so nothing here
+// ever goes stale. Once write() allocates zones, it has to call
+// invalidate_ptr_cache().
+const PTR_CACHE_SIZE: usize = 16;
+static mut MFS_PTR_CACHE: [Option<BTreeMap<u32, Buffer>>; 8] = [None, None, None, None, None, None, None, None];
+
+/// Minix 3 can be made with 1, 2, or 4 KiB blocks, and a zone can be more
+/// than one block (2^log_zone_size of them). Inodes, bitmaps, and pointer
+/// blocks are counted in blocks, but inode.zones[] and the pointer blocks
+/// hold zone numbers.
+#[derive(Copy, Clone)]
+struct Geometry {
+	block_size: u32,
+	zone_size:  u32,
+}
+
+impl Geometry {
+	fn new(super_block: &SuperBlock) -> Option<Self> {
+		let block_size = super_block.block_size as u32;
+		if block_size != 1024 && block_size != 2048 && block_size != 4096 {
+			return None;
+		}
+		// A zone bigger than this is a corrupt superblock, not a real file system.
+		if super_block.log_zone_size > 4 {
+			return None;
+		}
+		Some(Self { block_size,
+		            zone_size: block_size << super_block.log_zone_size })
+	}
+
+	/// How many zone pointers fit in an indirect block.
+	fn num_iptrs(&self) -> usize {
+		self.block_size as usize / size_of::<u32>()
+	}
+}
+
+// Filled in by check_super_block() when we mount.
+static mut MFS_GEOMETRY: [Option<Geometry>; 8] = [None, None, None, None, None, None, None, None];
 
 impl MinixFileSystem {
-	/// Inodes are the meta-data of a file, including the mode (permissions and type) and
-	/// the file's size. They are stored above the data zones, but to figure out where we
-	/// need to go to get the inode, we first need the superblock, which is where we can
-	/// find all of the information about the filesystem itself.
-	pub fn get_inode(bdev: usize, inode_num: u32) -> Option<Inode> {
+	/// Where on the disk, in bytes, is inode number inode_num? Inodes are
+	/// stored above the data zones, but to figure out where, we first need the
+	/// superblock, which is where we can find all of the information about the
+	/// filesystem itself. get_inode() and put_inode() both come through here,
+	/// so they can't disagree about where an inode lives.
+	fn inode_offset(bdev: usize, inode_num: u32) -> Option<usize> {
+		// The inode comes to us as a NUMBER, not an index. There is no inode 0.
+		if inode_num == 0 {
+			return None;
+		}
 		// When we read, everything needs to be a multiple of a sector (512 bytes)
 		// So, we need to have memory available that's at least 512 bytes, even if
-		// we only want 10 bytes or 32 bytes (size of an Inode).
-		let mut buffer = Buffer::new(1024);
-
-		// Here is a little memory trick. We have a reference and it will refer to the
-		// top portion of our buffer. Since we won't be using the super block and inode
-		// simultaneously, we can overlap the memory regions.
-
-		// For Rust-ers, I'm showing two ways here. The first way is to get a reference
-		// from a pointer. You will see the &* a lot in Rust for references. Rust
-		// makes dereferencing a pointer cumbersome, which lends to not using them.
+		// we only want the superblock.
+		let mut buffer = Buffer::new(512);
+		// For Rust-ers, this is how we get a reference from a pointer. You will see
+		// the &* a lot in Rust for references. Rust makes dereferencing a pointer
+		// cumbersome, which lends to not using them.
 		let super_block = unsafe { &*(buffer.get_mut() as *mut SuperBlock) };
-		// I opted for a pointer here instead of a reference because we will be offsetting the inode by a certain amount.
-		let inode = buffer.get_mut() as *mut Inode;
 		// Read from the block device. The size is 1 sector (512 bytes) and our offset is past
 		// the boot block (first 1024 bytes). This is where the superblock sits.
-		syc_read(bdev, buffer.get_mut(), 512, 1024);
-		if super_block.magic == MAGIC {
-			// If we get here, we successfully read what we think is the super block.
-			// The math here is 2 - one for the boot block, one for the super block. Then we
-			// have to skip the bitmaps blocks. We have a certain number of inode map blocks (imap)
-			// and zone map blocks (zmap).
-			// The inode comes to us as a NUMBER, not an index. So, we need to subtract 1.
-			let inode_offset = (2 + super_block.imap_blocks + super_block.zmap_blocks) as usize * BLOCK_SIZE as usize
-			                   + ((inode_num as usize - 1) / (BLOCK_SIZE as usize / size_of::<Inode>())) * BLOCK_SIZE as usize;
-
-			// Now, we read the inode itself.
-			// The block driver requires that our offset be a multiple of 512. We do that with the
-			// inode_offset. However, we're going to be reading a group of inodes.
-			syc_read(bdev, buffer.get_mut(), 1024, inode_offset as u32);
-
-			// There are 1024 / size_of<Inode>() inodes in each read that we can do. However, we need to figure out which inode in that group we need to read. We just take the % of this to find out.
-			let read_this_node = (inode_num as usize - 1) % (BLOCK_SIZE as usize / size_of::<Inode>());
-
-			// We copy the inode over. This might not be the best thing since the Inode will
-			// eventually have to change after writing.
-			return unsafe { Some(*(inode.add(read_this_node))) };
-		}
-		// If we get here, some result wasn't OK. Either the super block
-		// or the inode itself.
-		None
+		syc_read(bdev, buffer.get_mut(), 512, BLOCK_SIZE);
+		if super_block.magic != MAGIC || inode_num > super_block.ninodes {
+			return None;
+		}
+		// Inodes are packed into blocks, so we need to know how big a block is.
+		let block_size = Geometry::new(super_block)?.block_size as usize;
+		// The math here is 2 - one for the boot block, one for the super block. Then we
+		// have to skip the bitmaps blocks. We have a certain number of inode map blocks (imap)
+		// and zone map blocks (zmap). The super block is at byte 1024 whatever the block
+		// size is, but the bitmaps always start at block 2. The inodes are packed one
+		// after the other from there.
+		Some((2 + super_block.imap_blocks as usize + super_block.zmap_blocks as usize) * block_size
+		     + (inode_num as usize - 1) * size_of::<Inode>())
+	}
+
+	/// Read inode number inode_num off of the disk. We read the whole
+	/// sector that it's in, since the block driver only does whole sectors.
+	pub fn get_inode(bdev: usize, inode_num: u32) -> Option<Inode> {
+		let offset = Self::inode_offset(bdev, inode_num)?;
+		let sector = offset & !511;
+		let mut buffer = Buffer::new(512);
+		syc_read(bdev, buffer.get_mut(), 512, sector as u32);
+		// Inodes are 64 bytes, so one never hangs over the end of a sector.
+		unsafe { Some((buffer.get().add(offset - sector) as *const Inode).read()) }
+	}
+
+	/// Write inode back to the disk as inode number inode_num. Other inodes
+	/// share its sector, so we read the sector, change just this inode, and
+	/// write the sector back. Run this ONLY in a process, just like reading.
+	/// The inode cache that open() uses isn't updated.
+	pub fn put_inode(bdev: usize, inode_num: u32, inode: &Inode) -> Result<(), FsError> {
+		Self::check_writable(bdev)?;
+		let offset = match Self::inode_offset(bdev, inode_num) {
+			Some(offset) => offset,
+			None => return Err(FsError::FileNotFound),
+		};
+		let sector = offset & !511;
+		let mut buffer = Buffer::new(512);
+		if syc_read(bdev, buffer.get_mut(), 512, sector as u32) != VIRTIO_BLK_S_OK {
+			return Err(FsError::Io);
+		}
+		unsafe {
+			(buffer.get_mut().add(offset - sector) as *mut Inode).write(*inode);
+		}
+		if syc_write(bdev, buffer.get(), 512, sector as u32) != VIRTIO_BLK_S_OK {
+			return Err(FsError::Io);
+		}
+		Ok(())
 	}
 }
 
 impl MinixFileSystem {
 	/// Init is where we would cache the superblock and inode to avoid having to read
 	/// it over and over again, like we do for read right now.
-	fn cache_at(btm: &mut BTreeMap<String, Inode>, cwd: &String, inode_num: u32, bdev: usize) {
+	fn cache_at(btm: &mut BTreeMap<String, (u32, Inode)>, cwd: &str, inode_num: u32, bdev: usize) {
 		let ino = Self::get_inode(bdev, inode_num).unwrap();
-		let mut buf = Buffer::new(((ino.size + BLOCK_SIZE - 1) & !BLOCK_SIZE) as usize);
+		let zone_size = Self::geometry(bdev).zone_size;
+		let mut buf = Buffer::new(((ino.size + zone_size - 1) & !(zone_size - 1)) as usize);
 		let dirents = buf.get() as *const DirEntry;
-		let sz = Self::read(bdev, &ino, buf.get_mut(), BLOCK_SIZE, 0);
+		let sz = Self::read(bdev, &ino, buf.get_mut(), buf.len() as u32, 0);
 		let num_dirents = sz as usize / size_of::<DirEntry>();
 		// We start at 2 because the first two entries are . and ..
 		for i in 2..num_dirents {
 			unsafe {
-				let ref d = *dirents.add(i);
+				let d = &*dirents.add(i);
 				let d_ino = Self::get_inode(bdev, d.inode).unwrap();
 				let mut new_cwd = String::with_capacity(120);
 				for i in cwd.bytes() {
@@ -160,20 +241,81 @@ impl MinixFileSystem {
 					Self::cache_at(btm, &new_cwd, d.inode, bdev);
 				}
 				else {
-					btm.insert(new_cwd, d_ino);
+					btm.insert(new_cwd, (d.inode, d_ino));
 				}
 			}
 		}
 	}
 
-	// Run this ONLY in a process!
-	pub fn init(bdev: usize) {
+	/// Read the superblock and make sure this really is a Minix 3 file system
+	/// before we believe anything else it says. Besides the magic, the bitmaps
+	/// and inode table have to fit on the device, or else get_inode() will go
+	/// reading past the end of it.
+	fn check_super_block(bdev: usize) -> Result<(), FsError> {
+		let mut buffer = Buffer::new(1024);
+		let super_block = unsafe { &*(buffer.get_mut() as *mut SuperBlock) };
+		syc_read(bdev, buffer.get_mut(), 512, 1024);
+		if super_block.magic != MAGIC {
+			println!("KERNEL: Block device {} is not Minix 3 (magic 0x{:04x})", bdev, super_block.magic);
+			return Err(FsError::NotMinix);
+		}
+		let geometry = match Geometry::new(super_block) {
+			Some(geometry) => geometry,
+			None => {
+				println!(
+				         "KERNEL: Block device {} has an unsupported block size ({} bytes, log zone size {})",
+				         bdev, super_block.block_size, super_block.log_zone_size
+				);
+				return Err(FsError::NotMinix);
+			},
+		};
+		let inodes_per_block = geometry.block_size as usize / size_of::<Inode>();
+		let inode_blocks = (super_block.ninodes as usize).div_ceil(inodes_per_block);
+		// Boot block, super block, both bitmaps, then the inodes themselves.
+		let needed = (2 + super_block.imap_blocks as usize + super_block.zmap_blocks as usize + inode_blocks) as u64 * geometry.block_size as u64;
+		let capacity = capacity(bdev).unwrap_or(0);
+		if super_block.ninodes == 0 || super_block.imap_blocks == 0 || super_block.zmap_blocks == 0 || needed > capacity {
+			println!(
+			         "KERNEL: Block device {} has a corrupt superblock ({} inodes, {} imap, {} zmap, {} bytes)",
+			         bdev,
+			         super_block.ninodes,
+			         super_block.imap_blocks,
+			         super_block.zmap_blocks,
+			         capacity
+			);
+			return Err(FsError::NotMinix);
+		}
+		let _guard = PreemptGuard::new();
+		unsafe {
+			MFS_GEOMETRY[bdev - 1] = Some(geometry);
+		}
+		Ok(())
+	}
+
+	/// The block and zone sizes of a mounted file system. Before init(), we
+	/// assume the smallest.
+	fn geometry(bdev: usize) -> Geometry {
+		unsafe { MFS_GEOMETRY[bdev - 1] }.unwrap_or(Geometry { block_size: BLOCK_SIZE,
+		                                                       zone_size:  BLOCK_SIZE, })
+	}
+
+	/// How many bytes each zone pointer covers. read() goes a zone at a time.
+	pub fn zone_size(bdev: usize) -> u32 {
+		Self::geometry(bdev).zone_size
+	}
+
+	/// Run this ONLY in a process! This mounts the file system on bdev by
+	/// caching its inodes. If the superblock doesn't check out, nothing is
+	/// cached and open() won't find anything.
+	pub fn init(bdev: usize) -> Result<(), FsError> {
 		if unsafe { MFS_INODE_CACHE[bdev - 1].is_none() } {
+			Self::check_super_block(bdev)?;
 			let mut btm = BTreeMap::new();
 			let cwd = String::from("/");
 
 			// Let's look at the root (inode #1)
 			Self::cache_at(&mut btm, &cwd, 1, bdev);
+			let _guard = PreemptGuard::new();
 			unsafe {
 				MFS_INODE_CACHE[bdev - 1] = Some(btm);
 			}
@@ -181,16 +323,31 @@ impl MinixFileSystem {
 		else {
 			println!("KERNEL: Initialized an already initialized filesystem {}", bdev);
 		}
+		Ok(())
+	}
+
+	/// Has init() cached bdev's inodes yet? While someone is looking a path
+	/// up, the cache is taken, so this can say no for a moment even then.
+	pub fn is_mounted(bdev: usize) -> bool {
+		unsafe { MFS_INODE_CACHE[bdev - 1].is_some() }
 	}
 
 	/// The goal of open is to traverse the path given by path. If we cache the inodes
 	/// in RAM, it might make this much quicker. For now, this doesn't do anything since
 	/// we're just testing read based on if we know the Inode we're looking for.
 	pub fn open(bdev: usize, path: &str) -> Result<Inode, FsError> {
+		Self::lookup(bdev, path).map(|(_, inode)| inode)
+	}
+
+	/// open(), but we also want the inode number.
+	fn lookup(bdev: usize, path: &str) -> Result<(u32, Inode), FsError> {
+		// The cache is None while we have it. Don't let the timer switch to
+		// someone else who would then think there is no cache.
+		let _guard = PreemptGuard::new();
 		if let Some(cache) = unsafe { MFS_INODE_CACHE[bdev - 1].take() } {
 			let ret;
-			if let Some(inode) = cache.get(path) {
-				ret = Ok(*inode);
+			if let Some(entry) = cache.get(path) {
+				ret = Ok(*entry);
 			}
 			else {
 				ret = Err(FsError::FileNotFound);
@@ -205,13 +362,110 @@ impl MinixFileSystem {
 		}
 	}
 
+	/// The entries of the directory inode, . and .. included, as names and
+	/// inode numbers. Run this ONLY in a process, since it reads the disk.
+	fn dir_entries(bdev: usize, inode: &Inode) -> Vec<(String, u32)> {
+		let zone_size = Self::geometry(bdev).zone_size;
+		let mut buf = Buffer::new(((inode.size + zone_size - 1) & !(zone_size - 1)) as usize);
+		let dirents = buf.get() as *const DirEntry;
+		let sz = Self::read(bdev, inode, buf.get_mut(), buf.len() as u32, 0);
+		let mut ret = Vec::new();
+		for i in 0..sz as usize / size_of::<DirEntry>() {
+			let d = unsafe { &*dirents.add(i) };
+			// An inode of 0 is an entry that was removed.
+			if d.inode == 0 {
+				continue;
+			}
+			let len = d.name.iter().position(|c| *c == 0).unwrap_or(d.name.len());
+			ret.push((String::from_utf8_lossy(&d.name[..len]).into_owned(), d.inode));
+		}
+		ret
+	}
+
+	/// List the directory at path, which has to be absolute. The cache only
+	/// has files in it, so we walk down from the root (inode 1) instead.
+	/// Run this ONLY in a process, since it reads the disk.
+	pub fn list_dir(bdev: usize, path: &str) -> Result<Vec<(String, Inode)>, FsError> {
+		let mut inode = Self::get_inode(bdev, 1).ok_or(FsError::Io)?;
+		for name in path.split('/').filter(|name| !name.is_empty()) {
+			if inode.mode & S_IFMT != S_IFDIR {
+				return Err(FsError::IsFile);
+			}
+			let inode_num = match Self::dir_entries(bdev, &inode).into_iter().find(|(n, _)| n == name) {
+				Some((_, inode_num)) => inode_num,
+				None => return Err(FsError::FileNotFound),
+			};
+			inode = Self::get_inode(bdev, inode_num).ok_or(FsError::Io)?;
+		}
+		if inode.mode & S_IFMT != S_IFDIR {
+			return Err(FsError::IsFile);
+		}
+		let mut ret = Vec::new();
+		for (name, inode_num) in Self::dir_entries(bdev, &inode) {
+			if let Some(inode) = Self::get_inode(bdev, inode_num) {
+				ret.push((name, inode));
+			}
+		}
+		Ok(ret)
+	}
+
+	/// put_inode() changed inode number inode_num, so every path that names
+	/// it (there's more than one if it's hard linked) needs the new copy.
+	fn update_cache(bdev: usize, inode_num: u32, inode: &Inode) {
+		let _guard = PreemptGuard::new();
+		if let Some(cache) = unsafe { MFS_INODE_CACHE[bdev - 1].as_mut() } {
+			for entry in cache.values_mut() {
+				if entry.0 == inode_num {
+					entry.1 = *inode;
+				}
+			}
+		}
+	}
+
+	/// Change the permission bits of path to those in mode. The file type
+	/// stays what it was. Only root or the file's owner (uid is the caller's)
+	/// can do this. Run this ONLY in a process, since it reads and writes the
+	/// disk.
+	pub fn chmod(bdev: usize, path: &str, mode: u16, uid: u16) -> Result<(), FsError> {
+		Self::check_writable(bdev)?;
+		let (inode_num, _) = Self::lookup(bdev, path)?;
+		// The disk's copy is the one that we write back.
+		let mut inode = Self::get_inode(bdev, inode_num).ok_or(FsError::Io)?;
+		if uid != 0 && uid != inode.uid {
+			return Err(FsError::Permission);
+		}
+		inode.mode = (inode.mode & S_IFMT) | (mode & S_IPERM);
+		Self::put_inode(bdev, inode_num, &inode)?;
+		Self::update_cache(bdev, inode_num, &inode);
+		Ok(())
+	}
+
+	/// Give path to owner and group. Only root can do this. Like chmod(), run
+	/// this ONLY in a process.
+	pub fn chown(bdev: usize, path: &str, owner: u16, group: u16, uid: u16) -> Result<(), FsError> {
+		Self::check_writable(bdev)?;
+		if uid != 0 {
+			return Err(FsError::Permission);
+		}
+		let (inode_num, _) = Self::lookup(bdev, path)?;
+		let mut inode = Self::get_inode(bdev, inode_num).ok_or(FsError::Io)?;
+		inode.uid = owner;
+		inode.gid = group;
+		Self::put_inode(bdev, inode_num, &inode)?;
+		Self::update_cache(bdev, inode_num, &inode);
+		Ok(())
+	}
+
 	pub fn read(bdev: usize, inode: &Inode, buffer: *mut u8, size: u32, offset: u32) -> u32 {
 		// Our strategy here is to use blocks to see when we need to start reading
 		// based on the offset. That's offset_block. Then, the actual byte within
 		// that block that we need is offset_byte.
+		let geometry = Self::geometry(bdev);
+		let zone_size = geometry.zone_size;
+		let num_iptrs = geometry.num_iptrs();
 		let mut blocks_seen = 0u32;
-		let offset_block = offset / BLOCK_SIZE;
-		let mut offset_byte = offset % BLOCK_SIZE;
+		let offset_block = offset / zone_size;
+		let mut offset_byte = offset % zone_size;
 		// First, the _size parameter (now in bytes_left) is the size of the buffer, not
 		// necessarily the size of the file. If our buffer is bigger than the file, we're OK.
 		// If our buffer is smaller than the file, then we can only read up to the buffer size.
@@ -222,12 +476,15 @@ impl MinixFileSystem {
 			size
 		};
 		let mut bytes_read = 0u32;
+		// The zone each level of pointer buffers currently holds. If we come back to
+		// the same index, there's no point in loading it again.
+		let mut loaded = [0u32; 3];
 		// The block buffer automatically drops when we quit early due to an error or we've read enough. This will be the holding port when we go out and read a block. Recall that even if we want 10 bytes, we have to read the entire block (really only 512 bytes of the block) first. So, we use the block_buffer as the middle man, which is then copied into the buffer.
-		let mut block_buffer = Buffer::new(BLOCK_SIZE as usize);
-		// Triply indirect zones point to a block of pointers (BLOCK_SIZE / 4). Each one of those pointers points to another block of pointers (BLOCK_SIZE / 4). Each one of those pointers yet again points to another block of pointers (BLOCK_SIZE / 4). This is why we have indirect, iindirect (doubly), and iiindirect (triply).
-		let mut indirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-		let mut iindirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-		let mut iiindirect_buffer = Buffer::new(BLOCK_SIZE as usize);
+		let mut block_buffer = Buffer::new(zone_size as usize);
+		// Triply indirect zones point to a block of pointers (block size / 4). Each one of those pointers points to another block of pointers (block size / 4). Each one of those pointers yet again points to another block of pointers (block size / 4). This is why we have indirect, iindirect (doubly), and iiindirect (triply).
+		let mut indirect_buffer = Buffer::new(geometry.block_size as usize);
+		let mut iindirect_buffer = Buffer::new(geometry.block_size as usize);
+		let mut iiindirect_buffer = Buffer::new(geometry.block_size as usize);
 		// I put the pointers *const u32 here. That means we will allocate the indirect, doubly indirect, and triply indirect even for small files. I initially had these in their respective scopes, but that required us to recreate the indirect buffer for doubly indirect and both the indirect and doubly indirect buffers for the triply indirect. Not sure which is better, but I probably wasted brain cells on this.
 		let izones = indirect_buffer.get() as *const u32;
 		let iizones = iindirect_buffer.get() as *const u32;
@@ -249,22 +506,22 @@ impl MinixFileSystem {
 			if offset_block <= blocks_seen {
 				// If we get here, then our offset is within our window that we want to see.
 				// We need to go to the direct pointer's index. That'll give us a block INDEX.
-				// That makes it easy since all we have to do is multiply the block size
+				// That makes it easy since all we have to do is multiply the zone size
 				// by whatever we get. If it's 0, we skip it and move on.
-				let zone_offset = inode.zones[i] * BLOCK_SIZE;
-				// We read the zone, which is where the data is located. The zone offset is simply the block
+				let zone_offset = inode.zones[i] * zone_size;
+				// We read the zone, which is where the data is located. The zone offset is simply the zone
 				// size times the zone number. This makes it really easy to read!
-				syc_read(bdev, block_buffer.get_mut(), BLOCK_SIZE, zone_offset);
+				syc_read(bdev, block_buffer.get_mut(), zone_size, zone_offset);
 
 				// There's a little bit of math to see how much we need to read. We don't want to read
 				// more than the buffer passed in can handle, and we don't want to read if we haven't
 				// taken care of the offset. For example, an offset of 10000 with a size of 2 means we
 				// can only read bytes 10,000 and 10,001.
-				let read_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
+				let read_this_many = if zone_size - offset_byte > bytes_left {
 					bytes_left
 				}
 				else {
-					BLOCK_SIZE - offset_byte
+					zone_size - offset_byte
 				};
 				// Once again, here we actually copy the bytes into the final destination, the buffer. This memcpy
 				// is written in cpu.rs.
@@ -294,19 +551,19 @@ impl MinixFileSystem {
 		// point to zones where the data can be found. Just like with the direct zones,
 		// we need to make sure the zone isn't 0. A zone of 0 means skip it.
 		if inode.zones[7] != 0 {
-			syc_read(bdev, indirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * inode.zones[7]);
+			Self::load_ptrs(bdev, inode.zones[7], &mut indirect_buffer, &mut loaded[0]);
 			let izones = indirect_buffer.get() as *const u32;
-			for i in 0..NUM_IPTRS {
+			for i in 0..num_iptrs {
 				// Where do I put unsafe? Dereferencing the pointers and memcpy are the unsafe functions.
 				unsafe {
 					if izones.add(i).read() != 0 {
 						if offset_block <= blocks_seen {
-							syc_read(bdev, block_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * izones.add(i).read());
-							let read_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
+							syc_read(bdev, block_buffer.get_mut(), zone_size, zone_size * izones.add(i).read());
+							let read_this_many = if zone_size - offset_byte > bytes_left {
 								bytes_left
 							}
 							else {
-								BLOCK_SIZE - offset_byte
+								zone_size - offset_byte
 							};
 							memcpy(buffer.add(bytes_read as usize), block_buffer.get().add(offset_byte as usize), read_this_many as usize);
 							bytes_read += read_this_many;
@@ -325,23 +582,23 @@ impl MinixFileSystem {
 		// // DOUBLY INDIRECT ZONES
 		// ////////////////////////////////////////////
 		if inode.zones[8] != 0 {
-			syc_read(bdev, indirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * inode.zones[8]);
+			Self::load_ptrs(bdev, inode.zones[8], &mut indirect_buffer, &mut loaded[0]);
 			unsafe {
-				for i in 0..NUM_IPTRS {
+				for i in 0..num_iptrs {
 					if izones.add(i).read() != 0 {
-						syc_read(bdev, iindirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * izones.add(i).read());
-						for j in 0..NUM_IPTRS {
+						Self::load_ptrs(bdev, izones.add(i).read(), &mut iindirect_buffer, &mut loaded[1]);
+						for j in 0..num_iptrs {
 							if iizones.add(j).read() != 0 {
 								// Notice that this inner code is the same for all end-zone pointers. I'm thinking about
 								// moving this out of here into a function of its own, but that might make it harder
 								// to follow.
 								if offset_block <= blocks_seen {
-									syc_read(bdev, block_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * iizones.add(j).read());
-									let read_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
+									syc_read(bdev, block_buffer.get_mut(), zone_size, zone_size * iizones.add(j).read());
+									let read_this_many = if zone_size - offset_byte > bytes_left {
 										bytes_left
 									}
 									else {
-										BLOCK_SIZE - offset_byte
+										zone_size - offset_byte
 									};
 									memcpy(
 									       buffer.add(bytes_read as usize),
@@ -366,24 +623,24 @@ impl MinixFileSystem {
 		// // TRIPLY INDIRECT ZONES
 		// ////////////////////////////////////////////
 		if inode.zones[9] != 0 {
-			syc_read(bdev, indirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * inode.zones[9]);
+			Self::load_ptrs(bdev, inode.zones[9], &mut indirect_buffer, &mut loaded[0]);
 			unsafe {
-				for i in 0..NUM_IPTRS {
+				for i in 0..num_iptrs {
 					if izones.add(i).read() != 0 {
-						syc_read(bdev, iindirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * izones.add(i).read());
-						for j in 0..NUM_IPTRS {
+						Self::load_ptrs(bdev, izones.add(i).read(), &mut iindirect_buffer, &mut loaded[1]);
+						for j in 0..num_iptrs {
 							if iizones.add(j).read() != 0 {
-								syc_read(bdev, iiindirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * iizones.add(j).read());
-								for k in 0..NUM_IPTRS {
+								Self::load_ptrs(bdev, iizones.add(j).read(), &mut iiindirect_buffer, &mut loaded[2]);
+								for k in 0..num_iptrs {
 									if iiizones.add(k).read() != 0 {
 										// Hey look! This again.
 										if offset_block <= blocks_seen {
-											syc_read(bdev, block_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * iiizones.add(k).read());
-											let read_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
+											syc_read(bdev, block_buffer.get_mut(), zone_size, zone_size * iiizones.add(k).read());
+											let read_this_many = if zone_size - offset_byte > bytes_left {
 												bytes_left
 											}
 											else {
-												BLOCK_SIZE - offset_byte
+												zone_size - offset_byte
 											};
 											memcpy(
 											       buffer.add(bytes_read as usize),
@@ -412,8 +669,90 @@ impl MinixFileSystem {
 		bytes_read
 	}
 
-	pub fn write(&mut self, _desc: &Inode, _buffer: *const u8, _offset: u32, _size: u32) -> u32 {
-		0
+	/// Load a block of zone pointers into buffer. loaded is the zone that buffer
+	/// already holds, so walking back into the same pointer block costs nothing.
+	/// Otherwise, we look in the pointer cache before going to the block device.
+	fn load_ptrs(bdev: usize, zone: u32, buffer: &mut Buffer, loaded: &mut u32) {
+		if *loaded == zone {
+			return;
+		}
+		// The guard only covers the cache itself. syc_read() puts us to
+		// sleep until the block device answers, and we can't sleep with
+		// preemption off.
+		let hit = {
+			let _guard = PreemptGuard::new();
+			unsafe {
+				// We copy out of the cache rather than hand back the cached buffer itself.
+				// The doubly and triply indirect loops hold on to the outer levels while
+				// they load the inner ones, which might evict the outer ones.
+				match MFS_PTR_CACHE[bdev - 1].as_ref().and_then(|cache| cache.get(&zone)) {
+					Some(cached) => {
+						memcpy(buffer.get_mut(), cached.get(), buffer.len());
+						true
+					}
+					None => false,
+				}
+			}
+		};
+		if !hit {
+			// The pointers only fill the first block of the zone.
+			let geometry = Self::geometry(bdev);
+			syc_read(bdev, buffer.get_mut(), geometry.block_size, geometry.zone_size * zone);
+			let _guard = PreemptGuard::new();
+			unsafe {
+				let cache = MFS_PTR_CACHE[bdev - 1].get_or_insert_with(BTreeMap::new);
+				// Someone else might have loaded it while we slept.
+				if !cache.contains_key(&zone) {
+					if cache.len() >= PTR_CACHE_SIZE {
+						// This isn't LRU, but we just need to stay bounded.
+						let victim = *cache.keys().next().unwrap();
+						cache.remove(&victim);
+					}
+					cache.insert(zone, buffer.clone());
+				}
+			}
+		}
+		*loaded = zone;
+	}
+
+	/// Throw away every cached pointer block for a block device. Anything that
+	/// changes an indirect zone must call this.
+	pub fn invalidate_ptr_cache(bdev: usize) {
+		let _guard = PreemptGuard::new();
+		unsafe {
+			MFS_PTR_CACHE[bdev - 1] = None;
+		}
+	}
+
+	/// Anything that modifies the disk must call this first. If the block
+	/// device is read-only, we would just be building a request that the
+	/// block driver will refuse at the very last moment, so fail up front.
+	/// The caller just isn't allowed to change this disk, so that's a
+	/// permission error.
+	fn check_writable(bdev: usize) -> Result<(), FsError> {
+		if is_read_only(bdev) {
+			Err(FsError::Permission)
+		}
+		else {
+			Ok(())
+		}
+	}
+
+	pub fn write(bdev: usize, _inode: &Inode, _buffer: *const u8, _offset: u32, _size: u32) -> Result<u32, FsError> {
+		Self::check_writable(bdev)?;
+		// Writing zones isn't supported yet.
+		Ok(0)
+	}
+
+	/// The mode a new regular file gets. Whatever the caller asked for is
+	/// masked by the process' umask before we add the file type.
+	pub fn create_mode(requested: u16, umask: u16) -> u16 {
+		(requested & !umask & S_IPERM) | S_IFREG
+	}
+
+	/// Same as create_mode(), but for mkdir().
+	pub fn mkdir_mode(requested: u16, umask: u16) -> u16 {
+		(requested & !umask & S_IPERM) | S_IFDIR
 	}
 
 	pub fn stat(&self, inode: &Inode) -> Stat {
@@ -431,11 +770,16 @@ fn syc_read(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
 	syscall_block_read(bdev, buffer, size, offset)
 }
 
+/// The other direction. The size and offset have to be whole sectors.
+fn syc_write(bdev: usize, buffer: *const u8, size: u32, offset: u32) -> u8 {
+	syscall_block_write(bdev, buffer, size, offset)
+}
+
 // We have to start a process when reading from a file since the block
 // device will block. We only want to block in a process context, not an
 // interrupt context.
+#[derive(Clone, Copy)]
 struct ProcArgs {
-	pub pid:    u16,
 	pub dev:    usize,
 	pub buffer: *mut u8,
 	pub size:   u32,
@@ -444,41 +788,35 @@ struct ProcArgs {
 }
 
 // This is the actual code ran inside of the read process.
-fn read_proc(args_addr: usize) {
-	let args = unsafe { Box::from_raw(args_addr as *mut ProcArgs) };
+fn read_proc(args_addr: usize) -> usize {
+	// This is ours to free now. See process_read().
+	let args = unsafe { *(args_addr as *const ProcArgs) };
+	tfree(unsafe { &mut *(args_addr as *mut ProcArgs) });
 
 	// Start the read! Since we're in a kernel process, we can block by putting this
 	// process into a waiting state and wait until the block driver returns.
 	let inode = MinixFileSystem::get_inode(args.dev, args.node);
-	let bytes = MinixFileSystem::read(args.dev, &inode.unwrap(), args.buffer, args.size, args.offset);
-
-	// Let's write the return result into regs[10], which is A0.
-	unsafe {
-		let ptr = get_by_pid(args.pid);
-		if !ptr.is_null() {
-			(*(*ptr).frame).regs[Registers::A0 as usize] = bytes as usize;
-		}
-	}
-	// This is the process making the system call. The system itself spawns another process
-	// which goes out to the block device. Since we're passed the read call, we need to awaken
-	// the process and get it ready to go. The only thing this process needs to clean up is the
-	// tfree(), but the user process doesn't care about that.
-	set_running(args.pid);
+	// What we return goes into the caller's A0, and it's woken back up.
+	// See process::Completion::Wake.
+	MinixFileSystem::read(args.dev, &inode.unwrap(), args.buffer, args.size, args.offset) as usize
 }
 
 /// System calls will call process_read, which will spawn off a kernel process to read
-/// the requested data.
-pub fn process_read(pid: u16, dev: usize, node: u32, buffer: *mut u8, size: u32, offset: u32) {
+/// the requested data. If there's no memory for the arguments, pid is left
+/// running and we return OutOfMemory.
+pub fn process_read(pid: u16, dev: usize, node: u32, buffer: *mut u8, size: u32, offset: u32) -> Result<(), FsError> {
 	// println!("FS read {}, {}, 0x{:x}, {}, {}", pid, dev, buffer as usize, size, offset);
-	let args = ProcArgs { pid,
-	                      dev,
-	                      buffer,
-	                      size,
-	                      offset,
-	                      node };
-	let boxed_args = Box::new(args);
+	// The arguments have to outlive this call, so they go on the heap,
+	// and read_proc() frees them.
+	let args = talloc::<ProcArgs>().ok_or(FsError::OutOfMemory)?;
+	*args = ProcArgs { dev,
+	                   buffer,
+	                   size,
+	                   offset,
+	                   node };
 	set_waiting(pid);
-	let _ = add_kernel_process_args(read_proc, Box::into_raw(boxed_args) as usize);
+	let _ = add_kernel_worker(read_proc, args as *mut ProcArgs as usize, "fs_read", Completion::Wake(pid));
+	Ok(())
 }
 
 /// Stats on a file. This generally mimics an inode
@@ -497,5 +835,13 @@ pub enum FsError {
 	FileNotFound,
 	Permission,
 	IsFile,
-	IsDirectory
+	IsDirectory,
+	NotMinix,
+	ReadOnly,
+	// The block device couldn't do what we asked.
+	Io,
+	// The file would grow past what the file system allows.
+	TooBig,
+	// The kernel heap couldn't give us what we needed.
+	OutOfMemory
 }
@@ -4,16 +4,27 @@
 // 12 May 2020
 
 #![allow(dead_code)]
-use crate::{page::{zalloc, PAGE_SIZE},
+use crate::{cpu::{get_mtime, Registers, FREQ},
+            input,
+            page::{zalloc, zalloc_aligned, PAGE_SIZE},
 			kmem::{kmalloc, kfree},
+            lock::SpinLock,
+            process::{get_by_pid, set_running, set_sleeping, ProcessState},
             virtio,
-            virtio::{MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE, Descriptor, VIRTIO_DESC_F_WRITE, VIRTIO_DESC_F_NEXT}};
+            virtio::{init_queue, DeviceTypes, MmioOffsets, Queue, StatusField, Descriptor, VIRTIO_DESC_F_WRITE}};
 use core::{mem::size_of, ptr::null_mut};
+use alloc::vec::Vec;
 // use alloc::boxed::Box;
 
 const F_VIRGL: u32 = 0;
 const F_EDID: u32 = 1;
 const EVENT_DISPLAY: u32 = 1 << 0;
+// How many descriptors we ask for on the control queue. Every command
+// takes two or three, so this is bigger than virtio::VIRTIO_RING_SIZE.
+const GPU_RING_SIZE: u16 = 1 << 8;
+// How long wait_for_display_event() waits if no event comes: one frame at
+// 60 Hz, in mtime ticks.
+pub const FRAME_TIME: usize = FREQ as usize / 60;
 #[repr(C)]
 struct Config {
 	//events_read signals pending events to the driver. The driver MUST NOT write to this field.
@@ -108,7 +119,10 @@ struct RespEdid {
 	padding: u32,
 	edid: [u8; 1024],
 }
+// These are the names the virtio spec uses.
 #[repr(u32)]
+#[derive(Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
 enum Formats {
 	B8G8R8A8Unorm = 1,
 	B8G8R8X8Unorm = 2,
@@ -195,6 +209,14 @@ struct UpdateCursor {
 	padding: u32,
 }
 
+/// One pixel of the framebuffer, in memory order: red, green, blue, then
+/// alpha. This is what Formats::R8G8B8A8Unorm means, which is what we ask
+/// the host for. If the host won't take that, init_device() falls back to
+/// B8G8R8A8Unorm, and then blue comes first in memory. The kernel's own
+/// drawing (fill_rect()) swaps for you, but anyone who writes the
+/// framebuffer directly has to check Device::is_bgra(), or from user
+/// space, syscall 1010 (get framebuffer format).
+#[repr(C)]
 #[derive(Clone, Copy)]
 pub struct Pixel {
 	pub r: u8,
@@ -209,6 +231,11 @@ impl Pixel {
 		}
 	}
 }
+// The framebuffer is 4 bytes per pixel no matter what, so this won't
+// compile if Pixel ever grows.
+const _: [(); 4] = [(); size_of::<Pixel>()];
+// The framebuffer starts on a 2 MiB (megapage) boundary when it can.
+const FRAMEBUFFER_ALIGN: usize = 21;
 
 // This is not in the specification, but this makes
 // it easier for us to do just a single kfree.
@@ -246,25 +273,36 @@ impl<RqT, RmT, RpT> Request3<RqT, RmT, RpT> {
 	}
 }
 
+// A process sleeping in wait_for_display_event(). We keep when its sleep
+// ends so that we can tell if it already timed out and went to sleep for
+// some other reason.
+struct DisplayWaiter {
+	pid:   u16,
+	until: usize,
+}
+
 pub struct Device {
-	queue:        *mut Queue,
+	queue:        Queue,
 	dev:          *mut u32,
-	idx:          u16,
 	ack_used_idx: u16,
 	framebuffer:  *mut Pixel,
 	width:        u32,
 	height:       u32,
+	// The host took B8G8R8A8Unorm instead of R8G8B8A8Unorm.
+	bgra:         bool,
+	display_waiters: Vec<DisplayWaiter>,
 }
 
 impl Device {
 	pub const fn new() -> Self {
-		Self { queue:        null_mut(),
+		Self { queue:        Queue::null(),
 		       dev:          null_mut(),
-		       idx:          0,
 			   ack_used_idx: 0, 
 			   framebuffer:  null_mut(),
 			   width: 640,
-			   height: 480
+			   height: 480,
+			   bgra: false,
+			   display_waiters: Vec::new(),
 		}
 	}
 	pub fn get_framebuffer(&self) -> *mut Pixel {
@@ -276,20 +314,37 @@ impl Device {
 	pub fn get_height(&self) -> u32 {
 		self.height
 	}
+	/// Whether the framebuffer is blue, green, red, alpha in memory
+	/// instead of what Pixel says.
+	pub fn is_bgra(&self) -> bool {
+		self.bgra
+	}
 }
 
-pub static mut GPU_DEVICES: [Option<Device>; 8] = [
-	None,
-	None,
-	None,
-	None,
-	None,
-	None,
-	None,
-	None,
+/// The number of pages backing a framebuffer. We round up so that a
+/// framebuffer that isn't a multiple of PAGE_SIZE still gets its last
+/// partial page, and then we add two pages of slack past the end.
+/// Anyone mapping the framebuffer should map this many pages.
+pub const fn framebuffer_pages(width: u32, height: u32) -> usize {
+	let bytes = width as usize * height as usize * size_of::<Pixel>();
+	bytes.div_ceil(PAGE_SIZE) + 2
+}
+
+// Each GPU is locked on its own so that a command going into the control
+// queue and the interrupt handler acknowledging old ones don't interleave.
+pub static GPU_DEVICES: [SpinLock<Option<Device>>; 8] = [
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
+	SpinLock::new(None),
 ];
 
 pub fn fill_rect(dev: &mut Device, rect: Rect, color: Pixel) {
+	let color = if dev.bgra { Pixel::new(color.b, color.g, color.r, color.a) } else { color };
 	for row in rect.y..(rect.y+rect.height) {
 		for col in rect.x..(rect.x+rect.width) {
 			let byte = row as usize * dev.width as usize + col as usize;
@@ -333,50 +388,68 @@ pub fn stroke_rect(dev: &mut Device, rect: Rect, color: Pixel, size: u32) {
 	), color);
 }
 
-pub fn init(gdev: usize)  {
-	if let Some(mut dev) = unsafe { GPU_DEVICES[gdev-1].take() } {
+/// Figure out which GPU someone means. 0 means whichever GPU probe()
+/// found first. Anything else has to be the device number of a GPU that
+/// was set up, otherwise we give back None.
+pub fn resolve(gdev: usize) -> Option<usize> {
+	if gdev == 0 {
+		virtio::find(DeviceTypes::Gpu)
+	}
+	else if virtio::is_ready(gdev, DeviceTypes::Gpu) {
+		Some(gdev)
+	}
+	else {
+		None
+	}
+}
+
+/// Set up the first GPU that probe() found and put up a blank screen.
+/// Returns its device number, or None if there's no GPU.
+pub fn init() -> Option<usize> {
+	let gdev = resolve(0)?;
+	init_device(gdev);
+	Some(gdev)
+}
+
+/// Create the framebuffer's resource on the host, back it with our
+/// memory, and put it on scanout 0.
+pub fn init_device(gdev: usize) {
+	if let Some(dev) = GPU_DEVICES[gdev-1].lock().as_mut() {
+		// A relative mouse moves a pointer around this framebuffer.
+		input::set_pointer_bounds(dev.width, dev.height);
+		// //// STEP 1: Create a host resource using create 2d
+		// We want the format that matches Pixel. If the host won't
+		// do that, BGRA is what everybody supports, and fill_rect()
+		// can swap red and blue for us.
+		dev.bgra = false;
+		let (width, height) = (dev.width, dev.height);
+		for &(format, bgra) in [(Formats::R8G8B8A8Unorm, false), (Formats::B8G8R8A8Unorm, true)].iter() {
+			let resp = send_and_wait(dev, ResourceCreate2d {
+				hdr: CtrlHeader {
+					ctrl_type: CtrlType::CmdResourceCreate2d,
+					flags: 0,
+					fence_id: 0,
+					ctx_id: 0,
+					padding: 0,
+				},
+				resource_id: 1,
+				format,
+				width,
+				height,
+			});
+			if resp == CtrlType::RespOkNoData as u32 {
+				dev.bgra = bgra;
+				break;
+			}
+			println!("GPU didn't take format {}: response 0x{:x}", format as u32, resp);
+		}
 		// Put some crap in the framebuffer:
-		// First clear the buffer to white?
-		fill_rect(&mut dev, Rect::new(0, 0, 640, 480), Pixel::new(2, 2, 2, 255));
+		// First clear the buffer to white? This has to wait until we
+		// know the format, or red and blue could come out backwards.
+		fill_rect(dev, Rect::new(0, 0, 640, 480), Pixel::new(2, 2, 2, 255));
 		// fill_rect(&mut dev, Rect::new(15, 15, 200, 200), Pixel::new(255, 130, 0, 255));
 		// stroke_rect(&mut dev, Rect::new( 255, 15, 150, 150), Pixel::new( 0, 0, 0, 255), 5);
 		// draw_cosine(&mut dev, Rect::new(0, 300, 550, 60), Pixel::new(255, 15, 15, 255));
-		// //// STEP 1: Create a host resource using create 2d
-		let rq = Request::new(ResourceCreate2d {
-			hdr: CtrlHeader {
-				ctrl_type: CtrlType::CmdResourceCreate2d,
-				flags: 0,
-				fence_id: 0,
-				ctx_id: 0,
-				padding: 0,
-			},
-			resource_id: 1,
-			format: Formats::R8G8B8A8Unorm,
-			width: dev.width,
-			height: dev.height,
-		});
-		let desc_c2d = Descriptor {
-			addr: unsafe { &(*rq).request as *const ResourceCreate2d as u64 },
-			len: size_of::<ResourceCreate2d>() as u32,
-			flags: VIRTIO_DESC_F_NEXT,
-			next: (dev.idx + 1) % VIRTIO_RING_SIZE as u16,
-		};
-		let desc_c2d_resp = Descriptor {
-			addr: unsafe { &(*rq).response as *const CtrlHeader as u64 },
-			len: size_of::<CtrlHeader>() as u32,
-			flags: VIRTIO_DESC_F_WRITE,
-			next: 0,
-		};
-		unsafe {
-			let head = dev.idx;
-			(*dev.queue).desc[dev.idx as usize] = desc_c2d;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).desc[dev.idx as usize] = desc_c2d_resp;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).avail.ring[(*dev.queue).avail.idx as usize % VIRTIO_RING_SIZE] = head;
-			(*dev.queue).avail.idx =
-				(*dev.queue).avail.idx.wrapping_add(1);
-		}
 		// //// STEP 2: Attach backing
 		let rq = Request3::new(AttachBacking {
 			hdr: CtrlHeader {
@@ -395,38 +468,30 @@ pub fn init(gdev: usize)  {
 			padding: 0, 
 		}
 		);
-		let desc_ab = Descriptor {
-			addr: unsafe { &(*rq).request as *const AttachBacking as u64 },
-			len: size_of::<AttachBacking>() as u32,
-			flags: VIRTIO_DESC_F_NEXT,
-			next: (dev.idx + 1) % VIRTIO_RING_SIZE as u16,
-		};
-		let desc_ab_mementry = Descriptor {
-			addr: unsafe { &(*rq).mementries as *const MemEntry as u64 },
-			len: size_of::<MemEntry>() as u32,
-			flags: VIRTIO_DESC_F_NEXT,
-			next: (dev.idx + 2) % VIRTIO_RING_SIZE as u16,
-		};
-		let desc_ab_resp = Descriptor {
-			addr: unsafe { &(*rq).response as *const CtrlHeader as u64 },
-			len: size_of::<CtrlHeader>() as u32,
-			flags: VIRTIO_DESC_F_WRITE,
-			next: 0,
-		};
 		unsafe {
-			let head = dev.idx;
-			(*dev.queue).desc[dev.idx as usize] = desc_ab;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).desc[dev.idx as usize] = desc_ab_mementry;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).desc[dev.idx as usize] = desc_ab_resp;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).avail.ring[(*dev.queue).avail.idx as usize % VIRTIO_RING_SIZE] = head;
-			(*dev.queue).avail.idx =
-				(*dev.queue).avail.idx.wrapping_add(1);
+			virtio::submit(&mut dev.queue, &[
+				Descriptor {
+					addr: &(*rq).request as *const AttachBacking as u64,
+					len: size_of::<AttachBacking>() as u32,
+					flags: 0,
+					next: 0,
+				},
+				Descriptor {
+					addr: &(*rq).mementries as *const MemEntry as u64,
+					len: size_of::<MemEntry>() as u32,
+					flags: 0,
+					next: 0,
+				},
+				Descriptor {
+					addr: &(*rq).response as *const CtrlHeader as u64,
+					len: size_of::<CtrlHeader>() as u32,
+					flags: VIRTIO_DESC_F_WRITE,
+					next: 0,
+				},
+			]);
 		}
 		// //// STEP 3: Set scanout
-		let rq = Request::new(SetScanout {
+		send(&mut dev.queue, SetScanout {
 			hdr: CtrlHeader {
 				ctrl_type: CtrlType::CmdSetScanout,
 				flags: 0,
@@ -438,30 +503,8 @@ pub fn init(gdev: usize)  {
 			resource_id: 1,
 			scanout_id: 0,
 		});
-		let desc_sso = Descriptor {
-			addr: unsafe { &(*rq).request as *const SetScanout as u64 },
-			len: size_of::<SetScanout>() as u32,
-			flags: VIRTIO_DESC_F_NEXT,
-			next: (dev.idx + 1) % VIRTIO_RING_SIZE as u16,
-		};
-		let desc_sso_resp = Descriptor {
-			addr: unsafe { &(*rq).response as *const CtrlHeader as u64 },
-			len: size_of::<CtrlHeader>() as u32,
-			flags: VIRTIO_DESC_F_WRITE,
-			next: 0,
-		};
-		unsafe {
-			let head = dev.idx;
-			(*dev.queue).desc[dev.idx as usize] = desc_sso;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).desc[dev.idx as usize] = desc_sso_resp;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).avail.ring[(*dev.queue).avail.idx as usize % VIRTIO_RING_SIZE] = head;
-			(*dev.queue).avail.idx =
-				(*dev.queue).avail.idx.wrapping_add(1);
-		}
 		// //// STEP 4: Transfer to host
-		let rq = Request::new(TransferToHost2d {
+		send(&mut dev.queue, TransferToHost2d {
 			hdr: CtrlHeader {
 				ctrl_type: CtrlType::CmdTransferToHost2d,
 				flags: 0,
@@ -474,30 +517,8 @@ pub fn init(gdev: usize)  {
 			resource_id: 1,
 			padding: 0,
 		});
-		let desc_t2h = Descriptor {
-			addr: unsafe { &(*rq).request as *const TransferToHost2d as u64 },
-			len: size_of::<TransferToHost2d>() as u32,
-			flags: VIRTIO_DESC_F_NEXT,
-			next: (dev.idx + 1) % VIRTIO_RING_SIZE as u16,
-		};
-		let desc_t2h_resp = Descriptor {
-			addr: unsafe { &(*rq).response as *const CtrlHeader as u64 },
-			len: size_of::<CtrlHeader>() as u32,
-			flags: VIRTIO_DESC_F_WRITE,
-			next: 0,
-		};
-		unsafe {
-			let head = dev.idx;
-			(*dev.queue).desc[dev.idx as usize] = desc_t2h;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).desc[dev.idx as usize] = desc_t2h_resp;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).avail.ring[(*dev.queue).avail.idx as usize % VIRTIO_RING_SIZE] = head;
-			(*dev.queue).avail.idx =
-				(*dev.queue).avail.idx.wrapping_add(1);
-		}
 		// Step 5: Flush
-		let rq = Request::new(ResourceFlush {
+		send(&mut dev.queue, ResourceFlush {
 			hdr: CtrlHeader {
 				ctrl_type: CtrlType::CmdResourceFlush,
 				flags: 0,
@@ -509,43 +530,73 @@ pub fn init(gdev: usize)  {
 			resource_id: 1,
 			padding: 0,
 		});
-		let desc_rf = Descriptor {
-			addr: unsafe { &(*rq).request as *const ResourceFlush as u64 },
-			len: size_of::<ResourceFlush>() as u32,
-			flags: VIRTIO_DESC_F_NEXT,
-			next: (dev.idx + 1) % VIRTIO_RING_SIZE as u16,
-		};
-		let desc_rf_resp = Descriptor {
-			addr: unsafe { &(*rq).response as *const CtrlHeader as u64 },
-			len: size_of::<CtrlHeader>() as u32,
-			flags: VIRTIO_DESC_F_WRITE,
-			next: 0,
-		};
-		unsafe {
-			let head = dev.idx;
-			(*dev.queue).desc[dev.idx as usize] = desc_rf;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).desc[dev.idx as usize] = desc_rf_resp;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).avail.ring[(*dev.queue).avail.idx as usize % VIRTIO_RING_SIZE] = head;
-			(*dev.queue).avail.idx =
-				(*dev.queue).avail.idx.wrapping_add(1);
-		}
-		// Run Queue
-		unsafe {
-			dev.dev
-			.add(MmioOffsets::QueueNotify.scale32())
-			.write_volatile(0);
-			GPU_DEVICES[gdev-1].replace(dev);
+	}
+}
+
+/// Send a control request that the device answers with just a header.
+/// The whole request is one allocation, which pending() frees.
+fn send<RqT>(queue: &mut Queue, request: RqT) {
+	let rq = Request::<RqT, CtrlHeader>::new(request);
+	unsafe {
+		virtio::submit(queue, &[
+			Descriptor {
+				addr: &(*rq).request as *const RqT as u64,
+				len: size_of::<RqT>() as u32,
+				flags: 0,
+				next: 0,
+			},
+			Descriptor {
+				addr: &(*rq).response as *const CtrlHeader as u64,
+				len: size_of::<CtrlHeader>() as u32,
+				flags: VIRTIO_DESC_F_WRITE,
+				next: 0,
+			},
+		]);
+	}
+}
+
+/// Send a control request and spin until the device answers it, then give
+/// back the response's ctrl_type. This is only for setting up, since we
+/// hold the device the whole time. Whatever else finishes first gets
+/// cleaned up like pending() would.
+fn send_and_wait<RqT>(dev: &mut Device, request: RqT) -> u32 {
+	let rq = Request::<RqT, CtrlHeader>::new(request);
+	unsafe {
+		let head = virtio::submit(&mut dev.queue, &[
+			Descriptor {
+				addr: &(*rq).request as *const RqT as u64,
+				len: size_of::<RqT>() as u32,
+				flags: 0,
+				next: 0,
+			},
+			Descriptor {
+				addr: &(*rq).response as *const CtrlHeader as u64,
+				len: size_of::<CtrlHeader>() as u32,
+				flags: VIRTIO_DESC_F_WRITE,
+				next: 0,
+			},
+		]);
+		loop {
+			let elem = virtio::wait_used(&dev.queue, &mut dev.ack_used_idx);
+			if elem.id as u16 == head {
+				break;
+			}
+			kfree(dev.queue.desc(elem.id as u16).addr as *mut u8);
 		}
+		// The device could have written anything here, so don't read it
+		// as a CtrlType.
+		let resp = (&(*rq).response as *const CtrlHeader as *const u32).read_volatile();
+		kfree(rq as *mut u8);
+		resp
 	}
 }
 
 /// Invalidate and transfer a rectangular portion of the screen.
 /// I found out that width and height are actually x2, y2...oh well.
 pub fn transfer(gdev: usize, x: u32, y: u32, width: u32, height: u32) {
-	if let Some(mut dev) = unsafe { GPU_DEVICES[gdev-1].take() } {
-		let rq = Request::new(TransferToHost2d {
+	virtio::reset_if_needed(gdev);
+	if let Some(dev) = GPU_DEVICES[gdev-1].lock().as_mut() {
+		send(&mut dev.queue, TransferToHost2d {
 			hdr: CtrlHeader {
 				ctrl_type: CtrlType::CmdTransferToHost2d,
 				flags: 0,
@@ -558,30 +609,8 @@ pub fn transfer(gdev: usize, x: u32, y: u32, width: u32, height: u32) {
 			resource_id: 1,
 			padding: 0,
 		});
-		let desc_t2h = Descriptor {
-			addr: unsafe { &(*rq).request as *const TransferToHost2d as u64 },
-			len: size_of::<TransferToHost2d>() as u32,
-			flags: VIRTIO_DESC_F_NEXT,
-			next: (dev.idx + 1) % VIRTIO_RING_SIZE as u16,
-		};
-		let desc_t2h_resp = Descriptor {
-			addr: unsafe { &(*rq).response as *const CtrlHeader as u64 },
-			len: size_of::<CtrlHeader>() as u32,
-			flags: VIRTIO_DESC_F_WRITE,
-			next: 0,
-		};
-		unsafe {
-			let head = dev.idx;
-			(*dev.queue).desc[dev.idx as usize] = desc_t2h;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).desc[dev.idx as usize] = desc_t2h_resp;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).avail.ring[(*dev.queue).avail.idx as usize % VIRTIO_RING_SIZE] = head;
-			(*dev.queue).avail.idx =
-				(*dev.queue).avail.idx.wrapping_add(1);
-		}
 		// Step 5: Flush
-		let rq = Request::new(ResourceFlush {
+		send(&mut dev.queue, ResourceFlush {
 			hdr: CtrlHeader {
 				ctrl_type: CtrlType::CmdResourceFlush,
 				flags: 0,
@@ -593,35 +622,6 @@ pub fn transfer(gdev: usize, x: u32, y: u32, width: u32, height: u32) {
 			resource_id: 1,
 			padding: 0,
 		});
-		let desc_rf = Descriptor {
-			addr: unsafe { &(*rq).request as *const ResourceFlush as u64 },
-			len: size_of::<ResourceFlush>() as u32,
-			flags: VIRTIO_DESC_F_NEXT,
-			next: (dev.idx + 1) % VIRTIO_RING_SIZE as u16,
-		};
-		let desc_rf_resp = Descriptor {
-			addr: unsafe { &(*rq).response as *const CtrlHeader as u64 },
-			len: size_of::<CtrlHeader>() as u32,
-			flags: VIRTIO_DESC_F_WRITE,
-			next: 0,
-		};
-		unsafe {
-			let head = dev.idx;
-			(*dev.queue).desc[dev.idx as usize] = desc_rf;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).desc[dev.idx as usize] = desc_rf_resp;
-			dev.idx = (dev.idx + 1) % VIRTIO_RING_SIZE as u16;
-			(*dev.queue).avail.ring[(*dev.queue).avail.idx as usize % VIRTIO_RING_SIZE] = head;
-			(*dev.queue).avail.idx =
-				(*dev.queue).avail.idx.wrapping_add(1);
-		}
-		// Run Queue
-		unsafe {
-			dev.dev
-			.add(MmioOffsets::QueueNotify.scale32())
-			.write_volatile(0);
-			GPU_DEVICES[gdev-1].replace(dev);
-		}
 	}
 }
 
@@ -657,73 +657,46 @@ pub fn setup_gpu_device(ptr: *mut u32) -> bool {
 		// that means that the device couldn't accept
 		// the features that we request. Therefore, this is
 		// considered a "failed" state.
-		if false == StatusField::features_ok(status_ok) {
+		if !StatusField::features_ok(status_ok) {
 			print!("features fail...");
 			ptr.add(MmioOffsets::Status.scale32()).write_volatile(StatusField::Failed.val32());
 			return false;
 		}
 		// 7. Perform device-specific setup.
-		// Set the queue num. We have to make sure that the
-		// queue size is valid because the device can only take
-		// a certain size.
-		let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
-		ptr.add(MmioOffsets::QueueNum.scale32()).write_volatile(VIRTIO_RING_SIZE as u32);
-		if VIRTIO_RING_SIZE as u32 > qnmax {
-			print!("queue size fail...");
-			return false;
-		}
-		// First, if the block device array is empty, create it!
-		// We add 4095 to round this up and then do an integer
-		// divide to truncate the decimal. We don't add 4096,
-		// because if it is exactly 4096 bytes, we would get two
-		// pages, not one.
-		let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
-		// println!("np = {}", num_pages);
-		// We allocate a page for each device. This will the the
-		// descriptor where we can communicate with the block
-		// device. We will still use an MMIO register (in
-		// particular, QueueNotify) to actually tell the device
-		// we put something in memory. We also have to be
-		// careful with memory ordering. We don't want to
-		// issue a notify before all memory writes have
-		// finished. We will look at that later, but we need
-		// what is called a memory "fence" or barrier.
-		ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(0);
+		// Queue 0 is the control queue.
 		// TODO: Set up queue #1 (cursorq)
-
-		// Alignment is very important here. This is the memory address
-		// alignment between the available and used rings. If this is wrong,
-		// then we and the device will refer to different memory addresses
-		// and hence get the wrong data in the used ring.
-		// ptr.add(MmioOffsets::QueueAlign.scale32()).write_volatile(2);
-		let queue_ptr = zalloc(num_pages) as *mut Queue;
-		let queue_pfn = queue_ptr as u32;
-		ptr.add(MmioOffsets::GuestPageSize.scale32()).write_volatile(PAGE_SIZE as u32);
-		// QueuePFN is a physical page number, however it
-		// appears for QEMU we have to write the entire memory
-		// address. This is a physical memory address where we
-		// (the OS) and the block device have in common for
-		// making and receiving requests.
-		ptr.add(MmioOffsets::QueuePfn.scale32()).write_volatile(queue_pfn / PAGE_SIZE as u32);
+		// Transfers and flushes can be big, so give the control queue
+		// more room than the default if the device will take it.
+		let queue = match init_queue(ptr, 0, GPU_RING_SIZE) {
+			Some(queue) => queue,
+			None => return false,
+		};
 		// 8. Set the DRIVER_OK status bit. Device is now "live"
 		status_bits |= StatusField::DriverOk.val32();
 		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
 
 		// We are going to give the framebuffer to user space, so this needs to be page aligned
 		// so that we can map it into the user space's MMU. This is why we don't want kmalloc here!
-		let num_pages = (PAGE_SIZE * 2+640*480*size_of::<Pixel>())/PAGE_SIZE;
-		let page_alloc = zalloc(num_pages) as *mut Pixel;
+		// We also line it up on a 2 MiB boundary, so it could be mapped with
+		// megapages. If there's no room for that, any pages will do, since
+		// we map it a page at a time for now anyway.
+		let num_pages = framebuffer_pages(640, 480);
+		let mut page_alloc = zalloc_aligned(num_pages, FRAMEBUFFER_ALIGN) as *mut Pixel;
+		if page_alloc.is_null() {
+			page_alloc = zalloc(num_pages) as *mut Pixel;
+		}
 		let dev = Device {
-			queue: queue_ptr,
+			queue,
 			dev: ptr,
-			idx: 0,
 			ack_used_idx: 0,
 			framebuffer: page_alloc,
 			width: 640,
 			height: 480,
+			bgra: false,
+			display_waiters: Vec::new(),
 		};
 
-		GPU_DEVICES[idx] = Some(dev);
+		*GPU_DEVICES[idx].lock() = Some(dev);
 
 		true
 	}
@@ -733,25 +706,72 @@ pub fn pending(dev: &mut Device) {
 	// Here we need to check the used ring and then free the resources
 	// given by the descriptor id.
 	unsafe {
-		let ref queue = *dev.queue;
-		while dev.ack_used_idx != queue.used.idx {
-			let ref elem = queue.used.ring
-				[dev.ack_used_idx as usize % VIRTIO_RING_SIZE];
+		let queue = dev.queue;
+		while let Some(elem) = virtio::next_used(&queue, &mut dev.ack_used_idx) {
 			// println!("Ack {}, elem {}, len {}", dev.ack_used_idx, elem.id, elem.len);
-			let ref desc = queue.desc[elem.id as usize];
+			let desc = queue.desc(elem.id as u16);
 			// Requests stay resident on the heap until this
 			// function, so we can recapture the address here
 			kfree(desc.addr as *mut u8);
-			dev.ack_used_idx = dev.ack_used_idx.wrapping_add(1);
+		}
+	}
+}
 
+/// Put pid to sleep until the GPU at gdev (1-based) signals a display
+/// event, or until timeout mtime ticks go by. The virtio GPU doesn't have
+/// a vblank, and the only display event it has is the display changing,
+/// so most of the time this is a frame timer. The caller sets the return
+/// value for the timeout; if an event wakes pid, we change it to 1.
+pub fn wait_for_display_event(gdev: usize, pid: u16, timeout: usize) {
+	unsafe {
+		if let Some(dev) = GPU_DEVICES[gdev - 1].lock().as_mut() {
+			if !set_sleeping(pid, timeout) {
+				return;
+			}
+			// Anyone whose sleep is over has stopped waiting on us.
+			let now = get_mtime();
+			dev.display_waiters.retain(|w| w.until > now);
+			dev.display_waiters.push(DisplayWaiter { pid,
+			                                         until: (*get_by_pid(pid)).sleep_until, });
+		}
+	}
+}
+
+/// The device's configuration changed. If that's a display event, wake up
+/// everyone waiting for one.
+fn config_changed(dev: &mut Device) {
+	unsafe {
+		let config = (dev.dev as usize + MmioOffsets::Config.val()) as *mut Config;
+		let events = (*config).events_read;
+		if events & EVENT_DISPLAY == 0 {
+			return;
+		}
+		(*config).events_clear = EVENT_DISPLAY;
+		for w in dev.display_waiters.drain(..) {
+			let process = get_by_pid(w.pid);
+			if process.is_null() {
+				continue;
+			}
+			if let ProcessState::Sleeping = (*process).state {
+				if (*process).sleep_until == w.until {
+					(*(*process).frame).regs[Registers::A0 as usize] = 1;
+					set_running(w.pid);
+				}
+			}
 		}
 	}
 }
 
 pub fn handle_interrupt(idx: usize) {
 	unsafe {
-		if let Some(bdev) = GPU_DEVICES[idx].as_mut() {
+		if let Some(bdev) = GPU_DEVICES[idx].lock().as_mut() {
+			// Bit 0 is a used buffer, bit 1 is a configuration change.
+			let status = bdev.dev.add(MmioOffsets::InterruptStatus.scale32()).read_volatile();
+			bdev.dev.add(MmioOffsets::InterruptAck.scale32()).write_volatile(status);
 			pending(bdev);
+			if status & 2 != 0 {
+				config_changed(bdev);
+			}
 		}
 		else {
 			println!(
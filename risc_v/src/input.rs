@@ -2,9 +2,10 @@
 // Input handling.
 // Stephen Marz
 
-use crate::virtio::{Queue, MmioOffsets, MMIO_VIRTIO_START, StatusField, VIRTIO_RING_SIZE, Descriptor, VIRTIO_DESC_F_WRITE, VIRTIO_F_RING_EVENT_IDX};
-use crate::kmem::kmalloc;
-use crate::page::{PAGE_SIZE, zalloc};
+use crate::virtio;
+use crate::virtio::{init_queue, Queue, MmioOffsets, MMIO_VIRTIO_START, StatusField, VIRTIO_RING_SIZE, Descriptor, VIRTIO_DESC_F_WRITE, VIRTIO_F_RING_EVENT_IDX};
+use crate::kmem::kcalloc;
+use crate::sched::PreemptGuard;
 use core::mem::size_of;
 use alloc::collections::VecDeque;
 
@@ -12,6 +13,28 @@ pub static mut ABS_EVENTS: Option<VecDeque<Event>> = None;
 // pub static mut ABS_OBSERVERS: Option<VecDeque<u16>> = None;
 pub static mut KEY_EVENTS: Option<VecDeque<Event>> = None;
 // pub static mut KEY_OBSERVERS: Option<VecDeque<u16>> = None;
+// Relative (mouse) movement, exactly as the device sent it. The value is
+// really an i32 delta.
+pub static mut REL_EVENTS: Option<VecDeque<Event>> = None;
+
+// Codes for EventType::Rel, from input-event-codes.h
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+
+// Where relative movement has taken the pointer. It starts in the middle
+// of the screen and never leaves it. The bounds are the framebuffer's,
+// which gpu::init() sets.
+struct Pointer {
+	x:      u32,
+	y:      u32,
+	width:  u32,
+	height: u32,
+}
+
+static mut POINTER: Pointer = Pointer { x:      320,
+                                        y:      240,
+                                        width:  640,
+                                        height: 480, };
 
 const EVENT_BUFFER_ELEMENTS: usize = 64;
 
@@ -97,9 +120,8 @@ pub enum EventType {
 const EVENT_SIZE: usize = size_of::<Event>();
 
 pub struct Device {
-	event_queue:  *mut Queue,
-	status_queue: *mut Queue,  
-	event_idx:          u16,
+	event_queue:  Queue,
+	status_queue: Queue,
 	event_ack_used_idx: u16,
 	event_buffer: *mut Event,
 	status_ack_used_idx: u16,
@@ -150,59 +172,23 @@ pub fn setup_input_device(ptr: *mut u32) -> bool {
 		// that means that the device couldn't accept
 		// the features that we request. Therefore, this is
 		// considered a "failed" state.
-		if false == StatusField::features_ok(status_ok) {
+		if !StatusField::features_ok(status_ok) {
 			print!("features fail...");
 			ptr.add(MmioOffsets::Status.scale32()).write_volatile(StatusField::Failed.val32());
 			return false;
 		}
 		// 7. Perform device-specific setup.
-		// Set the queue num. We have to make sure that the
-		// queue size is valid because the device can only take
-		// a certain size.
-		let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
-		ptr.add(MmioOffsets::QueueNum.scale32()).write_volatile(VIRTIO_RING_SIZE as u32);
-		if VIRTIO_RING_SIZE as u32 > qnmax {
-			print!("queue size fail...");
-			return false;
-		}
-		// First, if the block device array is empty, create it!
-		// We add 4095 to round this up and then do an integer
-		// divide to truncate the decimal. We don't add 4096,
-		// because if it is exactly 4096 bytes, we would get two
-		// pages, not one.
-		let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
-		// println!("np = {}", num_pages);
-		// We allocate a page for each device. This will the the
-		// descriptor where we can communicate with the block
-		// device. We will still use an MMIO register (in
-		// particular, QueueNotify) to actually tell the device
-		// we put something in memory. We also have to be
-		// careful with memory ordering. We don't want to
-		// issue a notify before all memory writes have
-		// finished. We will look at that later, but we need
-		// what is called a memory "fence" or barrier.
-		ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(0);
-
-		// Alignment is very important here. This is the memory address
-		// alignment between the available and used rings. If this is wrong,
-		// then we and the device will refer to different memory addresses
-		// and hence get the wrong data in the used ring.
-		// ptr.add(MmioOffsets::QueueAlign.scale32()).write_volatile(2);
-		let event_queue_ptr = zalloc(num_pages) as *mut Queue;
-		let queue_pfn = event_queue_ptr as u32;
-		ptr.add(MmioOffsets::GuestPageSize.scale32()).write_volatile(PAGE_SIZE as u32);
-		ptr.add(MmioOffsets::QueuePfn.scale32()).write_volatile(queue_pfn / PAGE_SIZE as u32);
-		// Status queue
-		ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(1);
-		// Alignment is very important here. This is the memory address
-		// alignment between the available and used rings. If this is wrong,
-		// then we and the device will refer to different memory addresses
-		// and hence get the wrong data in the used ring.
-		// ptr.add(MmioOffsets::QueueAlign.scale32()).write_volatile(2);
-		let status_queue_ptr = zalloc(num_pages) as *mut Queue;
-		let queue_pfn = status_queue_ptr as u32;
-		ptr.add(MmioOffsets::GuestPageSize.scale32()).write_volatile(PAGE_SIZE as u32);
-		ptr.add(MmioOffsets::QueuePfn.scale32()).write_volatile(queue_pfn / PAGE_SIZE as u32);
+		// Queue 0 is the event queue, where the device gives us input
+		// events. Queue 1 is the status queue, where we could send
+		// things such as LED changes back to the device.
+		let event_queue = match init_queue(ptr, 0, VIRTIO_RING_SIZE as u16) {
+			Some(queue) => queue,
+			None => return false,
+		};
+		let status_queue = match init_queue(ptr, 1, VIRTIO_RING_SIZE as u16) {
+			Some(queue) => queue,
+			None => return false,
+		};
 		// 8. Set the DRIVER_OK status bit. Device is now "live"
 		status_bits |= StatusField::DriverOk.val32();
 		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
@@ -219,14 +205,15 @@ pub fn setup_input_device(ptr: *mut u32) -> bool {
 		// println!("Min: {}, Max: {}, fuzz: {}, flat: {}, res: {}", id.min, id.max, id.fuzz, id.flat, id.res);
 
 		let mut dev = Device {
-			event_queue: event_queue_ptr,
-			status_queue: status_queue_ptr,
+			event_queue,
+			status_queue,
 			status_ack_used_idx: 0,
-			event_idx: 0,
 			event_ack_used_idx: 0,
-			event_buffer: kmalloc(EVENT_SIZE * EVENT_BUFFER_ELEMENTS) as *mut Event,
+			event_buffer: kcalloc(EVENT_BUFFER_ELEMENTS, EVENT_SIZE) as *mut Event,
 		};
-		for i in 0..EVENT_BUFFER_ELEMENTS {
+		// Every buffer needs its own descriptor, so we can't give the
+		// device more buffers than it has room for.
+		for i in 0..EVENT_BUFFER_ELEMENTS.min(event_queue.size() as usize) {
 			repopulate_event(&mut dev, i);
 		}
 		INPUT_DEVICES[idx] = Some(dev);
@@ -234,6 +221,7 @@ pub fn setup_input_device(ptr: *mut u32) -> bool {
 		// ABS_OBSERVERS = Some(VecDeque::new());
 		KEY_EVENTS = Some(VecDeque::with_capacity(10));
 		// KEY_OBSERVERS = Some(VecDeque::new());
+		REL_EVENTS = Some(VecDeque::with_capacity(100));
 
 		true
 	}
@@ -247,27 +235,26 @@ unsafe fn repopulate_event(dev: &mut Device, buffer: usize) {
 		flags: VIRTIO_DESC_F_WRITE,
 		next: 0
 	};
-	let head = dev.event_idx as u16;
-	(*dev.event_queue).desc[dev.event_idx as usize] = desc;
-	dev.event_idx = (dev.event_idx + 1) % VIRTIO_RING_SIZE as u16;
-	(*dev.event_queue).avail.ring[(*dev.event_queue).avail.idx as usize % VIRTIO_RING_SIZE] = head;
-	(*dev.event_queue).avail.idx = (*dev.event_queue).avail.idx.wrapping_add(1);
+	virtio::submit(&mut dev.event_queue, &[desc]);
 }
 
 fn pending(dev: &mut Device) {
+	// We're usually in an interrupt here, but this keeps the event queues
+	// safe if we're ever called from a process.
+	let _guard = PreemptGuard::new();
 	// Here we need to check the used ring and then free the resources
 	// given by the descriptor id.
 	unsafe {
 		// Check the event queue first
-		let ref queue = *dev.event_queue;
-		while dev.event_ack_used_idx != queue.used.idx {
-			let ref elem = queue.used.ring[dev.event_ack_used_idx as usize % VIRTIO_RING_SIZE];
-			let ref desc = queue.desc[elem.id as usize];
+		let queue = dev.event_queue;
+		while let Some(elem) = virtio::next_used(&queue, &mut dev.event_ack_used_idx) {
+			let desc = queue.desc(elem.id as u16);
 			let event = (desc.addr as *const Event).as_ref().unwrap();
 			// print!("EAck {}, elem {}, len {}, addr 0x{:08x}: ", dev.event_ack_used_idx, elem.id, elem.len, desc.addr as usize);
 			// println!("Type = {:x}, Code = {:x}, Value = {:x}", event.event_type, event.code, event.value);
-			repopulate_event(dev, elem.id as usize);
-			dev.event_ack_used_idx = dev.event_ack_used_idx.wrapping_add(1);
+			// The descriptors go around the ring, but the buffers stay
+			// put, so the buffer comes from the address and not the id.
+			repopulate_event(dev, (desc.addr as usize - dev.event_buffer as usize) / EVENT_SIZE);
 			match event.event_type {
 				EventType::Abs => {
 					let mut ev = ABS_EVENTS.take().unwrap();
@@ -279,24 +266,63 @@ fn pending(dev: &mut Device) {
 					ev.push_back(*event);
 					KEY_EVENTS.replace(ev);	
 				},
+				EventType::Rel => {
+					move_pointer(event.code, event.value as i32);
+					let mut ev = REL_EVENTS.take().unwrap();
+					ev.push_back(*event);
+					REL_EVENTS.replace(ev);
+				},
 				_ => {
 
 				}
 			}
 		}
 		// Next, the status queue
-		let ref queue = *dev.status_queue;
-		while dev.status_ack_used_idx != queue.used.idx {
-			let ref elem = queue.used.ring[dev.status_ack_used_idx as usize % VIRTIO_RING_SIZE];
-			print!("SAck {}, elem {}, len {}: ", dev.status_ack_used_idx, elem.id, elem.len);
-			let ref desc = queue.desc[elem.id as usize];
+		let queue = dev.status_queue;
+		while let Some(elem) = virtio::next_used(&queue, &mut dev.status_ack_used_idx) {
+			print!("SAck elem {}, len {}: ", elem.id, elem.len);
+			let desc = queue.desc(elem.id as u16);
 			let event = (desc.addr as *const Event).as_ref().unwrap();
 			println!("Type = {:x}, Code = {:x}, Value = {:x}", event.event_type as u8, event.code, event.value);
-			dev.status_ack_used_idx = dev.status_ack_used_idx.wrapping_add(1);
 		}
 	}
 }
 
+/// Move the pointer by a relative event's delta, keeping it on the screen.
+fn move_pointer(code: u16, delta: i32) {
+	unsafe {
+		match code {
+			REL_X => POINTER.x = clamp_add(POINTER.x, delta, POINTER.width),
+			REL_Y => POINTER.y = clamp_add(POINTER.y, delta, POINTER.height),
+			// Wheels and such don't move the pointer.
+			_ => {},
+		}
+	}
+}
+
+fn clamp_add(pos: u32, delta: i32, bound: u32) -> u32 {
+	let max = bound.saturating_sub(1) as i64;
+	(pos as i64 + delta as i64).max(0).min(max) as u32
+}
+
+/// Where the pointer is, in framebuffer pixels.
+pub fn pointer() -> (u32, u32) {
+	let _guard = PreemptGuard::new();
+	unsafe { (POINTER.x, POINTER.y) }
+}
+
+/// The framebuffer changed size, so the pointer has to stay inside of the
+/// new one.
+pub fn set_pointer_bounds(width: u32, height: u32) {
+	let _guard = PreemptGuard::new();
+	unsafe {
+		POINTER.width = width;
+		POINTER.height = height;
+		POINTER.x = POINTER.x.min(width.saturating_sub(1));
+		POINTER.y = POINTER.y.min(height.saturating_sub(1));
+	}
+}
+
 pub fn handle_interrupt(idx: usize) {
 	unsafe {
 		if let Some(bdev) = INPUT_DEVICES[idx].as_mut() {
@@ -4,11 +4,15 @@
 // 7 October 2019
 
 use crate::page::{align_val, zalloc, Table, PAGE_SIZE};
-use core::{mem::size_of, ptr::null_mut};
+use core::{mem::{align_of, size_of}, ptr::null_mut, sync::atomic::{AtomicU8, Ordering}};
 
 #[repr(usize)]
 enum AllocListFlags {
 	Taken = 1 << 63,
+	// Not a real header. This sits right before a pointer that
+	// kmalloc_aligned() moved up to meet an alignment, and the rest of
+	// the bits are how far it moved.
+	Offset = 1 << 62,
 }
 impl AllocListFlags {
 	pub fn val(self) -> usize {
@@ -16,10 +20,38 @@ impl AllocListFlags {
 	}
 }
 
+// Every header starts with this. If it isn't there, something wrote past
+// the end of the chunk before it. It spells KMEMKMEM.
+const KMEM_CANARY: usize = 0x4b4d_454d_4b4d_454d;
+
+// The canary comes first so that flags_size is the word right before the
+// memory we hand out. kmalloc_aligned() only has room for that one word.
+#[repr(C)]
 struct AllocList {
+	pub canary:     usize,
 	pub flags_size: usize,
 }
 impl AllocList {
+	// Start a new chunk header here.
+	pub fn init(&mut self, sz: usize, taken: bool) {
+		self.canary = KMEM_CANARY;
+		self.flags_size = sz;
+		if taken {
+			self.set_taken();
+		}
+	}
+
+	// Is the canary still there? If not, say so, since nothing about
+	// this chunk can be trusted.
+	pub fn canary_ok(&self) -> bool {
+		if self.canary == KMEM_CANARY {
+			return true;
+		}
+		println!("kmem: header at {:p} is corrupt, canary is 0x{:x}, expected 0x{:x}",
+		         self, self.canary, KMEM_CANARY);
+		false
+	}
+
 	pub fn is_taken(&self) -> bool {
 		self.flags_size & AllocListFlags::Taken.val() != 0
 	}
@@ -47,6 +79,19 @@ impl AllocList {
 	pub fn get_size(&self) -> usize {
 		self.flags_size & !AllocListFlags::Taken.val()
 	}
+
+	pub fn is_offset(&self) -> bool {
+		self.flags_size & AllocListFlags::Taken.val() == 0
+		&& self.flags_size & AllocListFlags::Offset.val() != 0
+	}
+
+	pub fn get_offset(&self) -> usize {
+		self.flags_size & !AllocListFlags::Offset.val()
+	}
+
+	pub fn set_offset(&mut self, off: usize) {
+		self.flags_size = off | AllocListFlags::Offset.val();
+	}
 }
 
 // This is the head of the allocation. We start here when
@@ -65,7 +110,7 @@ pub fn get_head() -> *mut u8 {
 }
 
 pub fn get_page_table() -> *mut Table {
-	unsafe { KMEM_PAGE_TABLE as *mut Table }
+	unsafe { KMEM_PAGE_TABLE }
 }
 
 pub fn get_num_allocations() -> usize {
@@ -83,8 +128,7 @@ pub fn init() {
 		let k_alloc = zalloc(KMEM_ALLOC);
 		assert!(!k_alloc.is_null());
 		KMEM_HEAD = k_alloc as *mut AllocList;
-		(*KMEM_HEAD).set_free();
-		(*KMEM_HEAD).set_size(KMEM_ALLOC * PAGE_SIZE);
+		(*KMEM_HEAD).init(KMEM_ALLOC * PAGE_SIZE, false);
 		KMEM_PAGE_TABLE = zalloc(1) as *mut Table;
 	}
 }
@@ -104,62 +148,312 @@ pub fn kzmalloc(sz: usize) -> *mut u8 {
 	ret
 }
 
+/// How kmalloc() picks which free chunk to carve an allocation out of.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FitStrategy {
+	// The first chunk that's big enough. This is fast, but big
+	// allocations end up breaking up the free space at the front.
+	FirstFit = 0,
+	// The smallest chunk that's big enough. This has to look at every
+	// chunk, but it leaves the big free chunks alone for big allocations.
+	BestFit = 1,
+}
+
+// Which FitStrategy kmalloc() uses. It can be changed at any time, since
+// both strategies leave the chunks looking the same.
+static FIT_STRATEGY: AtomicU8 = AtomicU8::new(FitStrategy::FirstFit as u8);
+
+/// Change how kmalloc() picks a chunk from now on.
+pub fn set_fit_strategy(strategy: FitStrategy) {
+	FIT_STRATEGY.store(strategy as u8, Ordering::Relaxed);
+}
+
+pub fn fit_strategy() -> FitStrategy {
+	match FIT_STRATEGY.load(Ordering::Relaxed) {
+		0 => FitStrategy::FirstFit,
+		_ => FitStrategy::BestFit,
+	}
+}
+
 /// Allocate sub-page level allocation based on bytes
 pub fn kmalloc(sz: usize) -> *mut u8 {
 	unsafe {
 		let size = align_val(sz, 3) + size_of::<AllocList>();
+		let head = match fit_strategy() {
+			FitStrategy::FirstFit => first_fit(size),
+			FitStrategy::BestFit => best_fit(size),
+		};
+		if head.is_null() {
+			// If we get here, we didn't find any free chunks--i.e.
+			// there isn't enough memory for this. TODO: Add
+			// on-demand page allocation.
+			return null_mut();
+		}
+		let chunk_size = (*head).get_size();
+		let rem = chunk_size - size;
+		if rem > size_of::<AllocList>() {
+			let next = (head as *mut u8).add(size)
+			           as *mut AllocList;
+			// There is space remaining here.
+			(*next).init(rem, false);
+			(*head).init(size, true);
+		}
+		else {
+			// If we get here, take the entire chunk
+			(*head).init(chunk_size, true);
+		}
+		head.add(1) as *mut u8
+	}
+}
+
+/// The first free chunk with at least size bytes (header included), or null.
+unsafe fn first_fit(size: usize) -> *mut AllocList {
+	let mut head = KMEM_HEAD;
+	// .add() uses pointer arithmetic, so we type-cast into a u8
+	// so that we multiply by an absolute size (KMEM_ALLOC *
+	// PAGE_SIZE).
+	let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
+	           as *mut AllocList;
+	while head < tail {
+		if (*head).is_free() && size <= (*head).get_size() {
+			return head;
+		}
+		// If we get here, what we saw wasn't a free chunk (or it
+		// was too small), move on to the next.
+		head = (head as *mut u8).add((*head).get_size())
+		       as *mut AllocList;
+	}
+	null_mut()
+}
+
+/// The smallest free chunk with at least size bytes (header included), or
+/// null. A chunk that's exactly right can't be beaten, so we stop there.
+unsafe fn best_fit(size: usize) -> *mut AllocList {
+	let mut head = KMEM_HEAD;
+	let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
+	           as *mut AllocList;
+	let mut best: *mut AllocList = null_mut();
+	while head < tail {
+		let chunk_size = (*head).get_size();
+		if (*head).is_free() && size <= chunk_size && (best.is_null() || chunk_size < (*best).get_size()) {
+			best = head;
+			if chunk_size == size {
+				break;
+			}
+		}
+		head = (head as *mut u8).add(chunk_size) as *mut AllocList;
+	}
+	best
+}
+
+/// How broken up the free memory is, as (number of free chunks, bytes in
+/// the largest one). The largest one is the biggest kmalloc() that can
+/// work right now, header included.
+pub fn fragmentation() -> (usize, usize) {
+	let mut chunks = 0;
+	let mut largest = 0;
+	unsafe {
 		let mut head = KMEM_HEAD;
-		// .add() uses pointer arithmetic, so we type-cast into a u8
-		// so that we multiply by an absolute size (KMEM_ALLOC *
-		// PAGE_SIZE).
 		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
 		           as *mut AllocList;
-
 		while head < tail {
-			if (*head).is_free() && size <= (*head).get_size() {
-				let chunk_size = (*head).get_size();
-				let rem = chunk_size - size;
-				(*head).set_taken();
-				if rem > size_of::<AllocList>() {
-					let next = (head as *mut u8).add(size)
-					           as *mut AllocList;
-					// There is space remaining here.
-					(*next).set_free();
-					(*next).set_size(rem);
-					(*head).set_size(size);
-				}
-				else {
-					// If we get here, take the entire chunk
-					(*head).set_size(chunk_size);
-				}
-				return head.add(1) as *mut u8;
+			if (*head).is_free() {
+				chunks += 1;
+				largest = largest.max((*head).get_size());
 			}
-			else {
-				// If we get here, what we saw wasn't a free
-				// chunk, move on to the next.
-				head = (head as *mut u8).add((*head).get_size())
-				       as *mut AllocList;
+			head = (head as *mut u8).add((*head).get_size())
+			       as *mut AllocList;
+		}
+	}
+	(chunks, largest)
+}
+
+/// Allocate an array of count elements that are size bytes each, and zero
+/// it. If count * size doesn't fit in a usize, we'd get back something
+/// much smaller than the caller thinks, so that's null instead.
+pub fn kcalloc(count: usize, size: usize) -> *mut u8 {
+	match count.checked_mul(size) {
+		Some(total) => kzmalloc(total),
+		None => null_mut(),
+	}
+}
+
+/// Allocate sub-page level allocation based on bytes where the returned
+/// pointer is a multiple of align. align must be a power of two. kmalloc
+/// always gives us 8-byte alignment, so for anything bigger, we ask for
+/// align more bytes than we need and move the pointer up. The word right
+/// before the pointer we hand back records how far we moved it, so
+/// kfree() can find the real header.
+pub fn kmalloc_aligned(sz: usize, align: usize) -> *mut u8 {
+	if align <= 8 {
+		return kmalloc(sz);
+	}
+	let raw = kmalloc(sz + align);
+	if raw.is_null() {
+		return raw;
+	}
+	let ret = align_ptr(raw, align);
+	if ret != raw {
+		// raw and ret are both 8-byte aligned, so if they're different,
+		// there's at least one word between them to put the offset in.
+		// That's the flags_size word of a header that would be right
+		// before ret, which is all kfree() looks at. We don't touch the
+		// canary word, since it might be the real header's.
+		unsafe {
+			let marker = (ret as *mut AllocList).offset(-1);
+			(*marker).set_offset(ret as usize - raw as usize);
+		}
+	}
+	ret
+}
+
+/// Free something from kmalloc_aligned(). kfree() can already tell when a
+/// pointer was moved up, so this is the same thing, but it reads better
+/// next to the kmalloc_aligned() it goes with.
+pub fn kfree_aligned(ptr: *mut u8) {
+	kfree(ptr);
+}
+
+/// Same as kmalloc_aligned(), but zero the memory.
+pub fn kzmalloc_aligned(sz: usize, align: usize) -> *mut u8 {
+	let size = align_val(sz, 3);
+	let ret = kmalloc_aligned(size, align);
+
+	if !ret.is_null() {
+		for i in 0..size {
+			unsafe {
+				(*ret.add(i)) = 0;
 			}
 		}
 	}
-	// If we get here, we didn't find any free chunks--i.e. there isn't
-	// enough memory for this. TODO: Add on-demand page allocation.
-	null_mut()
+	ret
+}
+
+/// Allocate room for one T on the kernel heap and hand it back as a
+/// reference. The memory is zeroed, but no constructor runs, and tfree()
+/// doesn't run a destructor either. That's why T has to be Copy, so it
+/// can't own anything that needs dropping. It also has to be something
+/// that's fine as all zeroes, which is on the caller. Give it back with
+/// tfree() when you're done. Returns None if we're out of memory.
+pub fn talloc<T: Copy>() -> Option<&'static mut T> {
+	let ptr = kzmalloc_aligned(size_of::<T>(), align_of::<T>()) as *mut T;
+	unsafe { ptr.as_mut() }
+}
+
+/// Free something that came from talloc().
+pub fn tfree<T: Copy>(t: &mut T) {
+	kfree(t as *mut T as *mut u8);
+}
+
+/// Round ptr up to the next multiple of align (a power of two).
+fn align_ptr(ptr: *mut u8, align: usize) -> *mut u8 {
+	((ptr as usize + align - 1) & !(align - 1)) as *mut u8
 }
 
 /// Free a sub-page level allocation
 pub fn kfree(ptr: *mut u8) {
+	if !try_kfree(ptr) {
+		println!("double free detected at {:p}", ptr);
+	}
+}
+
+/// kfree(), but instead of complaining about a double free, return false.
+/// The self-test uses this so that it can make one on purpose without
+/// printing anything.
+pub fn try_kfree(ptr: *mut u8) -> bool {
 	unsafe {
 		if !ptr.is_null() {
-			let p = (ptr as *mut AllocList).offset(-1);
-			if (*p).is_taken() {
-				(*p).set_free();
+			let mut p = (ptr as *mut AllocList).offset(-1);
+			if (*p).is_offset() {
+				// This came from kmalloc_aligned(), which moved the
+				// pointer up. Go back to where kmalloc() put it.
+				p = (ptr.sub((*p).get_offset()) as *mut AllocList).offset(-1);
 			}
+			if !(*p).canary_ok() {
+				// Freeing this would join who knows what into the
+				// chain, so we leave it alone.
+				return true;
+			}
+			if (*p).is_free() {
+				// Somebody already freed this. It might be part of
+				// a bigger free chunk by now, or even be someone
+				// else's, so we don't touch anything.
+				return false;
+			}
+			(*p).set_free();
 			// After we free, see if we can combine adjacent free
 			// spots to see if we can reduce fragmentation.
 			coalesce();
 		}
 	}
+	true
+}
+
+/// Resize the allocation at ptr to new_size bytes, and return where it is
+/// now. If the chunk right after it is free and big enough, it just grows
+/// into it, and shrinking always happens in place. Otherwise, we kmalloc a
+/// new chunk, copy over what fits, and free the old one. If that kmalloc
+/// fails, we return null and ptr is left alone. A null ptr is the same as
+/// kmalloc(new_size).
+/// Something from kmalloc_aligned() stays aligned if it's resized in place,
+/// but if it has to move, it's only 8-byte aligned.
+pub fn krealloc(ptr: *mut u8, new_size: usize) -> *mut u8 {
+	if ptr.is_null() {
+		return kmalloc(new_size);
+	}
+	unsafe {
+		let mut head = (ptr as *mut AllocList).offset(-1);
+		let mut offset = 0;
+		if (*head).is_offset() {
+			offset = (*head).get_offset();
+			head = (ptr.sub(offset) as *mut AllocList).offset(-1);
+		}
+		if !(*head).canary_ok() {
+			return null_mut();
+		}
+		assert!((*head).is_taken(), "krealloc of a free chunk {:p}", ptr);
+		let size = (*head).get_size();
+		// This is how much the chunk has to be for new_size bytes at ptr.
+		let needed = align_val(new_size, 3) + size_of::<AllocList>() + offset;
+		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
+		           as *mut AllocList;
+		let next = (head as *mut u8).add(size) as *mut AllocList;
+		let available = if size >= needed {
+			size
+		}
+		else if next < tail && (*next).is_free() {
+			size + (*next).get_size()
+		}
+		else {
+			0
+		};
+		if available >= needed {
+			// We fit where we are, so give back whatever is left over,
+			// just like kmalloc() splits a chunk.
+			let rem = available - needed;
+			if rem > size_of::<AllocList>() {
+				let next = (head as *mut u8).add(needed) as *mut AllocList;
+				(*next).init(rem, false);
+				(*head).set_size(needed);
+				// What we gave back might be next to another
+				// free chunk.
+				coalesce();
+			}
+			else {
+				(*head).set_size(available);
+			}
+			return ptr;
+		}
+		let new = kmalloc(new_size);
+		if new.is_null() {
+			return new;
+		}
+		let old_size = size - size_of::<AllocList>() - offset;
+		core::ptr::copy_nonoverlapping(ptr, new, old_size.min(new_size));
+		kfree(ptr);
+		new
+	}
 }
 
 /// Merge smaller chunks into a bigger chunk
@@ -172,7 +466,12 @@ pub fn coalesce() {
 		while head < tail {
 			let next = (head as *mut u8).add((*head).get_size())
 			           as *mut AllocList;
-			if (*head).get_size() == 0 {
+			if !(*head).canary_ok() {
+				// The size is as bad as the canary, so we can't
+				// find the next chunk.
+				break;
+			}
+			else if (*head).get_size() == 0 {
 				// If this happens, then we have a bad heap
 				// (double free or something). However, that
 				// will cause an infinite loop since the next
@@ -188,6 +487,10 @@ pub fn coalesce() {
 				// need to do.
 				break;
 			}
+			else if !(*next).canary_ok() {
+				// Don't merge a chunk we can't trust into this one.
+				break;
+			}
 			else if (*head).is_free() && (*next).is_free() {
 				// This means we have adjacent blocks needing to
 				// be freed. So, we combine them into one
@@ -205,6 +508,38 @@ pub fn coalesce() {
 	}
 }
 
+/// How the kernel heap is doing, as (bytes used, bytes free, biggest
+/// kmalloc() that would work right now). A taken chunk's header counts as
+/// used, but a free chunk's counts as free, since it goes away when the
+/// chunk is joined to its neighbor. This is the same walk print_table()
+/// does.
+pub fn usage() -> (usize, usize, usize) {
+	let mut used = 0;
+	let mut free = 0;
+	let mut largest = 0;
+	unsafe {
+		let mut head = KMEM_HEAD;
+		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
+		           as *mut AllocList;
+		while head < tail {
+			let size = (*head).get_size();
+			if size == 0 {
+				// A broken chain would keep us here forever.
+				break;
+			}
+			if (*head).is_free() {
+				free += size;
+				largest = largest.max(size - size_of::<AllocList>());
+			}
+			else {
+				used += size;
+			}
+			head = (head as *mut u8).add(size) as *mut AllocList;
+		}
+	}
+	(used, free, largest)
+}
+
 /// For debugging purposes, print the kmem table
 pub fn print_table() {
 	unsafe {
@@ -241,15 +576,16 @@ struct OsGlobalAlloc;
 
 unsafe impl GlobalAlloc for OsGlobalAlloc {
 	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-		// We align to the next page size so that when
-		// we divide by PAGE_SIZE, we get exactly the number
-		// of pages necessary.
-		kzmalloc(layout.size())
+		// Most of what the alloc crate asks for only needs 8-byte
+		// alignment, which kmalloc gives us anyway. Anything that
+		// needs more (#[repr(align(64))], for example) gets moved up.
+		kzmalloc_aligned(layout.size(), layout.align())
 	}
 
 	unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
 		// We ignore layout since our allocator uses ptr_start -> last
-		// to determine the span of an allocation.
+		// to determine the span of an allocation. kfree figures out if
+		// the pointer was moved up for alignment.
 		kfree(ptr);
 	}
 }
@@ -260,14 +596,6 @@ unsafe impl GlobalAlloc for OsGlobalAlloc {
 /// copying a value.
 static GA: OsGlobalAlloc = OsGlobalAlloc {};
 
-#[alloc_error_handler]
-/// If for some reason alloc() in the global allocator gets null_mut(),
-/// then we come here. This is a divergent function, so we call panic to
-/// let the tester know what's going on.
-pub fn alloc_error(l: Layout) -> ! {
-	panic!(
-	       "Allocator failed to allocate {} bytes with {}-byte alignment.",
-	       l.size(),
-	       l.align()
-	);
-}
+// If alloc() above hands back null_mut(), the alloc crate's default
+// error handler panics with the size it wanted, and we end up in our
+// #[panic_handler] in main.rs like any other panic.
@@ -3,7 +3,13 @@
 // Stephen Marz
 // 26 Apr 2020
 
-use crate::syscall::syscall_sleep;
+use crate::{cpu::{get_mtime, interrupts_disable, interrupts_restore},
+            process::current_pid,
+            syscall::syscall_sleep};
+use core::{arch::asm,
+           cell::UnsafeCell,
+           ops::{Deref, DerefMut},
+           sync::atomic::{AtomicBool, Ordering}};
 
 pub const DEFAULT_LOCK_SLEEP: usize = 10000;
 #[repr(u32)]
@@ -12,14 +18,22 @@ pub enum MutexState {
 	Locked = 1
 }
 
+// The assembly swaps the state field by its own address, so it doesn't
+// matter where it sits. Who took the lock and when are only for diagnostics
+// (see process::watchdog()), so they're a best guess: a trap that takes a
+// lock records whichever process it interrupted.
 #[repr(C)]
 pub struct Mutex {
-	state: MutexState
+	state: MutexState,
+	owner: u16,
+	since: usize,
 }
 
 impl<'a> Mutex {
 	pub const fn new() -> Self {
-		Self { state: MutexState::Unlocked }
+		Self { state: MutexState::Unlocked,
+		       owner: 0,
+		       since: 0, }
 	}
 
 	pub fn val(&'a self) -> &'a MutexState {
@@ -29,12 +43,16 @@ impl<'a> Mutex {
 	/// Try to lock the Mutex. If the mutex is already locked, this function returns false, otherwise it will return true if the mutex was acquired.
 	pub fn try_lock(&mut self) -> bool {
 		unsafe {
-			let state: MutexState;
-			llvm_asm!("amoswap.w.aq $0, $1, ($2)\n" : "=r"(state) : "r"(1), "r"(self) :: "volatile");
-			match state {
-				// amoswap returns the OLD state of the lock.  If it was already locked, we didn't acquire it.
-				MutexState::Locked => false,
-				MutexState::Unlocked => true
+			let state: u32;
+			asm!("amoswap.w.aq {}, {}, ({})", out(reg) state, in(reg) MutexState::Locked as u32, in(reg) &mut self.state as *mut MutexState, options(nostack));
+			// amoswap returns the OLD state of the lock.  If it was already locked, we didn't acquire it.
+			if state == MutexState::Locked as u32 {
+				false
+			}
+			else {
+				self.owner = current_pid();
+				self.since = get_mtime();
+				true
 			}
 		}
 	}
@@ -53,10 +71,95 @@ impl<'a> Mutex {
 		while !self.try_lock() {}
 	}
 
+	/// If the mutex is locked, which PID took it, and at what mtime?
+	pub fn holder(&self) -> Option<(u16, usize)> {
+		let state = unsafe { (&self.state as *const MutexState as *const u32).read_volatile() };
+		if state == MutexState::Locked as u32 {
+			Some((self.owner, self.since))
+		}
+		else {
+			None
+		}
+	}
+
 	/// Unlock a mutex without regard for its previous state.
 	pub fn unlock(&mut self) {
 		unsafe {
-			llvm_asm!("amoswap.w.rl zero, zero, ($0)" :: "r"(self) :: "volatile");
+			asm!("amoswap.w.rl zero, zero, ({})", in(reg) &mut self.state as *mut MutexState, options(nostack));
 		}
 	}
 }
+
+/// A spinlock that owns what it protects, so the only way to get at the
+/// data is through the guard lock() hands back. The lock is taken with an
+/// atomic compare-and-swap (LR/SC), and this hart's interrupts stay off
+/// while it's held. That way an interrupt handler that wants the same lock
+/// can't interrupt the holder and spin forever. Keep critical sections
+/// short, and never sleep or make a system call with the lock held.
+pub struct SpinLock<T> {
+	locked: AtomicBool,
+	data:   UnsafeCell<T>,
+}
+
+// The whole point is to share the data between harts and interrupts, and
+// the lock is what makes that safe.
+unsafe impl<T> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+	pub const fn new(data: T) -> Self {
+		Self { locked: AtomicBool::new(false),
+		       data:   UnsafeCell::new(data), }
+	}
+
+	/// Spin until we have the lock. Safe in an interrupt context.
+	pub fn lock(&self) -> SpinLockGuard<'_, T> {
+		let interrupts = interrupts_disable();
+		while self.locked
+		          .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+		          .is_err()
+		{}
+		SpinLockGuard { lock: self, interrupts }
+	}
+
+	/// Take the lock only if nobody has it. This is for when spinning
+	/// could never end, like a panic that went off while we held it.
+	pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+		let interrupts = interrupts_disable();
+		if self.locked
+		       .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+		       .is_ok()
+		{
+			Some(SpinLockGuard { lock: self, interrupts })
+		}
+		else {
+			interrupts_restore(interrupts);
+			None
+		}
+	}
+}
+
+pub struct SpinLockGuard<'a, T> {
+	lock:       &'a SpinLock<T>,
+	interrupts: bool,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		unsafe { &*self.lock.data.get() }
+	}
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe { &mut *self.lock.data.get() }
+	}
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+	fn drop(&mut self) {
+		self.lock.locked.store(false, Ordering::Release);
+		interrupts_restore(self.interrupts);
+	}
+}
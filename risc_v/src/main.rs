@@ -3,22 +3,20 @@
 // 21 Sep 2019
 #![no_main]
 #![no_std]
-#![feature(panic_info_message,
-           asm,
-		   llvm_asm,
-		   global_asm,
-           allocator_api,
-           alloc_error_handler,
-           alloc_prelude,
-		   const_raw_ptr_to_usize_cast,
-		   lang_items)]
-
-#[lang = "eh_personality"] extern fn eh_personality() {}
+// The kernel keeps its global state in static muts and hands raw pointers
+// to and from the hardware and user space everywhere, so these would fire
+// on nearly every module. We only run on RV64, so 64-bit discriminants
+// are fine.
+#![allow(static_mut_refs,
+         clippy::not_unsafe_ptr_arg_deref,
+         clippy::missing_safety_doc,
+         clippy::new_without_default,
+         clippy::too_many_arguments,
+         clippy::enum_clike_unportable_variant)]
 
 // #[macro_use]
 extern crate alloc;
-// This is experimental and requires alloc_prelude as a feature
-// use alloc::prelude::v1::*;
+use core::arch::asm;
 
 // ///////////////////////////////////
 // / RUST MACROS
@@ -28,7 +26,7 @@ macro_rules! print
 {
 	($($args:tt)+) => ({
 			use core::fmt::Write;
-			let _ = write!(crate::uart::Uart::new(0x1000_0000), $($args)+);
+			let _ = write!($crate::uart::Uart::default(), $($args)+);
 			});
 }
 #[macro_export]
@@ -51,25 +49,31 @@ macro_rules! println
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+	// We won't be taking interrupts anymore, so get whatever is queued
+	// out of the UART and print the rest directly.
+	uart::sync();
 	print!("Aborting: ");
 	if let Some(p) = info.location() {
 		println!(
 		         "line {}, file {}: {}",
 		         p.line(),
 		         p.file(),
-		         info.message().unwrap()
+		         info.message()
 		);
 	}
 	else {
 		println!("no information available.");
 	}
+	let pid = process::current_pid();
+	println!("Running PID {} ({})", pid, process::name_of(pid));
+	cpu::backtrace();
 	abort();
 }
 #[no_mangle]
 extern "C" fn abort() -> ! {
 	loop {
 		unsafe {
-			llvm_asm!("wfi"::::"volatile");
+			asm!("wfi", options(nomem, nostack));
 		}
 	}
 }
@@ -84,6 +88,15 @@ extern "C" {
 /// the stack, since we will recapture the stack during m_trap.
 fn rust_switch_to_user(frame: usize) -> ! {
 	unsafe {
+		if frame != 0 {
+			let tf = &*(frame as *const cpu::TrapFrame);
+			process::set_current(cpu::mhartid_read(), tf.pid as u16);
+			// Kernel processes run with the MMU off, so they don't have an
+			// address space to fence. The ASID is bits 59:44 of SATP.
+			if tf.is_translated() {
+				cpu::fence_asid_if_stale(tf.satp >> 44);
+			}
+		}
 		switch_to_user(frame);
 	}
 }
@@ -92,7 +105,16 @@ fn rust_switch_to_user(frame: usize) -> ! {
 // ///////////////////////////////////
 #[no_mangle]
 extern "C" fn kinit() {
-	uart::Uart::new(0x1000_0000).init();
+	uart::Uart::default().init();
+	// If the UART doesn't work, nothing below this is going to print. If
+	// only the receiver is broken, this message might still get out.
+	#[cfg(feature = "uart-selftest")]
+	{
+		if !uart::Uart::default().loopback_test() {
+			println!("UART loopback test failed at {:#x}", uart::UART_BASE);
+		}
+	}
+	trap::place_trap_stack_canary();
 	page::init();
 	kmem::init();
 	process::init();
@@ -109,15 +131,20 @@ extern "C" fn kinit() {
 	}
 	// Set up virtio. This requires a working heap and page-grained allocator.
 	virtio::probe();
+	virtio::summary();
 
 	console::init();
-	process::add_kernel_process(test::test);
+	process::add_kernel_process(test::test, "test");
 	// Get the GPU going
-	gpu::init(6);
-	// We schedule the next context switch using a multiplier of 1
+	if gpu::init().is_none() {
+		println!("No GPU found.");
+	}
+	// We schedule the next context switch using the quantum of whomever
+	// the scheduler picked.
 	// Block testing code removed.
-	trap::schedule_next_context_switch(1);
-	rust_switch_to_user(sched::schedule());
+	let frame = sched::schedule();
+	trap::schedule_next_context_switch(sched::frame_quantum(frame));
+	rust_switch_to_user(frame);
 	// switch_to_user will not return, so we should never get here
 }
 #[no_mangle]
@@ -130,6 +157,7 @@ extern "C" fn kinit_hart(_hartid: usize) {
 // / RUST MODULES
 // ///////////////////////////////////
 
+pub mod ansi;
 pub mod assembly;
 pub mod block;
 pub mod buffer;
@@ -144,9 +172,14 @@ pub mod lock;
 pub mod page;
 pub mod plic;
 pub mod process;
+pub mod procfs;
+pub mod ramfs;
 pub mod rng;
 pub mod sched;
+pub mod shell;
+pub mod sound;
 pub mod syscall;
+pub mod system;
 pub mod trap;
 pub mod uart;
 pub mod vfs;
@@ -3,6 +3,8 @@
 // Stephen Marz
 // 6 October 2019
 
+use crate::cpu::{satp_fence_vaddr, SatpMode};
+use alloc::vec::Vec;
 use core::{mem::size_of, ptr::null_mut};
 
 // ////////////////////////////////
@@ -46,8 +48,13 @@ impl PageBits {
 // Each page is described by the Page structure. Linux does this
 // as well, where each 4096-byte chunk of memory has a structure
 // associated with it. However, there structure is much larger.
+// Each page also counts how many owners it has (every process that has it
+// mapped, for example), so that it's only freed when the last one lets go.
+// Pages are counted one at a time, since a process that forked can copy
+// one page of a segment and keep sharing the rest.
 pub struct Page {
 	flags: u8,
+	refs:  u16,
 }
 
 impl Page {
@@ -71,6 +78,7 @@ impl Page {
 	// Clear the Page structure and all associated allocations.
 	pub fn clear(&mut self) {
 		self.flags = PageBits::Empty.val();
+		self.refs = 0;
 	}
 
 	// Set a certain flag. We ran into trouble here since PageBits
@@ -85,12 +93,27 @@ impl Page {
 	}
 }
 
+// A run of free pages. The first page of every run holds one of these, so
+// the free list doesn't take any memory of its own. The list is kept in
+// address order, and runs that touch are always joined into one, so the
+// list is only as long as the number of holes in the heap.
+struct FreeRun {
+	pages: usize,
+	next:  *mut FreeRun,
+}
+
+static mut FREE_LIST: *mut FreeRun = null_mut();
+
 /// Initialize the allocation system. There are several ways that we can
 /// implement the page allocator:
 /// 1. Free list (singly linked list where it starts at the first free
-/// allocation) 2. Bookkeeping list (structure contains a taken and length)
-/// 3. Allocate one Page structure per 4096 bytes (this is what I chose)
+///    allocation)
+/// 2. Bookkeeping list (structure contains a taken and length)
+/// 3. Allocate one Page structure per 4096 bytes
 /// 4. Others
+///
+/// I chose both 1 and 3. The free list finds memory without looking at
+/// every page, and the Page structures keep track of who has what.
 pub fn init() {
 	unsafe {
 		// let desc_per_page = PAGE_SIZE / size_of::<Page>();
@@ -112,64 +135,181 @@ pub fn init() {
 		                        + num_pages * size_of::<Page>(),
 		                        PAGE_ORDER,
 		);
+		// Everything is free, so that's one run.
+		FREE_LIST = null_mut();
+		free_run_insert(ALLOC_START, alloc_pages());
 	}
 }
 
+/// How many pages there are to hand out. There are Page structures for
+/// the whole heap, but the ones that describe the Page structures
+/// themselves (everything before ALLOC_START) can never be given out.
+fn alloc_pages() -> usize {
+	unsafe { (HEAP_SIZE - (ALLOC_START - HEAP_START)) / PAGE_SIZE }
+}
+
 /// Allocate a page or multiple pages
 /// pages: the number of PAGE_SIZE pages to allocate
 pub fn alloc(pages: usize) -> *mut u8 {
+	// Every page is aligned to a page, so this is just first fit.
+	alloc_aligned(pages, PAGE_ORDER)
+}
+
+/// Allocate pages contiguous pages where the first one is at an address
+/// that's a multiple of 1 << align_log2. Some things (a megapage, for one)
+/// need more than page alignment. Returns null if there isn't a free run
+/// that long starting at an aligned address.
+pub fn alloc_aligned(pages: usize, align_log2: usize) -> *mut u8 {
 	// We have to find a contiguous allocation of pages
 	assert!(pages > 0);
+	// Anything we hand out is at least page aligned.
+	let align = 1usize << align_log2.max(PAGE_ORDER);
 	unsafe {
-		// We create a Page structure for each page on the heap. We
-		// actually might have more since HEAP_SIZE moves and so does
-		// the size of our structure, but we'll only waste a few bytes.
-		let num_pages = HEAP_SIZE / PAGE_SIZE;
-		let ptr = HEAP_START as *mut Page;
-		for i in 0..num_pages - pages {
-			let mut found = false;
-			// Check to see if this Page is free. If so, we have our
-			// first candidate memory address.
-			if (*ptr.add(i)).is_free() {
-				// It was FREE! Yay!
-				found = true;
-				for j in i..i + pages {
-					// Now check to see if we have a
-					// contiguous allocation for all of the
-					// request pages. If not, we should
-					// check somewhere else.
-					if (*ptr.add(j)).is_taken() {
-						found = false;
-						break;
-					}
-				}
+		// This is first fit, but we only look at the free runs, not
+		// every page.
+		let addr = free_run_take(pages, align);
+		if addr == 0 {
+			// If we get here, that means that no contiguous
+			// allocation was found.
+			return null_mut();
+		}
+		let first = page_of(addr as *mut u8);
+		// Whoever asked for these is the one owner.
+		for k in 0..pages {
+			(*first.add(k)).set_flag(PageBits::Taken);
+			(*first.add(k)).refs = 1;
+		}
+		// The marker for the last page is PageBits::Last This lets us
+		// know when we've hit the end of this particular allocation.
+		(*first.add(pages - 1)).set_flag(PageBits::Last);
+		addr as *mut u8
+	}
+}
+
+/// Take pages pages out of the first free run that has that many starting
+/// at a multiple of align. Whatever is left of the run on either side goes
+/// back on the list. Returns the address of the first page, or 0 if no run
+/// fits.
+unsafe fn free_run_take(pages: usize, align: usize) -> usize {
+	let mut prev: *mut FreeRun = null_mut();
+	let mut cur = FREE_LIST;
+	while !cur.is_null() {
+		let run_start = cur as usize;
+		let run_end = run_start + (*cur).pages * PAGE_SIZE;
+		let start = (run_start + align - 1) & !(align - 1);
+		let end = start + pages * PAGE_SIZE;
+		if end <= run_end {
+			// Unlink the whole run, then put back the pieces we
+			// didn't use.
+			if prev.is_null() {
+				FREE_LIST = (*cur).next;
 			}
-			// We've checked to see if there are enough contiguous
-			// pages to form what we need. If we couldn't, found
-			// will be false, otherwise it will be true, which means
-			// we've found valid memory we can allocate.
-			if found {
-				for k in i..i + pages - 1 {
-					(*ptr.add(k)).set_flag(PageBits::Taken);
-				}
-				// The marker for the last page is
-				// PageBits::Last This lets us know when we've
-				// hit the end of this particular allocation.
-				(*ptr.add(i+pages-1)).set_flag(PageBits::Taken);
-				(*ptr.add(i+pages-1)).set_flag(PageBits::Last);
-				// The Page structures themselves aren't the
-				// useful memory. Instead, there is 1 Page
-				// structure per 4096 bytes starting at
-				// ALLOC_START.
-				return (ALLOC_START + PAGE_SIZE * i)
-				       as *mut u8;
+			else {
+				(*prev).next = (*cur).next;
+			}
+			if start > run_start {
+				free_run_insert(run_start, (start - run_start) / PAGE_SIZE);
+			}
+			if run_end > end {
+				free_run_insert(end, (run_end - end) / PAGE_SIZE);
+			}
+			return start;
+		}
+		prev = cur;
+		cur = (*cur).next;
+	}
+	0
+}
+
+/// Put pages free pages starting at addr on the free list, joining them
+/// with the runs right before and after them if they touch.
+unsafe fn free_run_insert(addr: usize, pages: usize) {
+	// Find the runs on either side of addr.
+	let mut prev: *mut FreeRun = null_mut();
+	let mut next = FREE_LIST;
+	while !next.is_null() && (next as usize) < addr {
+		prev = next;
+		next = (*next).next;
+	}
+	let run = if !prev.is_null() && prev as usize + (*prev).pages * PAGE_SIZE == addr {
+		// This is just more of the run before it.
+		(*prev).pages += pages;
+		prev
+	}
+	else {
+		let run = addr as *mut FreeRun;
+		(*run).pages = pages;
+		(*run).next = next;
+		if prev.is_null() {
+			FREE_LIST = run;
+		}
+		else {
+			(*prev).next = run;
+		}
+		run
+	};
+	if !next.is_null() && run as usize + (*run).pages * PAGE_SIZE == next as usize {
+		// The run after it starts right where this one ends.
+		(*run).pages += (*next).pages;
+		(*run).next = (*next).next;
+	}
+}
+
+/// Walk the free list and make sure it agrees with the Page structures:
+/// every run is in order, doesn't touch the one before it (it would have
+/// been joined), and is made of free pages, and there are as many pages on
+/// the list as there are free Page structures. This is for testing.
+#[cfg(feature = "mem-selftest")]
+pub fn free_list_ok() -> bool {
+	unsafe {
+		let (num_pages, free) = page_stats();
+		let heap_end = ALLOC_START + num_pages * PAGE_SIZE;
+		let mut listed = 0;
+		let mut prev_end = 0;
+		let mut cur = FREE_LIST;
+		while !cur.is_null() {
+			let start = cur as usize;
+			let pages = (*cur).pages;
+			// A run that touches the last one should have been
+			// joined to it. This also catches a list that loops.
+			if pages == 0 || start <= prev_end || start + pages * PAGE_SIZE > heap_end || listed + pages > num_pages {
+				return false;
+			}
+			let first = page_of(cur as *mut u8);
+			if (0..pages).any(|k| (*first.add(k)).is_taken()) {
+				return false;
+			}
+			listed += pages;
+			prev_end = start + pages * PAGE_SIZE;
+			cur = (*cur).next;
+		}
+		listed == free
+	}
+}
+
+/// The free run that addr is in, as where the run starts and how many pages
+/// it has, or None if addr isn't free. This is for testing.
+#[cfg(feature = "mem-selftest")]
+pub fn free_run_containing(addr: usize) -> Option<(usize, usize)> {
+	unsafe {
+		let mut cur = FREE_LIST;
+		// The list is in address order, so once we're past addr, it
+		// isn't on the list.
+		while !cur.is_null() && cur as usize <= addr {
+			if addr < cur as usize + (*cur).pages * PAGE_SIZE {
+				return Some((cur as usize, (*cur).pages));
 			}
+			cur = (*cur).next;
 		}
 	}
+	None
+}
 
-	// If we get here, that means that no contiguous allocation was
-	// found.
-	null_mut()
+/// The most pages that alloc() could give back in one allocation right now.
+/// If this is a lot less than the free count from page_stats(), the free
+/// pages are scattered between allocations.
+pub fn largest_free_run() -> usize {
+	stats().largest_free_run
 }
 
 /// Allocate and zero a page or multiple pages
@@ -177,9 +317,16 @@ pub fn alloc(pages: usize) -> *mut u8 {
 /// Each page is PAGE_SIZE which is calculated as 1 << PAGE_ORDER
 /// On RISC-V, this typically will be 4,096 bytes.
 pub fn zalloc(pages: usize) -> *mut u8 {
-	// Allocate and zero a page.
-	// First, let's get the allocation
-	let ret = alloc(pages);
+	zero_pages(alloc(pages), pages)
+}
+
+/// alloc_aligned(), but the pages are zeroed like zalloc().
+pub fn zalloc_aligned(pages: usize, align_log2: usize) -> *mut u8 {
+	zero_pages(alloc_aligned(pages, align_log2), pages)
+}
+
+/// Zero the pages we just allocated at ret, if we got any.
+fn zero_pages(ret: *mut u8, pages: usize) -> *mut u8 {
 	if !ret.is_null() {
 		let size = (PAGE_SIZE * pages) / 8;
 		let big_ptr = ret as *mut u64;
@@ -198,39 +345,240 @@ pub fn zalloc(pages: usize) -> *mut u8 {
 	ret
 }
 
+/// Fill pages with POISON_BYTE when they're freed, so that anything still
+/// using one reads garbage that's easy to spot in a dump instead of
+/// whatever was there before. The first few bytes of a free run are the
+/// free list's, so those won't be poisoned. This costs a memset per page,
+/// so it's only on in debug builds.
+pub const POISON_FREED_PAGES: bool = cfg!(debug_assertions);
+const POISON_BYTE: u8 = 0xde;
+
+// What alloc_stack() fills a guard page with. It spells GUARD_PG.
+const GUARD_FILL: u64 = 0x4755_4152_445f_5047;
+
+/// Allocate a stack of pages pages with one more page under it as a guard,
+/// and return the top of the stack, which is where sp starts. Returns null
+/// if there isn't enough memory.
+/// The kernel runs in machine mode, which doesn't translate addresses, so
+/// we can't leave the guard page unmapped and fault on it like a user
+/// stack. Instead, the guard page is filled with a pattern, and whoever
+/// runs on the stack checks it with guard_ok(). An overflow has to get
+/// through the guard page before it hits anyone else's memory, so it's
+/// usually caught before that.
+pub fn alloc_stack(pages: usize) -> *mut u8 {
+	let guard = zalloc(pages + 1);
+	if guard.is_null() {
+		return null_mut();
+	}
+	unsafe {
+		let words = guard as *mut u64;
+		for i in 0..PAGE_SIZE / 8 {
+			words.add(i).write_volatile(GUARD_FILL);
+		}
+		guard.add((pages + 1) * PAGE_SIZE)
+	}
+}
+
+/// The guard page under a stack from alloc_stack(). This is also the
+/// allocation, so it's what gets passed to dealloc().
+pub fn guard_page(top: *mut u8, pages: usize) -> *mut u8 {
+	(top as usize - (pages + 1) * PAGE_SIZE) as *mut u8
+}
+
+/// Has anything written to the guard page? It's checked from the top down,
+/// since the top is what an overflow hits first.
+pub fn guard_ok(guard: *const u8) -> bool {
+	let words = guard as *const u64;
+	(0..PAGE_SIZE / 8).rev().all(|i| unsafe { words.add(i).read_volatile() } == GUARD_FILL)
+}
+
+/// Get the Page structure for the page at ptr.
+fn page_of(ptr: *mut u8) -> *mut Page {
+	// Make sure we don't try to free a null pointer.
+	assert!(!ptr.is_null());
+	unsafe {
+		// Make sure that the address makes sense. There's one Page
+		// structure per page, so this is which one.
+		assert!(is_heap_page(ptr as usize));
+		(HEAP_START as *mut Page).add((ptr as usize - ALLOC_START) / PAGE_SIZE)
+	}
+}
+
+/// The address of the page that p describes. This is page_of() backwards.
+fn page_addr(p: *const Page) -> usize {
+	unsafe { ALLOC_START + (p as usize - HEAP_START) / size_of::<Page>() * PAGE_SIZE }
+}
+
+/// Is addr in memory that alloc() hands out? Anything else (MMIO, the
+/// kernel image) isn't reference counted.
+pub fn is_heap_page(addr: usize) -> bool {
+	unsafe { addr >= ALLOC_START && addr < ALLOC_START + alloc_pages() * PAGE_SIZE }
+}
+
+/// Mark the one page p describes as free. The page before it might have
+/// been in the same allocation, and if so, that allocation ends there now.
+/// The page after it (if it's taken) starts its own. This doesn't put it
+/// on the free list, since the caller might be freeing the pages after it
+/// too, and it's cheaper to put them on together.
+unsafe fn free_page(p: *mut Page) {
+	(*p).clear();
+	if POISON_FREED_PAGES {
+		core::ptr::write_bytes(page_addr(p) as *mut u8, POISON_BYTE, PAGE_SIZE);
+	}
+	if p as usize > HEAP_START {
+		let prev = p.sub(1);
+		if (*prev).is_taken() && !(*prev).is_last() {
+			(*prev).set_flag(PageBits::Last);
+		}
+	}
+}
+
+/// Could alloc() have returned addr? It has to be a page in the heap
+/// that's taken, and it has to start its allocation, so the page before
+/// it is either free or the last page of some other allocation. This
+/// catches freeing the middle of an allocation and most double frees.
+fn is_alloc_head(addr: usize) -> bool {
+	if !is_heap_page(addr) || !(addr - unsafe { ALLOC_START }).is_multiple_of(PAGE_SIZE) {
+		return false;
+	}
+	let p = page_of(addr as *mut u8);
+	unsafe {
+		if !(*p).is_taken() {
+			return false;
+		}
+		p as usize == HEAP_START || {
+			let prev = p.sub(1);
+			(*prev).is_free() || (*prev).is_last()
+		}
+	}
+}
+
+/// Add an owner to the page at ptr. Each owner gives it back with
+/// decref(), and the page is freed when the last one does.
+pub fn incref(ptr: *mut u8) {
+	let p = page_of(ptr);
+	unsafe {
+		assert!((*p).is_taken(), "incref of a non-taken page?");
+		assert!((*p).refs < u16::MAX, "Too many references to page {:p}", ptr);
+		(*p).refs += 1;
+	}
+}
+
+/// How many owners the page at ptr has.
+pub fn refcount(ptr: *mut u8) -> usize {
+	unsafe { (*page_of(ptr)).refs as usize }
+}
+
+/// Let go of the page at ptr. If we were the last owner, it's freed. This
+/// returns how many owners are left.
+pub fn decref(ptr: *mut u8) -> usize {
+	let p = page_of(ptr);
+	unsafe {
+		assert!((*p).is_taken() && (*p).refs > 0, "Freeing a non-taken page {:p}?", ptr);
+		(*p).refs -= 1;
+		if (*p).refs == 0 {
+			free_page(p);
+			free_run_insert(page_addr(p), 1);
+		}
+		(*p).refs as usize
+	}
+}
+
 /// Deallocate a page by its pointer
 /// The way we've structured this, it will automatically coalesce
-/// contiguous pages.
+/// contiguous pages. This drops one reference from every page in the
+/// allocation, so a page someone else still owns (see incref()) stays.
 pub fn dealloc(ptr: *mut u8) {
-	// Make sure we don't try to free a null pointer.
-	assert!(!ptr.is_null());
+	if !is_alloc_head(ptr as usize) {
+		// Freeing this would tear apart whatever allocation ptr is
+		// in (or isn't in). Losing a few pages is better than that.
+		println!("dealloc: 0x{:x} didn't come from alloc(), not freeing it", ptr as usize);
+		return;
+	}
+	let mut p = page_of(ptr);
 	unsafe {
-		let addr =
-			HEAP_START + (ptr as usize - ALLOC_START) / PAGE_SIZE;
-		// Make sure that the address makes sense. The address we
-		// calculate here is the page structure, not the HEAP address!
-		assert!(addr >= HEAP_START && addr < ALLOC_START);
-		let mut p = addr as *mut Page;
 		// println!("PTR in is {:p}, addr is 0x{:x}", ptr, addr);
-		assert!((*p).is_taken(), "Freeing a non-taken page?");
-		// Keep clearing pages until we hit the last page.
-		while (*p).is_taken() && !(*p).is_last() {
-			(*p).clear();
+		// The pages we've freed since the last one that someone else
+		// still owns. They go on the free list together.
+		let mut freed: *mut Page = null_mut();
+		let mut num_freed = 0;
+		// Keep dropping pages until we hit the last page.
+		loop {
+			// If the following assertion fails, it is most likely
+			// caused by a double-free.
+			assert!(
+			        (*p).is_taken() && (*p).refs > 0,
+			        "Possible double-free detected! (Not taken found \
+			         before last)"
+			);
+			// We have to look before the page might get cleared.
+			let last = (*p).is_last();
+			(*p).refs -= 1;
+			if (*p).refs == 0 {
+				free_page(p);
+				if num_freed == 0 {
+					freed = p;
+				}
+				num_freed += 1;
+			}
+			else if num_freed > 0 {
+				free_run_insert(page_addr(freed), num_freed);
+				num_freed = 0;
+			}
+			if last {
+				break;
+			}
 			p = p.add(1);
 		}
-		// If the following assertion fails, it is most likely
-		// caused by a double-free.
-		assert!(
-		        (*p).is_last() == true,
-		        "Possible double-free detected! (Not taken found \
-		         before last)"
-		);
-		// If we get here, we've taken care of all previous pages and
-		// we are on the last page.
-		(*p).clear();
+		if num_freed > 0 {
+			free_run_insert(page_addr(freed), num_freed);
+		}
+	}
+}
+
+/// How many pages are there to allocate, and how many of them are free?
+pub fn page_stats() -> (usize, usize) {
+	unsafe {
+		let num_pages = alloc_pages();
+		let beg = HEAP_START as *const Page;
+		let free = (0..num_pages).filter(|&i| (*beg.add(i)).is_free()).count();
+		(num_pages, free)
 	}
 }
 
+/// What stats() found out about the page allocator. The free pages only
+/// help a big zalloc() if they're together, so largest_free_run is usually
+/// the number to look at when one fails.
+#[derive(Clone, Copy, Debug)]
+pub struct PageStats {
+	pub total:            usize,
+	pub free:             usize,
+	pub used:             usize,
+	// How many pieces the free pages are in.
+	pub free_runs:        usize,
+	pub largest_free_run: usize,
+}
+
+/// Count up the free and used pages and how scattered the free ones are.
+pub fn stats() -> PageStats {
+	let (total, free) = page_stats();
+	let mut free_runs = 0;
+	let mut largest_free_run = 0;
+	unsafe {
+		let mut cur = FREE_LIST;
+		while !cur.is_null() {
+			free_runs += 1;
+			largest_free_run = largest_free_run.max((*cur).pages);
+			cur = (*cur).next;
+		}
+	}
+	PageStats { total,
+	            free,
+	            used: total - free,
+	            free_runs,
+	            largest_free_run }
+}
+
 /// Print all page allocations
 /// This is mainly used for debugging.
 pub fn print_page_allocations() {
@@ -250,24 +598,17 @@ pub fn print_page_allocations() {
 		let mut num = 0;
 		while beg < end {
 			if (*beg).is_taken() {
-				let start = beg as usize;
-				let memaddr = ALLOC_START
-				              + (start - HEAP_START)
-				                * PAGE_SIZE;
+				let start = beg;
+				let memaddr = page_addr(start);
 				print!("0x{:x} => ", memaddr);
 				loop {
 					num += 1;
 					if (*beg).is_last() {
-						let end = beg as usize;
-						let memaddr = ALLOC_START
-						              + (end
-						                 - HEAP_START)
-						                * PAGE_SIZE
-						              + PAGE_SIZE - 1;
+						let memaddr = page_addr(beg) + PAGE_SIZE - 1;
 						print!(
 						       "0x{:x}: {:>3} page(s)",
 						       memaddr,
-						       (end - start + 1)
+						       (beg as usize - start as usize) / size_of::<Page>() + 1
 						);
 						println!(".");
 						break;
@@ -288,6 +629,13 @@ pub fn print_page_allocations() {
 		         num_pages - num,
 		         (num_pages - num) * PAGE_SIZE
 		);
+		let stats = stats();
+		println!(
+		         "Free runs: {:>6}, largest {} pages ({} bytes).",
+		         stats.free_runs,
+		         stats.largest_free_run,
+		         stats.largest_free_run * PAGE_SIZE
+		);
 		println!();
 	}
 }
@@ -310,6 +658,10 @@ pub enum EntryBits {
 	Global = 1 << 5,
 	Access = 1 << 6,
 	Dirty = 1 << 7,
+	// Bits 8 and 9 (RSW) are left for software. We use bit 8 to mark a
+	// page that isn't writable only because it's shared, so a store to
+	// it gets a copy. See resolve_cow().
+	Cow = 1 << 8,
 
 	// Convenience combinations
 	ReadWrite = 1 << 1 | 1 << 2,
@@ -317,6 +669,7 @@ pub enum EntryBits {
 	ReadWriteExecute = 1 << 1 | 1 << 2 | 1 << 3,
 
 	// User Convenience Combinations
+	UserRead = 1 << 1 | 1 << 4,
 	UserReadWrite = 1 << 1 | 1 << 2 | 1 << 4,
 	UserReadExecute = 1 << 1 | 1 << 3 | 1 << 4,
 	UserReadWriteExecute = 1 << 1 | 1 << 2 | 1 << 3 | 1 << 4,
@@ -331,6 +684,67 @@ impl EntryBits {
 	}
 }
 
+/// The value of a page table entry, copied out of the table so we can ask
+/// it questions without getting the bit positions wrong. Entry is the one
+/// that's actually in the table.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Pte(pub usize);
+
+impl Pte {
+	// The first bit (bit index #0) is the V bit for valid.
+	pub fn is_valid(self) -> bool {
+		self.has(EntryBits::Valid)
+	}
+
+	// A leaf has one or more RWX bits set. Otherwise, it points to the
+	// next level's table.
+	pub fn is_leaf(self) -> bool {
+		self.0 & EntryBits::ReadWriteExecute.val() != 0
+	}
+
+	pub fn readable(self) -> bool {
+		self.has(EntryBits::Read)
+	}
+
+	pub fn writable(self) -> bool {
+		self.has(EntryBits::Write)
+	}
+
+	pub fn executable(self) -> bool {
+		self.has(EntryBits::Execute)
+	}
+
+	// Can a process running in user mode use this page?
+	pub fn user(self) -> bool {
+		self.has(EntryBits::User)
+	}
+
+	// See mark_cow().
+	pub fn is_cow(self) -> bool {
+		self.has(EntryBits::Cow)
+	}
+
+	// The physical page number is 44 bits, starting at bit #10. The bits
+	// above it are reserved (or Svpbmt's, which we don't use).
+	pub fn ppn(self) -> usize {
+		(self.0 >> 10) & 0xfff_ffff_ffff
+	}
+
+	// The physical address of the page or table this points to.
+	pub fn addr(self) -> usize {
+		self.ppn() << 12
+	}
+
+	// Just the flags, V through the two RSW bits.
+	pub fn bits(self) -> usize {
+		self.0 & 0x3ff
+	}
+
+	fn has(self, bit: EntryBits) -> bool {
+		self.0 & bit.val() != 0
+	}
+}
+
 // A single entry.
 pub struct Entry {
 	pub entry: usize,
@@ -339,19 +753,20 @@ pub struct Entry {
 // The Entry structure describes one of the 512 entries per table, which is
 // described in the RISC-V privileged spec Figure 4.18.
 impl Entry {
+	pub fn pte(&self) -> Pte {
+		Pte(self.entry)
+	}
+
 	pub fn is_valid(&self) -> bool {
-		self.get_entry() & EntryBits::Valid.val() != 0
+		self.pte().is_valid()
 	}
 
-	// The first bit (bit index #0) is the V bit for
-	// valid.
 	pub fn is_invalid(&self) -> bool {
 		!self.is_valid()
 	}
 
-	// A leaf has one or more RWX bits set
 	pub fn is_leaf(&self) -> bool {
-		self.get_entry() & 0xe != 0
+		self.pte().is_leaf()
 	}
 
 	pub fn is_branch(&self) -> bool {
@@ -377,9 +792,46 @@ impl Table {
 		512
 	}
 }
+/// The paging modes we can build page tables for. Each VPN is 9 bits no
+/// matter which, so the only difference is how many levels there are,
+/// which is what the value is.
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+	// 39-bit virtual addresses, three levels (2, 1, 0)
+	Sv39 = 3,
+	// 48-bit virtual addresses, four levels (3, 2, 1, 0)
+	Sv48 = 4,
+}
+
+impl PagingMode {
+	/// What goes in the MODE field of SATP for this mode.
+	pub fn satp_mode(self) -> SatpMode {
+		match self {
+			PagingMode::Sv39 => SatpMode::Sv39,
+			PagingMode::Sv48 => SatpMode::Sv48,
+		}
+	}
+}
+
+/// The paging mode every page table is built for. Change this to Sv48 to
+/// get four levels. The SATP we build for a process picks the mode up
+/// from here, so nothing else has to change.
+pub const PAGING_MODE: PagingMode = PagingMode::Sv39;
+/// The level of the root table. This is 2 for Sv39 and 3 for Sv48.
+pub const ROOT_LEVEL: usize = PAGING_MODE as usize - 1;
+/// How many bits of a virtual address mean anything. The bits above this
+/// have to be copies of the top one.
+pub const VA_BITS: usize = 12 + PAGING_MODE as usize * 9;
 
-/// Map a virtual address to a physical address using 4096-byte page
-/// size.
+/// Get VPN[level] out of vaddr. On the virtual address, each VPN is
+/// exactly 9 bits, which is why we use the mask 0x1ff = 0b1_1111_1111 (9
+/// bits). VPN[0] = vaddr[20:12], VPN[1] = vaddr[29:21], and so on.
+fn vpn(vaddr: usize, level: usize) -> usize {
+	(vaddr >> (12 + level * 9)) & 0x1ff
+}
+
+/// Map a virtual address to a physical address.
 /// root: a mutable reference to the root Table
 /// vaddr: The virtual address to map
 /// paddr: The physical address to map
@@ -389,6 +841,10 @@ impl Table {
 ///       The bits MUST include one or more of the following:
 ///          Read, Write, Execute
 ///       The valid bit automatically gets added.
+/// level: Which level the leaf goes in. 0 is a 4 KiB page, 1 is a 2 MiB
+///        megapage, 2 is a 1 GiB gigapage, and with Sv48, 3 is a 512 GiB
+///        terapage. vaddr and paddr both have to be aligned to the size of
+///        the page.
 pub fn map(root: &mut Table,
            vaddr: usize,
            paddr: usize,
@@ -398,41 +854,28 @@ pub fn map(root: &mut Table,
 	// Make sure that Read, Write, or Execute have been provided
 	// otherwise, we'll leak memory and always create a page fault.
 	assert!(bits & 0xe != 0);
-	// Extract out each VPN from the virtual address
-	// On the virtual address, each VPN is exactly 9 bits,
-	// which is why we use the mask 0x1ff = 0b1_1111_1111 (9 bits)
-	let vpn = [
-	           // VPN[0] = vaddr[20:12]
-	           (vaddr >> 12) & 0x1ff,
-	           // VPN[1] = vaddr[29:21]
-	           (vaddr >> 21) & 0x1ff,
-	           // VPN[2] = vaddr[38:30]
-	           (vaddr >> 30) & 0x1ff,
-	];
-
-	// Just like the virtual address, extract the physical address
-	// numbers (PPN). However, PPN[2] is different in that it stores
-	// 26 bits instead of 9. Therefore, we use,
-	// 0x3ff_ffff = 0b11_1111_1111_1111_1111_1111_1111 (26 bits).
-	let ppn = [
-	           // PPN[0] = paddr[20:12]
-	           (paddr >> 12) & 0x1ff,
-	           // PPN[1] = paddr[29:21]
-	           (paddr >> 21) & 0x1ff,
-	           // PPN[2] = paddr[55:30]
-	           (paddr >> 30) & 0x3ff_ffff,
-	];
+	// A megapage's or gigapage's leaf doesn't have room for the low bits
+	// of the address. If they aren't zero, the MMU throws a page fault.
+	assert!(level <= ROOT_LEVEL);
+	let page_size = level_size(level);
+	assert!(vaddr.is_multiple_of(page_size) && paddr.is_multiple_of(page_size),
+	        "map: 0x{:x} -> 0x{:x} isn't aligned for a level {} page",
+	        vaddr, paddr, level);
+
 	// We will use this as a floating reference so that we can set
 	// individual entries as we walk the table.
-	let mut v = &mut root.entries[vpn[2]];
+	let mut v = &mut root.entries[vpn(vaddr, ROOT_LEVEL)];
 	// Now, we're going to traverse the page table and set the bits
 	// properly. We expect the root to be valid, however we're required to
 	// create anything beyond the root.
 	// In Rust, we create a range iterator using the .. operator.
 	// The .rev() will reverse the iteration since we need to start with
-	// VPN[2] The .. operator is inclusive on start but exclusive on end.
-	// So, (0..2) will iterate 0 and 1.
-	for i in (level..2).rev() {
+	// the level under the root. The .. operator is inclusive on start
+	// but exclusive on end. So, for Sv39, (0..2) will iterate 0 and 1.
+	for i in (level..ROOT_LEVEL).rev() {
+		// If there's a bigger page here, there's no table to put
+		// this one in.
+		assert!(!(v.is_valid() && v.is_leaf()), "map: 0x{:x} is inside of a bigger page", vaddr);
 		if !v.is_valid() {
 			// Allocate a page
 			let page = zalloc(1);
@@ -444,16 +887,20 @@ pub fn map(root: &mut Table,
 			            | EntryBits::Valid.val(),
 			);
 		}
-		let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
-		v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
+		let entry = v.pte().addr() as *mut Entry;
+		v = unsafe { entry.add(vpn(vaddr, i)).as_mut().unwrap() };
 	}
-	// When we get here, we should be at VPN[0] and v should be pointing to
-	// our entry.
+	// When we get here, we should be at VPN[level] and v should be
+	// pointing to our entry. If that's a table, putting a leaf over it
+	// would lose everything under it.
+	assert!(v.is_invalid() || v.is_leaf(), "map: 0x{:x} already has smaller pages in it", vaddr);
 	// The entry structure is Figure 4.18 in the RISC-V Privileged
-	// Specification
-	let entry = (ppn[2] << 28) |   // PPN[2] = [53:28]
-	            (ppn[1] << 19) |   // PPN[1] = [27:19]
-				(ppn[0] << 10) |   // PPN[0] = [18:10]
+	// Specification. The physical page number (PPN) is paddr[55:12], and
+	// it goes in entry[53:10]. It's split up into PPN[0], PPN[1], and so
+	// on just like the VPNs, but since they sit right next to each other,
+	// we can move them all at once. For a bigger page, the low PPNs are
+	// zero since paddr is aligned.
+	let entry = ((paddr >> 12) & 0xfff_ffff_ffff) << 10 |
 				bits |                    // Specified bits, such as User, Read, Write, etc
 				EntryBits::Valid.val() |  // Valid bit
 				EntryBits::Dirty.val() |  // Some machines require this to =1
@@ -464,6 +911,12 @@ pub fn map(root: &mut Table,
 	v.set_entry(entry);
 }
 
+/// How many bytes a leaf at level maps. 4 KiB at level 0, 2 MiB at level 1,
+/// and 1 GiB at level 2.
+pub const fn level_size(level: usize) -> usize {
+	1 << (12 + level * 9)
+}
+
 /// Unmaps and frees all memory associated with a table.
 /// root: The root table to start freeing.
 /// NOTE: This does NOT free root directly. This must be
@@ -471,51 +924,69 @@ pub fn map(root: &mut Table,
 /// The reason we don't free the root is because it is
 /// usually embedded into the Process structure.
 pub fn unmap(root: &mut Table) {
-	// Start with level 2
-	for lv2 in 0..Table::len() {
-		let ref entry_lv2 = root.entries[lv2];
-		if entry_lv2.is_valid() && entry_lv2.is_branch() {
+	// Start with the root's level
+	unmap_level(root, ROOT_LEVEL);
+}
+
+/// Free every table under table, which is at level.
+fn unmap_level(table: &Table, level: usize) {
+	// Level 0 only has leaves, so there aren't any tables under it.
+	if level == 0 {
+		return;
+	}
+	for entry in table.entries.iter() {
+		if entry.is_valid() && entry.is_branch() {
 			// This is a valid entry, so drill down and free.
-			let memaddr_lv1 = (entry_lv2.get_entry() & !0x3ff) << 2;
-			let table_lv1 = unsafe {
-				// Make table_lv1 a mutable reference instead of
-				// a pointer.
-				(memaddr_lv1 as *mut Table).as_mut().unwrap()
-			};
-			for lv1 in 0..Table::len() {
-				let ref entry_lv1 = table_lv1.entries[lv1];
-				if entry_lv1.is_valid() && entry_lv1.is_branch()
-				{
-					let memaddr_lv0 = (entry_lv1.get_entry()
-					                   & !0x3ff) << 2;
-					// The next level is level 0, which
-					// cannot have branches, therefore,
-					// we free here.
-					dealloc(memaddr_lv0 as *mut u8);
-				}
-			}
-			dealloc(memaddr_lv1 as *mut u8);
+			let memaddr = entry.pte().addr();
+			unmap_level(unsafe { &*(memaddr as *const Table) }, level - 1);
+			dealloc(memaddr as *mut u8);
 		}
 	}
 }
 
+/// Take away the one mapping vaddr is in, and leave the rest of the table
+/// alone. Tables under root stay, even if they're empty now, since unmap()
+/// frees them when the process goes away. The mapping's reference to the
+/// memory goes with it (see release_leaves()). Returns false if vaddr
+/// wasn't mapped.
+/// This only fences this hart. If the address space could be running on
+/// another one, the caller should asid_changed() it too.
+pub fn unmap_one(root: &mut Table, vaddr: usize) -> bool {
+	let (paddr, _, level) = match walk_detailed(root, vaddr) {
+		Some(found) => found,
+		None => return false,
+	};
+	// This might be a megapage, so we let go of every page it covers.
+	let size = level_size(level);
+	let first = paddr & !(size - 1);
+	if let Some(v) = leaf_mut(root, vaddr) {
+		v.set_entry(0);
+	}
+	satp_fence_vaddr(vaddr & !(size - 1));
+	for page in (first..first + size).step_by(PAGE_SIZE) {
+		if is_heap_page(page) {
+			decref(page as *mut u8);
+		}
+	}
+	true
+}
+
 /// Walk the page table to convert a virtual address to a
 /// physical address.
 /// If a page fault would occur, this returns None
 /// Otherwise, it returns Some with the physical address.
 pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
+	walk_detailed(root, vaddr).map(|(paddr, _, _)| paddr)
+}
+
+/// The same walk as virt_to_phys(), but this also gives back the leaf's
+/// whole entry and the level it was found at, as (physical address, entry,
+/// level). With the entry, the caller can check that the page can be read
+/// or written, or that it's a user page, before touching it.
+pub fn walk_detailed(root: &Table, vaddr: usize) -> Option<(usize, Pte, usize)> {
 	// Walk the page table pointed to by root
-	let vpn = [
-	           // VPN[0] = vaddr[20:12]
-	           (vaddr >> 12) & 0x1ff,
-	           // VPN[1] = vaddr[29:21]
-	           (vaddr >> 21) & 0x1ff,
-	           // VPN[2] = vaddr[38:30]
-	           (vaddr >> 30) & 0x1ff,
-	];
-
-	let mut v = &root.entries[vpn[2]];
-	for i in (0..=2).rev() {
+	let mut v = &root.entries[vpn(vaddr, ROOT_LEVEL)];
+	for i in (0..=ROOT_LEVEL).rev() {
 		if v.is_invalid() {
 			// This is an invalid entry, page fault.
 			break;
@@ -526,22 +997,196 @@ pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
 			// The offset mask masks off the PPN. Each PPN is 9
 			// bits and they start at bit #12. So, our formula
 			// 12 + i * 9
-			let off_mask = (1 << (12 + i * 9)) - 1;
+			let off_mask = level_size(i) - 1;
 			let vaddr_pgoff = vaddr & off_mask;
-			let addr = ((v.get_entry() << 2) as usize) & !off_mask;
-			return Some(addr | vaddr_pgoff);
+			let addr = v.pte().addr() & !off_mask;
+			return Some((addr | vaddr_pgoff, v.pte(), i));
+		}
+		else if i == 0 {
+			// A branch at level 0 has nowhere to go.
+			break;
 		}
 		// Set v to the next entry which is pointed to by this
 		// entry. However, the address was shifted right by 2 places
 		// when stored in the page table entry, so we shift it left
 		// to get it back into place.
-		let entry = ((v.get_entry() & !0x3ff) << 2) as *const Entry;
-		// We do i - 1 here, however we should get None or Some() above
-		// before we do 0 - 1 = -1.
-		v = unsafe { entry.add(vpn[i - 1]).as_ref().unwrap() };
+		let entry = v.pte().addr() as *const Entry;
+		v = unsafe { entry.add(vpn(vaddr, i - 1)).as_ref().unwrap() };
 	}
 
 	// If we get here, we've exhausted all valid tables and haven't
 	// found a leaf.
 	None
 }
+
+/// Find the leaf entry for vaddr so that we can change it.
+fn leaf_mut(root: &mut Table, vaddr: usize) -> Option<&mut Entry> {
+	let mut v = &mut root.entries[vpn(vaddr, ROOT_LEVEL)];
+	for i in (0..=ROOT_LEVEL).rev() {
+		if v.is_invalid() {
+			return None;
+		}
+		else if v.is_leaf() {
+			return Some(v);
+		}
+		else if i == 0 {
+			return None;
+		}
+		let entry = v.pte().addr() as *mut Entry;
+		v = unsafe { entry.add(vpn(vaddr, i - 1)).as_mut().unwrap() };
+	}
+	None
+}
+
+/// Make the page at vaddr copy-on-write. It can't be written anymore,
+/// and the first store to it goes through resolve_cow(). Returns false if
+/// there's no writable page there.
+pub fn mark_cow(root: &mut Table, vaddr: usize) -> bool {
+	match leaf_mut(root, vaddr) {
+		Some(v) if v.pte().writable() => {
+			v.set_entry(v.get_entry() & !EntryBits::Write.val() | EntryBits::Cow.val());
+			true
+		}
+		_ => false,
+	}
+}
+
+/// Map every page in src into dst at the same address, which is what
+/// fork() does with the parent's memory. Nothing is copied. Writable pages
+/// become copy-on-write in both tables, so whichever one stores to a page
+/// first gets its own copy. dst's mappings each hold a reference to their
+/// page. The caller has to fence src's ASID, since its pages just lost
+/// their write permission.
+pub fn share_cow(src: &mut Table, dst: &mut Table) {
+	let mut leaves = Vec::new();
+	walk_leaves(src, ROOT_LEVEL, 0, &mut |vaddr, paddr, size, bits| leaves.push((vaddr, paddr, size, bits)));
+	for (vaddr, paddr, size, bits) in leaves {
+		// User memory is all 4 KiB pages. A megapage would need all 512
+		// of its pages copied at once, so we don't share those.
+		if size != PAGE_SIZE {
+			continue;
+		}
+		let bits = if mark_cow(src, vaddr) { bits & !EntryBits::Write.val() | EntryBits::Cow.val() } else { bits };
+		map(dst, vaddr, paddr, bits, 0);
+		if is_heap_page(paddr) {
+			incref(paddr as *mut u8);
+		}
+	}
+}
+
+/// Something stored to vaddr, which is copy-on-write. Give root its own
+/// copy of the page, writable, and let go of the shared one. If nobody
+/// else has the page anymore, there's nothing to copy, so it just becomes
+/// writable again. Returns false if the page isn't copy-on-write (or we're
+/// out of memory). root is a pointer because the kernel's copies into user
+/// memory only have a shared reference to the table. The caller has to
+/// fence the table's ASID.
+pub unsafe fn resolve_cow(root: *mut Table, vaddr: usize) -> bool {
+	let v = match leaf_mut(&mut *root, vaddr) {
+		Some(v) if v.pte().is_cow() => v,
+		_ => return false,
+	};
+	let pte = v.pte();
+	let bits = pte.bits() & !EntryBits::Cow.val() | EntryBits::Write.val();
+	let old = pte.addr() as *mut u8;
+	if is_heap_page(old as usize) && refcount(old) == 1 {
+		v.set_entry(pte.0 & !0x3ff | bits);
+		return true;
+	}
+	let new = zalloc(1);
+	if new.is_null() {
+		return false;
+	}
+	core::ptr::copy_nonoverlapping(old, new, PAGE_SIZE);
+	// The page is aligned by 4,096, so shifting it right by 2 puts the
+	// PPN right where it goes.
+	v.set_entry((new as usize >> 2) | bits);
+	if is_heap_page(old as usize) {
+		decref(old);
+	}
+	true
+}
+
+/// Let go of every page mapped in root. Each page a process has mapped
+/// holds a reference to it, which is how a page shared with another
+/// process (a framebuffer, or one we haven't copied since fork) stays
+/// around for whoever is still using it. Pages that didn't come from
+/// alloc() aren't counted, so they're left alone.
+pub fn release_leaves(root: &Table) {
+	walk_leaves(root, ROOT_LEVEL, 0, &mut |_, paddr, size, _| {
+		for page in (paddr..paddr + size).step_by(PAGE_SIZE) {
+			if is_heap_page(page) {
+				decref(page as *mut u8);
+			}
+		}
+	});
+}
+
+// A run of pages that print_table_mappings() is building up.
+struct Mapping {
+	vaddr: usize,
+	paddr: usize,
+	size:  usize,
+	bits:  usize,
+}
+
+/// Print every mapping in the page table at root, one line per range of
+/// pages that are contiguous in both virtual and physical memory and have
+/// the same permissions. This is mainly used for debugging.
+pub fn print_table_mappings(root: &Table) {
+	let mut run: Option<Mapping> = None;
+	let mut pages = 0;
+	println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+	walk_leaves(root, ROOT_LEVEL, 0, &mut |vaddr, paddr, size, bits| {
+		pages += size / PAGE_SIZE;
+		if let Some(r) = run.as_mut() {
+			if r.vaddr + r.size == vaddr && r.paddr + r.size == paddr && r.bits == bits {
+				r.size += size;
+				return;
+			}
+			print_mapping(r);
+		}
+		run = Some(Mapping { vaddr, paddr, size, bits });
+	});
+	if let Some(r) = run.as_ref() {
+		print_mapping(r);
+	}
+	println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+	println!("Mapped: {:>6} pages ({:>10} bytes).", pages, pages * PAGE_SIZE);
+}
+
+fn print_mapping(run: &Mapping) {
+	// Sv39 addresses are 39 bits, and bits 63:39 have to match bit 38.
+	// It's the same for Sv48 with 48 bits.
+	let sign_extend = |vaddr: usize| if vaddr & 1 << (VA_BITS - 1) != 0 { vaddr | !((1 << VA_BITS) - 1) } else { vaddr };
+	let flag = |bit: EntryBits, c: char| if run.bits & bit.val() != 0 { c } else { '-' };
+	println!("0x{:016x}-0x{:016x} -> 0x{:x}  {}{}{}{}{}{}",
+	         sign_extend(run.vaddr),
+	         sign_extend(run.vaddr + run.size - 1),
+	         run.paddr,
+	         flag(EntryBits::Read, 'R'),
+	         flag(EntryBits::Write, 'W'),
+	         flag(EntryBits::Execute, 'X'),
+	         flag(EntryBits::User, 'U'),
+	         flag(EntryBits::Global, 'G'),
+	         flag(EntryBits::Cow, 'C'));
+}
+
+/// Call f(vaddr, paddr, size, bits) for each leaf under table, in order of
+/// virtual address. The traversal is the same as virt_to_phys(), except we
+/// go down every valid branch instead of just one.
+fn walk_leaves(table: &Table, level: usize, base: usize, f: &mut dyn FnMut(usize, usize, usize, usize)) {
+	for (i, entry) in table.entries.iter().enumerate() {
+		if entry.is_invalid() {
+			continue;
+		}
+		let vaddr = base | i << (12 + level * 9);
+		let addr = entry.pte().addr();
+		if entry.is_leaf() {
+			f(vaddr, addr, level_size(level), entry.pte().bits());
+		}
+		else if level > 0 {
+			walk_leaves(unsafe { &*(addr as *const Table) }, level - 1, vaddr, f);
+		}
+	}
+}
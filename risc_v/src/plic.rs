@@ -113,24 +113,55 @@ pub fn set_priority(id: u32, prio: u8) {
     }
 }
 
+// How many external interrupts came in that nobody wanted. A misbehaving
+// device can fire these constantly, so we count them instead of printing
+// every single one.
+static mut SPURIOUS_COUNT: usize = 0;
+
+/// Get the number of spurious or unknown interrupts we've seen since boot.
+pub fn spurious_count() -> usize {
+    unsafe { SPURIOUS_COUNT }
+}
+
+/// Record a spurious interrupt. We only print on powers of two (1, 2, 4, 8...)
+/// so that a stuck line shows up on the console without flooding it.
+fn spurious(id: u32) {
+    let count = unsafe {
+        SPURIOUS_COUNT += 1;
+        SPURIOUS_COUNT
+    };
+    if count.is_power_of_two() {
+        println!("Spurious external interrupt {} ({} so far)", id, count);
+    }
+}
+
 pub fn handle_interrupt() {
     if let Some(interrupt) = next() {
         // If we get here, we've got an interrupt from the claim register. The PLIC will
         // automatically prioritize the next interrupt, so when we get it from claim, it
         // will be the next in priority order.
-        match interrupt {
+        let handled = match interrupt {
             1..=8 => {
-                virtio::handle_interrupt(interrupt);
+                virtio::handle_interrupt(interrupt)
             }
             10 => { // Interrupt 10 is the UART interrupt.
                 uart::handle_interrupt();
+                true
             }
-            _ => {
-                println!("Unknown external interrupt: {}", interrupt);
-            }
+            _ => false,
+        };
+        if !handled {
+            spurious(interrupt);
         }
         // We've claimed it, so now say that we've handled it. This resets the interrupt pending
         // and allows the UART to interrupt again. Otherwise, the UART will get "stuck".
+        // This has to happen even if nobody handled it. The PLIC won't give us this id
+        // again until it's completed, so skipping this would silently kill the line.
         complete(interrupt);
     }
+    else {
+        // We were told there was an external interrupt, but by the time we claimed
+        // it, there was nothing there. There's nothing to complete here.
+        spurious(0);
+    }
 }
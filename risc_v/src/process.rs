@@ -3,29 +3,74 @@
 // Stephen Marz
 // 27 Nov 2019
 
-use crate::{cpu::{get_mtime,
+use crate::{block::{clear_watcher, BlockFile},
+            cpu::{asid_changed,
+                  context_switch_time,
+                  fence_asid_if_stale,
+                  get_mtime,
+                  mhartid_read,
+                  satp_fence_all,
                   CpuMode,
 				  TrapFrame,
 				  Registers},
-			fs::Inode,
-            page::{dealloc,
+			fs::MinixFile,
+			procfs::ProcFile,
+			ramfs::RamFile,
+			sched::{PreemptGuard, MAX_HARTS},
+            page::{alloc_stack,
+                   dealloc,
+                   guard_ok,
+                   guard_page,
+                   map,
+                   print_table_mappings,
+                   release_leaves,
+                   resolve_cow,
                    unmap,
+                   virt_to_phys,
+				   EntryBits,
+				   PAGE_SIZE,
 				   zalloc,
 				   Table},
-            syscall::{syscall_exit, syscall_yield}};
-use alloc::{string::String, collections::{vec_deque::VecDeque, BTreeMap}};
-use core::ptr::null_mut;
+            syscall::{syscall_exit, syscall_yield, SysError}};
+use alloc::{string::String, vec::Vec, collections::{vec_deque::VecDeque, BTreeMap}};
+use core::{mem::swap, ptr::null_mut, sync::atomic::{AtomicUsize, Ordering}};
 use crate::lock::Mutex;
 
 // How many pages are we going to give a process for their
 // stack?
 pub const STACK_PAGES: usize = 35;
+// A kernel process' stack has a guard page under it (see new_kernel_stack()),
+// so this is how far from the bottom of it the stack pointer starts.
+const KERNEL_STACK_SIZE: usize = (STACK_PAGES + 1) * PAGE_SIZE;
 // We want to adjust the stack to be at the bottom of the memory allocation
 // regardless of where it is on the kernel heap.
 pub const STACK_ADDR: usize = 0x1_0000_0000;
 // All processes will have a defined starting point in virtual memory.
 // We will use this later when we load processes from disk.
 pub const PROCESS_STARTING_ADDR: usize = 0x2000_0000;
+// Priorities mirror the PLIC's 0..7 range. A process gets one
+// CONTEXT_SWITCH_TIME period (its quantum) for every priority level, so
+// a higher priority means a LONGER time slice before it gets preempted.
+// The default priority therefore gives the same single-period slice that
+// every process used to get. A process' block requests also get in
+// line by its priority when the device is busy. See block::block_op().
+pub const MIN_PRIORITY: u8 = 1;
+pub const DEFAULT_PRIORITY: u8 = 1;
+pub const MAX_PRIORITY: u8 = 7;
+// Group and others can't write to new files unless the process asks.
+pub const DEFAULT_UMASK: u16 = 0o022;
+// The lowest doubleword of every kernel stack holds this. Stacks grow
+// down, so if it ever changes, whoever owns the stack ran off the end of
+// it and has been scribbling over whatever is below it on the heap.
+pub const STACK_CANARY: u64 = 0x5354_4143_4b5f_4f4b;
+// The most files all processes together can have open. Every descriptor
+// lives on the kernel heap along with whatever it holds on to (an inode,
+// a procfs snapshot), so without this, one process could open its way
+// through all of kmalloc.
+pub const MAX_OPEN_FILES: usize = 256;
+// How many of those are open right now. The console descriptors every
+// process starts out with don't count.
+static OPEN_FILES: AtomicUsize = AtomicUsize::new(0);
 
 // Here, we store a process list. It uses the global allocator
 // that we made before and its job is to store all processes.
@@ -39,8 +84,84 @@ pub const PROCESS_STARTING_ADDR: usize = 0x2000_0000;
 pub static mut PROCESS_LIST: Option<VecDeque<Process>> = None;
 pub static mut PROCESS_LIST_MUTEX: Mutex = Mutex::new();
 // We can search through the process list to get a new PID, but
-// it's probably easier and faster just to increase the pid:
-pub static mut NEXT_PID: u16 = 1;
+// it's probably easier and faster just to increase the pid. This is the
+// next one we'll try. See alloc_pid().
+static mut NEXT_PID: u16 = 1;
+// The PID each hart is running, or 0 if it hasn't run anyone yet. The
+// trap frame's pid is what really says who's running; this is a copy
+// that rust_switch_to_user() keeps so we can ask without a frame.
+static mut CURRENT_PID: [u16; MAX_HARTS] = [0; MAX_HARTS];
+
+/// Remember that hart is about to run pid.
+pub fn set_current(hart: usize, pid: u16) {
+	unsafe {
+		CURRENT_PID[hart] = pid;
+	}
+}
+
+/// The PID running on this hart.
+pub fn current_pid() -> u16 {
+	unsafe { CURRENT_PID[mhartid_read()] }
+}
+
+// If anybody holds PROCESS_LIST_MUTEX for this many context switch periods,
+// the watchdog complains about them.
+pub const WATCHDOG_TICKS: usize = 500;
+// The acquisition time we last complained about, so we only say it once
+// for each time the lock is held too long.
+static mut WATCHDOG_REPORTED: usize = 0;
+
+/// The timer calls this every tick. Nothing can be scheduled while somebody
+/// holds PROCESS_LIST_MUTEX, so a kernel process that hangs with it hangs
+/// everything, and quietly. This at least says who it was. We don't kill
+/// the holder: it might have the process list taken out of PROCESS_LIST,
+/// and then there's no list to delete it from.
+pub fn watchdog() {
+	unsafe {
+		if let Some((pid, since)) = PROCESS_LIST_MUTEX.holder() {
+			// The period can change with set_context_switch_hz(), so it's
+			// whatever it is now, not CONTEXT_SWITCH_TIME.
+			let ticks = (get_mtime() - since) / context_switch_time() as usize;
+			if ticks >= WATCHDOG_TICKS && WATCHDOG_REPORTED != since {
+				WATCHDOG_REPORTED = since;
+				println!("watchdog: PID {} has held PROCESS_LIST_MUTEX for {} ticks", pid, ticks);
+			}
+		}
+	}
+}
+
+/// Allocate a stack for a kernel process with a guard page at the bottom.
+/// This returns the guard page, which is where the allocation starts. The
+/// top of the stack is KERNEL_STACK_SIZE bytes above it.
+fn new_kernel_stack() -> *mut u8 {
+	let top = alloc_stack(STACK_PAGES);
+	if top.is_null() {
+		return null_mut();
+	}
+	guard_page(top, STACK_PAGES)
+}
+
+/// Put the canary at the bottom (lowest address) of a stack.
+pub fn place_canary(stack: *mut u8) {
+	if !stack.is_null() {
+		unsafe {
+			(stack as *mut u64).write_volatile(STACK_CANARY);
+		}
+	}
+}
+
+/// Is the canary at the bottom of this stack still there?
+pub fn canary_ok(stack: *const u8) -> bool {
+	stack.is_null() || unsafe { (stack as *const u64).read_volatile() } == STACK_CANARY
+}
+
+/// A stack has overflowed. We can't trust anything on the heap anymore,
+/// including the process list, so don't try to clean up. Just say what
+/// happened and stop.
+pub fn stack_overflow(hart: usize, pid: usize) -> ! {
+	println!("kernel stack overflow on hart {} / PID {}", hart, pid);
+	crate::abort();
+}
 
 // The following set_* and get_by_pid functions are C-style functions
 // They probably need to be re-written in a more Rusty style, but for
@@ -52,6 +173,7 @@ pub static mut NEXT_PID: u16 = 1;
 pub fn set_running(pid: u16) -> bool {
 	// Yes, this is O(n). A better idea here would be a static list
 	// of process pointers.
+	let _guard = PreemptGuard::new();
 	let mut retval = false;
 	unsafe {
 		if let Some(mut pl) = PROCESS_LIST.take() {
@@ -77,6 +199,7 @@ pub fn set_running(pid: u16) -> bool {
 pub fn set_waiting(pid: u16) -> bool {
 	// Yes, this is O(n). A better idea here would be a static list
 	// of process pointers.
+	let _guard = PreemptGuard::new();
 	let mut retval = false;
 	unsafe {
 		if let Some(mut pl) = PROCESS_LIST.take() {
@@ -100,6 +223,7 @@ pub fn set_waiting(pid: u16) -> bool {
 pub fn set_sleeping(pid: u16, duration: usize) -> bool {
 	// Yes, this is O(n). A better idea here would be a static list
 	// of process pointers.
+	let _guard = PreemptGuard::new();
 	let mut retval = false;
 	unsafe {
 		if let Some(mut pl) = PROCESS_LIST.take() {
@@ -120,14 +244,45 @@ pub fn set_sleeping(pid: u16, duration: usize) -> bool {
 	retval
 }
 
+/// Charge a process for the CPU time it just used. The ticks are in
+/// context switch periods. This is called from the timer interrupt, so if
+/// someone else has the process list, we just don't charge them this time.
+pub fn add_cpu_ticks(pid: u16, ticks: usize) {
+	let _guard = PreemptGuard::new();
+	unsafe {
+		if let Some(mut pl) = PROCESS_LIST.take() {
+			for proc in pl.iter_mut() {
+				if proc.pid == pid {
+					proc.data.cpu_ticks += ticks;
+					break;
+				}
+			}
+			PROCESS_LIST.replace(pl);
+		}
+	}
+}
+
 /// Delete a process given by pid. If this process doesn't exist,
-/// this function does nothing.
+/// this function does nothing. This is for a process that didn't get to
+/// exit on its own, so a worker's completion gets -EIO.
 pub fn delete_process(pid: u16) {
+	exit_process(pid, SysError::EIO.to_ret());
+}
+
+/// Take pid off of the process list. If it's a kernel worker, value is
+/// what it returned, and we hand that to whoever is waiting on it.
+pub fn exit_process(pid: u16, value: usize) {
+	let _guard = PreemptGuard::new();
+	// Any block requests this process is waiting on will finish after
+	// it's gone, so make sure they don't try to wake it up.
+	clear_watcher(pid);
+	let mut completion = None;
 	unsafe {
 		if let Some(mut pl) = PROCESS_LIST.take() {
 			for i in 0..pl.len() {
 				let p = pl.get_mut(i).unwrap();
-				if (*(*p).frame).pid as u16 == pid {
+				if (*p.frame).pid as u16 == pid {
+					completion = p.data.completion.take();
 					// When the structure gets dropped, all
 					// of the allocations get deallocated.
 					pl.remove(i);
@@ -140,11 +295,28 @@ pub fn delete_process(pid: u16) {
 			PROCESS_LIST.replace(pl);
 		}
 	}
+	match completion {
+		Some(Completion::Slot(slot)) => unsafe {
+			slot.write_volatile(Some(value));
+		},
+		Some(Completion::Wake(waiter)) => {
+			// The waiter might have been killed while we were at it.
+			unsafe {
+				let p = get_by_pid(waiter);
+				if !p.is_null() {
+					(*(*p).frame).regs[Registers::A0 as usize] = value;
+				}
+			}
+			set_running(waiter);
+		}
+		None => {},
+	}
 }
 
 /// Get a process by PID. Since we leak the process list, this is
 /// unsafe since the process can be deleted and we'll still have a pointer.
 pub unsafe fn get_by_pid(pid: u16) -> *mut Process {
+	let _guard = PreemptGuard::new();
 	let mut ret = null_mut();
 	if let Some(mut pl) = PROCESS_LIST.take() {
 		for i in pl.iter_mut() {
@@ -158,12 +330,75 @@ pub unsafe fn get_by_pid(pid: u16) -> *mut Process {
 	ret
 }
 
+/// Run f on the process with the given PID while holding the process list
+/// mutex. Unlike get_by_pid(), the process can't be deleted or switched out
+/// while f is using it. System calls come through here from a trap, so we
+/// can't wait for the mutex. If a kernel process has it, or there is no
+/// such process, we return None and f never runs. Don't call anything in f
+/// that takes the mutex itself, such as add_kernel_process().
+pub fn with_process<R, F>(pid: u16, f: F) -> Option<R>
+	where F: FnOnce(&mut Process) -> R
+{
+	try_with_process(pid, f).flatten()
+}
+
+/// with_process(), for when it matters why f didn't run. None means we
+/// couldn't get the process list, so the process might well be there, and
+/// it's worth trying again later. Some(None) means it really is gone.
+pub fn try_with_process<R, F>(pid: u16, f: F) -> Option<Option<R>>
+	where F: FnOnce(&mut Process) -> R
+{
+	unsafe {
+		if !PROCESS_LIST_MUTEX.try_lock() {
+			return None;
+		}
+		let ret = {
+			let _guard = PreemptGuard::new();
+			// get_by_pid() can't tell a busy list from a missing process
+			// either, so we look first.
+			if PROCESS_LIST.is_none() {
+				None
+			}
+			else {
+				Some(get_by_pid(pid).as_mut().map(f))
+			}
+		};
+		PROCESS_LIST_MUTEX.unlock();
+		ret
+	}
+}
+
+/// The name of a process for printing. We're usually in a trap when we
+/// want this, so if we can't get the process list, or the process is gone,
+/// we just say "?".
+pub fn name_of(pid: u16) -> String {
+	with_process(pid, |process| process.name.clone()).unwrap_or_else(|| String::from("?"))
+}
+
+/// Print the page table mappings of a user process. Kernel processes run
+/// with the MMU off, so they don't have any.
+pub fn print_mappings(pid: u16) {
+	let printed = with_process(pid, |process| {
+		if unsafe { (*process.frame).mode } == CpuMode::User as usize {
+			println!("Mappings for PID {} ({})", pid, process.name);
+			print_table_mappings(unsafe { &*process.mmu_table });
+		}
+	});
+	if printed.is_none() {
+		println!("Can't get PID {}'s page table right now.", pid);
+	}
+}
+
 /// We will eventually move this function out of here, but its
 /// job is just to take a slot in the process list.
 fn init_process() {
 	// We can't do much here until we have system calls because
 	// we're running in User space.
 	println!("Init process started...");
+	// The kernel's shell takes the console instead of /shell, which
+	// test::test() would have run.
+	#[cfg(feature = "kernel-shell")]
+	add_kernel_process(crate::shell::shell, "shell");
 	loop {
 		// Alright, I forgot. We cannot put init to sleep since the
 		// scheduler will loop until it finds a process to run. Since
@@ -173,8 +408,45 @@ fn init_process() {
 	}
 }
 
-/// Add a kernel process.
-pub fn add_kernel_process(func: fn()) -> u16 {
+/// Hand out a PID that nobody is using. PIDs wrap around, but 0 is the
+/// kernel's ASID, so we skip it, along with every PID that's still on pl,
+/// the process list. If we wrapped, every ASID we're about to hand out
+/// might still have translations for some long dead process in the TLB,
+/// so everyone fences. Returns 0 if every PID is taken.
+pub fn alloc_pid(pl: &VecDeque<Process>) -> u16 {
+	unsafe {
+		for _ in 0..u16::MAX {
+			let pid = NEXT_PID;
+			NEXT_PID = NEXT_PID.wrapping_add(1);
+			if NEXT_PID == 0 {
+				NEXT_PID = 1;
+				satp_fence_all();
+			}
+			if !pl.iter().any(|p| p.pid == pid) {
+				return pid;
+			}
+		}
+	}
+	0
+}
+
+/// alloc_pid() for when we don't have the process list. Someone might have
+/// a PID we hand out here before their process is on the list, but
+/// NEXT_PID has moved past it, so we won't hand it out again until we've
+/// wrapped all the way around. Returns 0 if we couldn't get one.
+pub fn new_pid() -> u16 {
+	let _guard = PreemptGuard::new();
+	unsafe {
+		PROCESS_LIST_MUTEX.spin_lock();
+		let pid = PROCESS_LIST.as_ref().map(alloc_pid).unwrap_or(0);
+		PROCESS_LIST_MUTEX.unlock();
+		pid
+	}
+}
+
+/// Add a kernel process. The name is only for diagnostics, but it's
+/// usually the name of func.
+pub fn add_kernel_process(func: fn(), name: &str) -> u16 {
 	// This is the Rust-ism that really trips up C++ programmers.
 	// PROCESS_LIST is wrapped in an Option<> enumeration, which
 	// means that the Option owns the Deque. We can only borrow from
@@ -188,24 +460,23 @@ pub fn add_kernel_process(func: fn()) -> u16 {
 	let func_addr = func as usize;
 	let func_vaddr = func_addr; //- 0x6000_0000;
 			// println!("func_addr = {:x} -> {:x}", func_addr, func_vaddr);
-			// We will convert NEXT_PID below into an atomic increment when
-			// we start getting into multi-hart processing. For now, we want
-			// a process. Get it to work, then improve it!
-	let my_pid = unsafe { NEXT_PID };
-	let mut ret_proc =
+	let my_pid = new_pid();
+	if my_pid == 0 {
+		return 0;
+	}
+	let ret_proc =
 		Process { frame:       zalloc(1) as *mut TrapFrame,
-					stack:       zalloc(STACK_PAGES),
+					stack:       new_kernel_stack(),
 					pid:         my_pid,
 					mmu_table:   zalloc(1) as *mut Table,
 					state:       ProcessState::Running,
 					data:        ProcessData::new(),
 					sleep_until: 0,
-					program:     null_mut(),
 					brk:         0,
+					heap:        0,
+					priority:    DEFAULT_PRIORITY,
+					name:        String::from(name),
 					};
-	unsafe {
-		NEXT_PID += 1;
-	}
 	// Now we move the stack pointer to the bottom of the
 	// allocation. The spec shows that register x2 (2) is the stack
 	// pointer.
@@ -219,13 +490,14 @@ pub fn add_kernel_process(func: fn()) -> u16 {
 		// 1 is the return address register. This makes it so we
 		// don't have to do syscall_exit() when a kernel process
 		// finishes.
-		(*ret_proc.frame).regs[Registers::Ra as usize] = ra_delete_proc as usize;
+		(*ret_proc.frame).regs[Registers::Ra as usize] = ra_delete_proc as *const () as usize;
 		(*ret_proc.frame).regs[Registers::Sp as usize] =
-			ret_proc.stack as usize + STACK_PAGES * 4096;
+			ret_proc.stack as usize + KERNEL_STACK_SIZE;
 		(*ret_proc.frame).mode = CpuMode::Machine as usize;
 		(*ret_proc.frame).pid = ret_proc.pid as usize;
 	}
 
+	let _guard = PreemptGuard::new();
 	if let Some(mut pl) = unsafe { PROCESS_LIST.take() } {
 		pl.push_back(ret_proc);
 		// Now, we no longer need the owned Deque, so we hand it
@@ -251,47 +523,58 @@ pub fn add_kernel_process(func: fn()) -> u16 {
 /// (ra) register. This function address is what it will return to, which
 /// in turn calls exit. If we don't exit, the process will most likely
 /// fault.
-fn ra_delete_proc() {
-	syscall_exit();
+/// The return value is still sitting in A0, so it looks like our argument.
+/// Only a worker (see add_kernel_worker()) has one, but nobody looks at it
+/// unless the process has a completion.
+fn ra_delete_proc(ret: usize) {
+	syscall_exit(ret);
 }
 
 /// This is the same as the add_kernel_process function, except you can pass
 /// arguments. Typically, this will be a memory address on the heap where
 /// arguments can be found.
-pub fn add_kernel_process_args(func: fn(args_ptr: usize), args: usize) -> u16 {
-	// This is the Rust-ism that really trips up C++ programmers.
-	// PROCESS_LIST is wrapped in an Option<> enumeration, which
-	// means that the Option owns the Deque. We can only borrow from
-	// it or move ownership to us. In this case, we choose the
-	// latter, where we move ownership to us, add a process, and
-	// then move ownership back to the PROCESS_LIST.
-	// This allows mutual exclusion as anyone else trying to grab
-	// the process list will get None rather than the Deque.
+pub fn add_kernel_process_args(func: fn(args_ptr: usize), args: usize, name: &str) -> u16 {
+	spawn_kernel_process(func as usize, args, name, None)
+}
+
+/// This is add_kernel_process_args() for a worker that has something to
+/// say when it's done. Whatever func returns goes to completion. If the
+/// worker dies before it returns, completion gets -EIO instead, so
+/// whoever is waiting on it is never left hanging.
+pub fn add_kernel_worker(func: fn(args_ptr: usize) -> usize, args: usize, name: &str, completion: Completion) -> u16 {
+	spawn_kernel_process(func as usize, args, name, Some(completion))
+}
+
+fn spawn_kernel_process(func_addr: usize, args: usize, name: &str, completion: Option<Completion>) -> u16 {
+	let _guard = PreemptGuard::new();
 	unsafe {PROCESS_LIST_MUTEX.spin_lock(); }
 	if let Some(mut pl) = unsafe { PROCESS_LIST.take() } {
 		// .take() will replace PROCESS_LIST with None and give
 		// us the only copy of the Deque.
-		let func_addr = func as usize;
 		let func_vaddr = func_addr; //- 0x6000_0000;
 			    // println!("func_addr = {:x} -> {:x}", func_addr, func_vaddr);
-			    // We will convert NEXT_PID below into an atomic increment when
-			    // we start getting into multi-hart processing. For now, we want
-			    // a process. Get it to work, then improve it!
-		let my_pid = unsafe { NEXT_PID };
+		// We have the list, so nobody can take this PID out from under us.
+		let my_pid = alloc_pid(&pl);
+		if my_pid == 0 {
+			unsafe {
+				PROCESS_LIST.replace(pl);
+				PROCESS_LIST_MUTEX.unlock();
+			}
+			return 0;
+		}
 		let mut ret_proc =
 			Process { frame:       zalloc(1) as *mut TrapFrame,
-			          stack:       zalloc(STACK_PAGES),
+			          stack:       new_kernel_stack(),
 			          pid:         my_pid,
 			          mmu_table:        zalloc(1) as *mut Table,
 			          state:       ProcessState::Running,
 			          data:        ProcessData::new(),
 					  sleep_until: 0, 
-					  program:		null_mut(),
 					  brk:         0,
+					  heap:        0,
+					  priority:    DEFAULT_PRIORITY,
+					  name:        String::from(name),
 					};
-		unsafe {
-			NEXT_PID += 1;
-		}
 		// Now we move the stack pointer to the bottom of the
 		// allocation. The spec shows that register x2 (2) is the stack
 		// pointer.
@@ -306,12 +589,13 @@ pub fn add_kernel_process_args(func: fn(args_ptr: usize), args: usize) -> u16 {
 			// 1 is the return address register. This makes it so we
 			// don't have to do syscall_exit() when a kernel process
 			// finishes.
-			(*ret_proc.frame).regs[Registers::Ra as usize] = ra_delete_proc as usize;
+			(*ret_proc.frame).regs[Registers::Ra as usize] = ra_delete_proc as *const () as usize;
 			(*ret_proc.frame).regs[Registers::Sp as usize] =
-				ret_proc.stack as usize + STACK_PAGES * 4096;
+				ret_proc.stack as usize + KERNEL_STACK_SIZE;
 			(*ret_proc.frame).mode = CpuMode::Machine as usize;
 			(*ret_proc.frame).pid = ret_proc.pid as usize;
 		}
+		ret_proc.data.completion = completion;
 		pl.push_back(ret_proc);
 		// Now, we no longer need the owned Deque, so we hand it
 		// back by replacing the PROCESS_LIST's None with the
@@ -334,6 +618,23 @@ pub fn add_kernel_process_args(func: fn(args_ptr: usize), args: usize) -> u16 {
 	}
 }
 
+/// Put a process that's ready to go, such as one from elf::File::load_proc(),
+/// on the process list. Returns its PID, or 0 if we couldn't get the list.
+pub fn add_process(process: Process) -> u16 {
+	let _guard = PreemptGuard::new();
+	unsafe { PROCESS_LIST_MUTEX.spin_lock(); }
+	let pid = process.pid;
+	let ret = match unsafe { PROCESS_LIST.as_mut() } {
+		Some(pl) => {
+			pl.push_back(process);
+			pid
+		}
+		None => 0,
+	};
+	unsafe { PROCESS_LIST_MUTEX.unlock(); }
+	ret
+}
+
 /// This should only be called once, and its job is to create
 /// the init process. Right now, this process is in the kernel,
 /// but later, it should call the shell.
@@ -342,7 +643,7 @@ pub fn init() -> usize {
 		PROCESS_LIST_MUTEX.spin_lock();
 		PROCESS_LIST = Some(VecDeque::with_capacity(15));
 		// add_process_default(init_process);
-		add_kernel_process(init_process);
+		add_kernel_process(init_process, "init");
 		// Ugh....Rust is giving me fits over here!
 		// I just want a memory address to the trap frame, but
 		// due to the borrow rules of Rust, I'm fighting here. So,
@@ -382,39 +683,217 @@ pub struct Process {
 	pub state:       ProcessState,
 	pub data:        ProcessData,
 	pub sleep_until: usize,
-	pub program:	 *mut u8,
 	pub brk:         usize,
+	// Where the break started. Everything in heap..brk belongs to the
+	// program, but a page isn't mapped until it's first touched. See
+	// demand_page().
+	pub heap:        usize,
+	pub priority:    u8,
+	// What we print next to the PID. For a program it's the basename of
+	// the path it was loaded from.
+	pub name:        String,
+}
+
+impl Process {
+	/// Halt if this is a kernel process that has run off the bottom of its
+	/// stack and into its guard page. User stacks are mapped with nothing
+	/// below them, so a user process overflowing gets a page fault instead,
+	/// and it can write anything it likes into its own stack anyway.
+	pub fn check_stack(&self) {
+		unsafe {
+			if (*self.frame).mode == CpuMode::Machine as usize && !self.stack.is_null() && !guard_ok(self.stack) {
+				stack_overflow(mhartid_read(), self.pid as usize);
+			}
+		}
+	}
+
+	/// The quantum is stored in the trap frame (qm) so that the trap
+	/// handler can arm the timer without having to look up the process.
+	/// It is the number of CONTEXT_SWITCH_TIME periods this process
+	/// runs before it is preempted. A quantum of 0 would never let the
+	/// process run, so it is bumped up to 1.
+	pub fn set_quantum(&mut self, qm: u16) {
+		let qm = if qm == 0 { 1 } else { qm };
+		unsafe {
+			(*self.frame).qm = qm as usize;
+		}
+	}
+
+	pub fn get_quantum(&self) -> u16 {
+		unsafe { (*self.frame).qm as u16 }
+	}
+
+	/// Set the priority and derive the quantum from it. Priorities
+	/// outside of MIN_PRIORITY..=MAX_PRIORITY are clamped.
+	pub fn set_priority(&mut self, priority: u8) {
+		let priority = priority.clamp(MIN_PRIORITY, MAX_PRIORITY);
+		self.priority = priority;
+		self.set_quantum(priority_to_quantum(priority));
+	}
+
+	pub fn get_priority(&self) -> u8 {
+		self.priority
+	}
+
+	pub fn get_name(&self) -> &str {
+		&self.name
+	}
+
+	/// This is the back half of execve. The image is a process built by
+	/// elf::File::load_image() with our PID. We take its trap frame, stack,
+	/// page table, and program memory and give it ours. When the image is
+	/// dropped, it frees our old address space for us. Everything else,
+	/// such as the PID, open files, cwd, and priority, stays with us. The
+	/// name goes with the program, so we take the image's.
+	pub fn replace_image(&mut self, mut image: Process) {
+		swap(&mut self.frame, &mut image.frame);
+		swap(&mut self.stack, &mut image.stack);
+		swap(&mut self.mmu_table, &mut image.mmu_table);
+		swap(&mut self.brk, &mut image.brk);
+		swap(&mut self.heap, &mut image.heap);
+		swap(&mut self.name, &mut image.name);
+		// The program's segments and the pages we got from sbrk are
+		// mapped into the old table, so they go away with it.
+		// The new frame has a quantum of 0, so put ours back.
+		self.set_priority(self.priority);
+		// Same ASID, different address space.
+		asid_changed(self.pid as usize);
+	}
+}
+
+/// A user process touched addr and it wasn't mapped. If addr is in the
+/// heap, which brk only reserves, give it a zeroed page there and return
+/// Some(true) so the faulting instruction can run again. Some(false) means
+/// it really was a bad access. None means we couldn't get at the process
+/// list right now, so the caller should try again later.
+pub fn demand_page(pid: u16, addr: usize) -> Option<bool> {
+	with_process(pid, |process| {
+		if !map_heap_page(process.mmu_table, process.heap, process.brk, process.pid, addr) {
+			return false;
+		}
+		// We go straight back to the process from the trap, so we won't
+		// pass through rust_switch_to_user's fence.
+		fence_asid_if_stale(process.pid as usize);
+		true
+	})
+}
+
+/// A user process stored to addr and the page wasn't writable. If it's
+/// copy-on-write, the process gets its own copy of the page, and we
+/// return Some(true) so the store can run again. The return values are the
+/// same as demand_page().
+pub fn cow_fault(pid: u16, addr: usize) -> Option<bool> {
+	with_process(pid, |process| {
+		if !unsafe { resolve_cow(process.mmu_table, addr) } {
+			return false;
+		}
+		// The old page might still be in the TLB, and it's read only.
+		asid_changed(process.pid as usize);
+		fence_asid_if_stale(process.pid as usize);
+		true
+	})
+}
+
+/// Give the heap page addr is in a zeroed page, if it doesn't have one
+/// yet. The heap is heap..brk in the process whose table and ASID (its
+/// pid) these are. Returns false if addr isn't in the heap, it's already
+/// mapped, or we're out of pages. This takes the pieces rather than the
+/// Process so that syscall::user_writable() can fault pages in with only a
+/// UserTable in hand.
+pub fn map_heap_page(table: *mut Table, heap: usize, brk: usize, pid: u16, addr: usize) -> bool {
+	if addr < heap || addr >= brk {
+		return false;
+	}
+	let table = match unsafe { table.as_mut() } {
+		Some(table) => table,
+		None => return false,
+	};
+	// The page is there, so this is a permission problem, not
+	// something we can fix by mapping.
+	if virt_to_phys(table, addr).is_some() {
+		return false;
+	}
+	// The mapping owns the page now. See release_leaves().
+	let page = zalloc(1);
+	if page.is_null() {
+		return false;
+	}
+	map(table, addr & !(PAGE_SIZE - 1), page as usize, EntryBits::UserReadWrite.val(), 0);
+	asid_changed(pid as usize);
+	true
+}
+
+/// Take one of the system's MAX_OPEN_FILES before opening something.
+/// Returns false if they're all in use.
+pub fn reserve_open_file() -> bool {
+	OPEN_FILES.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+		          if open < MAX_OPEN_FILES { Some(open + 1) } else { None }
+	          })
+	          .is_ok()
+}
+
+/// Give back what reserve_open_file() took, either because the open
+/// failed or because the descriptor is gone.
+pub fn release_open_file() {
+	OPEN_FILES.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Each priority level is worth one context switch period.
+pub const fn priority_to_quantum(priority: u8) -> u16 {
+	priority as u16
 }
 
 impl Drop for Process {
 	/// Since we're storing ownership of a Process in the linked list,
 	/// we can cause it to deallocate automatically when it is removed.
 	fn drop(&mut self) {
-		// We allocate the stack as a page.
-		dealloc(self.stack);
 		// This is unsafe, but it's at the drop stage, so we won't
 		// be using this again.
 		unsafe {
+			// Every page a user process has mapped (its program
+			// segments, stack, and heap) holds a reference, so this
+			// is how that memory is freed. Kernel processes don't have
+			// any, instead the program is linked directly in the
+			// kernel.
+			release_leaves(&*self.mmu_table);
 			// Remember that unmap unmaps all levels of page tables
 			// except for the root. It also deallocates the memory
 			// associated with the tables.
 			unmap(&mut *self.mmu_table);
 		}
+		// We allocate the stack as a page. For a user process, the
+		// mapping had its own reference, which is gone now.
+		dealloc(self.stack);
 		dealloc(self.mmu_table as *mut u8);
 		dealloc(self.frame as *mut u8);
-		for i in self.data.pages.drain(..) {
-			dealloc(i as *mut u8);
-		}
-		// Kernel processes don't have a program, instead the program is linked
-		// directly in the kernel.
-		if !self.program.is_null() {
-			dealloc(self.program);
+		// Whatever the process didn't close, we close for it.
+		for desc in self.data.fdesc.values() {
+			if desc.is_counted() {
+				release_open_file();
+			}
 		}
 	}
 }
 
+/// Where a kernel worker's return value goes when it's done. See
+/// add_kernel_worker(). A user process can have one too, and then it gets
+/// the process' exit status.
+pub enum Completion {
+	/// Write Some(value) here. That's the last thing that happens to the
+	/// slot, so whoever owns it has to keep it around until it isn't None.
+	/// Don't go by whether the process is still on the list instead,
+	/// get_by_pid() can come back null just because the list is busy.
+	Slot(*mut Option<usize>),
+	/// Put it into this process' A0 and wake it up. This is how a worker
+	/// finishes a system call for the process that made it.
+	Wake(u16),
+}
+
 pub enum Descriptor {
-	File(Inode),
+	File(MinixFile),
+	Proc(ProcFile),
+	Ram(RamFile),
+	Block(BlockFile),
 	Device(usize),
 	Framebuffer,
 	ButtonEvents,
@@ -424,6 +903,14 @@ pub enum Descriptor {
 	Unknown,
 }
 
+impl Descriptor {
+	/// Whether this descriptor holds one of the system's open files. The
+	/// console comes with every process, so it doesn't.
+	pub fn is_counted(&self) -> bool {
+		!matches!(self, Descriptor::Console)
+	}
+}
+
 // The private data in a process contains information
 // that is relevant to where we are, including the path
 // and open file descriptors.
@@ -434,7 +921,23 @@ pub struct ProcessData {
 	pub environ: BTreeMap<String, String>,
 	pub fdesc: BTreeMap<u16, Descriptor>,
 	pub cwd: String,
-	pub pages: VecDeque<usize>,
+	// We're a single user system for now, so everyone is root (0). These are
+	// u16s to match the uid/gid stored in a Minix inode.
+	pub uid: u16,
+	pub gid: u16,
+	// The PID that started us, or 0 if nobody did.
+	pub ppid: u16,
+	// How many context switch periods we've been preempted after. This
+	// is what /proc/<pid>/stat reports as CPU ticks.
+	pub cpu_ticks: usize,
+	// Permission bits to take away from any file or directory we create.
+	pub umask: u16,
+	// The arguments we were exec'd with. These are also on the stack, but
+	// the program is free to scribble over those. getmainvars hands out
+	// this copy.
+	pub argv: Vec<String>,
+	// Where our return value (or exit status) goes, if anyone wants it.
+	pub completion: Option<Completion>,
 }
 
 // This is private data that we can query with system calls.
@@ -442,11 +945,24 @@ pub struct ProcessData {
 // is a per-process block queuing algorithm, we can put that here.
 impl ProcessData {
 	pub fn new() -> Self {
+		// stdin, stdout, and stderr all start out on the console. What
+		// the syscalls do with an fd depends on what's here, not on its
+		// number.
+		let mut fdesc = BTreeMap::new();
+		fdesc.insert(0, Descriptor::Console);
+		fdesc.insert(1, Descriptor::Console);
+		fdesc.insert(2, Descriptor::Console);
 		ProcessData { 
 			environ: BTreeMap::new(),
-			fdesc: BTreeMap::new(),
+			fdesc,
 			cwd: String::from("/"),
-			pages: VecDeque::new(),
+			uid: 0,
+			gid: 0,
+			ppid: 0,
+			cpu_ticks: 0,
+			umask: DEFAULT_UMASK,
+			argv: Vec::new(),
+			completion: None,
 		 }
 	}
 }
@@ -0,0 +1,133 @@
+// procfs.rs
+// A read-only /proc pseudo-filesystem
+// Stephen Marz
+
+use crate::{process::{ProcessState, PROCESS_LIST, PROCESS_LIST_MUTEX},
+            virtio};
+use alloc::{format, string::String};
+
+// Nothing here lives on a block device. Every read regenerates the text
+// from the live process list, so a ps program always sees fresh data.
+// Until the VFS has mount points, open() sends anything under /proc here.
+pub const MOUNT_POINT: &str = "/proc";
+
+/// Is this path inside of /proc? We check for the separator so that a
+/// file such as /process still goes to the real filesystem.
+pub fn is_proc_path(path: &str) -> bool {
+	match path.strip_prefix(MOUNT_POINT) {
+		Some(rest) => rest.is_empty() || rest.starts_with('/'),
+		None => false,
+	}
+}
+
+#[derive(Copy, Clone)]
+pub enum ProcEntry {
+	// "/proc" lists every PID, one per line.
+	Root,
+	// "/proc/<pid>/stat"
+	Stat(u16),
+	// "/proc/<pid>/comm", the process' name.
+	Comm(u16),
+	// "/proc/interrupts", per device counts. See virtio::stats().
+	Interrupts,
+}
+
+// This is what goes into a process' file descriptor. We have to remember
+// how far into the file the process has read.
+pub struct ProcFile {
+	pub entry:  ProcEntry,
+	pub offset: usize,
+}
+
+/// Figure out which /proc entry a path refers to. Returns None if the path
+/// isn't in /proc or doesn't name anything we know about. The PID doesn't
+/// have to exist yet, a read of a dead PID just gives nothing back.
+pub fn open(path: &str) -> Option<ProcFile> {
+	if !is_proc_path(path) {
+		return None;
+	}
+	let rest = &path[MOUNT_POINT.len()..];
+	let rest = rest.trim_end_matches('/');
+	let entry = if rest.is_empty() {
+		ProcEntry::Root
+	}
+	else if rest == "/interrupts" {
+		ProcEntry::Interrupts
+	}
+	else {
+		let mut parts = rest.trim_start_matches('/').split('/');
+		let pid = parts.next()?.parse::<u16>().ok()?;
+		match (parts.next(), parts.next()) {
+			(Some("stat"), None) => ProcEntry::Stat(pid),
+			(Some("comm"), None) => ProcEntry::Comm(pid),
+			_ => return None,
+		}
+	};
+	Some(ProcFile { entry, offset: 0 })
+}
+
+/// Single characters for the process state, the same ones ps uses.
+fn state_char(state: &ProcessState) -> char {
+	match state {
+		ProcessState::Running => 'R',
+		ProcessState::Sleeping => 'S',
+		ProcessState::Waiting => 'W',
+		ProcessState::Dead => 'D',
+	}
+}
+
+/// Generate the contents of a /proc entry. We snapshot under the process
+/// list mutex. This is called from a system call, which is an interrupt
+/// context, so we can't sleep for the lock. If a kernel process has it,
+/// we return None and the read fails rather than deadlocking.
+pub fn generate(entry: &ProcEntry) -> Option<String> {
+	// This one doesn't need the process list.
+	if let ProcEntry::Interrupts = entry {
+		// dev interrupts reclaimed spurious
+		let mut out = String::new();
+		for dev in 1..=8 {
+			if let Some(st) = virtio::device_stats(dev) {
+				out.push_str(&format!("{} {} {} {}\n", dev, st.interrupts, st.reclaimed, st.spurious));
+			}
+		}
+		return Some(out);
+	}
+	unsafe {
+		if !PROCESS_LIST_MUTEX.try_lock() {
+			return None;
+		}
+		let mut ret = None;
+		if let Some(pl) = PROCESS_LIST.take() {
+			let mut out = String::new();
+			match entry {
+				ProcEntry::Root => {
+					for p in pl.iter() {
+						out.push_str(&format!("{}\n", p.pid));
+					}
+				},
+				ProcEntry::Stat(pid) => {
+					// pid ppid state priority pc ticks
+					if let Some(p) = pl.iter().find(|p| p.pid == *pid) {
+						out = format!("{} {} {} {} 0x{:x} {}\n",
+						              p.pid,
+						              p.data.ppid,
+						              state_char(&p.state),
+						              p.priority,
+						              (*p.frame).pc,
+						              p.data.cpu_ticks);
+					}
+				},
+				ProcEntry::Comm(pid) => {
+					if let Some(p) = pl.iter().find(|p| p.pid == *pid) {
+						out = format!("{}\n", p.get_name());
+					}
+				},
+				ProcEntry::Interrupts => {},
+			}
+			PROCESS_LIST.replace(pl);
+			ret = Some(out);
+		}
+		PROCESS_LIST_MUTEX.unlock();
+		ret
+	}
+}
@@ -0,0 +1,241 @@
+// ramfs.rs
+// An in-memory file system for scratch files
+// Stephen Marz
+
+use crate::{fs::{FsError, Stat, S_IFDIR, S_IFMT, S_IPERM},
+            sched::PreemptGuard};
+use alloc::{collections::{BTreeMap, BTreeSet}, string::String, vec::Vec};
+
+// Everything here lives on the kernel heap, so it works without any block
+// device at all, and it's all gone when we power off. Like /proc, there's
+// no mount table yet, so open() sends anything under /tmp here.
+pub const MOUNT_POINT: &str = "/tmp";
+// The biggest a file here can get. It all comes out of the kernel heap,
+// so one file can't be allowed to take all of it.
+pub const MAX_FILE_SIZE: usize = 1024 * 1024;
+
+/// Is this path inside of /tmp? See procfs::is_proc_path().
+pub fn is_ram_path(path: &str) -> bool {
+	match path.strip_prefix(MOUNT_POINT) {
+		Some(rest) => rest.is_empty() || rest.starts_with('/'),
+		None => false,
+	}
+}
+
+// Files and directories are keyed by their full path, so a lookup is just
+// a BTreeMap lookup.
+struct RamFs {
+	files: BTreeMap<String, Vec<u8>>,
+	// The mode (type and permissions) of everything in files and dirs.
+	modes: BTreeMap<String, u16>,
+	dirs:  BTreeSet<String>,
+}
+
+static mut RAMFS: Option<RamFs> = None;
+
+// This is what goes into a process' file descriptor.
+pub struct RamFile {
+	pub path:   String,
+	pub offset: usize,
+}
+
+/// Get the file system, creating it with just the mount point directory the
+/// first time. The caller needs to hold a PreemptGuard.
+unsafe fn ramfs() -> &'static mut RamFs {
+	if RAMFS.is_none() {
+		let mut fs = RamFs { files: BTreeMap::new(),
+		                     modes: BTreeMap::new(),
+		                     dirs:  BTreeSet::new(), };
+		fs.dirs.insert(String::from(MOUNT_POINT));
+		fs.modes.insert(String::from(MOUNT_POINT), S_IFDIR | 0o777);
+		RAMFS = Some(fs);
+	}
+	RAMFS.as_mut().unwrap()
+}
+
+/// Paths come to us from the user, so "/tmp/a/" and "/tmp/a" need to be the
+/// same thing.
+fn normalize(path: &str) -> String {
+	let trimmed = path.trim_end_matches('/');
+	if trimmed.is_empty() {
+		String::from("/")
+	}
+	else {
+		String::from(trimmed)
+	}
+}
+
+/// Everything up to the last separator.
+fn parent(path: &str) -> &str {
+	match path.rfind('/') {
+		Some(0) => "/",
+		Some(idx) => &path[..idx],
+		None => "",
+	}
+}
+
+/// Make sure there's a directory to put path in, and that path isn't
+/// already there.
+fn check_new(fs: &RamFs, path: &String) -> Result<(), FsError> {
+	if !fs.dirs.contains(parent(path)) {
+		return Err(FsError::FileNotFound);
+	}
+	if fs.dirs.contains(path) {
+		return Err(FsError::IsDirectory);
+	}
+	if fs.files.contains_key(path) {
+		return Err(FsError::IsFile);
+	}
+	Ok(())
+}
+
+/// Create an empty file. The mode should already have the umask applied,
+/// see MinixFileSystem::create_mode().
+pub fn create(path: &str, mode: u16) -> Result<(), FsError> {
+	let path = normalize(path);
+	let _guard = PreemptGuard::new();
+	let fs = unsafe { ramfs() };
+	check_new(fs, &path)?;
+	fs.files.insert(path.clone(), Vec::new());
+	fs.modes.insert(path, mode);
+	Ok(())
+}
+
+pub fn mkdir(path: &str, mode: u16) -> Result<(), FsError> {
+	let path = normalize(path);
+	let _guard = PreemptGuard::new();
+	let fs = unsafe { ramfs() };
+	check_new(fs, &path)?;
+	fs.dirs.insert(path.clone());
+	fs.modes.insert(path, mode);
+	Ok(())
+}
+
+/// Remove a file, or a directory if it's empty. The mount point itself
+/// can't go away.
+pub fn unlink(path: &str) -> Result<(), FsError> {
+	let path = normalize(path);
+	let _guard = PreemptGuard::new();
+	let fs = unsafe { ramfs() };
+	if fs.files.remove(&path).is_some() {
+		fs.modes.remove(&path);
+		return Ok(());
+	}
+	if !fs.dirs.contains(&path) {
+		return Err(FsError::FileNotFound);
+	}
+	if path == MOUNT_POINT || fs.modes.keys().any(|p| parent(p) == path) {
+		return Err(FsError::Permission);
+	}
+	fs.dirs.remove(&path);
+	fs.modes.remove(&path);
+	Ok(())
+}
+
+/// Open an existing file or directory.
+pub fn open(path: &str) -> Result<RamFile, FsError> {
+	let path = normalize(path);
+	let _guard = PreemptGuard::new();
+	let fs = unsafe { ramfs() };
+	if fs.modes.contains_key(&path) {
+		Ok(RamFile { path, offset: 0 })
+	}
+	else {
+		Err(FsError::FileNotFound)
+	}
+}
+
+/// Copy the file starting at offset into buffer. Reading at or past the end
+/// gives back 0 bytes.
+pub fn read(path: &str, offset: usize, buffer: &mut [u8]) -> Result<usize, FsError> {
+	let _guard = PreemptGuard::new();
+	let fs = unsafe { ramfs() };
+	match fs.files.get(path) {
+		Some(data) => {
+			let start = offset.min(data.len());
+			let end = start.saturating_add(buffer.len()).min(data.len());
+			buffer[..end - start].copy_from_slice(&data[start..end]);
+			Ok(end - start)
+		},
+		None if fs.dirs.contains(path) => Err(FsError::IsDirectory),
+		None => Err(FsError::FileNotFound),
+	}
+}
+
+/// Write buffer into the file at offset. The file grows to fit, and if the
+/// offset is past the end, the hole is filled with zeroes. A file can't
+/// grow past MAX_FILE_SIZE, and a write that would make it is TooBig.
+pub fn write(path: &str, offset: usize, buffer: &[u8]) -> Result<usize, FsError> {
+	let _guard = PreemptGuard::new();
+	let fs = unsafe { ramfs() };
+	match fs.files.get_mut(path) {
+		Some(data) => {
+			let end = match offset.checked_add(buffer.len()) {
+				Some(end) if end <= MAX_FILE_SIZE => end,
+				_ => return Err(FsError::TooBig),
+			};
+			if data.len() < end {
+				data.resize(end, 0);
+			}
+			data[offset..end].copy_from_slice(buffer);
+			Ok(buffer.len())
+		},
+		None if fs.dirs.contains(path) => Err(FsError::IsDirectory),
+		None => Err(FsError::FileNotFound),
+	}
+}
+
+/// Nobody owns anything here, so everything is root's.
+pub fn stat(path: &str) -> Result<Stat, FsError> {
+	let path = normalize(path);
+	let _guard = PreemptGuard::new();
+	let fs = unsafe { ramfs() };
+	match fs.modes.get(&path) {
+		Some(mode) => {
+			let size = fs.files.get(&path).map(|d| d.len()).unwrap_or(0);
+			Ok(Stat { mode: *mode,
+			          size: size as u32,
+			          uid:  0,
+			          gid:  0, })
+		},
+		None => Err(FsError::FileNotFound),
+	}
+}
+
+/// Change the permission bits of path, keeping its type. Everything here is
+/// root's, so only root (uid 0) can.
+pub fn chmod(path: &str, mode: u16, uid: u16) -> Result<(), FsError> {
+	if uid != 0 {
+		return Err(FsError::Permission);
+	}
+	let path = normalize(path);
+	let _guard = PreemptGuard::new();
+	let fs = unsafe { ramfs() };
+	match fs.modes.get_mut(&path) {
+		Some(m) => {
+			*m = (*m & S_IFMT) | (mode & S_IPERM);
+			Ok(())
+		},
+		None => Err(FsError::FileNotFound),
+	}
+}
+
+/// The names of everything directly inside of a directory.
+pub fn getdents(path: &str) -> Result<Vec<String>, FsError> {
+	let path = normalize(path);
+	let _guard = PreemptGuard::new();
+	let fs = unsafe { ramfs() };
+	match fs.modes.get(&path) {
+		Some(mode) if mode & S_IFMT == S_IFDIR => {
+			let mut ret = Vec::new();
+			for p in fs.modes.keys() {
+				if p != &path && parent(p) == path {
+					ret.push(String::from(&p[path.len() + 1..]));
+				}
+			}
+			Ok(ret)
+		},
+		Some(_) => Err(FsError::IsFile),
+		None => Err(FsError::FileNotFound),
+	}
+}
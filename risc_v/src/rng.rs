@@ -4,23 +4,26 @@
 // 16 March 2020
 
 #![allow(dead_code)]
-use crate::{kmem::{kfree, kmalloc},
-            page::{zalloc, PAGE_SIZE},
+use crate::{cpu::{get_mtime, mhartid_read},
+            kmem::{kfree, kmalloc},
+            lock::SpinLock,
             virtio,
-            virtio::{Descriptor, MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE}};
-use core::{mem::size_of, ptr::null_mut};
+            virtio::{init_queue, Descriptor, MmioOffsets, Queue, StatusField}};
+use core::ptr::null_mut;
+
+// Random numbers come back a few bytes at a time, so we don't need much of
+// a ring.
+const RNG_RING_SIZE: u16 = 1 << 3;
 
 pub struct EntropyDevice {
-	queue:        *mut Queue,
+	queue:        Queue,
 	dev:          *mut u32,
-	idx:          u16,
 	ack_used_idx: u16,
 }
 impl EntropyDevice {
 	pub const fn new() -> Self {
-		EntropyDevice { queue:        null_mut(),
+		EntropyDevice { queue:        Queue::null(),
 		                dev:          null_mut(),
-		                idx:          0,
 		                ack_used_idx: 0, }
 	}
 }
@@ -36,6 +39,12 @@ static mut ENTROPY_DEVICES: [Option<EntropyDevice>; 8] = [
 	None,
 ];
 
+// Without an entropy device, random numbers come from xorshift64 instead.
+// Anyone watching mtime could guess these, so they're fine for shuffling
+// things around but not for anything secret. See is_hardware(). Zero
+// means we haven't seeded it yet (xorshift can never get back to zero).
+static SOFT_STATE: SpinLock<u64> = SpinLock::new(0);
+
 pub fn setup_entropy_device(ptr: *mut u32) -> bool {
 	unsafe {
 		// We can get the index of the device based on its address.
@@ -68,60 +77,23 @@ pub fn setup_entropy_device(ptr: *mut u32) -> bool {
 		// that means that the device couldn't accept
 		// the features that we request. Therefore, this is
 		// considered a "failed" state.
-		if false == StatusField::features_ok(status_ok) {
+		if !StatusField::features_ok(status_ok) {
 			print!("features fail...");
 			ptr.add(MmioOffsets::Status.scale32()).write_volatile(StatusField::Failed.val32());
 			return false;
 		}
 		// 7. Perform device-specific setup.
-		// Set the queue num. We have to make sure that the
-		// queue size is valid because the device can only take
-		// a certain size.
-		let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
-		ptr.add(MmioOffsets::QueueNum.scale32()).write_volatile(VIRTIO_RING_SIZE as u32);
-		if VIRTIO_RING_SIZE as u32 > qnmax {
-			print!("queue size fail...");
-			return false;
-		}
-		// First, if the block device array is empty, create it!
-		// We add 4095 to round this up and then do an integer
-		// divide to truncate the decimal. We don't add 4096,
-		// because if it is exactly 4096 bytes, we would get two
-		// pages, not one.
-		let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
-		// println!("np = {}", num_pages);
-		// We allocate a page for each device. This will the the
-		// descriptor where we can communicate with the block
-		// device. We will still use an MMIO register (in
-		// particular, QueueNotify) to actually tell the device
-		// we put something in memory. We also have to be
-		// careful with memory ordering. We don't want to
-		// issue a notify before all memory writes have
-		// finished. We will look at that later, but we need
-		// what is called a memory "fence" or barrier.
-		ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(0);
-		// Alignment is very important here. This is the memory address
-		// alignment between the available and used rings. If this is wrong,
-		// then we and the device will refer to different memory addresses
-		// and hence get the wrong data in the used ring.
-		// ptr.add(MmioOffsets::QueueAlign.scale32()).write_volatile(2);
-		let queue_ptr = zalloc(num_pages) as *mut Queue;
-		let queue_pfn = queue_ptr as u32;
-		ptr.add(MmioOffsets::GuestPageSize.scale32()).write_volatile(PAGE_SIZE as u32);
-		// QueuePFN is a physical page number, however it
-		// appears for QEMU we have to write the entire memory
-		// address. This is a physical memory address where we
-		// (the OS) and the block device have in common for
-		// making and receiving requests.
-		ptr.add(MmioOffsets::QueuePfn.scale32()).write_volatile(queue_pfn / PAGE_SIZE as u32);
+		let queue = match init_queue(ptr, 0, RNG_RING_SIZE) {
+			Some(queue) => queue,
+			None => return false,
+		};
 		// 8. Set the DRIVER_OK status bit. Device is now "live"
 		status_bits |= StatusField::DriverOk.val32();
 		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
 
 		let rngdev = EntropyDevice {
-			queue: queue_ptr,
+			queue,
 			dev: ptr,
-			idx: 0,
 			ack_used_idx: 0,
 		};
 
@@ -131,21 +103,58 @@ pub fn setup_entropy_device(ptr: *mut u32) -> bool {
 	}
 }
 
+/// Whether get_random() and fill() are getting their numbers from an
+/// entropy device. If this is false, they're coming from xorshift64, and
+/// something that needs to keep a secret shouldn't use them.
+pub fn is_hardware() -> bool {
+	unsafe { ENTROPY_DEVICES.iter().any(|edev| edev.is_some()) }
+}
+
+/// xorshift64, seeded from the time and hart the first time we need it.
+fn soft_random() -> u64 {
+	let mut state = SOFT_STATE.lock();
+	if *state == 0 {
+		*state = (get_mtime() as u64 ^ (mhartid_read() as u64) << 48) | 1;
+	}
+	let mut x = *state;
+	x ^= x << 13;
+	x ^= x >> 7;
+	x ^= x << 17;
+	*state = x;
+	x
+}
+
+/// Ask the first entropy device for 8 random bytes. We don't have anything
+/// else to do until they come back, so we wait for them here instead of in
+/// an interrupt. If there's no entropy device, or we can't get memory for
+/// the request, these come from soft_random().
 pub fn get_random() -> u64 {
 	unsafe {
-		for i in ENTROPY_DEVICES.iter() {
-			if let Some(_edev) = i {
-				let ptr = kmalloc(8);
-				let _desc = Descriptor { addr:  ptr as u64,
-										len:   8,
-										flags: virtio::VIRTIO_DESC_F_WRITE,
-										next:  0, };
-				let _val = *ptr as u64;
-				kfree(ptr);
-				break;
+		// The first device we find is as good as any other.
+		if let Some(edev) = ENTROPY_DEVICES.iter_mut().flatten().next() {
+			let ptr = kmalloc(8);
+			if ptr.is_null() {
+				return soft_random();
 			}
+			virtio::submit(&mut edev.queue,
+			               &[Descriptor { addr:  ptr as u64,
+			                              len:   8,
+			                              flags: virtio::VIRTIO_DESC_F_WRITE,
+			                              next:  0, }]);
+			virtio::wait_used(&edev.queue, &mut edev.ack_used_idx);
+			let val = (ptr as *const u64).read_volatile();
+			kfree(ptr);
+			return val;
 		}
 	}
 
-	0u64.wrapping_sub(1)
+	soft_random()
+}
+
+/// Fill buffer with random bytes, 8 at a time from get_random().
+pub fn fill(buffer: &mut [u8]) {
+	for chunk in buffer.chunks_mut(8) {
+		let bytes = get_random().to_le_bytes();
+		chunk.copy_from_slice(&bytes[..chunk.len()]);
+	}
 }
@@ -4,7 +4,57 @@
 // 27 Dec 2019
 
 use crate::process::{ProcessState, PROCESS_LIST, PROCESS_LIST_MUTEX};
-use crate::cpu::get_mtime;
+use crate::cpu::{get_mtime, mhartid_read, TrapFrame};
+
+// QEMU's virt machine gives us up to 8 harts.
+pub const MAX_HARTS: usize = 8;
+
+// When a hart's count is non-zero, the timer won't switch away from
+// whatever that hart is running. This is a count instead of a flag so
+// that critical sections can nest.
+static mut PREEMPT_COUNT: [usize; MAX_HARTS] = [0; MAX_HARTS];
+
+/// Stop the context switch timer from switching away from us. Every call
+/// needs a matching preempt_enable(). Don't make a system call that
+/// sleeps or waits in here: the scheduler still runs for system calls, and
+/// the hart would stay non-preemptible for whomever it switched to.
+pub fn preempt_disable() {
+	unsafe {
+		PREEMPT_COUNT[mhartid_read()] += 1;
+	}
+}
+
+pub fn preempt_enable() {
+	unsafe {
+		let count = &mut PREEMPT_COUNT[mhartid_read()];
+		assert!(*count > 0, "preempt_enable() without preempt_disable()");
+		*count -= 1;
+	}
+}
+
+/// The timer interrupt asks this before switching. The trap handler is
+/// given the hart, so we don't need to read mhartid again.
+pub fn preempt_disabled(hart: usize) -> bool {
+	unsafe { PREEMPT_COUNT[hart] != 0 }
+}
+
+/// Disables preemption until it goes out of scope. This is what should
+/// wrap a take()/replace() on a shared static so the timer can't switch
+/// to someone else while the static is None.
+pub struct PreemptGuard;
+
+impl PreemptGuard {
+	pub fn new() -> Self {
+		preempt_disable();
+		PreemptGuard
+	}
+}
+
+impl Drop for PreemptGuard {
+	fn drop(&mut self) {
+		preempt_enable();
+	}
+}
 
 pub fn schedule() -> usize {
 	let mut frame_addr: usize = 0x1111;
@@ -12,10 +62,15 @@ pub fn schedule() -> usize {
 		// If we can't get the lock, then usually this means a kernel
 		// process has the lock. So, we return 0. This has a special
 		// meaning to whomever calls the scheduler to say "nobody else got scheduled"
-		if PROCESS_LIST_MUTEX.try_lock() == false {
+		if !PROCESS_LIST_MUTEX.try_lock() {
 			return 0;
 		}
 		if let Some(mut pl) = PROCESS_LIST.take() {
+			// The process at the front is the one we're switching away
+			// from, so make sure it stayed inside of its stack.
+			if let Some(prc) = pl.front() {
+				prc.check_stack();
+			}
 			// Rust allows us to label loops so that break statements can be
 			// targeted.
 			'procfindloop: loop {
@@ -26,19 +81,21 @@ pub fn schedule() -> usize {
 							frame_addr = prc.frame as usize;
 							break 'procfindloop;
 						},
-						ProcessState::Sleeping => {
-							// Awaken sleeping processes whose sleep until is in
-							// the past.
-							if prc.sleep_until <= get_mtime() {
-								prc.state = ProcessState::Running;
-								frame_addr = prc.frame as usize;
-								break 'procfindloop;
-							}
+						// Awaken sleeping processes whose sleep until is in
+						// the past.
+						ProcessState::Sleeping if prc.sleep_until <= get_mtime() => {
+							prc.state = ProcessState::Running;
+							frame_addr = prc.frame as usize;
+							break 'procfindloop;
 						},
 						_ => {},
 					}
 				}
 			}
+			// And the one we're switching to.
+			if let Some(prc) = pl.front() {
+				prc.check_stack();
+			}
 			PROCESS_LIST.replace(pl);
 		}
 		else {
@@ -48,3 +105,94 @@ pub fn schedule() -> usize {
 	}
 	frame_addr
 }
+
+/// Give up the rest of pid's time slice. schedule() always rotates the
+/// front of the list to the back before it looks for someone to run, and
+/// the process that's running is normally at the front. So, all we have
+/// to do is make sure pid is at the front. Then the schedule() that runs
+/// after every system call puts it last, behind everyone else that's
+/// waiting, and two yielding processes will trade off one for one.
+pub fn yield_process(pid: u16) {
+	unsafe {
+		// Like schedule(), we're in a trap, so we can't sleep for the lock.
+		// If a kernel process has it, the yield still happens, we just
+		// can't promise where in line we end up.
+		if !PROCESS_LIST_MUTEX.try_lock() {
+			return;
+		}
+		if let Some(mut pl) = PROCESS_LIST.take() {
+			if let Some(idx) = pl.iter().position(|p| p.pid == pid) {
+				if idx != 0 {
+					let prc = pl.remove(idx).unwrap();
+					pl.push_front(prc);
+				}
+			}
+			PROCESS_LIST.replace(pl);
+		}
+		PROCESS_LIST_MUTEX.unlock();
+	}
+}
+
+// Load averages are fixed point with LOAD_SHIFT fractional bits, like
+// Linux's. They're exponential moving averages of the number of runnable
+// processes, sampled on every timer tick, over 1, 5, and 15 ticks. Each
+// LOAD_EXP is e^(-1/n) in the same fixed point.
+pub const LOAD_SHIFT: usize = 11;
+const LOAD_ONE: usize = 1 << LOAD_SHIFT;
+const LOAD_EXP: [usize; 3] = [753, 1677, 1916];
+static mut LOAD_AVG: [usize; 3] = [0; 3];
+
+/// Count the processes that match, or None if someone has the process
+/// list. We're called from traps, so we can't wait for it.
+fn count_processes<F>(f: F) -> Option<usize>
+	where F: Fn(&ProcessState) -> bool
+{
+	unsafe {
+		if !PROCESS_LIST_MUTEX.try_lock() {
+			return None;
+		}
+		let ret = PROCESS_LIST.as_ref().map(|pl| pl.iter().filter(|p| f(&p.state)).count());
+		PROCESS_LIST_MUTEX.unlock();
+		ret
+	}
+}
+
+/// How many processes could run right now?
+pub fn runnable_count() -> Option<usize> {
+	count_processes(|state| matches!(state, ProcessState::Running))
+}
+
+/// How many processes are there at all?
+pub fn process_count() -> Option<usize> {
+	count_processes(|_| true)
+}
+
+/// The timer calls this every tick. If the process list is busy, we skip
+/// this sample rather than spin in a trap.
+pub fn sample_load() {
+	if let Some(n) = runnable_count() {
+		unsafe {
+			for (avg, exp) in LOAD_AVG.iter_mut().zip(LOAD_EXP.iter()) {
+				*avg = (*avg * exp + n * LOAD_ONE * (LOAD_ONE - exp)) >> LOAD_SHIFT;
+			}
+		}
+	}
+}
+
+/// The 1, 5, and 15 tick load averages, with LOAD_SHIFT fractional bits.
+pub fn load_average() -> [usize; 3] {
+	unsafe { LOAD_AVG }
+}
+
+/// The scheduler hands back a frame address. Each frame carries the
+/// quantum multiplier (qm) of its process, so we can look it up here to
+/// know how long to arm the timer for. A frame of 0 means nobody else got
+/// scheduled, so we just give it the default single period.
+pub fn frame_quantum(frame_addr: usize) -> u16 {
+	if frame_addr == 0 {
+		1
+	}
+	else {
+		unsafe { (*(frame_addr as *const TrapFrame)).qm as u16 }
+	}
+}
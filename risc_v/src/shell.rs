@@ -0,0 +1,167 @@
+// shell.rs
+// A shell that runs as a kernel process
+// Stephen Marz
+
+use crate::{buffer::Buffer,
+            elf,
+            fs::{MinixFileSystem, S_IFDIR, S_IFMT},
+            kmem,
+            page,
+            process::{add_process, Completion},
+            syscall::{push_argv, syscall_get_pid, syscall_read, syscall_sleep},
+            virtio};
+use alloc::{string::String, vec::Vec};
+
+// The file system the shell runs programs from.
+const BDEV: usize = 8;
+// The longest line we read at once. Anything past this comes back as the
+// next line. This keeps argv well inside the page push_argv() puts it in.
+const LINE_MAX: usize = 256;
+// How long we sleep between checking if the program we ran is done.
+const WAIT_SLEEP: usize = 10_000;
+
+/// The shell. Read a line from the console, run it, and do it all again,
+/// until someone types exit. The console is interrupt driven, so we sleep
+/// while the user is typing. Since we're a kernel process, we can read
+/// the disk and start programs without going through system calls for it.
+pub fn shell() {
+	let mut cwd = String::from("/");
+	// init starts us at boot (with the kernel-shell feature), but it's
+	// test::test() that mounts the file system, and we can't run anything
+	// until it has.
+	while !MinixFileSystem::is_mounted(BDEV) {
+		syscall_sleep(WAIT_SLEEP);
+	}
+	println!("Kernel shell started. Type the path of a program to run it.");
+	loop {
+		print!("{}$ ", cwd);
+		let line = read_line();
+		let argv: Vec<String> = line.split_whitespace().map(String::from).collect();
+		if argv.is_empty() {
+			continue;
+		}
+		match argv[0].as_str() {
+			"exit" => break,
+			"cd" => {
+				let path = resolve(&cwd, argv.get(1).map(|s| s.as_str()).unwrap_or("/"));
+				match MinixFileSystem::list_dir(BDEV, &path) {
+					Ok(_) => cwd = path,
+					Err(_) => println!("cd: {}: not a directory", path),
+				}
+			}
+			"ls" => {
+				let path = resolve(&cwd, argv.get(1).map(|s| s.as_str()).unwrap_or("."));
+				match MinixFileSystem::list_dir(BDEV, &path) {
+					Ok(entries) => {
+						for (name, inode) in entries.iter().filter(|(name, _)| name != "." && name != "..") {
+							let slash = if inode.mode & S_IFMT == S_IFDIR { "/" } else { "" };
+							println!("{:>8} {}{}", inode.size, name, slash);
+						}
+					}
+					Err(_) => println!("ls: {}: not a directory", path),
+				}
+			}
+			"stats" => virtio::stats(),
+			"mem" => {
+				let stats = page::stats();
+				println!("{} pages, {} used, {} free in {} runs, the largest is {} pages",
+				         stats.total, stats.used, stats.free, stats.free_runs, stats.largest_free_run);
+				let (used, free, largest) = kmem::usage();
+				println!("kernel heap: {} bytes used, {} free, the largest free chunk is {} bytes", used, free, largest);
+			}
+			_ => run(&cwd, argv),
+		}
+	}
+	println!("Kernel shell exiting.");
+}
+
+/// Read one line from stdin without the newline.
+fn read_line() -> String {
+	let mut buf = [0u8; LINE_MAX];
+	let bytes = syscall_read(0, buf.as_mut_ptr(), LINE_MAX);
+	// An error comes back as a negative number.
+	let bytes = if bytes > LINE_MAX { 0 } else { bytes };
+	let line = String::from_utf8_lossy(&buf[..bytes]);
+	String::from(line.trim_end_matches(['\n', '\r', '\x0b']))
+}
+
+/// Turn path into an absolute path without any . or .. in it. A relative
+/// path starts at cwd.
+fn resolve(cwd: &str, path: &str) -> String {
+	let mut parts: Vec<&str> = Vec::new();
+	let start = if path.starts_with('/') { "" } else { cwd };
+	for part in start.split('/').chain(path.split('/')) {
+		match part {
+			"" | "." => {},
+			".." => {
+				parts.pop();
+			}
+			_ => parts.push(part),
+		}
+	}
+	let mut ret = String::new();
+	for part in parts.iter() {
+		ret.push('/');
+		ret.push_str(part);
+	}
+	if ret.is_empty() {
+		ret.push('/');
+	}
+	ret
+}
+
+/// Load argv[0] and run it with argv, then wait for it to exit. A command
+/// without a / in it is looked for in cwd and then in /.
+fn run(cwd: &str, argv: Vec<String>) {
+	let candidates = if argv[0].contains('/') {
+		[resolve(cwd, &argv[0]), String::new()]
+	}
+	else {
+		[resolve(cwd, &argv[0]), resolve("/", &argv[0])]
+	};
+	let (path, inode) = match candidates.iter()
+	                                    .filter(|path| !path.is_empty())
+	                                    .find_map(|path| MinixFileSystem::open(BDEV, path).ok().map(|inode| (path, inode)))
+	{
+		Some(found) => found,
+		None => {
+			println!("{}: command not found", argv[0]);
+			return;
+		}
+	};
+	let mut buffer = Buffer::new(inode.size as usize);
+	MinixFileSystem::read(BDEV, &inode, buffer.get_mut(), inode.size, 0);
+	let mut child = match elf::File::load_proc(&buffer, path) {
+		Ok(child) => child,
+		Err(_) => {
+			println!("{}: not an executable", path);
+			return;
+		}
+	};
+	// The program's exit status goes here once it's off of the process
+	// list. See process::exit_process().
+	let mut status: Option<usize> = None;
+	unsafe {
+		push_argv(&mut child, &argv);
+	}
+	child.data.argv = argv;
+	child.data.cwd = String::from(cwd);
+	child.data.ppid = syscall_get_pid();
+	child.data.completion = Some(Completion::Slot(&mut status));
+	let pid = add_process(child);
+	if pid == 0 {
+		println!("{}: couldn't start it", path);
+		return;
+	}
+	// status lives on our stack, so we can't leave until the child has
+	// written it.
+	let status = loop {
+		match unsafe { (&status as *const Option<usize>).read_volatile() } {
+			Some(status) => break status as isize,
+			None => syscall_sleep(WAIT_SLEEP),
+		}
+	};
+	if status != 0 {
+		println!("{} exited with status {}", path, status);
+	}
+}
@@ -0,0 +1,316 @@
+// sound.rs
+// Sound using the VirtIO sound device
+// Stephen Marz
+
+#![allow(dead_code)]
+use crate::{kmem::{kfree, kmalloc},
+            virtio,
+            virtio::{init_queue, Descriptor, MmioOffsets, Queue, StatusField, VIRTIO_DESC_F_WRITE, VIRTIO_RING_SIZE}};
+use alloc::vec::Vec;
+use core::{mem::{size_of, size_of_val}, ptr::null_mut};
+
+// The sound device has four queues. We only talk to the control queue (to
+// set up the stream) and the transmit queue (to send it samples).
+const CONTROL_QUEUE: u32 = 0;
+const EVENT_QUEUE: u32 = 1;
+const TX_QUEUE: u32 = 2;
+const RX_QUEUE: u32 = 3;
+
+// Control request codes
+const R_PCM_SET_PARAMS: u32 = 0x0101;
+const R_PCM_PREPARE: u32 = 0x0102;
+const R_PCM_RELEASE: u32 = 0x0103;
+const R_PCM_START: u32 = 0x0104;
+const R_PCM_STOP: u32 = 0x0105;
+
+// Response status
+const S_OK: u32 = 0x8000;
+
+// We only ever send signed, 16-bit, mono samples. The format is an index
+// into the specification's list of formats, not a size.
+const PCM_FMT_S16: u8 = 5;
+const CHANNELS: u8 = 1;
+// We don't know which streams are outputs without querying the device. QEMU
+// puts its output stream first, so we use stream 0.
+const OUTPUT_STREAM: u32 = 0;
+// The rates are also an index, this time into this list.
+const PCM_RATES: [u32; 14] = [5512, 8000, 11025, 16000, 22050, 32000, 44100, 48000, 64000, 88200, 96000, 176400, 192000, 384000];
+// This is what blip() uses. It's low, but we're making beeps, not music.
+pub const BLIP_RATE: u32 = 8000;
+// The most samples we'll take from a process in one play_pcm system call.
+// That's 128 KiB, which is a bit over a second at 48 kHz. A program that
+// wants to play more can call it again.
+pub const MAX_PCM_SAMPLES: usize = 1 << 16;
+
+#[repr(C)]
+struct Config {
+	jacks:   u32,
+	streams: u32,
+	chmaps:  u32,
+}
+
+#[repr(C)]
+struct PcmHeader {
+	code:      u32,
+	stream_id: u32,
+}
+
+#[repr(C)]
+struct PcmSetParams {
+	hdr:          PcmHeader,
+	buffer_bytes: u32,
+	period_bytes: u32,
+	features:     u32,
+	channels:     u8,
+	format:       u8,
+	rate:         u8,
+	padding:      u8,
+}
+
+#[repr(C)]
+struct PcmXfer {
+	stream_id: u32,
+}
+
+#[repr(C)]
+struct PcmStatus {
+	status:        u32,
+	latency_bytes: u32,
+}
+
+// Just like the GPU, we put the request and its response into one
+// allocation so that we only need a single kfree when it comes back.
+struct Request<RqT> {
+	request:  RqT,
+	response: u32,
+}
+
+impl<RqT> Request<RqT> {
+	pub fn new(request: RqT) -> *mut Self {
+		let ptr = kmalloc(size_of::<Self>()) as *mut Self;
+		unsafe {
+			(*ptr).request = request;
+		}
+		ptr
+	}
+}
+
+pub struct Device {
+	control_queue:        Queue,
+	tx_queue:             Queue,
+	dev:                  *mut u32,
+	control_ack_used_idx: u16,
+	tx_ack_used_idx:      u16,
+	// The rate the stream is currently set up for, so that we don't
+	// re-send the parameters for every blip.
+	rate:                 Option<u32>,
+}
+
+impl Device {
+	pub const fn new() -> Self {
+		Self { control_queue:        Queue::null(),
+		       tx_queue:             Queue::null(),
+		       dev:                  null_mut(),
+		       control_ack_used_idx: 0,
+		       tx_ack_used_idx:      0,
+		       rate:                 None, }
+	}
+}
+
+pub static mut SOUND_DEVICES: [Option<Device>; 8] = [None, None, None, None, None, None, None, None];
+
+pub fn setup_sound_device(ptr: *mut u32) -> bool {
+	unsafe {
+		// We can get the index of the device based on its address.
+		// 0x1000_1000 is index 0
+		// 0x1000_2000 is index 1
+		// ...
+		// 0x1000_8000 is index 7
+		// To get the number that changes over, we shift right 12 places (3 hex digits)
+		let idx = (ptr as usize - virtio::MMIO_VIRTIO_START) >> 12;
+		// [Driver] Device Initialization
+		// 1. Reset the device (write 0 into status)
+		ptr.add(MmioOffsets::Status.scale32()).write_volatile(0);
+		let mut status_bits = StatusField::Acknowledge.val32();
+		// 2. Set ACKNOWLEDGE status bit
+		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
+		// 3. Set the DRIVER status bit
+		status_bits |= StatusField::DriverOk.val32();
+		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
+		// 4. Read device feature bits, write subset of feature
+		// bits understood by OS and driver    to the device.
+		let host_features = ptr.add(MmioOffsets::HostFeatures.scale32()).read_volatile();
+		ptr.add(MmioOffsets::GuestFeatures.scale32()).write_volatile(host_features);
+		// 5. Set the FEATURES_OK status bit
+		status_bits |= StatusField::FeaturesOk.val32();
+		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
+		// 6. Re-read status to ensure FEATURES_OK is still set.
+		// Otherwise, it doesn't support our features.
+		let status_ok = ptr.add(MmioOffsets::Status.scale32()).read_volatile();
+		if !StatusField::features_ok(status_ok) {
+			print!("features fail...");
+			ptr.add(MmioOffsets::Status.scale32()).write_volatile(StatusField::Failed.val32());
+			return false;
+		}
+		// 7. Perform device-specific setup.
+		// If there are no streams, there's nothing for us to play on.
+		let config = ptr.add(MmioOffsets::Config.scale32()) as *const Config;
+		if (*config).streams == 0 {
+			print!("no streams...");
+			return false;
+		}
+		// Set up the control queue and the transmit queue. Like the input
+		// device, each queue gets its own memory.
+		let mut queues = [Queue::null(); 2];
+		for (i, qnum) in [CONTROL_QUEUE, TX_QUEUE].iter().enumerate() {
+			queues[i] = match init_queue(ptr, *qnum, VIRTIO_RING_SIZE as u16) {
+				Some(queue) => queue,
+				None => return false,
+			};
+		}
+		// 8. Set the DRIVER_OK status bit. Device is now "live"
+		status_bits |= StatusField::DriverOk.val32();
+		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
+
+		let dev = Device { control_queue: queues[0],
+		                   tx_queue: queues[1],
+		                   dev: ptr,
+		                   ..Device::new() };
+		SOUND_DEVICES[idx] = Some(dev);
+
+		true
+	}
+}
+
+/// Send a control request. All the device gives back is a status code.
+unsafe fn control<RqT>(dev: &mut Device, request: RqT) {
+	let rq = Request::new(request);
+	virtio::submit(&mut dev.control_queue,
+	               &[Descriptor { addr:  &(*rq).request as *const RqT as u64,
+	                              len:   size_of::<RqT>() as u32,
+	                              flags: 0,
+	                              next:  0, },
+	                 Descriptor { addr:  &(*rq).response as *const u32 as u64,
+	                              len:   size_of::<u32>() as u32,
+	                              flags: VIRTIO_DESC_F_WRITE,
+	                              next:  0, }]);
+}
+
+fn pcm_header(code: u32) -> PcmHeader {
+	PcmHeader { code,
+	            stream_id: OUTPUT_STREAM }
+}
+
+/// Stream a buffer of signed 16-bit mono samples at the given rate. If
+/// there is no sound device (or the rate isn't one the specification
+/// lists), this does nothing and returns false.
+pub fn play_pcm(samples: &[i16], rate: u32) -> bool {
+	let rate_idx = match PCM_RATES.iter().position(|r| *r == rate) {
+		Some(i) => i as u8,
+		None => return false,
+	};
+	if samples.is_empty() {
+		return true;
+	}
+	let bytes = size_of_val(samples);
+	unsafe {
+		// The first device we find is the one we play on.
+		if let Some(dev) = SOUND_DEVICES.iter_mut().flatten().next() {
+			// The stream has to be stopped and released before we can
+			// change its parameters.
+			if dev.rate != Some(rate) {
+				if dev.rate.is_some() {
+					control(dev, pcm_header(R_PCM_STOP));
+					control(dev, pcm_header(R_PCM_RELEASE));
+				}
+				control(dev,
+				        PcmSetParams { hdr:          pcm_header(R_PCM_SET_PARAMS),
+				                       buffer_bytes: bytes as u32,
+				                       period_bytes: bytes as u32,
+				                       features:     0,
+				                       channels:     CHANNELS,
+				                       format:       PCM_FMT_S16,
+				                       rate:         rate_idx,
+				                       padding:      0, });
+				control(dev, pcm_header(R_PCM_PREPARE));
+				control(dev, pcm_header(R_PCM_START));
+				dev.rate = Some(rate);
+			}
+			// The transmit request is the stream id, the samples, and then
+			// a status that the device writes back. The samples have to
+			// stay alive until the device is done with them, so we copy
+			// them into one allocation that pending() frees.
+			let xfer_sz = size_of::<PcmXfer>();
+			let total = xfer_sz + bytes + size_of::<PcmStatus>();
+			let rq = kmalloc(total);
+			if rq.is_null() {
+				return false;
+			}
+			(rq as *mut PcmXfer).write(PcmXfer { stream_id: OUTPUT_STREAM });
+			let data = rq.add(xfer_sz);
+			for (i, s) in samples.iter().enumerate() {
+				(data as *mut i16).add(i).write_unaligned(*s);
+			}
+			let status = data.add(bytes);
+			virtio::submit(&mut dev.tx_queue,
+			               &[Descriptor { addr:  rq as u64,
+			                              len:   xfer_sz as u32,
+			                              flags: 0,
+			                              next:  0, },
+			                 Descriptor { addr:  data as u64,
+			                              len:   bytes as u32,
+			                              flags: 0,
+			                              next:  0, },
+			                 Descriptor { addr:  status as u64,
+			                              len:   size_of::<PcmStatus>() as u32,
+			                              flags: VIRTIO_DESC_F_WRITE,
+			                              next:  0, }]);
+			return true;
+		}
+	}
+	false
+}
+
+/// Play a short square wave. This is what you want for a "blip" when
+/// something hits a paddle.
+pub fn blip(freq: u32, ms: u32) -> bool {
+	if freq == 0 {
+		return false;
+	}
+	let num_samples = (BLIP_RATE * ms / 1000) as usize;
+	// A square wave flips sign every half period.
+	let half_period = (BLIP_RATE / freq / 2).max(1) as usize;
+	let mut samples = Vec::with_capacity(num_samples);
+	for i in 0..num_samples {
+		samples.push(if (i / half_period).is_multiple_of(2) { 8000i16 } else { -8000i16 });
+	}
+	play_pcm(&samples, BLIP_RATE)
+}
+
+fn pending(dev: &mut Device) {
+	// Both queues work the same way. The head descriptor's address is the
+	// start of the allocation, so that's what we free.
+	unsafe {
+		let queue = dev.control_queue;
+		while let Some(elem) = virtio::next_used(&queue, &mut dev.control_ack_used_idx) {
+			let desc = queue.desc(elem.id as u16);
+			kfree(desc.addr as *mut u8);
+		}
+		let queue = dev.tx_queue;
+		while let Some(elem) = virtio::next_used(&queue, &mut dev.tx_ack_used_idx) {
+			let desc = queue.desc(elem.id as u16);
+			kfree(desc.addr as *mut u8);
+		}
+	}
+}
+
+pub fn handle_interrupt(idx: usize) {
+	unsafe {
+		if let Some(sdev) = SOUND_DEVICES[idx].as_mut() {
+			pending(sdev);
+		}
+		else {
+			println!("Invalid sound device for interrupt {}", idx + 1);
+		}
+	}
+}
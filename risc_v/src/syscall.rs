@@ -3,25 +3,144 @@
 // Stephen Marz
 // 3 Jan 2020
 
-use crate::{block::block_op,
+use crate::{block,
+            block::block_op,
             buffer::Buffer,
-            cpu::{dump_registers, Registers, TrapFrame, gp},
+            cpu::{asid_all_changed, asid_changed, dump_registers, get_mtime, memcpy, set_context_switch_hz, Registers, TrapFrame, gp, FREQ},
             elf,
             fs,
             gpu,
-            input::{Event, ABS_EVENTS, KEY_EVENTS},
-            page::{map, virt_to_phys, EntryBits, Table, PAGE_SIZE, zalloc},
-			process::{add_kernel_process_args, delete_process, get_by_pid, set_sleeping, set_waiting, PROCESS_LIST, PROCESS_LIST_MUTEX, Descriptor}};
-use crate::console::{IN_LOCK, IN_BUFFER, push_queue};
-use alloc::{boxed::Box, string::String};
+            input,
+            input::{Event, ABS_EVENTS, KEY_EVENTS, REL_EVENTS},
+            kmem::{kfree, kmalloc, talloc, tfree},
+            page::{incref, map, page_stats, resolve_cow, virt_to_phys, walk_detailed, EntryBits, Pte, Table, PAGE_SIZE},
+            procfs,
+            ramfs,
+            rng,
+            sched::{load_average, process_count, yield_process, LOAD_SHIFT},
+            sound,
+            system,
+            uart::Uart,
+			process::{add_kernel_process_args, add_kernel_worker, exit_process, get_by_pid, map_heap_page, release_open_file, reserve_open_file, set_running, set_sleeping, set_waiting, try_with_process, with_process, Completion, Descriptor, Process, ProcessState, PROCESS_LIST_MUTEX, DEFAULT_PRIORITY, MAX_PRIORITY, STACK_ADDR, STACK_PAGES}};
+use crate::console::{IN_LOCK, IN_BUFFER, push_queue, stdin_waiting};
+use alloc::{boxed::Box, collections::VecDeque, string::String, vec, vec::Vec};
+use core::{mem::size_of, ptr::{null, null_mut}};
+
+// What uname reports. The release is the crate's version, so it changes
+// with Cargo.toml.
+const UNAME_SYSNAME: &str = "SteveOS";
+const UNAME_NODENAME: &str = "localhost";
+const UNAME_VERSION: &str = "osblog";
+const UNAME_MACHINE: &str = "riscv64";
+// Limits on what execv will copy out of the caller. All of argv, pointers
+// and strings, has to fit in the top page of the new stack (see
+// push_argv()), and we stop walking the pointer array after MAX_ARGS
+// entries even if we never find its NULL.
+const ARG_MAX: usize = PAGE_SIZE;
+const MAX_ARGS: usize = 64;
+// The longest path we'll copy out of a process, including its NUL.
+const PATH_MAX: usize = 1024;
+/// Error numbers from newlib's sys/errno.h. A failed system call returns the
+/// negative error number, and newlib turns that into errno and a -1.
+#[repr(isize)]
+#[derive(Copy, Clone)]
+pub enum SysError {
+	EPERM = 1,
+	ENOENT = 2,
+	ESRCH = 3,
+	EIO = 5,
+	E2BIG = 7,
+	ENOEXEC = 8,
+	EBADF = 9,
+	EAGAIN = 11,
+	ENOMEM = 12,
+	EACCES = 13,
+	EFAULT = 14,
+	EEXIST = 17,
+	ENODEV = 19,
+	ENOTDIR = 20,
+	EISDIR = 21,
+	EINVAL = 22,
+	ENFILE = 23,
+	EMFILE = 24,
+	EFBIG = 27,
+	ESPIPE = 29,
+	EROFS = 30,
+	ERANGE = 34,
+	ENOSYS = 88,
+	ENAMETOOLONG = 91,
+}
+
+impl SysError {
+	/// What goes into A0.
+	pub fn to_ret(self) -> usize {
+		-(self as isize) as usize
+	}
+}
+
+impl From<fs::FsError> for SysError {
+	fn from(e: fs::FsError) -> Self {
+		match e {
+			fs::FsError::FileNotFound => SysError::ENOENT,
+			fs::FsError::Permission => SysError::EACCES,
+			fs::FsError::ReadOnly => SysError::EROFS,
+			fs::FsError::IsFile => SysError::ENOTDIR,
+			fs::FsError::IsDirectory => SysError::EISDIR,
+			fs::FsError::NotMinix => SysError::EIO,
+			fs::FsError::Io => SysError::EIO,
+			fs::FsError::TooBig => SysError::EFBIG,
+			fs::FsError::OutOfMemory => SysError::ENOMEM,
+			fs::FsError::Success => SysError::EINVAL,
+		}
+	}
+}
+
+// reboot()'s cmd, from Linux's reboot.h.
+const LINUX_REBOOT_CMD_RESTART: u32 = 0x0123_4567;
+const LINUX_REBOOT_CMD_POWER_OFF: u32 = 0x4321_fedc;
+// lseek()'s whence
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+const SEEK_END: usize = 2;
+// newlib's open() flag to create the file if it isn't there.
+const O_CREAT: usize = 0x200;
+// This is _UTSNAME_LENGTH from newlib and Linux, including the NUL.
+const UTSNAME_LEN: usize = 65;
+// Flag for get framebuffer (1000) to map the framebuffer read-only.
+const FB_READ_ONLY: usize = 1;
+// What get framebuffer format (1010) returns: the byte order of a pixel.
+const FB_FORMAT_RGBA: usize = 0;
+const FB_FORMAT_BGRA: usize = 1;
+// struct sysinfo's load averages have this many fractional bits.
+const SI_LOAD_SHIFT: usize = 16;
+
+// This is struct sysinfo from Linux, which is what sysinfo() fills in.
+#[repr(C)]
+#[derive(Default)]
+struct SysInfo {
+	uptime:    usize,
+	loads:     [usize; 3],
+	totalram:  usize,
+	freeram:   usize,
+	sharedram: usize,
+	bufferram: usize,
+	totalswap: usize,
+	freeswap:  usize,
+	procs:     u16,
+	pad:       u16,
+	totalhigh: usize,
+	freehigh:  usize,
+	mem_unit:  u32,
+}
 
 /// do_syscall is called from trap.rs to invoke a system call. No discernment is
 /// made here whether this is a U-mode, S-mode, or M-mode system call.
 /// Since we can't do anything unless we dereference the passed pointer,
 /// I went ahead and made the entire function unsafe.
-/// If we return 0 from this function, the m_trap function will schedule
-/// the next process--consider this a yield. A non-0 is the program counter
-/// we want to go back to.
+/// When this returns, m_trap calls schedule() and re-arms the context switch
+/// timer, so every system call is a chance to switch processes. Where the
+/// caller ends up in line is what changes. Yield (1) uses this to make the
+/// caller go last.
 pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 	// Libgloss expects the system call number in A7, so let's follow
 	// their lead.
@@ -32,15 +151,20 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 	match syscall_number {
 		93 | 94 => {
 			// exit and exit_group
-			delete_process((*frame).pid as u16);
+			exit_process((*frame).pid as u16, (*frame).regs[gp(Registers::A0)]);
 		}
 		1 => {
-			//yield
-			// We don't do anything, but we don't want to print "unknown system call"
+			// yield
+			// Move ourselves so that the schedule() m_trap calls after we
+			// return picks the next process in line and we go to the back.
+			// The timer gets re-armed for whoever that is, so they get a
+			// full time slice instead of the rest of ours.
+			yield_process((*frame).pid as u16);
+			(*frame).regs[gp(Registers::A0)] = 0;
 		}
 		2 => {
 			// Easy putchar
-			print!("{}", (*frame).regs[Registers::A0 as usize] as u8 as char);
+			Uart::default().put((*frame).regs[Registers::A0 as usize] as u8);
 		}
 		8 => {
 			dump_registers(frame);
@@ -53,249 +177,658 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 			// execv
 			// A0 = path
 			// A1 = argv
-			let mut path_addr = (*frame).regs[Registers::A0 as usize];
-			// If the MMU is turned on, translate.
-			if (*frame).satp >> 60 != 0 {
-				let p = get_by_pid((*frame).pid as u16);
-				let table = ((*p).mmu_table).as_ref().unwrap();
-				path_addr = virt_to_phys(table, path_addr).unwrap();
-			}
-			// Our path address here is now a physical address. If it came in virtual,
-			// it is now physical.
-			let path_bytes = path_addr as *const u8;
-			let mut path = String::new();
-			let mut iterator: usize = 0;
-			// I really have to figure out how to change an array of bytes
-			// to a string. For now, this is very C-style and mimics strcpy.
-			loop {
-				let ch = *path_bytes.add(iterator);
-				if ch == 0 {
-					break;
-				}
-				iterator += 1;
-				path.push(ch as char);
-			}
+			// The caller keeps its PID and its place in the process list. Only its
+			// address space gets replaced.
+			let pid = (*frame).pid as u16;
+			let path = (*frame).regs[Registers::A0 as usize];
+			let argv = (*frame).regs[Registers::A1 as usize];
+			// We copy all of argv before we go any further, so if it's bad, the
+			// caller just gets an error back and keeps running as it was.
+			let copied = with_caller(frame, mepc, |process| {
+				let table = user_table(frame, process);
+				let path = user_path(table, path)?;
+				match user_argv(table, argv, &path) {
+					Ok(argv) => Ok((path, argv)),
+					Err(e) => {
+						println!("execv: bad or oversized argv for '{}'.", path);
+						Err(e)
+					}
+				}
+			});
+			let (path, argv) = match copied {
+				Some(Ok(copied)) => copied,
+				Some(Err(e)) => {
+					(*frame).regs[Registers::A0 as usize] = e.to_ret();
+					return;
+				}
+				None => return,
+			};
 			// See if we can find the path.
 			if let Ok(inode) = fs::MinixFileSystem::open(8, &path) {
-				let inode_heap = Box::new(inode);
-				// The Box above moves the Inode to a new memory location on the heap.
-				// This needs to be on the heap since we are about to hand over control
-				// to a kernel process.
-				// THERE is an issue here. If we fail somewhere inside the kernel process,
-				// we shouldn't delete our process here. However, since this is asynchronous
-				// our process will still get deleted and the error won't be reported.
-				// We have to make sure we relinquish Box control here by using into_raw.
-				// Otherwise, the Box will free the memory associated with this inode.
-				add_kernel_process_args(exec_func, Box::into_raw(inode_heap) as usize);
-				// This deletes us, which is what we want.
-				delete_process((*frame).pid as u16);
+				// Reading the file may sleep, so a kernel process has to do the
+				// loading. It will wake us back up if it fails, otherwise we
+				// come back as the new program. This all needs to be on the heap
+				// since we're handing it to another process. See ExecArgs.
+				match ExecArgs::give(inode, pid, &path, &argv) {
+					Some(args) => {
+						set_waiting(pid);
+						add_kernel_process_args(exec_func, args as *mut ExecArgs as usize, "exec");
+					}
+					None => (*frame).regs[Registers::A0 as usize] = SysError::ENOMEM.to_ret(),
+				}
 			}
 			else {
 				// If we get here, the path couldn't be found, or for some reason
-				// open failed. So, we return -1 and move on.
+				// open failed. So, we return ENOENT and move on.
 				println!("Could not open path '{}'.", path);
-				(*frame).regs[Registers::A0 as usize] = -1isize as usize;
+				(*frame).regs[Registers::A0 as usize] = SysError::ENOENT.to_ret();
 			}
 		}
 		17 => { //getcwd
-			let mut buf = (*frame).regs[gp(Registers::A0)] as *mut u8;
+			let buf = (*frame).regs[gp(Registers::A0)] as *mut u8;
 			let size = (*frame).regs[gp(Registers::A1)];
-			let process = get_by_pid((*frame).pid as u16).as_ref().unwrap();
-			let mut iter = 0usize;
-			if (*frame).satp >> 60 != 0 {
-				let table = ((*process).mmu_table).as_mut().unwrap();
-				let paddr = virt_to_phys(table, buf as usize);
-				if let Some(bufaddr) = paddr {
-					buf = bufaddr as *mut u8;
+			// On success, A0 stays as buf, which is what getcwd returns.
+			let ret = with_caller(frame, mepc, |process| {
+				// The NUL has to fit too, or the caller gets ERANGE and
+				// nothing in buf.
+				let cwd = process.data.cwd.as_bytes();
+				if cwd.len() + 1 > size {
+					return Err(SysError::ERANGE);
 				}
-				else {
-					(*frame).regs[gp(Registers::A0)] = -1isize as usize;
-					return;
-				}
-			}
-			for i in process.data.cwd.as_bytes() {
-				if iter == 0 || iter >= size {
-					break;
+				let mut path = Vec::with_capacity(cwd.len() + 1);
+				path.extend_from_slice(cwd);
+				path.push(0);
+				if copy_to_user(user_table(frame, process), buf, &path) != path.len() {
+					return Err(SysError::EFAULT);
 				}
-				buf.add(iter).write(*i);
-				iter += 1;
+				Ok(())
+			});
+			if let Some(Err(e)) = ret {
+				(*frame).regs[gp(Registers::A0)] = e.to_ret();
 			}
 		}
 		48 => {
 		// #define SYS_faccessat 48
-			(*frame).regs[gp(Registers::A0)] = -1isize as usize;
+			(*frame).regs[gp(Registers::A0)] = SysError::ENOSYS.to_ret();
 		}
 		57 => {
 			// #define SYS_close 57
 			let fd = (*frame).regs[gp(Registers::A0)] as u16;
-			let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
-			if process.data.fdesc.contains_key(&fd) {
-				process.data.fdesc.remove(&fd);
-				(*frame).regs[gp(Registers::A0)] = 0;
-			}
-			else {
-				(*frame).regs[gp(Registers::A0)] = -1isize as usize;
+			let ret = with_caller(frame, mepc, |process| match process.data.fdesc.remove(&fd) {
+				Some(desc) => {
+					if desc.is_counted() {
+						release_open_file();
+					}
+					0
+				}
+				None => SysError::EBADF.to_ret(),
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
 			}
 			// Flush?
 		}
+		62 => {
+			// #define SYS_lseek 62
+			// off_t lseek(int fd, off_t offset, int whence)
+			let fd = (*frame).regs[gp(Registers::A0)] as u16;
+			let offset = (*frame).regs[gp(Registers::A1)] as i64;
+			let whence = (*frame).regs[gp(Registers::A2)];
+			let ret = with_caller(frame, mepc, |process| {
+				// Where the file is now, and where its end is, if we know.
+				let (cur, end) = match process.data.fdesc.get(&fd) {
+					None => return SysError::EBADF.to_ret(),
+					Some(Descriptor::Ram(rf)) => {
+						(rf.offset as i64, ramfs::stat(&rf.path).ok().map(|st| st.size as i64))
+					}
+					Some(Descriptor::Proc(pf)) => (pf.offset as i64, None),
+					Some(Descriptor::Block(bf)) => {
+						(bf.offset as i64, block::capacity(bf.dev).map(|c| c as i64))
+					}
+					Some(Descriptor::File(mf)) => (mf.offset as i64, Some(mf.inode.size as i64)),
+					Some(_) => return SysError::ESPIPE.to_ret(),
+				};
+				let new = match (whence, end) {
+					(SEEK_SET, _) => offset,
+					(SEEK_CUR, _) => cur + offset,
+					(SEEK_END, Some(end)) => end + offset,
+					_ => return SysError::EINVAL.to_ret(),
+				};
+				if new < 0 {
+					return SysError::EINVAL.to_ret();
+				}
+				match process.data.fdesc.get_mut(&fd) {
+					Some(Descriptor::Ram(rf)) => rf.offset = new as usize,
+					Some(Descriptor::Proc(pf)) => pf.offset = new as usize,
+					Some(Descriptor::Block(bf)) => bf.offset = new as u64,
+					Some(Descriptor::File(mf)) => mf.offset = new as u32,
+					_ => {},
+				}
+				new as usize
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
+			}
+		}
 		63 => { // sys_read
 			let fd = (*frame).regs[gp(Registers::A0)] as u16;
-			let mut buf = (*frame).regs[gp(Registers::A1)] as *mut u8;
+			let buf = (*frame).regs[gp(Registers::A1)] as *mut u8;
 			let size = (*frame).regs[gp(Registers::A2)];
-			let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
-			let mut ret = 0usize;
-			// If we return 0, the trap handler will schedule
-			// another process.
-			if fd == 0 { // stdin
-				IN_LOCK.spin_lock();
-				if let Some(mut inb) = IN_BUFFER.take() {
-					let num_elements = if inb.len() >= size { size } else { inb.len() };
-					let mut buf_ptr = buf as *mut u8;
-					if num_elements == 0 {
-						push_queue((*frame).pid as u16);
-						set_waiting((*frame).pid as u16);
+			let pid = (*frame).pid as u16;
+			// /proc files are generated fresh on every read, but generating them
+			// takes the process list mutex, and so does with_caller(). So, we
+			// look up the entry and generate it before we go in for the read.
+			let proc_entry = match with_caller(frame, mepc, |process| match process.data.fdesc.get(&fd) {
+				Some(Descriptor::Proc(pf)) => Some(pf.entry),
+				_ => None,
+			}) {
+				Some(proc_entry) => proc_entry,
+				None => return,
+			};
+			let proc_contents = proc_entry.and_then(|entry| procfs::generate(&entry));
+			let ret = with_caller(frame, mepc, |process| {
+				let mut ret = 0usize;
+				let table = user_table(frame, process);
+				// If we return 0, the trap handler will schedule
+				// another process.
+				if let Some(Descriptor::Console) = process.data.fdesc.get(&fd) {
+					// Reading the console is reading stdin, whatever fd it's on.
+					IN_LOCK.spin_lock();
+					if let Some(mut inb) = IN_BUFFER.take() {
+						let num_elements = if inb.len() >= size { size } else { inb.len() };
+						// If someone is already waiting, they get the next line, so
+						// we get in line behind them.
+						if num_elements == 0 || stdin_waiting() {
+							// We sleep until push_stdin() hands us a line. It
+							// copies it into buf and puts the count in our A0.
+							push_queue(pid, buf, size);
+							set_waiting(pid);
+						}
+						else {
+							// Kernel processes (table is None) read from stdin too,
+							// see console::getc_blocking().
+							let line: Vec<u8> = inb.drain(0..num_elements).collect();
+							ret = copy_to_user(table, buf, &line);
+						}
+						IN_BUFFER.replace(inb);
 					}
-					else {
-						for i in inb.drain(0..num_elements) {
-							if (*frame).satp >> 60 != 0 {
-								let table = ((*process).mmu_table).as_mut().unwrap();
-								let buf_addr = virt_to_phys(table, buf as usize);
-								if buf_addr.is_none() {
-									break;
-								}
-								buf_ptr = buf_addr.unwrap() as *mut u8;
-								buf_ptr.write(i);
-								ret += 1;
-								println!("R: {}", ret);
+					IN_LOCK.unlock();
+				}
+				else if let Some(Descriptor::Ram(rf)) = process.data.fdesc.get_mut(&fd) {
+					// The user's buffer might not be contiguous in physical memory,
+					// so read into a kernel buffer first. No file is bigger than
+					// MAX_FILE_SIZE, so neither is the buffer.
+					let mut kbuf = vec![0; size.min(ramfs::MAX_FILE_SIZE)];
+					match ramfs::read(&rf.path, rf.offset, &mut kbuf) {
+						Ok(bytes) => {
+							ret = copy_to_user(table, buf, &kbuf[..bytes]);
+							rf.offset += ret;
+						}
+						Err(e) => ret = SysError::from(e).to_ret(),
+					}
+				}
+				else if let Some(Descriptor::Block(bf)) = process.data.fdesc.get_mut(&fd) {
+					// The device reads right into the user's memory, so we
+					// stop at the end of the page buf is in, and at the end
+					// of the device. The process waits, and block::pending()
+					// gives it the byte count.
+					let capacity = block::capacity(bf.dev).unwrap_or(0);
+					let mut addr = buf as usize;
+					if let Some(table) = table {
+						match user_writable(table, addr) {
+							Some(paddr) => addr = paddr,
+							None => return SysError::EFAULT.to_ret(),
+						}
+					}
+					let bytes = size.min(PAGE_SIZE - buf as usize % PAGE_SIZE)
+					                .min(capacity.saturating_sub(bf.offset) as usize);
+					if bytes > 0 {
+						match block::file_op(bf.dev, addr as *mut u8, bytes as u32, bf.offset, false, pid, process.priority, null_mut()) {
+							Ok(_) => {
+								bf.offset += bytes as u64;
+								set_waiting(pid);
 							}
-							buf = buf.add(1);
-							buf_ptr = buf_ptr.add(1);
+							Err(_) => ret = SysError::EIO.to_ret(),
 						}
 					}
-					IN_BUFFER.replace(inb);
 				}
-				IN_LOCK.unlock();
+				else if let Some(Descriptor::Proc(pf)) = process.data.fdesc.get_mut(&fd) {
+					// We keep our place in the file with the descriptor's offset.
+					match &proc_contents {
+						Some(contents) => {
+							let bytes = contents.as_bytes();
+							let start = pf.offset.min(bytes.len());
+							let end = (start + size).min(bytes.len());
+							ret = copy_to_user(table, buf, &bytes[start..end]);
+							pf.offset += ret;
+						}
+						None => ret = SysError::EAGAIN.to_ret(),
+					}
+				}
+				ret
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
 			}
-			(*frame).regs[gp(Registers::A0)] = ret;
 		}
 		64 => { // sys_write
 			let fd = (*frame).regs[gp(Registers::A0)] as u16;
 			let buf = (*frame).regs[gp(Registers::A1)] as *const u8;
 			let size = (*frame).regs[gp(Registers::A2)];
-			let process = get_by_pid((*frame).pid as u16).as_ref().unwrap();
-			if fd == 1 || fd == 2 {
-				// stdout / stderr
-				// println!("WRITE {}, 0x{:08x}, {}", fd, bu/f as usize, size);
-				let mut iter = 0;
-				for i in 0..size {
-					iter += 1;
-					if (*frame).satp >> 60 != 0 {
-						let table = ((*process).mmu_table).as_mut().unwrap();
-						// We don't need to do the following until we reach a page boundary,
-						// however that code isn't written, yet.
-						let paddr = virt_to_phys(table, buf.add(i) as usize);
-						if let Some(bufaddr) = paddr {
-							print!("{}", *(bufaddr as *const u8) as char);
+			let ret = with_caller(frame, mepc, |process| {
+				let table = user_table(frame, process);
+				match process.data.fdesc.get_mut(&fd) {
+					None => SysError::EBADF.to_ret(),
+					Some(Descriptor::Console) => {
+						// The UART. Every process starts with this on 0, 1, and 2,
+						// but any of them could have been closed and reused.
+						// We print up to a page that isn't mapped.
+						match for_user_runs(table, buf, size, |run| {
+							Uart::default().put_bytes(run);
+							run.len()
+						}) {
+							Ok(written) | Err(written) => written,
 						}
-						else {
-							break;
+					}
+					Some(Descriptor::Ram(rf)) => {
+						// Anything past MAX_FILE_SIZE would fail anyway, so we
+						// don't copy it in. The caller gets a short write.
+						let mut kbuf = vec![0; size.min(ramfs::MAX_FILE_SIZE)];
+						let bytes = copy_from_user(table, buf, &mut kbuf);
+						match ramfs::write(&rf.path, rf.offset, &kbuf[..bytes]) {
+							Ok(bytes) => {
+								rf.offset += bytes;
+								bytes
+							},
+							Err(e) => SysError::from(e).to_ret(),
 						}
 					}
-				}
-				(*frame).regs[gp(Registers::A0)] = iter as usize;
-			}
-			else {
-				let descriptor = process.data.fdesc.get(&fd);
-				if descriptor.is_none() {
-					(*frame).regs[gp(Registers::A0)] = 0;
-					return;
-				}
-				else {
-					let descriptor = descriptor.unwrap();
-					match descriptor {
-						Descriptor::Framebuffer => {
-
+					Some(Descriptor::Block(bf)) => {
+						// Writes have to be whole sectors. We copy the data
+						// into the kernel, since the user's buffer might not
+						// be contiguous, and block::pending() frees the copy.
+						// Nothing gets queued for zero bytes, so we can't wait.
+						if size == 0 {
+							return 0;
 						}
-						Descriptor::File(inode) => {
-
-						
+						if !size.is_multiple_of(512) || bf.offset % 512 != 0 {
+							return SysError::EINVAL.to_ret();
+						}
+						if bf.offset + size as u64 > block::capacity(bf.dev).unwrap_or(0) {
+							return SysError::EINVAL.to_ret();
+						}
+						let kbuf = kmalloc(size);
+						let bytes = copy_from_user(table, buf, core::slice::from_raw_parts_mut(kbuf, size));
+						if bytes != size {
+							kfree(kbuf);
+							return SysError::EFAULT.to_ret();
+						}
+						match block::file_op(bf.dev, kbuf, size as u32, bf.offset, true, (*frame).pid as u16, process.priority, kbuf) {
+							Ok(_) => {
+								bf.offset += size as u64;
+								set_waiting((*frame).pid as u16);
+								0
+							}
+							Err(block::BlockErrors::ReadOnly) => {
+								kfree(kbuf);
+								SysError::EROFS.to_ret()
+							}
+							Err(_) => {
+								kfree(kbuf);
+								SysError::EIO.to_ret()
+							}
 						}
-						_ => {
-							// unsupported
-							(*frame).regs[gp(Registers::A0)] = 0;
+					}
+					Some(Descriptor::File(mf)) => {
+						// A read-only disk gives us FsError::Permission, so the
+						// user just sees -1. We write a run at a time, so an
+						// error after the first one is a short write.
+						let mut error = None;
+						let written = for_user_runs(table, buf, size, |run| {
+							match fs::MinixFileSystem::write(mf.bdev, &mf.inode, run.as_ptr(), mf.offset, run.len() as u32) {
+								Ok(bytes) => {
+									mf.offset += bytes;
+									bytes as usize
+								}
+								Err(e) => {
+									error = Some(e);
+									0
+								}
+							}
+						});
+						match (written, error) {
+							(Ok(0), Some(e)) | (Err(0), Some(e)) => SysError::from(e).to_ret(),
+							(Err(0), None) => SysError::EFAULT.to_ret(),
+							(Ok(bytes), _) | (Err(bytes), _) => bytes,
 						}
 					}
+					Some(_) => {
+						// unsupported, this includes the framebuffer
+						0
+					}
 				}
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
 			}
 		}
 		66 => {
-			(*frame).regs[gp(Registers::A0)] = -1isize as usize;
+			(*frame).regs[gp(Registers::A0)] = SysError::ENOSYS.to_ret();
 		}
 		// #define SYS_fstat 80
 		80 => {
 			// int fstat(int filedes, struct stat *buf)
 			(*frame).regs[gp(Registers::A0)] = 0;
 		}
+		118 | 121 => {
+			// #define SYS_sched_setparam 118
+			// #define SYS_sched_getparam 121
+			// int sched_setparam(pid_t pid, const struct sched_param *param)
+			// struct sched_param only holds an int sched_priority. The priority
+			// sets the process' quantum (see process::set_priority). A pid of 0
+			// means the calling process.
+			let pid = match (*frame).regs[gp(Registers::A0)] as u16 {
+				0 => (*frame).pid as u16,
+				p => p,
+			};
+			let param = (*frame).regs[gp(Registers::A1)];
+			let caller = with_caller(frame, mepc, |process| {
+				let mut param = param;
+				if let Some(table) = user_table(frame, process) {
+					// getparam writes the priority back, so it needs a page the
+					// caller could write.
+					let paddr = if syscall_number == 118 {
+						user_readable(table, param)
+					}
+					else {
+						user_writable(table, param)
+					};
+					param = paddr.unwrap_or(0);
+				}
+				(param, process.data.uid)
+			});
+			let (param, uid) = match caller {
+				Some(caller) => caller,
+				None => return,
+			};
+			if param == 0 {
+				(*frame).regs[gp(Registers::A0)] = SysError::EFAULT.to_ret();
+				return;
+			}
+			// Anybody can look, but only root can change someone else's
+			// priority. Otherwise, any process could starve init.
+			if syscall_number == 118 && uid != 0 && pid != (*frame).pid as u16 {
+				(*frame).regs[gp(Registers::A0)] = SysError::EPERM.to_ret();
+				return;
+			}
+			let param = param as *mut i32;
+			// The target can't go anywhere while we have it, and we can tell
+			// a busy list (try again) from a PID that isn't there.
+			let found = try_with_process(pid, |target| {
+				if syscall_number == 118 {
+					let prio = (*param).max(0).min(MAX_PRIORITY as i32);
+					target.set_priority(prio as u8);
+				}
+				else {
+					*param = target.get_priority() as i32;
+				}
+			});
+			match found {
+				Some(Some(_)) => (*frame).regs[gp(Registers::A0)] = 0,
+				Some(None) => (*frame).regs[gp(Registers::A0)] = SysError::ESRCH.to_ret(),
+				None => (*frame).pc = mepc,
+			}
+		}
+		142 => {
+			// #define SYS_reboot 142
+			// int reboot(int cmd)
+			// Only root can shut the machine down. We flush the block
+			// devices first, and that sleeps, so a kernel process does it.
+			// We never come back, but the caller goes back to running
+			// until the queues are empty.
+			let restart = match (*frame).regs[gp(Registers::A0)] as u32 {
+				LINUX_REBOOT_CMD_POWER_OFF => 0,
+				LINUX_REBOOT_CMD_RESTART => 1,
+				_ => {
+					(*frame).regs[gp(Registers::A0)] = SysError::EINVAL.to_ret();
+					return;
+				}
+			};
+			// add_kernel_process_args() takes the process list mutex, so we
+			// only look at the uid while we have the caller.
+			let uid = match with_caller(frame, mepc, |process| process.data.uid) {
+				Some(uid) => uid,
+				None => return,
+			};
+			if uid != 0 {
+				(*frame).regs[gp(Registers::A0)] = SysError::EPERM.to_ret();
+				return;
+			}
+			(*frame).regs[gp(Registers::A0)] = 0;
+			add_kernel_process_args(system::shutdown, restart, "shutdown");
+		}
+		144 | 146 => {
+			// #define SYS_setgid 144
+			// #define SYS_setuid 146
+			// Only root (uid 0) may change its identity.
+			let id = (*frame).regs[gp(Registers::A0)] as u16;
+			let ret = with_caller(frame, mepc, |process| {
+				if process.data.uid != 0 {
+					return SysError::EPERM.to_ret();
+				}
+				if syscall_number == 146 {
+					process.data.uid = id;
+				}
+				else {
+					process.data.gid = id;
+				}
+				0
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
+			}
+		}
+		160 => {
+			// #define SYS_uname 160
+			// A0 = struct utsname *
+			// Each field of utsname is a UTSNAME_LEN character array, and
+			// they come in this order.
+			let fields = [UNAME_SYSNAME, UNAME_NODENAME, env!("CARGO_PKG_VERSION"), UNAME_VERSION, UNAME_MACHINE, ""];
+			let buf = (*frame).regs[gp(Registers::A0)];
+			let ret = with_caller(frame, mepc, |process| {
+				for (i, field) in fields.iter().enumerate() {
+					let mut addr = buf + i * UTSNAME_LEN;
+					// Translate each field on its own so that the whole struct
+					// doesn't have to sit in one page.
+					if let Some(table) = user_table(frame, process) {
+						match user_writable(table, addr) {
+							Some(paddr) => addr = paddr,
+							None => return SysError::EFAULT.to_ret(),
+						}
+					}
+					let dst = addr as *mut u8;
+					let len = field.len().min(UTSNAME_LEN - 1);
+					memcpy(dst, field.as_ptr(), len);
+					for j in len..UTSNAME_LEN {
+						dst.add(j).write(0);
+					}
+				}
+				0
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
+			}
+		}
+		166 => {
+			// #define SYS_umask 166
+			// A0 = new mask, returns the old mask. This can't fail.
+			let mask = (*frame).regs[gp(Registers::A0)] as u16 & 0o777;
+			let old = with_caller(frame, mepc, |process| core::mem::replace(&mut process.data.umask, mask));
+			if let Some(old) = old {
+				(*frame).regs[gp(Registers::A0)] = old as usize;
+			}
+		}
 		172 => {
 			// A0 = pid
 			(*frame).regs[Registers::A0 as usize] = (*frame).pid;
 		}
-		180 => {
-			set_waiting((*frame).pid as u16);
-			let _ = block_op(
-			                 (*frame).regs[Registers::A0 as usize],
-			                 (*frame).regs[Registers::A1 as usize] as *mut u8,
-			                 (*frame).regs[Registers::A2 as usize] as u32,
-			                 (*frame).regs[Registers::A3 as usize] as u64,
-			                 false,
-			                 (*frame).pid as u16
+		174..=177 => {
+			// #define SYS_getuid 174
+			// #define SYS_geteuid 175
+			// #define SYS_getgid 176
+			// #define SYS_getegid 177
+			// We don't have a separate effective id, so the effective
+			// ids are the real ids.
+			let id = with_caller(frame, mepc, |process| match syscall_number {
+				174 | 175 => process.data.uid,
+				_ => process.data.gid,
+			});
+			if let Some(id) = id {
+				(*frame).regs[gp(Registers::A0)] = id as usize;
+			}
+		}
+		179 => {
+			// #define SYS_sysinfo 179
+			// int sysinfo(struct sysinfo *info)
+			// The load averages are over 1, 5, and 15 timer ticks, not
+			// minutes. See sched::sample_load().
+			let procs = match process_count() {
+				Some(procs) => procs,
+				None => {
+					(*frame).regs[gp(Registers::A0)] = SysError::EAGAIN.to_ret();
+					return;
+				}
+			};
+			let (total_pages, free_pages) = page_stats();
+			let loads = load_average();
+			let info = SysInfo { uptime:   get_mtime() / FREQ as usize,
+			                     loads:    loads.map(|load| load << (SI_LOAD_SHIFT - LOAD_SHIFT)),
+			                     totalram: total_pages * PAGE_SIZE,
+			                     freeram:  free_pages * PAGE_SIZE,
+			                     procs:    procs as u16,
+			                     mem_unit: 1,
+			                     ..SysInfo::default() };
+			let buf = (*frame).regs[gp(Registers::A0)] as *mut u8;
+			let bytes = core::slice::from_raw_parts(&info as *const SysInfo as *const u8, size_of::<SysInfo>());
+			let ret = with_caller(frame, mepc, |process| {
+				if copy_to_user(user_table(frame, process), buf, bytes) == bytes.len() {
+					0
+				}
+				else {
+					SysError::EFAULT.to_ret()
+				}
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
+			}
+		}
+		180 | 181 => {
+			// 180 reads and 181 writes. Either way, we sleep until
+			// block::pending() wakes us up with the status in A0. If the
+			// request never made it to the device, nobody will.
+			// The buffer is a physical address handed straight to the
+			// device, so only a kernel process (no translation) can ask
+			// for this. A user process could otherwise read or write
+			// any memory it likes.
+			if (*frame).is_translated() {
+				(*frame).regs[gp(Registers::A0)] = SysError::EPERM.to_ret();
+				return;
+			}
+			// block_op() doesn't queue anything for zero bytes, so nobody
+			// would ever wake us. There's nothing to do, so it's done.
+			if (*frame).regs[Registers::A2 as usize] as u32 == 0 {
+				(*frame).regs[gp(Registers::A0)] = block::VIRTIO_BLK_S_OK as usize;
+				return;
+			}
+			let pid = (*frame).pid as u16;
+			let priority = get_by_pid(pid).as_ref().map_or(DEFAULT_PRIORITY, |p| p.priority);
+			set_waiting(pid);
+			let ret = block_op(
+			                   (*frame).regs[Registers::A0 as usize],
+			                   (*frame).regs[Registers::A1 as usize] as *mut u8,
+			                   (*frame).regs[Registers::A2 as usize] as u32,
+			                   (*frame).regs[Registers::A3 as usize] as u64,
+			                   syscall_number == 181,
+			                   pid,
+			                   priority
 			);
+			if ret.is_err() {
+				set_running(pid);
+				(*frame).regs[gp(Registers::A0)] = block::VIRTIO_BLK_S_IOERR as usize;
+			}
 		}
 		214 => { // brk
 			// #define SYS_brk 214
 			// void *brk(void *addr);
 			let addr = (*frame).regs[gp(Registers::A0)];
-			let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
-			// println!("Break move from 0x{:08x} to 0x{:08x}", process.brk, addr);
-			if addr > process.brk {
-				if (*frame).satp >> 60 != 0 {
-					let table = ((*process).mmu_table).as_mut().unwrap();
-					let diff = (addr + PAGE_SIZE - process.brk) / PAGE_SIZE;
-					for i in 0..diff {
-						let new_addr = zalloc(1) as usize;
-						process.data.pages.push_back(new_addr);
-						map(table, process.brk + (i << 12), new_addr, EntryBits::UserReadWrite.val(), 0);
-					}
+			let ret = with_caller(frame, mepc, |process| {
+				// println!("Break move from 0x{:08x} to 0x{:08x}", process.brk, addr);
+				// We only move the break here. The pages under it get
+				// mapped when they're first touched (see
+				// process::demand_page). The heap can't grow into the
+				// stack.
+				if addr > process.brk && addr <= STACK_ADDR {
+					process.brk = addr;
 				}
-				process.brk = addr;
+				process.brk
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
 			}
-			(*frame).regs[gp(Registers::A0)] = process.brk;
 		}
 		// System calls 1000 and above are "special" system calls for our OS. I'll
 		// try to mimic the normal system calls below 1000 so that this OS is compatible
 		// with libraries.
 		1000 => {
 			// get framebuffer
-			// syscall_get_framebuffer(device)
+			// syscall_get_framebuffer(device, flags)
+			// The device is 0 for the first GPU, or a GPU's device number. If
+			// it isn't a GPU, we return ENODEV instead of mapping something else.
+			// With FB_READ_ONLY, the pages are mapped without write permission,
+			// so a viewer can read another program's display but a store
+			// faults. Any number of processes can map the same framebuffer.
 			let dev = (*frame).regs[Registers::A0 as usize];
-			(*frame).regs[Registers::A0 as usize] = 0;
-			if dev > 0 && dev <= 8 {
-				if let Some(p) = gpu::GPU_DEVICES[dev - 1].take() {
+			let bits = if (*frame).regs[Registers::A1 as usize] & FB_READ_ONLY != 0 {
+				EntryBits::UserRead.val()
+			}
+			else {
+				EntryBits::UserReadWrite.val()
+			};
+			// If the process list is busy, we'll be back, so A0 has to keep
+			// the device until we know.
+			let mut ret = Some(SysError::ENODEV.to_ret());
+			if let Some(dev) = gpu::resolve(dev) {
+				if let Some(p) = gpu::GPU_DEVICES[dev - 1].lock().as_ref() {
 					let ptr = p.get_framebuffer() as usize;
-					if (*frame).satp >> 60 != 0 {
-						let process = get_by_pid((*frame).pid as u16);
-						let table = ((*process).mmu_table).as_mut().unwrap();
-						let num_pages = (p.get_width() * p.get_height() * 4) as usize / PAGE_SIZE;
-						for i in 0..num_pages {
-							let vaddr = 0x3000_0000 + (i << 12);
-							let paddr = ptr + (i << 12);
-							map(table, vaddr, paddr, EntryBits::UserReadWrite as usize, 0);
-						}
-						gpu::GPU_DEVICES[dev - 1].replace(p);
+					ret = Some(0x3000_0000);
+					if (*frame).is_translated() {
+						ret = with_caller(frame, mepc, |process| {
+							let table = process.mmu_table.as_mut().unwrap();
+							// This is the same number of pages that setup_gpu_device
+							// allocated, so the last row of pixels (and the slack
+							// after it) is mapped too.
+							let num_pages = gpu::framebuffer_pages(p.get_width(), p.get_height());
+							for i in 0..num_pages {
+								let vaddr = 0x3000_0000 + (i << 12);
+								let paddr = ptr + (i << 12);
+								// Every mapped page holds a reference (see
+								// page::release_leaves), so the framebuffer stays
+								// around for the GPU when this process is gone. If
+								// it's already mapped, it already has ours.
+								if virt_to_phys(table, vaddr) != Some(paddr) {
+									incref(paddr as *mut u8);
+								}
+								map(table, vaddr, paddr, bits, 0);
+							}
+							asid_changed((*frame).pid);
+							0x3000_0000
+						});
 					}
-					(*frame).regs[Registers::A0 as usize] = 0x3000_0000;
 				}
 			}
+			if let Some(ret) = ret {
+				(*frame).regs[Registers::A0 as usize] = ret;
+			}
 		}
 		1001 => {
 			// transfer rectangle and invalidate
@@ -304,121 +837,360 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 			let y = (*frame).regs[Registers::A2 as usize] as u32;
 			let width = (*frame).regs[Registers::A3 as usize] as u32;
 			let height = (*frame).regs[Registers::A4 as usize] as u32;
-			gpu::transfer(dev, x, y, width, height);
+			// Same device numbers as get framebuffer.
+			(*frame).regs[Registers::A0 as usize] = match gpu::resolve(dev) {
+				Some(dev) => {
+					gpu::transfer(dev, x, y, width, height);
+					0
+				},
+				None => SysError::ENODEV.to_ret(),
+			};
 		}
 		1002 => {
 			// wait for keyboard events
 			let mut ev = KEY_EVENTS.take().unwrap();
-			let max_events = (*frame).regs[Registers::A1 as usize];
-			let vaddr = (*frame).regs[Registers::A0 as usize] as *const Event;
-			if (*frame).satp >> 60 != 0 {
-				let process = get_by_pid((*frame).pid as u16);
-				let table = (*process).mmu_table.as_mut().unwrap();
-				(*frame).regs[Registers::A0 as usize] = 0;
-				let num_events = if max_events <= ev.len() {
-					max_events
-				}
-				else {
-					ev.len()
-				};
-				for i in 0..num_events {
-					let paddr = virt_to_phys(table, vaddr.add(i) as usize);
-					if paddr.is_none() {
-						break;
-					}
-					let paddr = paddr.unwrap() as *mut Event;
-					*paddr = ev.pop_front().unwrap();
-					(*frame).regs[Registers::A0 as usize] += 1;
-				}
-			}
+			give_events(frame, mepc, &mut ev);
 			KEY_EVENTS.replace(ev);
 		}
 		1004 => {
 			// wait for abs events
 			let mut ev = ABS_EVENTS.take().unwrap();
-			let max_events = (*frame).regs[Registers::A1 as usize];
-			let vaddr = (*frame).regs[Registers::A0 as usize] as *const Event;
-			if (*frame).satp >> 60 != 0 {
-				let process = get_by_pid((*frame).pid as u16);
-				let table = ((*process).mmu_table as *mut Table).as_mut().unwrap();
-				(*frame).regs[Registers::A0 as usize] = 0;
-				for i in 0..if max_events <= ev.len() {
-					max_events
-				}
-				else {
-					ev.len()
-				} {
-					let paddr = virt_to_phys(table, vaddr.add(i) as usize);
-					if paddr.is_none() {
-						break;
-					}
-					let paddr = paddr.unwrap() as *mut Event;
-					*paddr = ev.pop_front().unwrap();
-					(*frame).regs[Registers::A0 as usize] += 1;
+			give_events(frame, mepc, &mut ev);
+			ABS_EVENTS.replace(ev);
+		}
+		1007 => {
+			// wait for rel events
+			// These are the raw deltas. 1008 gives where they've taken the pointer.
+			let mut ev = REL_EVENTS.take().unwrap();
+			give_events(frame, mepc, &mut ev);
+			REL_EVENTS.replace(ev);
+		}
+		1008 => {
+			// get pointer
+			// Returns x in the upper 32 bits and y in the lower 32 bits.
+			let (x, y) = input::pointer();
+			(*frame).regs[Registers::A0 as usize] = (x as usize) << 32 | y as usize;
+		}
+		1009 => {
+			// wait for display event
+			// syscall_wait_display(device, timeout)
+			// The timeout is in mtime ticks, or 0 for one frame. Returns 1 if
+			// the display signalled us, 0 if we timed out.
+			let dev = (*frame).regs[Registers::A0 as usize];
+			let timeout = match (*frame).regs[Registers::A1 as usize] {
+				0 => gpu::FRAME_TIME,
+				t => t,
+			};
+			match gpu::resolve(dev) {
+				Some(dev) => {
+					(*frame).regs[Registers::A0 as usize] = 0;
+					gpu::wait_for_display_event(dev, (*frame).pid as u16, timeout);
 				}
+				None => (*frame).regs[Registers::A0 as usize] = SysError::ENODEV.to_ret(),
 			}
-			ABS_EVENTS.replace(ev);
 		}
-		1024 => {
-			// #define SYS_open 1024
-			let mut path = (*frame).regs[gp(Registers::A0)];
-			let _perm = (*frame).regs[gp(Registers::A1)];
-			let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
-			if (*frame).satp >> 60 != 0 {
-				let table = process.mmu_table.as_mut().unwrap();
-				let paddr = virt_to_phys(table, path);
-				if paddr.is_none() {
-					(*frame).regs[gp(Registers::A0)] = -1isize as usize;
-					return;
+		1010 => {
+			// get framebuffer format
+			// syscall_get_fb_format(device)
+			// The framebuffer from 1000 is 4 bytes a pixel, but the host
+			// decides whether red or blue comes first. This returns
+			// FB_FORMAT_RGBA or FB_FORMAT_BGRA so a program can lay its
+			// pixels out to match, or ENODEV if it isn't a GPU.
+			let dev = (*frame).regs[Registers::A0 as usize];
+			(*frame).regs[Registers::A0 as usize] = SysError::ENODEV.to_ret();
+			if let Some(dev) = gpu::resolve(dev) {
+				if let Some(p) = gpu::GPU_DEVICES[dev - 1].lock().as_ref() {
+					(*frame).regs[Registers::A0 as usize] = if p.is_bgra() { FB_FORMAT_BGRA } else { FB_FORMAT_RGBA };
 				}
-				path = paddr.unwrap();
 			}
-			let path_ptr = path as *const u8;
-			let mut str_path = String::new();
-			for i in 0..256 {
-				let c = path_ptr.add(i).read();
-				if c == 0 {
-					break;
+		}
+		1005 => {
+			// play pcm
+			// syscall_play_pcm(samples, num_samples, rate)
+			// This does nothing if there is no sound device. num_samples is up
+			// to the user, so it's capped before we allocate anything for it.
+			let vaddr = (*frame).regs[Registers::A0 as usize] as *const i16;
+			let num_samples = (*frame).regs[Registers::A1 as usize].min(sound::MAX_PCM_SAMPLES);
+			let rate = (*frame).regs[Registers::A2 as usize] as u32;
+			let mut samples = Vec::with_capacity(num_samples);
+			let copied = with_caller(frame, mepc, |process| {
+				if let Some(table) = user_table(frame, process) {
+					for i in 0..num_samples {
+						match user_readable(table, vaddr.add(i) as usize) {
+							Some(paddr) => samples.push((paddr as *const i16).read_unaligned()),
+							None => break,
+						}
+					}
 				}
-				str_path.push(c as char);
+			});
+			if copied.is_none() {
+				return;
 			}
-			// Allocate a blank file descriptor
-			let mut max_fd = 2;
-			for k in process.data.fdesc.keys() {
-				if *k > max_fd {
-					max_fd = *k;
+			(*frame).regs[Registers::A0 as usize] = sound::play_pcm(&samples, rate) as usize;
+		}
+		1006 => {
+			// Set the context switch frequency
+			// A0 = switches per second
+			// Only root can change how everyone gets scheduled.
+			let hz = (*frame).regs[gp(Registers::A0)];
+			let uid = match with_caller(frame, mepc, |process| process.data.uid) {
+				Some(uid) => uid,
+				None => return,
+			};
+			(*frame).regs[gp(Registers::A0)] = if uid != 0 {
+				SysError::EPERM.to_ret()
+			}
+			else if hz <= u32::MAX as usize && set_context_switch_hz(hz as u32) {
+				0
+			}
+			else {
+				SysError::EINVAL.to_ret()
+			};
+		}
+		278 => {
+			// #define SYS_getrandom 278
+			// ssize_t getrandom(void *buf, size_t buflen, unsigned int flags)
+			// We never block, so flags doesn't matter. Like Linux, we may
+			// give back fewer bytes than asked for, here up to a page.
+			let buf = (*frame).regs[gp(Registers::A0)];
+			let len = (*frame).regs[gp(Registers::A1)].min(PAGE_SIZE);
+			let mut bytes = vec![0u8; len];
+			rng::fill(&mut bytes);
+			let ret = with_caller(frame, mepc, |process| {
+				if copy_to_user(user_table(frame, process), buf as *mut u8, &bytes) == len {
+					len
+				}
+				else {
+					SysError::EFAULT.to_ret()
 				}
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
 			}
-			max_fd += 1;
-			match str_path.as_str() {
-				"/dev/fb" => {
-					// framebuffer
-					process.data.fdesc.insert(max_fd, Descriptor::Framebuffer);
+		}
+		1024 => {
+			// #define SYS_open 1024
+			// A0 = path, A1 = flags, A2 = mode (only used for O_CREAT)
+			let path = (*frame).regs[gp(Registers::A0)];
+			let flags = (*frame).regs[gp(Registers::A1)];
+			let perm = (*frame).regs[gp(Registers::A2)];
+			let ret = with_caller(frame, mepc, |process| {
+				let mut path = path;
+				if let Some(table) = user_table(frame, process) {
+					let paddr = user_readable(table, path);
+					if paddr.is_none() {
+						return SysError::EFAULT.to_ret();
+					}
+					path = paddr.unwrap();
 				}
-				"/dev/butev" => {
-					process.data.fdesc.insert(max_fd, Descriptor::ButtonEvents);
+				let path_ptr = path as *const u8;
+				let mut str_path = String::new();
+				for i in 0..256 {
+					let c = path_ptr.add(i).read();
+					if c == 0 {
+						break;
+					}
+					str_path.push(c as char);
 				}
-				"/dev/absev" => {
-					process.data.fdesc.insert(max_fd, Descriptor::AbsoluteEvents);
+				// Allocate a blank file descriptor
+				let mut max_fd = 2;
+				for k in process.data.fdesc.keys() {
+					if *k > max_fd {
+						max_fd = *k;
+					}
 				}
-				_ => {
-					let res = fs::MinixFileSystem::open(8, &str_path);
-					if res.is_err() {
-						(*frame).regs[gp(Registers::A0)] = -1isize as usize;
-						return;
+				max_fd += 1;
+				// The system only has so many open files to go around. Every
+				// failure below has to give this back.
+				if !reserve_open_file() {
+					return SysError::ENFILE.to_ret();
+				}
+				match str_path.as_str() {
+					"/dev/fb" => {
+						// framebuffer
+						process.data.fdesc.insert(max_fd, Descriptor::Framebuffer);
 					}
-					else {
-						let inode = res.ok().unwrap();
-						process.data.fdesc.insert(max_fd, Descriptor::File(inode));
+					"/dev/butev" => {
+						process.data.fdesc.insert(max_fd, Descriptor::ButtonEvents);
+					}
+					"/dev/absev" => {
+						process.data.fdesc.insert(max_fd, Descriptor::AbsoluteEvents);
+					}
+					_ if str_path.starts_with("/dev/block") => {
+						// The whole disk, not the file system on it.
+						match block::device_path(&str_path) {
+							Some(dev) => {
+								process.data.fdesc.insert(max_fd, Descriptor::Block(block::BlockFile { dev, offset: 0 }));
+							}
+							None => {
+								release_open_file();
+								return SysError::ENOENT.to_ret();
+							}
+						}
+					}
+					_ if ramfs::is_ram_path(&str_path) => {
+						if flags & O_CREAT != 0 {
+							let mode = fs::MinixFileSystem::create_mode(perm as u16, process.data.umask);
+							// It's fine if it's already there.
+							let _ = ramfs::create(&str_path, mode);
+						}
+						match ramfs::open(&str_path) {
+							Ok(rf) => {
+								process.data.fdesc.insert(max_fd, Descriptor::Ram(rf));
+							}
+							Err(e) => {
+								release_open_file();
+								return SysError::from(e).to_ret();
+							}
+						}
+					}
+					_ if procfs::is_proc_path(&str_path) => {
+						match procfs::open(&str_path) {
+							Some(pf) => {
+								process.data.fdesc.insert(max_fd, Descriptor::Proc(pf));
+							}
+							None => {
+								release_open_file();
+								return SysError::ENOENT.to_ret();
+							}
+						}
+					}
+					_ => {
+						match fs::MinixFileSystem::open(8, &str_path) {
+							Ok(inode) => {
+								process.data.fdesc.insert(max_fd, Descriptor::File(fs::MinixFile { bdev: 8, inode, offset: 0 }));
+							}
+							Err(e) => {
+								release_open_file();
+								return SysError::from(e).to_ret();
+							}
+						}
+					}
+				}
+				max_fd as usize
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
+			}
+		}
+		1026 | 1030 => {
+			// #define SYS_unlink 1026
+			// #define SYS_mkdir 1030
+			// A0 = path, A1 = mode for mkdir
+			// Only the ramfs can be changed right now, the Minix file system
+			// can't write yet.
+			let path = (*frame).regs[gp(Registers::A0)];
+			let mode = (*frame).regs[gp(Registers::A1)] as u16;
+			let ret = with_caller(frame, mepc, |process| {
+				let ret = match user_path(user_table(frame, process), path) {
+					Ok(path) if ramfs::is_ram_path(&path) => {
+						if syscall_number == 1026 {
+							ramfs::unlink(&path)
+						}
+						else {
+							ramfs::mkdir(&path, fs::MinixFileSystem::mkdir_mode(mode, process.data.umask))
+						}
 					}
+					Ok(_) => Err(fs::FsError::ReadOnly),
+					Err(e) => return e.to_ret(),
+				};
+				match ret {
+					Ok(_) => 0,
+					Err(e) => SysError::from(e).to_ret(),
 				}
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
+			}
+		}
+		1028 | 1029 => {
+			// #define SYS_chmod 1028
+			// #define SYS_chown 1029
+			// int chmod(const char *path, mode_t mode)
+			// int chown(const char *path, uid_t owner, gid_t group)
+			let pid = (*frame).pid as u16;
+			let path = (*frame).regs[gp(Registers::A0)];
+			// add_kernel_worker() takes the process list mutex, so we get
+			// everything we need from the caller up front.
+			let (path, uid) = match with_caller(frame, mepc, |process| {
+				user_path(user_table(frame, process), path).map(|path| (path, process.data.uid))
+			}) {
+				Some(Ok(ret)) => ret,
+				Some(Err(e)) => {
+					(*frame).regs[gp(Registers::A0)] = e.to_ret();
+					return;
+				}
+				None => return,
+			};
+			let chown = syscall_number == 1029;
+			let arg1 = (*frame).regs[gp(Registers::A1)] as u16;
+			let arg2 = (*frame).regs[gp(Registers::A2)] as u16;
+			if ramfs::is_ram_path(&path) {
+				// Nobody can own anything in the ramfs but root.
+				let ret = if chown { Err(fs::FsError::Permission) } else { ramfs::chmod(&path, arg1, uid) };
+				(*frame).regs[gp(Registers::A0)] = match ret {
+					Ok(_) => 0,
+					Err(e) => SysError::from(e).to_ret(),
+				};
+			}
+			else if procfs::is_proc_path(&path) {
+				(*frame).regs[gp(Registers::A0)] = SysError::EROFS.to_ret();
+			}
+			else {
+				// The inode has to be read and written back, which sleeps, so a
+				// kernel process does it and wakes us up with the result.
+				let args = Box::new(AttrArgs { path,
+				                               chown,
+				                               arg1,
+				                               arg2,
+				                               uid });
+				set_waiting(pid);
+				add_kernel_worker(attr_func, Box::into_raw(args) as usize, "chattr", Completion::Wake(pid));
 			}
-			(*frame).regs[gp(Registers::A0)] = max_fd as usize;
 		}
 		1062 => {
 			// gettime
 			(*frame).regs[Registers::A0 as usize] = crate::cpu::get_mtime();
 		}
+		2011 => {
+			// #define SYS_getmainvars 2011
+			// long getmainvars(long *buf, size_t limit)
+			// This is the libgloss layout: argc, then argc pointers and a
+			// null, then the strings. The pointers point into buf, so the
+			// caller doesn't need anything but this one buffer.
+			let buf = (*frame).regs[gp(Registers::A0)];
+			let limit = (*frame).regs[gp(Registers::A1)];
+			let ret = with_caller(frame, mepc, |process| {
+				let argv = &process.data.argv;
+				let ptrs_len = (argv.len() + 2) * 8;
+				let size = ptrs_len + argv.iter().map(|arg| arg.len() + 1).sum::<usize>();
+				// libgloss just wants -1 here, not an errno.
+				if size > limit {
+					return -1isize as usize;
+				}
+				let mut out = Vec::with_capacity(size);
+				out.extend_from_slice(&argv.len().to_le_bytes());
+				let mut str_addr = buf + ptrs_len;
+				for arg in argv.iter() {
+					out.extend_from_slice(&str_addr.to_le_bytes());
+					str_addr += arg.len() + 1;
+				}
+				out.extend_from_slice(&0usize.to_le_bytes());
+				for arg in argv.iter() {
+					out.extend_from_slice(arg.as_bytes());
+					out.push(0);
+				}
+				if copy_to_user(user_table(frame, process), buf as *mut u8, &out) == out.len() {
+					0
+				}
+				else {
+					SysError::EFAULT.to_ret()
+				}
+			});
+			if let Some(ret) = ret {
+				(*frame).regs[gp(Registers::A0)] = ret;
+			}
+		}
 		_ => {
 			println!("Unknown syscall number {}", syscall_number);
 		}
@@ -437,8 +1209,8 @@ pub fn syscall_yield() {
 	let _ = do_make_syscall(1, 0, 0, 0, 0, 0, 0);
 }
 
-pub fn syscall_exit() {
-	let _ = do_make_syscall(93, 0, 0, 0, 0, 0, 0);
+pub fn syscall_exit(code: usize) {
+	let _ = do_make_syscall(93, code, 0, 0, 0, 0, 0);
 }
 
 pub fn syscall_execv(path: *const u8, argv: usize) -> usize {
@@ -453,6 +1225,15 @@ pub fn syscall_block_read(dev: usize, buffer: *mut u8, size: u32, offset: u32) -
 	do_make_syscall(180, dev, buffer as usize, size as usize, offset as usize, 0, 0) as u8
 }
 
+/// The size and offset have to be whole sectors.
+pub fn syscall_block_write(dev: usize, buffer: *const u8, size: u32, offset: u32) -> u8 {
+	do_make_syscall(181, dev, buffer as usize, size as usize, offset as usize, 0, 0) as u8
+}
+
+pub fn syscall_read(fd: u16, buffer: *mut u8, size: usize) -> usize {
+	do_make_syscall(63, fd as usize, buffer as usize, size, 0, 0, 0)
+}
+
 pub fn syscall_sleep(duration: usize) {
 	let _ = do_make_syscall(10, duration, 0, 0, 0, 0, 0);
 }
@@ -461,33 +1242,418 @@ pub fn syscall_get_pid() -> u16 {
 	do_make_syscall(172, 0, 0, 0, 0, 0, 0) as u16
 }
 
+/// A process' page table, along with where its heap is. The kernel copies
+/// straight into and out of a process' memory, and it doesn't take page
+/// faults for the process when it does, so user_writable() and
+/// user_readable() have to map untouched heap pages themselves.
+#[derive(Clone, Copy)]
+pub struct UserTable<'a> {
+	table: &'a Table,
+	heap:  usize,
+	brk:   usize,
+	pid:   u16,
+}
+
+impl UserTable<'_> {
+	/// walk_detailed(), except that an untouched heap page gets mapped
+	/// first, just like the process' own access would have done.
+	fn walk(&self, vaddr: usize) -> Option<(usize, Pte, usize)> {
+		let walked = walk_detailed(self.table, vaddr);
+		if walked.is_none()
+		   && map_heap_page(self.table as *const Table as *mut Table, self.heap, self.brk, self.pid, vaddr)
+		{
+			return walk_detailed(self.table, vaddr);
+		}
+		walked
+	}
+}
+
+/// with_process() on whoever made the system call in frame. They're the
+/// process that's running, so they're certainly on the list, and if we
+/// get None, it's only because someone else has the process list mutex.
+/// Then, like a page fault that can't get the list, we back the caller up
+/// to its ecall, so that it makes the call again the next time it's
+/// scheduled. A None leaves the caller's registers alone, so don't put
+/// anything in A0 for it.
+unsafe fn with_caller<R, F>(frame: *mut TrapFrame, mepc: usize, f: F) -> Option<R>
+	where F: FnOnce(&mut Process) -> R
+{
+	let ret = with_process((*frame).pid as u16, f);
+	if ret.is_none() {
+		(*frame).pc = mepc;
+	}
+	ret
+}
+
+/// The event system calls (1002, 1004, 1007). Copy up to A1 events off of
+/// ev into the caller's array at A0, and put how many we copied in A0. If
+/// the process list is busy, we leave ev alone and the caller tries again.
+unsafe fn give_events(frame: *mut TrapFrame, mepc: usize, ev: &mut VecDeque<Event>) {
+	let max_events = (*frame).regs[Registers::A1 as usize];
+	let vaddr = (*frame).regs[Registers::A0 as usize] as *const Event;
+	let ret = with_caller(frame, mepc, |process| {
+		user_table(frame, process).map(|table| {
+			let mut copied = 0;
+			for i in 0..max_events.min(ev.len()) {
+				let paddr = match user_writable(table, vaddr.add(i) as usize) {
+					Some(paddr) => paddr as *mut Event,
+					None => break,
+				};
+				*paddr = ev.pop_front().unwrap();
+				copied += 1;
+			}
+			copied
+		})
+	});
+	if let Some(Some(copied)) = ret {
+		(*frame).regs[Registers::A0 as usize] = copied;
+	}
+}
+
+/// The page table to translate a process' addresses through, or None if
+/// its addresses are already physical.
+pub unsafe fn user_table<'a>(frame: *const TrapFrame, process: &Process) -> Option<UserTable<'a>> {
+	if (*frame).is_translated() {
+		process.mmu_table.as_ref().map(|table| UserTable { table,
+		                                                   heap: process.heap,
+		                                                   brk: process.brk,
+		                                                   pid: process.pid })
+	}
+	else {
+		None
+	}
+}
+
+/// Translate an address in a user's buffer that we're about to write to.
+/// virt_to_phys() only says that something is mapped there. It also has to
+/// be a user page that the process could write itself, otherwise a read()
+/// into the process' own code would write right over it.
+pub fn user_writable(table: UserTable, vaddr: usize) -> Option<usize> {
+	// A copy-on-write page isn't writable until it's been copied, so we
+	// copy it now, just like the process' own store would have.
+	if let Some((_, pte, _)) = table.walk(vaddr) {
+		if pte.is_cow() && unsafe { resolve_cow(table.table as *const Table as *mut Table, vaddr) } {
+			// We don't know whose table this is, so everyone fences.
+			asid_all_changed();
+		}
+	}
+	match walk_detailed(table.table, vaddr) {
+		Some((paddr, pte, _)) if pte.user() && pte.writable() => Some(paddr),
+		_ => None,
+	}
+}
+
+/// The same as user_writable(), but for reading the user's memory.
+pub fn user_readable(table: UserTable, vaddr: usize) -> Option<usize> {
+	match table.walk(vaddr) {
+		Some((paddr, pte, _)) if pte.user() && pte.readable() => Some(paddr),
+		_ => None,
+	}
+}
+
+/// Copy data into a user's buffer a byte at a time. If table is Some, each
+/// address is translated on its own, since the buffer might cross pages.
+/// Returns how many bytes made it.
+pub unsafe fn copy_to_user(table: Option<UserTable>, buf: *mut u8, data: &[u8]) -> usize {
+	for (i, b) in data.iter().enumerate() {
+		let mut addr = buf.add(i) as usize;
+		if let Some(table) = table {
+			match user_writable(table, addr) {
+				Some(paddr) => addr = paddr,
+				None => return i,
+			}
+		}
+		(addr as *mut u8).write(*b);
+	}
+	data.len()
+}
+
+/// The other direction of copy_to_user().
+unsafe fn copy_from_user(table: Option<UserTable>, buf: *const u8, data: &mut [u8]) -> usize {
+	for (i, b) in data.iter_mut().enumerate() {
+		let mut addr = buf.add(i) as usize;
+		if let Some(table) = table {
+			match user_readable(table, addr) {
+				Some(paddr) => addr = paddr,
+				None => return i,
+			}
+		}
+		*b = (addr as *const u8).read();
+	}
+	data.len()
+}
+
+/// Go through the user's buffer at buf a run at a time. A run ends at a page
+/// boundary, since pages that are next to each other in virtual memory might
+/// not be in physical memory, so we only translate once per page. f gets
+/// each run and says how much of it it used, and if that's not all of it,
+/// we stop. We give back how many bytes f used, or Err with that many if we
+/// ran into a page that isn't mapped.
+unsafe fn for_user_runs<F>(table: Option<UserTable>, buf: *const u8, size: usize, mut f: F) -> Result<usize, usize>
+	where F: FnMut(&[u8]) -> usize
+{
+	let mut done = 0;
+	while done < size {
+		let vaddr = buf as usize + done;
+		let run = (PAGE_SIZE - vaddr % PAGE_SIZE).min(size - done);
+		let paddr = match table {
+			Some(table) => match user_readable(table, vaddr) {
+				Some(paddr) => paddr,
+				None => return Err(done),
+			},
+			None => vaddr,
+		};
+		let used = f(core::slice::from_raw_parts(paddr as *const u8, run));
+		done += used;
+		if used < run {
+			break;
+		}
+	}
+	Ok(done)
+}
+
+/// Copy a NUL-terminated string out of user memory. If table is Some, the
+/// address is virtual, and we translate it again every time the string
+/// crosses into a new page. We give up with E2BIG if there's no NUL in the
+/// first max bytes (including the NUL), and with EFAULT if we run into a
+/// page that isn't mapped.
+unsafe fn user_string(table: Option<UserTable>, addr: usize, max: usize) -> Result<String, SysError> {
+	let mut ret = String::new();
+	let mut bytes = null::<u8>();
+	// I really have to figure out how to change an array of bytes
+	// to a string. For now, this is very C-style and mimics strcpy.
+	for iterator in 0..max {
+		let vaddr = addr + iterator;
+		if iterator == 0 || vaddr.is_multiple_of(PAGE_SIZE) {
+			bytes = match table {
+				Some(table) => user_readable(table, vaddr).ok_or(SysError::EFAULT)?,
+				None => vaddr,
+			} as *const u8;
+		}
+		let ch = *bytes;
+		if ch == 0 {
+			return Ok(ret);
+		}
+		ret.push(ch as char);
+		bytes = bytes.add(1);
+	}
+	Err(SysError::E2BIG)
+}
+
+/// A path is just a user string, but too long is a different error.
+unsafe fn user_path(table: Option<UserTable>, addr: usize) -> Result<String, SysError> {
+	match user_string(table, addr, PATH_MAX) {
+		Err(SysError::E2BIG) => Err(SysError::ENAMETOOLONG),
+		other => other,
+	}
+}
+
+/// Copy a NULL-terminated argv out of user memory. A NULL argv is allowed,
+/// and then the program just gets its path as argv[0]. Every pointer goes
+/// through the MMU just like the strings do. If there are more than
+/// MAX_ARGS entries, or the whole thing won't fit in ARG_MAX, we stop right
+/// there with E2BIG rather than copy any more of it.
+unsafe fn user_argv(table: Option<UserTable>, addr: usize, path: &str) -> Result<Vec<String>, SysError> {
+	let mut argv = Vec::new();
+	if addr == 0 {
+		argv.push(String::from(path));
+	}
+	else {
+		if !addr.is_multiple_of(size_of::<usize>()) {
+			return Err(SysError::EFAULT);
+		}
+		// The NULL at the end of the pointer array.
+		let mut size = size_of::<usize>();
+		let mut ptrs = null::<usize>();
+		let mut i = 0;
+		loop {
+			let vaddr = addr + i * size_of::<usize>();
+			if i == 0 || vaddr.is_multiple_of(PAGE_SIZE) {
+				ptrs = match table {
+					Some(table) => user_readable(table, vaddr).ok_or(SysError::EFAULT)?,
+					None => vaddr,
+				} as *const usize;
+			}
+			let arg = *ptrs;
+			if arg == 0 {
+				break;
+			}
+			if i == MAX_ARGS {
+				return Err(SysError::E2BIG);
+			}
+			// This argument's pointer, then whatever room is left for its string.
+			size += size_of::<usize>();
+			if size >= ARG_MAX {
+				return Err(SysError::E2BIG);
+			}
+			let arg = user_string(table, arg, ARG_MAX - size)?;
+			size += arg.len() + 1;
+			argv.push(arg);
+			ptrs = ptrs.add(1);
+			i += 1;
+		}
+	}
+	if argv_size(&argv) > ARG_MAX {
+		Err(SysError::E2BIG)
+	}
+	else {
+		Ok(argv)
+	}
+}
+
+/// How many bytes argv takes up on the stack: the pointer array with its
+/// NULL at the end, followed by each string with its NUL.
+fn argv_size(argv: &[String]) -> usize {
+	(argv.len() + 1) * 8 + argv.iter().map(|a| a.len() + 1).sum::<usize>()
+}
+
+/// Put argv into the top page of a fresh image's stack and point A0 and A1
+/// at it, so main(argc, argv) gets it. The initial stack pointer sits at
+/// the bottom of this page, so nothing else uses it.
+/// argv has to fit, see argv_size().
+pub unsafe fn push_argv(image: &mut Process, argv: &[String]) {
+	let page_offset = (STACK_PAGES - 1) * PAGE_SIZE;
+	let phys = image.stack.add(page_offset);
+	let virt = STACK_ADDR + page_offset;
+	let ptrs = phys as *mut usize;
+	let mut str_offset = (argv.len() + 1) * 8;
+	for (i, arg) in argv.iter().enumerate() {
+		ptrs.add(i).write(virt + str_offset);
+		memcpy(phys.add(str_offset), arg.as_ptr(), arg.len());
+		phys.add(str_offset + arg.len()).write(0);
+		str_offset += arg.len() + 1;
+	}
+	ptrs.add(argv.len()).write(0);
+	(*image.frame).regs[gp(Registers::A0)] = argv.len();
+	(*image.frame).regs[gp(Registers::A1)] = virt;
+}
+
+/// What execv hands over to exec_func. This comes from talloc(), so it
+/// can't own anything. Instead, the path and argv are packed into strings,
+/// a kmalloc()ed block of len bytes: the path, then each of the argc
+/// arguments, each followed by a NUL. None of them can have a NUL of their
+/// own, since user_string() stops at the first one.
+#[derive(Clone, Copy)]
+struct ExecArgs {
+	inode:   fs::Inode,
+	pid:     u16,
+	argc:    usize,
+	strings: *mut u8,
+	len:     usize,
+}
+
+impl ExecArgs {
+	/// Put everything exec_func needs on the heap. Returns None if there's
+	/// no room for it.
+	fn give(inode: fs::Inode, pid: u16, path: &str, argv: &[String]) -> Option<&'static mut ExecArgs> {
+		let len = path.len() + 1 + argv.iter().map(|arg| arg.len() + 1).sum::<usize>();
+		let strings = kmalloc(len);
+		if strings.is_null() {
+			return None;
+		}
+		let args = match talloc::<ExecArgs>() {
+			Some(args) => args,
+			None => {
+				kfree(strings);
+				return None;
+			}
+		};
+		let mut offset = 0;
+		for s in core::iter::once(path).chain(argv.iter().map(|arg| arg.as_str())) {
+			unsafe {
+				memcpy(strings.add(offset), s.as_ptr(), s.len());
+				strings.add(offset + s.len()).write(0);
+			}
+			offset += s.len() + 1;
+		}
+		*args = ExecArgs { inode,
+		                   pid,
+		                   argc: argv.len(),
+		                   strings,
+		                   len };
+		Some(args)
+	}
+
+	/// Take back what give() put on the heap at addr and free it. We get
+	/// the arguments, the path, and argv.
+	fn take(addr: usize) -> (ExecArgs, String, Vec<String>) {
+		let heap_args = unsafe { &mut *(addr as *mut ExecArgs) };
+		let args = *heap_args;
+		tfree(heap_args);
+		let bytes = unsafe { core::slice::from_raw_parts(args.strings, args.len) };
+		let mut strings = bytes.split(|c| *c == 0).map(|s| String::from_utf8_lossy(s).into_owned());
+		let path = strings.next().unwrap_or_default();
+		let argv = strings.take(args.argc).collect();
+		kfree(args.strings);
+		(args, path, argv)
+	}
+}
+
+/// What chmod and chown hand over to attr_func. For chmod, arg1 is the
+/// mode. For chown, arg1 and arg2 are the owner and group. uid is the
+/// caller's.
+struct AttrArgs {
+	path:  String,
+	chown: bool,
+	arg1:  u16,
+	arg2:  u16,
+	uid:   u16,
+}
+
+fn attr_func(args: usize) -> usize {
+	let args = unsafe { Box::from_raw(args as *mut AttrArgs) };
+	let ret = if args.chown {
+		fs::MinixFileSystem::chown(8, &args.path, args.arg1, args.arg2, args.uid)
+	}
+	else {
+		fs::MinixFileSystem::chmod(8, &args.path, args.arg1, args.uid)
+	};
+	match ret {
+		Ok(_) => 0,
+		Err(e) => SysError::from(e).to_ret(),
+	}
+}
+
 /// This is a helper function ran as a process in kernel space
 /// to finish loading and executing a process.
 fn exec_func(args: usize) {
 	unsafe {
-		// We got the inode from the syscall. Its Box rid itself of control, so
-		// we take control back here. The Box now owns the Inode and will complete
-		// freeing the heap memory allocated for it.
-		let inode = Box::from_raw(args as *mut fs::Inode);
+		// We got the arguments from the syscall. They're ours to free now, and
+		// take() does that once it has copied them out.
+		let (args, path, argv) = ExecArgs::take(args);
+		let inode = &args.inode;
 		let mut buffer = Buffer::new(inode.size as usize);
 		// This is why we need to be in a process context. The read() call may sleep as it
 		// waits for the block driver to return.
-		fs::MinixFileSystem::read(8, &inode, buffer.get_mut(), inode.size, 0);
-		// Now we have the data, so the following will load the ELF file and give us a process.
-		let proc = elf::File::load_proc(&buffer);
-		if proc.is_err() {
-			println!("Failed to launch process.");
-		}
-		else {
-			let process = proc.ok().unwrap();
-			// If we hold this lock, we can still be preempted, but the scheduler will
-			// return control to us. This required us to use try_lock in the scheduler.
-			PROCESS_LIST_MUTEX.sleep_lock();
-			if let Some(mut proc_list) = PROCESS_LIST.take() {
-				proc_list.push_back(process);
-				PROCESS_LIST.replace(proc_list);
-			}
-			PROCESS_LIST_MUTEX.unlock();
+		fs::MinixFileSystem::read(8, inode, buffer.get_mut(), inode.size, 0);
+		// Now we have the data, so the following will load the ELF file and give us a new
+		// image for the process that called execv.
+		match elf::File::load_image(&buffer, args.pid, &path) {
+			Ok(mut image) => {
+				push_argv(&mut image, &argv);
+				// If we hold this lock, we can still be preempted, but the scheduler will
+				// return control to us. This required us to use try_lock in the scheduler.
+				// It also means nobody can run the caller while we're swapping it out.
+				PROCESS_LIST_MUTEX.sleep_lock();
+				let process = get_by_pid(args.pid);
+				// The caller might have been killed while we were reading. If so, the
+				// image just drops here.
+				if !process.is_null() {
+					(*process).replace_image(image);
+					(*process).data.argv = argv;
+					(*process).state = ProcessState::Running;
+				}
+				PROCESS_LIST_MUTEX.unlock();
+			},
+			Err(_) => {
+				println!("Failed to launch process.");
+				// The caller is still around with its old image, so tell it.
+				let process = get_by_pid(args.pid);
+				if !process.is_null() {
+					(*(*process).frame).regs[gp(Registers::A0)] = SysError::ENOEXEC.to_ret();
+				}
+				set_running(args.pid);
+			},
 		}
 	}
 }
@@ -521,6 +1687,8 @@ fn exec_func(args: usize) {
 // #define SYS_mmap 222
 // #define SYS_link 1025
 // #define SYS_unlink 1026
+// #define SYS_chmod 1028
+// #define SYS_chown 1029
 // #define SYS_mkdir 1030
 // #define SYS_access 1033
 // #define SYS_stat 1038
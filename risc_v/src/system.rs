@@ -0,0 +1,71 @@
+// system.rs
+// Powering off and resetting the machine
+// Stephen Marz
+
+use crate::{block, syscall::syscall_sleep, uart};
+use core::arch::asm;
+
+// QEMU's virt machine has SiFive's test finisher here. Whatever we write
+// into it decides what QEMU does next.
+const TEST_FINISHER: *mut u32 = 0x10_0000 as *mut u32;
+// Exit QEMU with status 0.
+const FINISHER_PASS: u32 = 0x5555;
+// (0x3333 would exit with a failure, with the code in the top 16 bits.)
+// Reset the machine, which starts us over from the top of boot.S.
+const FINISHER_RESET: u32 = 0x7777;
+
+// How long shutdown() sleeps between looking at the block queues.
+const FLUSH_SLEEP: usize = 10_000;
+
+/// Turn QEMU off. QEMU exits with a status of 0.
+pub fn poweroff() -> ! {
+	finish(FINISHER_PASS)
+}
+
+/// Reset the machine.
+pub fn reboot() -> ! {
+	finish(FINISHER_RESET)
+}
+
+fn finish(value: u32) -> ! {
+	// Make sure the last thing we printed actually comes out.
+	uart::sync();
+	unsafe {
+		TEST_FINISHER.write_volatile(value);
+	}
+	// If there's no test finisher (not QEMU's virt), we end up here.
+	// There's nothing else to do, so park the hart.
+	loop {
+		unsafe {
+			asm!("wfi", options(nomem, nostack));
+		}
+	}
+}
+
+/// Wait for every block device to finish what it has queued. We don't
+/// cache dirty blocks, so once the queues are empty, everything a
+/// process wrote is on the disk. This sleeps, so only a kernel process
+/// can call it.
+pub fn flush() {
+	while (1..=8).any(|dev| match block::queue_depth(dev) {
+		                   Some((waiting, in_flight)) => waiting + in_flight > 0,
+		                   None => false,
+	                   })
+	{
+		syscall_sleep(FLUSH_SLEEP);
+	}
+}
+
+/// This is the kernel process the reboot system call starts. The argument
+/// is true to reset the machine and false to power it off.
+pub fn shutdown(restart: usize) {
+	flush();
+	if restart != 0 {
+		println!("Restarting...");
+		reboot();
+	}
+	else {
+		println!("Powering off...");
+		poweroff();
+	}
+}
@@ -1,13 +1,420 @@
 // test.rs
+use crate::buffer::Buffer;
 use crate::fs::MinixFileSystem;
+#[cfg(not(feature = "kernel-shell"))]
 use crate::syscall;
+#[cfg(feature = "mem-selftest")]
+use crate::{kmem, page, sched::PreemptGuard};
+#[cfg(feature = "mem-selftest")]
+use alloc::{boxed::Box, string::String, vec::Vec};
+#[cfg(feature = "mem-selftest")]
+use core::ptr::null_mut;
+
+// The Minix read self-test looks for this file. make_fixture.py in userspace
+// writes it, and upload.sh puts it on the disk. Byte i of the file is
+// fixture_byte(i). It's big enough to go past the 7 direct zones and into
+// the singly indirect zone.
+const FIXTURE_PATH: &str = "/fsfixture.bin";
+const FIXTURE_SIZE: u32 = 40_000;
+
+/// 251 is prime, so a block read from the wrong place won't happen to have
+/// the right bytes in it.
+fn fixture_byte(i: u32) -> u8 {
+	(i % 251) as u8
+}
+
+/// Print how a self-test went, as NAME: PASS or NAME: FAIL, so that a CI
+/// script can grep the UART output for it. We hand passed right back.
+fn report(name: &str, passed: bool) -> bool {
+	println!("{}: {}", name, if passed { "PASS" } else { "FAIL" });
+	passed
+}
+
+/// Fletcher-16 over a run of bytes.
+fn checksum<I: Iterator<Item = u8>>(bytes: I) -> u16 {
+	let mut a = 0u16;
+	let mut b = 0u16;
+	for byte in bytes {
+		a = (a + byte as u16) % 255;
+		b = (b + a) % 255;
+	}
+	b << 8 | a
+}
+
+/// Read the fixture in different sizes and at different offsets, and make
+/// sure what we get matches what's on the disk. This prints PASS or FAIL
+/// for each read so that a CI script can grep the UART output. It has to
+/// run in a process, since reading sleeps.
+pub fn fs_selftest(bdev: usize) -> bool {
+	let inode = match MinixFileSystem::open(bdev, FIXTURE_PATH) {
+		Ok(inode) => inode,
+		Err(_) => {
+			println!("FSTEST: SKIP, no {}", FIXTURE_PATH);
+			return true;
+		},
+	};
+	if inode.size != FIXTURE_SIZE {
+		println!("FSTEST: {} is {} bytes, expected {}", FIXTURE_PATH, inode.size, FIXTURE_SIZE);
+		return report("FSTEST", false);
+	}
+	// The 7 direct zones hold this many bytes. Reads around here cross from
+	// the direct zones into the indirect zone.
+	let direct_end = 7 * MinixFileSystem::zone_size(bdev);
+	// (offset, size)
+	let reads = [(0, FIXTURE_SIZE),
+	             (0, 1),
+	             (1023, 2),
+	             (100, 1024),
+	             (direct_end - 10, 20),
+	             (direct_end - 1024, 3000),
+	             (direct_end, 1024),
+	             (30_001, 512),
+	             (FIXTURE_SIZE - 7, 7)];
+	let mut passed = true;
+	for (offset, size) in reads.iter() {
+		let mut buffer = Buffer::new(*size as usize);
+		let bytes = MinixFileSystem::read(bdev, &inode, buffer.get_mut(), *size, *offset);
+		let expected = checksum((*offset..*offset + *size).map(fixture_byte));
+		let got = checksum(buffer[..bytes as usize].iter().copied());
+		if bytes == *size && got == expected {
+			println!("FSTEST: PASS offset {} size {}", offset, size);
+		}
+		else {
+			println!(
+			         "FSTEST: FAIL offset {} size {}: read {} bytes, checksum 0x{:04x}, expected 0x{:04x}",
+			         offset, size, bytes, got, expected
+			);
+			passed = false;
+		}
+	}
+	report("FSTEST", passed)
+}
+
+/// Write the root inode back with a different atime, read it back to see
+/// that it stuck, and then put the original back. The root inode shares its
+/// sector with other inodes, so we check that its neighbor didn't change.
+/// This writes to the disk, so it only runs with the inode-selftest feature.
+#[cfg(feature = "inode-selftest")]
+pub fn inode_selftest(bdev: usize) -> bool {
+	if crate::block::is_read_only(bdev) {
+		println!("INODETEST: SKIP, block device {} is read-only", bdev);
+		return true;
+	}
+	let (orig, neighbor) = match (MinixFileSystem::get_inode(bdev, 1), MinixFileSystem::get_inode(bdev, 2)) {
+		(Some(orig), Some(neighbor)) => (orig, neighbor),
+		_ => {
+			println!("INODETEST: can't read inodes 1 and 2");
+			return report("INODETEST", false);
+		},
+	};
+	let mut changed = orig;
+	changed.atime = orig.atime ^ 0x5a5a_5a5a;
+	let passed = match MinixFileSystem::put_inode(bdev, 1, &changed) {
+		Ok(_) => {
+			let back = MinixFileSystem::get_inode(bdev, 1).map(|i| i.atime);
+			let next = MinixFileSystem::get_inode(bdev, 2).map(|i| i.atime);
+			back == Some(changed.atime) && next == Some(neighbor.atime)
+		},
+		Err(_) => false,
+	};
+	if MinixFileSystem::put_inode(bdev, 1, &orig).is_err() {
+		println!("INODETEST: couldn't restore the root inode");
+		return report("INODETEST", false);
+	}
+	report("INODETEST", passed)
+}
+
+/// Allocate pages in a pattern that leaves holes, free them, and make sure
+/// the holes were joined back into runs and alloc() can give us the whole
+/// largest free run that's left in one piece. Then make sure an allocation
+/// with two owners is only freed by the second, and that the free list
+/// agrees with the Page structures all along.
+#[cfg(feature = "mem-selftest")]
+pub fn page_selftest() -> bool {
+	let _guard = PreemptGuard::new();
+	let mut pages = [null_mut(); 16];
+	for (i, p) in pages.iter_mut().enumerate() {
+		*p = page::alloc(1 + i % 2);
+	}
+	// Every other allocation first, so the free pages are in holes, and
+	// then the rest.
+	for p in pages.iter().step_by(2).chain(pages.iter().skip(1).step_by(2)) {
+		if !p.is_null() {
+			page::dealloc(*p);
+		}
+	}
+	// The free list should have joined every hole back together. Each
+	// allocation's pages have to be inside of one free run, and if the
+	// next allocation came right after it, it has to be in the same run.
+	// From a fresh heap, that's all 24 pages in one run from pages[0].
+	let mut passed = page::free_list_ok() && pages.iter().all(|p| !p.is_null());
+	let end = |i: usize| pages[i] as usize + (1 + i % 2) * page::PAGE_SIZE;
+	for (i, p) in pages.iter().enumerate() {
+		let run = page::free_run_containing(*p as usize);
+		passed &= match run {
+			Some((start, len)) => end(i) <= start + len * page::PAGE_SIZE,
+			None => false,
+		};
+		match pages.get(i + 1) {
+			Some(next) if end(i) == *next as usize => passed &= run == page::free_run_containing(*next as usize),
+			_ => {},
+		}
+	}
+	let run = page::largest_free_run();
+	let big = if run > 0 { page::alloc(run) } else { null_mut() };
+	passed &= !big.is_null();
+	if !big.is_null() {
+		// A second owner keeps the pages around after the first one
+		// lets go.
+		page::incref(big);
+		passed &= page::decref(big) == 1 && page::refcount(big) == 1;
+		page::dealloc(big);
+		passed &= page::largest_free_run() >= run;
+	}
+	// Freeing the middle of an allocation should be refused, and so
+	// should freeing it twice. Both print a complaint.
+	let two = page::alloc(2);
+	if !two.is_null() {
+		let second = unsafe { two.add(page::PAGE_SIZE) };
+		page::dealloc(second);
+		passed &= page::refcount(two) == 1 && page::refcount(second) == 1;
+		page::dealloc(two);
+		page::dealloc(two);
+		passed &= page::refcount(two) == 0 && page::refcount(second) == 0;
+	}
+	// Now leave holes, so the free list has runs in the middle of the
+	// heap, and then let go of a page from the middle of one allocation.
+	for (i, p) in pages.iter_mut().enumerate() {
+		*p = page::alloc(1 + i % 3);
+	}
+	for p in pages.iter().step_by(3) {
+		if !p.is_null() {
+			page::dealloc(*p);
+		}
+	}
+	passed &= page::free_list_ok();
+	if !pages[2].is_null() {
+		// pages[2] is 3 pages, and this splits it into two allocations of
+		// one page each.
+		page::decref(unsafe { pages[2].add(page::PAGE_SIZE) });
+		passed &= page::free_list_ok();
+		page::dealloc(pages[2]);
+		page::dealloc(unsafe { pages[2].add(2 * page::PAGE_SIZE) });
+	}
+	for (i, p) in pages.iter().enumerate() {
+		if i % 3 != 0 && i != 2 && !p.is_null() {
+			page::dealloc(*p);
+		}
+	}
+	passed &= page::free_list_ok();
+	let stats = page::stats();
+	passed &= stats.free + stats.used == stats.total && stats.free_runs > 0 && stats.largest_free_run <= stats.free;
+	println!("PAGETEST: largest free run {} pages", run);
+	report("PAGETEST", passed)
+}
+
+/// Map two pages into a table of our own, unmap one of them, and make sure
+/// only that one went away. The table never runs, so nothing is in the TLB.
+#[cfg(feature = "mem-selftest")]
+pub fn unmap_selftest() -> bool {
+	let _guard = PreemptGuard::new();
+	let table = page::zalloc(1) as *mut page::Table;
+	let mem = page::zalloc(2);
+	if table.is_null() || mem.is_null() {
+		println!("UNMAPTEST: out of memory");
+		return report("UNMAPTEST", false);
+	}
+	let vaddr = 0x2000_0000;
+	let paddr = mem as usize;
+	let table = unsafe { &mut *table };
+	for i in 0..2 {
+		page::map(table, vaddr + i * page::PAGE_SIZE, paddr + i * page::PAGE_SIZE, page::EntryBits::UserReadWrite.val(), 0);
+		// Each mapping holds a reference, just like a process' do.
+		page::incref(unsafe { mem.add(i * page::PAGE_SIZE) });
+	}
+	let mut passed = page::unmap_one(table, vaddr);
+	passed &= page::virt_to_phys(table, vaddr).is_none();
+	passed &= page::virt_to_phys(table, vaddr + page::PAGE_SIZE) == Some(paddr + page::PAGE_SIZE);
+	passed &= page::refcount(mem) == 1;
+	// There's nothing there anymore, so a second try doesn't do anything.
+	passed &= !page::unmap_one(table, vaddr);
+	page::release_leaves(table);
+	page::unmap(table);
+	page::dealloc(table as *mut page::Table as *mut u8);
+	page::dealloc(mem);
+	report("UNMAPTEST", passed)
+}
+
+/// Fill a kmalloc'd buffer with a pattern, krealloc it bigger and then
+/// smaller, and make sure the bytes that should have come along did. Then
+/// make sure kcalloc() won't let its multiply overflow and that
+/// kmalloc_aligned() lines up what it gives back, and that a double free
+/// is caught without touching the heap.
+#[cfg(feature = "mem-selftest")]
+pub fn kmem_selftest() -> bool {
+	let _guard = PreemptGuard::new();
+	let pattern = |i: usize| (i * 7 + 3) as u8;
+	let check = |ptr: *mut u8, len: usize| (0..len).all(|i| unsafe { *ptr.add(i) } == pattern(i));
+	let mut passed = true;
+	let mut ptr = kmem::kmalloc(100);
+	if !ptr.is_null() {
+		for i in 0..100 {
+			unsafe { *ptr.add(i) = pattern(i) };
+		}
+		// Something right after it means it can't grow in place, so
+		// this has to move it.
+		let blocker = kmem::kmalloc(8);
+		let grown = kmem::krealloc(ptr, 5000);
+		passed &= !grown.is_null() && check(grown, 100);
+		if !grown.is_null() {
+			ptr = grown;
+		}
+		let shrunk = kmem::krealloc(ptr, 40);
+		passed &= shrunk == ptr && check(shrunk, 40);
+		// What the shrink gave back is free now, so this grows in
+		// place.
+		let regrown = kmem::krealloc(shrunk, 64);
+		passed &= regrown == shrunk && check(regrown, 40);
+		kmem::kfree(regrown);
+		kmem::kfree(blocker);
+	}
+	else {
+		passed = false;
+	}
+	// This would be 2 * usize::MAX bytes, so it can't work, but the
+	// multiply wraps around to something that could.
+	passed &= kmem::kcalloc(usize::MAX, 2).is_null();
+	let zeroed = kmem::kcalloc(16, 8);
+	passed &= !zeroed.is_null() && (0..128).all(|i| unsafe { *zeroed.add(i) } == 0);
+	kmem::kfree(zeroed);
+	// Something in front of it makes it less likely that kmalloc()
+	// happens to give us 64-byte alignment anyway.
+	let before = kmem::kmalloc(8);
+	let aligned = kmem::kmalloc_aligned(100, 64);
+	passed &= !aligned.is_null() && (aligned as usize).is_multiple_of(64);
+	kmem::kfree_aligned(aligned);
+	kmem::kfree(before);
+	// Freeing twice should be caught and leave the heap alone. The
+	// chunk we free twice is held in place by taken chunks on both
+	// sides, so coalesce() can't merge its header away in between,
+	// and nobody else can be handed the memory.
+	let lo = kmem::kmalloc(8);
+	let victim = kmem::kmalloc(8);
+	let hi = kmem::kmalloc(8);
+	passed &= kmem::try_kfree(victim);
+	let (used, _, _) = kmem::usage();
+	passed &= !kmem::try_kfree(victim);
+	passed &= kmem::usage().0 == used;
+	kmem::kfree(hi);
+	kmem::kfree(lo);
+	report("KMEMTEST", passed)
+}
+
+/// Make some Box, Vec, and String allocations (through the global
+/// allocator) and make sure the kernel heap is back where it started once
+/// they're dropped.
+#[cfg(feature = "mem-selftest")]
+pub fn kmem_reclaim_selftest() -> bool {
+	let _guard = PreemptGuard::new();
+	let before = kmem::usage();
+	{
+		let boxed = Box::new([7u64; 32]);
+		let mut list: Vec<usize> = Vec::new();
+		for i in 0..500 {
+			list.push(i * boxed[i % 32] as usize);
+		}
+		let mut text = String::new();
+		for word in ["kernel", "heap", "reclaim"].iter() {
+			text.push_str(word);
+		}
+		// Make sure none of that is optimized away.
+		println!("KMEMRECLAIM: {} {} {}", boxed.len(), list.len(), text);
+	}
+	// The free chunks might not be joined back together the same way,
+	// but every byte that was taken has to have come back.
+	let (used_before, _, _) = before;
+	let (used_after, _, _) = kmem::usage();
+	if used_before != used_after {
+		println!("KMEMRECLAIM: {} bytes were used before and {} after", used_before, used_after);
+	}
+	report("KMEMRECLAIM", used_before == used_after)
+}
+
+/// Run the same mix of small (inode cache sized) and big (ELF buffer sized)
+/// allocations and frees under each kmalloc() strategy, and report the
+/// most free chunks and the smallest largest free chunk we saw along the
+/// way. This is for comparing them, so it only fails if an allocation does.
+#[cfg(feature = "mem-selftest")]
+pub fn kmem_fit_selftest() -> bool {
+	let _guard = PreemptGuard::new();
+	let original = kmem::fit_strategy();
+	let mut passed = true;
+	for strategy in [kmem::FitStrategy::FirstFit, kmem::FitStrategy::BestFit].iter() {
+		kmem::set_fit_strategy(*strategy);
+		let mut ptrs = [null_mut(); 48];
+		let mut peak_chunks = 0;
+		let mut worst_largest = usize::MAX;
+		let mut sample = || {
+			let (chunks, largest) = kmem::fragmentation();
+			peak_chunks = peak_chunks.max(chunks);
+			worst_largest = worst_largest.min(largest);
+		};
+		let size = |i: usize| if i.is_multiple_of(4) { 4096 + i * 97 % 4096 } else { 24 + i * 13 % 80 };
+		for (i, p) in ptrs.iter_mut().enumerate() {
+			*p = kmem::kmalloc(size(i));
+		}
+		sample();
+		// Free some of the small ones, then ask for big ones, which
+		// don't fit in the holes.
+		for p in ptrs.iter_mut().skip(1).step_by(3) {
+			kmem::kfree(*p);
+			*p = null_mut();
+		}
+		sample();
+		for p in ptrs.iter_mut().skip(1).step_by(3) {
+			*p = kmem::kmalloc(6000);
+		}
+		sample();
+		for p in ptrs.iter_mut().skip(2).step_by(2) {
+			kmem::kfree(*p);
+			*p = kmem::kmalloc(40);
+		}
+		sample();
+		passed &= ptrs.iter().all(|p| !p.is_null());
+		for p in ptrs.iter() {
+			kmem::kfree(*p);
+		}
+		println!("KMEMFIT: {:?}: at most {} free chunks, largest free chunk down to {} bytes", strategy, peak_chunks, worst_largest);
+	}
+	kmem::set_fit_strategy(original);
+	report("KMEMFIT", passed)
+}
+
 /// Test block will load raw binaries into memory to execute them. This function
 /// will load ELF files and try to execute them.
 pub fn test() {
 	// The majority of the testing code needs to move into a system call (execv maybe?)
-	MinixFileSystem::init(8);
-	let path = "/shell\0".as_bytes().as_ptr();
-	syscall::syscall_execv(path,0);
-	println!("I should never get here, execv should destroy our process.");
+	if MinixFileSystem::init(8).is_err() {
+		println!("Unable to mount the file system on block device 8.");
+		return;
+	}
+	fs_selftest(8);
+	#[cfg(feature = "inode-selftest")]
+	inode_selftest(8);
+	#[cfg(feature = "mem-selftest")]
+	{
+		page_selftest();
+		unmap_selftest();
+		kmem_selftest();
+		kmem_fit_selftest();
+		kmem_reclaim_selftest();
+	}
+	// With kernel-shell, init starts the kernel's shell, and it waits for
+	// the file system we just mounted. Otherwise, we become /shell.
+	#[cfg(not(feature = "kernel-shell"))]
+	{
+		let path = "/shell\0".as_bytes().as_ptr();
+		syscall::syscall_execv(path,0);
+		println!("I should never get here, execv should destroy our process.");
+	}
 }
-
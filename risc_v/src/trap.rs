@@ -3,13 +3,26 @@
 // Stephen Marz
 // 10 October 2019
 
-use crate::{cpu::{TrapFrame, CONTEXT_SWITCH_TIME},
+use crate::{block,
+            console,
+            cpu::{self, quantum_to_mtime, TrapFrame},
             plic,
-            process::delete_process,
+            process::{add_cpu_ticks, canary_ok, cow_fault, delete_process, demand_page, name_of, place_canary, print_mappings, stack_overflow, watchdog},
             rust_switch_to_user,
-            sched::schedule,
+            sched::{frame_quantum, preempt_disabled, sample_load, schedule},
             syscall::do_syscall};
 
+extern "C" {
+	// The bottom of the boot stack, which trap.S also uses for m_trap.
+	static KERNEL_STACK_START: usize;
+}
+
+/// Put a canary at the bottom of the trap stack. m_trap() checks it every
+/// time we take a trap. See process::STACK_CANARY.
+pub fn place_trap_stack_canary() {
+	place_canary(unsafe { KERNEL_STACK_START } as *mut u8);
+}
+
 #[no_mangle]
 /// The m_trap stands for "machine trap". Right now, we are handling
 /// all traps at machine mode. In this mode, we can figure out what's
@@ -19,21 +32,20 @@ extern "C" fn m_trap(epc: usize,
                      tval: usize,
                      cause: usize,
                      hart: usize,
-                     _status: usize,
+                     status: usize,
                      frame: *mut TrapFrame)
                      -> usize
 {
+	// If the trap stack overflowed last time, it ran down into the
+	// kernel's .bss, which sits right below it. Stop before we use any of it.
+	if !canary_ok(unsafe { KERNEL_STACK_START } as *const u8) {
+		let pid = if frame.is_null() { 0 } else { unsafe { (*frame).pid } };
+		stack_overflow(hart, pid);
+	}
 	// We're going to handle all traps in machine mode. RISC-V lets
 	// us delegate to supervisor mode, but switching out SATP (virtual memory)
 	// gets hairy.
-	let is_async = {
-		if cause >> 63 & 1 == 1 {
-			true
-		}
-		else {
-			false
-		}
-	};
+	let is_async = cause >> 63 & 1 == 1;
 	// The cause contains the type of trap (sync, async) as well as the cause
 	// number. So, here we narrow down just the cause number.
 	let cause_num = cause & 0xfff;
@@ -51,10 +63,25 @@ extern "C" fn m_trap(epc: usize,
 				// We would typically invoke the scheduler here to pick another
 				// process to run.
 				// Machine timer
-				let new_frame = schedule();
-				schedule_next_context_switch(1);
-				if new_frame != 0 {
-					rust_switch_to_user(new_frame);
+				// Whoever was running used up their whole quantum.
+				unsafe {
+					add_cpu_ticks((*frame).pid as u16, (*frame).qm);
+				}
+				sample_load();
+				watchdog();
+				console::retry_stdin();
+				block::retry_wakeups();
+				if preempt_disabled(hart) {
+					// We're in the middle of a critical section, so let this
+					// process keep going and try again next period.
+					schedule_next_context_switch(1);
+				}
+				else {
+					let new_frame = schedule();
+					schedule_next_context_switch(frame_quantum(new_frame));
+					if new_frame != 0 {
+						rust_switch_to_user(new_frame);
+					}
 				}
 			}
 			11 => {
@@ -74,16 +101,26 @@ extern "C" fn m_trap(epc: usize,
 	else {
 		// Synchronous trap
 		match cause_num {
+			2 if status >> 13 & 3 == 0 => {
+				// The FPU is off, which is how every process starts. If
+				// this was a floating point instruction, it's the first
+				// one since we switched to this process, so ask trap.S
+				// to load the fp registers out of the frame by marking
+				// them Dirty, then run the instruction again. If it
+				// wasn't a float, it'll come right back here with FS on
+				// and we'll take the arm below.
+				cpu::mstatus_write(cpu::mstatus_read() | 3 << 13);
+			}
 			2 => unsafe {
 				// Illegal instruction
-				println!("Illegal instruction CPU#{} -> 0x{:08x}: 0x{:08x}\n", hart, epc, tval);
+				println!("Illegal instruction CPU#{} PID {} ({}) -> 0x{:08x}: 0x{:08x}\n", hart, (*frame).pid, name_of((*frame).pid as u16), epc, tval);
 				// We need while trues here until we have a functioning "delete from scheduler"
 				// I use while true because Rust will warn us that it looks stupid.
 				// This is what I want so that I remember to remove this and replace
 				// them later.
 				delete_process((*frame).pid as u16);
 				let frame = schedule();
-				schedule_next_context_switch(1);
+				schedule_next_context_switch(frame_quantum(frame));
 				rust_switch_to_user(frame);
 			}
 			3 => {
@@ -92,10 +129,10 @@ extern "C" fn m_trap(epc: usize,
 				return_pc += 2;
 			}
 			7 => unsafe {
-				println!("Error with pid {}, at PC 0x{:08x}, mepc 0x{:08x}", (*frame).pid, (*frame).pc, epc);
+				println!("Error with pid {} ({}), at PC 0x{:08x}, mepc 0x{:08x}", (*frame).pid, name_of((*frame).pid as u16), (*frame).pc, epc);
 				delete_process((*frame).pid as u16);
 				let frame = schedule();
-				schedule_next_context_switch(1);
+				schedule_next_context_switch(frame_quantum(frame));
 				rust_switch_to_user(frame);
 			}
 			8 | 9 | 11 => unsafe {
@@ -103,32 +140,66 @@ extern "C" fn m_trap(epc: usize,
 				// println!("E-call from User mode! CPU#{} -> 0x{:08x}", hart, epc);
 				do_syscall(return_pc, frame);
 				let frame = schedule();
-				schedule_next_context_switch(1);
+				schedule_next_context_switch(frame_quantum(frame));
 				rust_switch_to_user(frame);
 			}
 			// Page faults
 			12 => unsafe {
 				// Instruction page fault
-				println!("Instruction page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
+				println!("Instruction page fault CPU#{} PID {} ({}) -> 0x{:08x}: 0x{:08x}", hart, (*frame).pid, name_of((*frame).pid as u16), epc, tval);
+				print_mappings((*frame).pid as u16);
 				delete_process((*frame).pid as u16);
 				let frame = schedule();
-				schedule_next_context_switch(1);
+				schedule_next_context_switch(frame_quantum(frame));
 				rust_switch_to_user(frame);
 			}
+			13 | 15 if unsafe { (*frame).is_translated() } => unsafe {
+				// Load or store page fault from a user process. A store
+				// might be to a copy-on-write page. Otherwise, this might
+				// just be the first touch of a heap page.
+				let pid = (*frame).pid as u16;
+				let fixed = match cause_num {
+					15 => match cow_fault(pid, tval) {
+						Some(false) => demand_page(pid, tval),
+						other => other,
+					},
+					_ => demand_page(pid, tval),
+				};
+				match fixed {
+					Some(true) => {},
+					None => {
+						// Someone has the process list. Let them finish
+						// and fault again when we come back here.
+						let frame = schedule();
+						schedule_next_context_switch(frame_quantum(frame));
+						rust_switch_to_user(frame);
+					}
+					Some(false) => {
+						println!("{} page fault CPU#{} PID {} ({}) -> 0x{:08x}: 0x{:08x}",
+						         if cause_num == 13 { "Load" } else { "Store" }, hart,
+						         (*frame).pid, name_of((*frame).pid as u16), epc, tval);
+						print_mappings((*frame).pid as u16);
+						delete_process((*frame).pid as u16);
+						let frame = schedule();
+						schedule_next_context_switch(frame_quantum(frame));
+						rust_switch_to_user(frame);
+					}
+				}
+			}
 			13 => unsafe {
 				// Load page fault
-				println!("Load page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
+				println!("Load page fault CPU#{} PID {} ({}) -> 0x{:08x}: 0x{:08x}", hart, (*frame).pid, name_of((*frame).pid as u16), epc, tval);
 				delete_process((*frame).pid as u16);
 				let frame = schedule();
-				schedule_next_context_switch(1);
+				schedule_next_context_switch(frame_quantum(frame));
 				rust_switch_to_user(frame);
 			}
 			15 => unsafe {
 				// Store page fault
-				println!("Store page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
+				println!("Store page fault CPU#{} PID {} ({}) -> 0x{:08x}: 0x{:08x}", hart, (*frame).pid, name_of((*frame).pid as u16), epc, tval);
 				delete_process((*frame).pid as u16);
 				let frame = schedule();
-				schedule_next_context_switch(1);
+				schedule_next_context_switch(frame_quantum(frame));
 				rust_switch_to_user(frame);
 			}
 			_ => {
@@ -146,8 +217,11 @@ extern "C" fn m_trap(epc: usize,
 pub const MMIO_MTIMECMP: *mut u64 = 0x0200_4000usize as *mut u64;
 pub const MMIO_MTIME: *const u64 = 0x0200_BFF8 as *const u64;
 
+/// Arm the timer for the next context switch. The qm is the quantum
+/// multiplier of the process we're about to run, so a process with a
+/// quantum of 3 will run for three CONTEXT_SWITCH_TIME periods.
 pub fn schedule_next_context_switch(qm: u16) {
 	unsafe {
-		MMIO_MTIMECMP.write_volatile(MMIO_MTIME.read_volatile().wrapping_add(CONTEXT_SWITCH_TIME * qm as u64));
+		MMIO_MTIMECMP.write_volatile(MMIO_MTIME.read_volatile().wrapping_add(quantum_to_mtime(qm)));
 	}
 }
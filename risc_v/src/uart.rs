@@ -2,29 +2,222 @@
 // UART routines and driver
 
 use core::{convert::TryInto,
-		   fmt::{Error, Write}};
-use crate::console::push_stdin;
+		   fmt::{Error, Write},
+		   sync::atomic::{AtomicBool, AtomicU8, Ordering}};
+use crate::{ansi::{AnsiDecoder, Key}, console::push_stdin, lock::SpinLock};
+
+/// Where the NS16550A is mapped. This is QEMU's virt machine. Another board
+/// puts it somewhere else (SiFive's U boards have it at 0x1001_0000), so
+/// porting only has to change this.
+pub const UART_BASE: usize = 0x1000_0000;
 
 pub struct Uart {
 	base_address: usize,
 }
 
+/// How many bytes the receive FIFO holds before the UART interrupts us.
+/// This goes into bits 6 and 7 of the FIFO control register (FCR). If
+/// fewer bytes than this come in and then the line goes quiet, the UART
+/// still interrupts us (a character timeout), so nothing gets stuck.
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum FifoTrigger {
+	One = 0b00 << 6,
+	Four = 0b01 << 6,
+	Eight = 0b10 << 6,
+	Fourteen = 0b11 << 6,
+}
+
+// FIFO control register (FCR) bits
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+// Interrupt enable register (IER) bits
+const IER_RX: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+
+// Modem control register (MCR) bits
+#[cfg(feature = "uart-selftest")]
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+// What loopback_test() sends to itself. Alternating bits, then all zeros and
+// all ones, so a stuck or crossed data line shows up.
+#[cfg(feature = "uart-selftest")]
+const LOOPBACK_PATTERN: [u8; 4] = [0x55, 0xaa, 0x00, 0xff];
+// How many times we look for a looped back byte before giving up on it.
+#[cfg(feature = "uart-selftest")]
+const LOOPBACK_SPINS: usize = 100_000;
+
+// Line control register (LCR) bits
+const LCR_DLAB: u8 = 1 << 7;
+
+/// What the line status register (LSR at offset 5) says. The error bits
+/// clear when the LSR is read, so read it once and ask this about it.
+#[derive(Clone, Copy)]
+pub struct LineStatus(u8);
+
+impl LineStatus {
+	const DATA_READY: u8 = 1 << 0;
+	const OVERRUN_ERROR: u8 = 1 << 1;
+	const PARITY_ERROR: u8 = 1 << 2;
+	const FRAMING_ERROR: u8 = 1 << 3;
+	const BREAK_INTERRUPT: u8 = 1 << 4;
+	const THR_EMPTY: u8 = 1 << 5;
+	const TRANSMITTER_EMPTY: u8 = 1 << 6;
+	const FIFO_ERROR: u8 = 1 << 7;
+
+	pub fn bits(&self) -> u8 {
+		self.0
+	}
+
+	/// There's at least one byte waiting in the receive FIFO.
+	pub fn data_ready(&self) -> bool {
+		self.0 & Self::DATA_READY != 0
+	}
+
+	/// A byte came in while the receive FIFO was full, so we lost it.
+	pub fn overrun_error(&self) -> bool {
+		self.0 & Self::OVERRUN_ERROR != 0
+	}
+
+	pub fn parity_error(&self) -> bool {
+		self.0 & Self::PARITY_ERROR != 0
+	}
+
+	/// A byte didn't end with a stop bit. This is usually the other end
+	/// using a different baud.
+	pub fn framing_error(&self) -> bool {
+		self.0 & Self::FRAMING_ERROR != 0
+	}
+
+	/// The line was held low for longer than a whole byte.
+	pub fn break_interrupt(&self) -> bool {
+		self.0 & Self::BREAK_INTERRUPT != 0
+	}
+
+	/// The transmit holding register (and FIFO) can take more.
+	pub fn thr_empty(&self) -> bool {
+		self.0 & Self::THR_EMPTY != 0
+	}
+
+	/// Everything we wrote has gone out on the wire.
+	pub fn transmitter_empty(&self) -> bool {
+		self.0 & Self::TRANSMITTER_EMPTY != 0
+	}
+
+	/// At least one byte in the receive FIFO has an error.
+	pub fn fifo_error(&self) -> bool {
+		self.0 & Self::FIFO_ERROR != 0
+	}
+
+	/// Did anything go wrong receiving? Breaks count, since they come
+	/// with a garbage 0 byte.
+	pub fn has_error(&self) -> bool {
+		self.0 & (Self::OVERRUN_ERROR | Self::PARITY_ERROR | Self::FRAMING_ERROR | Self::BREAK_INTERRUPT) != 0
+	}
+}
+
+// How many bytes the transmit FIFO holds. When THRE is set, the whole
+// FIFO is empty, so we can write this many without looking again.
+const TX_FIFO_SIZE: usize = 16;
+
+// The clock we set the divisor from in init(). QEMU doesn't care what
+// divisor we pick, so this is just the NS16550A's usual clock.
+const BASE_CLOCK: u32 = 22_729_000;
+const DEFAULT_BAUD: u32 = 2400;
+
+/// Why set_baud() couldn't set the signalling rate.
+#[derive(Debug)]
+pub enum BaudError {
+	/// The divisor would be 0: the clock is too slow for this baud.
+	TooFast,
+	/// The divisor doesn't fit in 16 bits: the clock is too fast for this
+	/// baud.
+	TooSlow,
+}
+
+// The FCR is write only, so we remember the trigger we gave it for
+// flush_fifo(). Every Uart we make is a new one, so this can't go in Uart.
+static FIFO_TRIGGER: AtomicU8 = AtomicU8::new(FifoTrigger::One as u8);
+
+/// Bytes on their way out of the console UART. put() adds to the back, and
+/// the THR empty interrupt takes from the front and puts them in the
+/// transmit FIFO. This way, printing a lot doesn't make us wait for the
+/// UART, unless we print so much that this fills up. This is a fixed
+/// array since we print long before we have a heap.
+struct TxQueue {
+	buffer: [u8; TX_QUEUE_SIZE],
+	head:   usize,
+	len:    usize,
+}
+
+const TX_QUEUE_SIZE: usize = 4096;
+
+impl TxQueue {
+	const fn new() -> Self {
+		Self { buffer: [0; TX_QUEUE_SIZE],
+		       head:   0,
+		       len:    0, }
+	}
+
+	fn push(&mut self, c: u8) -> bool {
+		if self.len == TX_QUEUE_SIZE {
+			return false;
+		}
+		self.buffer[(self.head + self.len) % TX_QUEUE_SIZE] = c;
+		self.len += 1;
+		true
+	}
+
+	fn pop(&mut self) -> Option<u8> {
+		if self.len == 0 {
+			return None;
+		}
+		let c = self.buffer[self.head];
+		self.head = (self.head + 1) % TX_QUEUE_SIZE;
+		self.len -= 1;
+		Some(c)
+	}
+}
+
+static TX_QUEUE: SpinLock<TxQueue> = SpinLock::new(TxQueue::new());
+// Until init() turns on the THR empty interrupt, nobody would drain
+// TX_QUEUE, so put() writes straight to the UART. sync() turns this back
+// off when we're going down and can't count on interrupts anymore.
+static TX_QUEUED: AtomicBool = AtomicBool::new(false);
+
 impl Write for Uart {
 	fn write_str(&mut self, out: &str) -> Result<(), Error> {
-		for c in out.bytes() {
-			self.put(c);
-		}
+		self.put_str(out);
 		Ok(())
 	}
 }
 
+impl Default for Uart {
+	/// The UART at UART_BASE, which is the one we use for the console.
+	fn default() -> Self {
+		Uart::new(UART_BASE)
+	}
+}
+
 impl Uart {
 	pub fn new(base_address: usize) -> Self {
 		Uart { base_address }
 	}
 
+	/// Set up the UART with the receive FIFO interrupting us on every
+	/// byte.
 	pub fn init(&mut self) {
+		self.init_with_fifo(FifoTrigger::One);
+	}
+
+	/// Set up the UART with the receive FIFO interrupting us once trigger
+	/// bytes are waiting. A bigger trigger means fewer interrupts when a
+	/// lot comes in at once (pasting into the shell, for example).
+	pub fn init_with_fifo(&mut self, trigger: FifoTrigger) {
 		let ptr = self.base_address as *mut u8;
+		FIFO_TRIGGER.store(trigger as u8, Ordering::Relaxed);
 		unsafe {
 			// First, set the word length, which
 			// are bits 0 and 1 of the line control register (LCR)
@@ -38,42 +231,68 @@ impl Uart {
 			ptr.add(3).write_volatile(lcr);
 
 			// Now, enable the FIFO, which is bit index 0 of the
-			// FIFO control register (FCR at offset 2).
-			// Again, we can just write 1 here, but when we use left
-			// shift, it's easier to see that we're trying to write
-			// bit index #0.
-			ptr.add(2).write_volatile(1 << 0);
+			// FIFO control register (FCR at offset 2). We also
+			// clear out whatever is sitting in both FIFOs (bits 1
+			// and 2) and set how full the receive FIFO gets before
+			// it interrupts us (bits 6 and 7).
+			ptr.add(2).write_volatile(FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | trigger as u8);
 
 			// Enable receiver buffer interrupts, which is at bit
 			// index 0 of the interrupt enable register (IER at
-			// offset 1).
-			ptr.add(1).write_volatile(1 << 0);
-
-			// If we cared about the divisor, the code below would
-			// set the divisor from a global clock rate of 22.729
-			// MHz (22,729,000 cycles per second) to a signaling
-			// rate of 2400 (BAUD). We usually have much faster
-			// signalling rates nowadays, but this demonstrates what
-			// the divisor actually does. The formula given in the
-			// NS16500A specification for calculating the divisor
-			// is:
-			// divisor = ceil( (clock_hz) / (baud_sps x 16) )
-			// So, we substitute our values and get:
-			// divisor = ceil( 22_729_000 / (2400 x 16) )
-			// divisor = ceil( 22_729_000 / 38_400 )
-			// divisor = ceil( 591.901 ) = 592
-
-			// The divisor register is two bytes (16 bits), so we
-			// need to split the value 592 into two bytes.
-			// Typically, we would calculate this based on measuring
-			// the clock rate, but again, for our purposes [qemu],
-			// this doesn't really do anything.
-			let divisor: u16 = 592;
-			let divisor_least: u8 =
-				(divisor & 0xff).try_into().unwrap();
-			let divisor_most: u8 =
-				(divisor >> 8).try_into().unwrap();
+			// offset 1). The THR empty interrupt (bit 1) only gets
+			// turned on when put() has something queued.
+			ptr.add(1).write_volatile(IER_RX);
+		}
+
+		// If we cared about the divisor, the code below would
+		// set the divisor from a global clock rate of 22.729
+		// MHz (22,729,000 cycles per second) to a signaling
+		// rate of 2400 (BAUD). We usually have much faster
+		// signalling rates nowadays, but this demonstrates what
+		// the divisor actually does. See set_baud() for how the
+		// divisor gets there. Typically, we would calculate this
+		// based on measuring the clock rate, but again, for our
+		// purposes [qemu], this doesn't really do anything.
+		self.set_baud(BASE_CLOCK, DEFAULT_BAUD).unwrap();
+		if self.base_address == UART_BASE {
+			TX_QUEUED.store(true, Ordering::Release);
+		}
+	}
+
+	/// Set the signalling rate to baud, where base_clock is the clock
+	/// (in Hz) the UART is fed with. The UART divides base_clock by 16 and
+	/// then by a 16-bit divisor, so not every baud is possible with every
+	/// clock. If it isn't, nothing is changed.
+	pub fn set_baud(&mut self, base_clock: u32, baud: u32) -> Result<(), BaudError> {
+		// The formula given in the NS16500A specification for
+		// calculating the divisor is:
+		// divisor = ceil( (clock_hz) / (baud_sps x 16) )
+		// So, for 22.729 MHz and 2400 BAUD, we substitute our values
+		// and get:
+		// divisor = ceil( 22_729_000 / (2400 x 16) )
+		// divisor = ceil( 22_729_000 / 38_400 )
+		// divisor = ceil( 591.901 ) = 592
+		// 16 x baud can be more than a u32 holds, so we do this in u64.
+		let per_divisor = 16 * baud as u64;
+		if per_divisor == 0 {
+			return Err(BaudError::TooSlow);
+		}
+		if (base_clock as u64) < per_divisor {
+			// The clock can't even do one count per bit.
+			return Err(BaudError::TooFast);
+		}
+		let divisor = (base_clock as u64).div_ceil(per_divisor);
+		if divisor > 0xffff {
+			return Err(BaudError::TooSlow);
+		}
 
+		// The divisor register is two bytes (16 bits), so we
+		// need to split the divisor into two bytes.
+		let divisor_least: u8 = (divisor & 0xff).try_into().unwrap();
+		let divisor_most: u8 = (divisor >> 8).try_into().unwrap();
+		let ptr = self.base_address as *mut u8;
+		unsafe {
+			let lcr = ptr.add(3).read_volatile() & !LCR_DLAB;
 			// Notice that the divisor register DLL (divisor latch
 			// least) and DLM (divisor latch most) have the same
 			// base address as the receiver/transmitter and the
@@ -82,7 +301,7 @@ impl Uart {
 			// writing 1 into the Divisor Latch Access Bit (DLAB),
 			// which is bit index 7 of the Line Control Register
 			// (LCR) which is at base_address + 3.
-			ptr.add(3).write_volatile(lcr | 1 << 7);
+			ptr.add(3).write_volatile(lcr | LCR_DLAB);
 
 			// Now, base addresses 0 and 1 point to DLL and DLM,
 			// respectively. Put the lower 8 bits of the divisor
@@ -92,49 +311,249 @@ impl Uart {
 
 			// Now that we've written the divisor, we never have to
 			// touch this again. In hardware, this will divide the
-			// global clock (22.729 MHz) into one suitable for 2,400
-			// signals per second. So, to once again get access to
-			// the RBR/THR/IER registers, we need to close the DLAB
+			// base clock into one suitable for baud signals per
+			// second. So, to once again get access to the
+			// RBR/THR/IER registers, we need to close the DLAB
 			// bit by clearing it to 0.
 			ptr.add(3).write_volatile(lcr);
 		}
+		Ok(())
 	}
 
+	/// Check that the UART works by talking to itself. Loopback mode (bit 4
+	/// of the modem control register, MCR at offset 4) connects the
+	/// transmitter to the receiver inside the UART, and nothing goes out on
+	/// the wire. We send LOOPBACK_PATTERN and see if it comes back. This
+	/// needs init() first, and it has to run before anything's printed,
+	/// since whatever is in the FIFOs gets thrown away.
+	#[cfg(feature = "uart-selftest")]
+	pub fn loopback_test(&mut self) -> bool {
+		let ptr = self.base_address as *mut u8;
+		let mcr = unsafe { ptr.add(4).read_volatile() };
+		unsafe {
+			ptr.add(4).write_volatile(mcr | MCR_LOOPBACK);
+		}
+		self.flush_fifo();
+		let mut passed = true;
+		for &sent in LOOPBACK_PATTERN.iter() {
+			self.put_sync(sent);
+			// Give it a while to come back, but not forever, since a
+			// broken UART might never send it.
+			let got = (0..LOOPBACK_SPINS).find_map(|_| self.get());
+			if got != Some(sent) {
+				passed = false;
+				break;
+			}
+		}
+		// Don't leave half of the pattern for the console to read.
+		self.flush_fifo();
+		unsafe {
+			ptr.add(4).write_volatile(mcr);
+		}
+		passed
+	}
+
+	/// Throw away everything in the receive and transmit FIFOs. The clear
+	/// bits clear themselves, so the FIFOs stay on with the trigger we
+	/// gave init_with_fifo().
+	pub fn flush_fifo(&mut self) {
+		let ptr = self.base_address as *mut u8;
+		let trigger = FIFO_TRIGGER.load(Ordering::Relaxed);
+		unsafe {
+			ptr.add(2).write_volatile(FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | trigger);
+		}
+	}
+
+	/// Send c out. For the console UART, this goes on TX_QUEUE, and we
+	/// only wait if that's full. Any other UART is written directly.
 	pub fn put(&mut self, c: u8) {
+		if self.base_address != UART_BASE || !TX_QUEUED.load(Ordering::Acquire) {
+			self.put_sync(c);
+			return;
+		}
+		let mut queue = TX_QUEUE.lock();
+		if queue.len == 0 && self.thr_empty() {
+			// Nothing's waiting and the UART is idle, so there's no
+			// reason to make an interrupt do this.
+			self.write_thr(c);
+			return;
+		}
+		while !queue.push(c) {
+			// We're printing faster than the UART can keep up and
+			// the queue is full. Our interrupts are off while we
+			// hold the lock, so we drain it ourselves.
+			while !self.thr_empty() {}
+			self.fill_fifo(&mut queue);
+		}
+		self.set_ier(IER_RX | IER_THRE);
+	}
+
+	/// Send out a string. The terminal on the other end decodes UTF-8, so
+	/// we send the bytes exactly as they are.
+	pub fn put_str(&mut self, out: &str) {
+		self.put_bytes(out.as_bytes());
+	}
+
+	/// Send out raw bytes. Don't turn these into chars first! A byte
+	/// that's part of a multi-byte UTF-8 character is not a char by
+	/// itself, and printing it with {} encodes it all over again.
+	pub fn put_bytes(&mut self, out: &[u8]) {
+		for &c in out {
+			self.put(c);
+		}
+	}
+
+	/// Wait for the UART to have room and then write c.
+	fn put_sync(&mut self, c: u8) {
+		while !self.thr_empty() {}
+		self.write_thr(c);
+	}
+
+	fn write_thr(&mut self, c: u8) {
 		let ptr = self.base_address as *mut u8;
 		unsafe {
 			ptr.add(0).write_volatile(c);
 		}
 	}
 
-	pub fn get(&mut self) -> Option<u8> {
+	fn set_ier(&mut self, ier: u8) {
 		let ptr = self.base_address as *mut u8;
 		unsafe {
-			if ptr.add(5).read_volatile() & 1 == 0 {
-				// The DR bit is 0, meaning no data
-				None
-			}
-			else {
-				// The DR bit is 1, meaning data!
-				Some(ptr.add(0).read_volatile())
+			ptr.add(1).write_volatile(ier);
+		}
+	}
+
+	/// Is the transmit holding register (and FIFO) empty?
+	fn thr_empty(&self) -> bool {
+		let ptr = self.base_address as *mut u8;
+		unsafe { LineStatus(ptr.add(5).read_volatile()).thr_empty() }
+	}
+
+	/// The transmit FIFO is empty, so move up to a FIFO's worth of bytes
+	/// from the queue into it.
+	fn fill_fifo(&mut self, queue: &mut TxQueue) {
+		for _ in 0..TX_FIFO_SIZE {
+			match queue.pop() {
+				Some(c) => self.write_thr(c),
+				None => break,
 			}
 		}
 	}
+
+	/// Called by the interrupt handler. If the UART is ready for more,
+	/// give it more. Once the queue is empty, we stop asking for THR
+	/// empty interrupts until put() queues something again.
+	fn drain(&mut self) {
+		let mut queue = TX_QUEUE.lock();
+		if self.thr_empty() {
+			self.fill_fifo(&mut queue);
+		}
+		if queue.len == 0 {
+			self.set_ier(IER_RX);
+		}
+	}
+
+	/// Read the line status register. This clears the error bits.
+	pub fn line_status(&self) -> LineStatus {
+		let ptr = self.base_address as *mut u8;
+		unsafe { LineStatus(ptr.add(5).read_volatile()) }
+	}
+
+	pub fn get(&mut self) -> Option<u8> {
+		if !self.line_status().data_ready() {
+			// The DR bit is 0, meaning no data
+			None
+		}
+		else {
+			// The DR bit is 1, meaning data!
+			Some(self.read_rbr())
+		}
+	}
+
+	fn read_rbr(&mut self) -> u8 {
+		let ptr = self.base_address as *mut u8;
+		unsafe { ptr.add(0).read_volatile() }
+	}
+}
+
+/// Write out everything still in TX_QUEUE and stop queueing. put() writes
+/// directly from here on. We call this when we're about to stop (a panic
+/// or powering off) and the THR empty interrupt won't be around to finish
+/// the job.
+pub fn sync() {
+	let mut uart = Uart::default();
+	TX_QUEUED.store(false, Ordering::Release);
+	// Interrupts are off while TX_QUEUE is held, so if it's taken now, we
+	// must have panicked in the middle of put() or the THR interrupt.
+	// Spinning would hang us before the panic message ever got out, so
+	// we leave whatever is queued and let put() go straight to the UART.
+	if let Some(mut queue) = TX_QUEUE.try_lock() {
+		while let Some(c) = queue.pop() {
+			uart.put_sync(c);
+		}
+	}
+	uart.set_ier(IER_RX);
+}
+
+// The console's decoder. It keeps its state between interrupts, since an
+// escape sequence can be split across them.
+static ANSI_DECODER: SpinLock<AnsiDecoder> = SpinLock::new(AnsiDecoder::new());
+
+/// Say what went wrong on the line. An overrun means input was lost,
+/// which is what we want to know when pasting into the console drops
+/// characters.
+fn report_line_error(status: LineStatus) {
+	if status.overrun_error() {
+		println!("uart: receive overrun, input was dropped");
+	}
+	if status.parity_error() {
+		println!("uart: parity error");
+	}
+	if status.framing_error() {
+		println!("uart: framing error (is the baud right?)");
+	}
+	if status.break_interrupt() {
+		println!("uart: break");
+	}
 }
 
 pub fn handle_interrupt() {
 	// We would typically set this to be handled out of the interrupt context,
 	// but we're testing here! C'mon!
 	// We haven't yet used the singleton pattern for my_uart, but remember, this
-	// just simply wraps 0x1000_0000 (UART).
-	let mut my_uart = Uart::new(0x1000_0000);
+	// just simply wraps UART_BASE.
+	let mut my_uart = Uart::default();
+	// The UART interrupts us when it's ready to send more and when it has
+	// something for us. First, keep the output moving.
+	if TX_QUEUED.load(Ordering::Acquire) {
+		my_uart.drain();
+	}
 	// If we get here, the UART better have something! If not, what happened??
-	if let Some(c) = my_uart.get() {
+	// With a FIFO trigger above one byte, there can be several waiting, so
+	// take all of them. We look at the line status ourselves instead of
+	// calling get(), since reading it clears the errors and we want to
+	// know about those.
+	loop {
+		let status = my_uart.line_status();
+		if status.has_error() {
+			report_line_error(status);
+		}
+		if !status.data_ready() {
+			break;
+		}
+		let c = my_uart.read_rbr();
 		// If you recognize this code, it used to be in the lib.rs under kmain(). That
 		// was because we needed to poll for UART data. Now that we have interrupts,
 		// here it goes!
 		push_stdin(c);
-		match c {
+		// Keys like the arrows come in as escape sequences. Echoing
+		// those would move the cursor around on the terminal, so we
+		// only echo plain characters.
+		let key = match ANSI_DECODER.lock().feed(c) {
+			Some(Key::Char(c)) => c,
+			_ => continue,
+		};
+		match key {
 			8 => {
 				// This is a backspace, so we
 				// essentially have to write a space and
@@ -146,7 +565,10 @@ pub fn handle_interrupt() {
 				println!();
 			},
 			_ => {
-				print!("{}", c as char);
+				// Echo the byte as it came in. If it's part of a
+				// UTF-8 character, the terminal puts it back
+				// together once the rest of it comes.
+				my_uart.put(c);
 			},
 		}	
 	}
@@ -3,11 +3,12 @@
 // Stephen Marz
 // 10 March 2020
 
-use crate::{block, block::setup_block_device, page::PAGE_SIZE};
+use crate::{block, block::setup_block_device, page::{zalloc, PAGE_SIZE}};
 use crate::rng::setup_entropy_device;
 use crate::{gpu, gpu::setup_gpu_device};
 use crate::{input, input::setup_input_device};
-use core::mem::size_of;
+use crate::{sound, sound::setup_sound_device};
+use core::{mem::size_of, ptr::null_mut};
 
 // Flags
 // Descriptor flags have VIRTIO_DESC_F as a prefix
@@ -25,9 +26,12 @@ pub const VIRTIO_AVAIL_F_NO_INTERRUPT: u16 = 1;
 
 pub const VIRTIO_USED_F_NO_NOTIFY: u16 = 1;
 
-// According to the documentation, this must be a power
-// of 2 for the new style. So, I'm changing this to use
-// 1 << instead because that will enforce this standard.
+// This is how many descriptors a queue gets unless the driver asks for
+// something else. It's only what we ask for. The device might only take
+// fewer, so drivers have to use the size that init_queue() gives them
+// (Queue::size()) for all of their ring math, not this. According to the
+// documentation, this must be a power of 2 for the new style. So, I'm
+// changing this to use 1 << instead because that will enforce this standard.
 pub const VIRTIO_RING_SIZE: usize = 1 << 7;
 
 // VirtIO structures
@@ -38,6 +42,7 @@ pub const VIRTIO_RING_SIZE: usize = 1 << 7;
 // specified above. Any descriptor can be chained, hence the
 // next field, but only if the F_NEXT flag is specified.
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub struct Descriptor {
 	pub addr:  u64,
 	pub len:   u32,
@@ -46,35 +51,137 @@ pub struct Descriptor {
 }
 
 #[repr(C)]
-pub struct Available {
-	pub flags: u16,
-	pub idx:   u16,
-	pub ring:  [u16; VIRTIO_RING_SIZE],
-	pub event: u16,
-}
-
-#[repr(C)]
+#[derive(Copy, Clone)]
 pub struct UsedElem {
 	pub id:  u32,
 	pub len: u32,
 }
 
-#[repr(C)]
-pub struct Used {
-	pub flags: u16,
-	pub idx:   u16,
-	pub ring:  [UsedElem; VIRTIO_RING_SIZE],
-	pub event: u16,
+/// A virtqueue that we share with the device. The legacy layout is the
+/// descriptor table, then the available ring right after it, then the used
+/// ring on the next page boundary. Where the rings are depends on how many
+/// descriptors we ended up with, so this can't be a fixed structure like
+/// the descriptors themselves. Instead, we keep pointers to each part.
+///
+/// The available ring is flags (u16), idx (u16), ring[size] (u16), event (u16).
+/// The used ring is flags (u16), idx (u16), ring[size] (UsedElem), event (u16).
+///
+/// We also keep which device and queue number this is, so that submit()
+/// can notify the device, and the next descriptor that submit() will use.
+#[derive(Copy, Clone)]
+pub struct Queue {
+	desc:  *mut Descriptor,
+	avail: *mut u16,
+	used:  *mut u16,
+	size:  u16,
+	dev:   *mut u32,
+	qnum:  u32,
+	free:  u16,
 }
 
-#[repr(C)]
-pub struct Queue {
-	pub desc:  [Descriptor; VIRTIO_RING_SIZE],
-	pub avail: Available,
-	// Calculating padding, we need the used ring to start on a page boundary. We take the page size, subtract the
-	// amount the descriptor ring takes then subtract the available structure and ring.
-	pub padding0: [u8; PAGE_SIZE - size_of::<Descriptor>() * VIRTIO_RING_SIZE - size_of::<Available>()],
-	pub used:     Used,
+impl Queue {
+	/// A queue that hasn't been set up yet. Don't use it until init_queue()
+	/// gives you a real one.
+	pub const fn null() -> Self {
+		Self { desc:  null_mut(),
+		       avail: null_mut(),
+		       used:  null_mut(),
+		       size:  0,
+		       dev:   null_mut(),
+		       qnum:  0,
+		       free:  0, }
+	}
+
+	/// How many descriptors the device agreed to. All of the ring indices
+	/// wrap at this.
+	pub fn size(&self) -> u16 {
+		self.size
+	}
+
+	/// The descriptor after idx, wrapping back to 0.
+	pub fn next(&self, idx: u16) -> u16 {
+		((idx as u32 + 1) % self.size as u32) as u16
+	}
+
+	/// The descriptor at idx, which has to be less than size(). Queue is
+	/// just a copy of the ring's pointers, so &mut self wouldn't stop
+	/// anyone else from getting at the descriptor anyway.
+	#[allow(clippy::mut_from_ref)]
+	pub unsafe fn desc(&self, idx: u16) -> &mut Descriptor {
+		&mut *self.desc.add(idx as usize)
+	}
+
+	/// Hand the descriptor chain starting at head to the device. We still
+	/// have to notify the device afterward.
+	pub unsafe fn push_avail(&self, head: u16) {
+		let idx = self.avail.add(1).read_volatile();
+		self.avail.add(2 + (idx % self.size) as usize).write_volatile(head);
+		self.avail.add(1).write_volatile(idx.wrapping_add(1));
+	}
+
+	/// The device bumps this every time it finishes a chain. Anything
+	/// between our own ack index and this hasn't been looked at yet.
+	pub unsafe fn used_idx(&self) -> u16 {
+		self.used.add(1).read_volatile()
+	}
+
+	/// The used element for the ack index idx. This wraps idx for you.
+	pub unsafe fn used_elem(&self, idx: u16) -> UsedElem {
+		(self.used.add(2) as *const UsedElem).add((idx % self.size) as usize).read_volatile()
+	}
+}
+
+/// Put descs into the queue as one chain, hand it to the device, and notify
+/// the device. We link the descriptors together ourselves, so the callers
+/// only have to set VIRTIO_DESC_F_WRITE on the ones the device writes to.
+/// The descriptors are used in ring order, so a chain that hasn't come back
+/// yet gets overwritten once the queue has gone all the way around. Returns
+/// the head, which is the id the device gives back in the used ring.
+pub unsafe fn submit(queue: &mut Queue, descs: &[Descriptor]) -> u16 {
+	let head = queue.free;
+	for (i, d) in descs.iter().enumerate() {
+		let mut desc = *d;
+		if i + 1 < descs.len() {
+			desc.flags |= VIRTIO_DESC_F_NEXT;
+			desc.next = queue.next(queue.free);
+		}
+		else {
+			desc.flags &= !VIRTIO_DESC_F_NEXT;
+			desc.next = 0;
+		}
+		*queue.desc(queue.free) = desc;
+		queue.free = queue.next(queue.free);
+	}
+	queue.push_avail(head);
+	queue.dev.add(MmioOffsets::QueueNotify.scale32()).write_volatile(queue.qnum);
+	head
+}
+
+/// The next chain the device has finished with, if there is one. ack_idx
+/// is the driver's own count of the used elements it has looked at, which
+/// we move past the one we return.
+pub unsafe fn next_used(queue: &Queue, ack_idx: &mut u16) -> Option<UsedElem> {
+	if *ack_idx == queue.used_idx() {
+		return None;
+	}
+	let elem = queue.used_elem(*ack_idx);
+	*ack_idx = ack_idx.wrapping_add(1);
+	let idx = (queue.dev as usize).wrapping_sub(MMIO_VIRTIO_START) / MMIO_VIRTIO_STRIDE;
+	if let Some(stats) = DEVICE_STATS.get_mut(idx) {
+		stats.reclaimed += 1;
+	}
+	Some(elem)
+}
+
+/// Spin until the device finishes another chain and return it. This is for
+/// drivers that don't wait for an interrupt, so don't use it on a queue
+/// that an interrupt handler also takes used elements from.
+pub unsafe fn wait_used(queue: &Queue, ack_idx: &mut u16) -> UsedElem {
+	loop {
+		if let Some(elem) = next_used(queue, ack_idx) {
+			return elem;
+		}
+	}
 }
 
 // The MMIO transport is "legacy" in QEMU, so these registers represent
@@ -134,7 +241,63 @@ pub struct MmioDevice {
 	// The config space starts at 0x100, but it is device dependent.
 }
 
+/// Select queue qnum on the device at ptr, give it memory, and tell the device
+/// where that memory is. Every driver sets up its queues through here so that
+/// the checks are in one place. We ask for want descriptors, but if the device
+/// can't take that many, we take as many as it can. We return None if the
+/// device doesn't have this queue or if we're out of memory.
+pub unsafe fn init_queue(ptr: *mut u32, qnum: u32, want: u16) -> Option<Queue> {
+	ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(qnum);
+	// Set the queue num. We have to make sure that the
+	// queue size is valid because the device can only take
+	// a certain size.
+	let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
+	let size = (want as u32).min(qnmax);
+	if size == 0 {
+		print!("queue {} not available...", qnum);
+		return None;
+	}
+	// The legacy ring has to be a power of two, so round down.
+	let size = 1u32 << (31 - size.leading_zeros());
+	ptr.add(MmioOffsets::QueueNum.scale32()).write_volatile(size);
+	// The available ring goes right after the descriptors, and the used
+	// ring starts on the next page after that.
+	let size = size as usize;
+	let avail_offset = size_of::<Descriptor>() * size;
+	let avail_end = avail_offset + size_of::<u16>() * (3 + size);
+	let used_offset = (avail_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+	let used_end = used_offset + size_of::<u16>() * 3 + size_of::<UsedElem>() * size;
+	// Round this up to whole pages. If it is exactly 4096 bytes, that's
+	// one page, not two.
+	let num_pages = used_end.div_ceil(PAGE_SIZE);
+	let queue_ptr = zalloc(num_pages);
+	if queue_ptr.is_null() {
+		print!("queue alloc fail...");
+		return None;
+	}
+	// The device only gets a page number, so the queue has to start on a
+	// page. If it doesn't, we and the device will look for the rings in
+	// different places, and every completion will look like garbage.
+	// That's much harder to track down than this.
+	assert!((queue_ptr as usize).is_multiple_of(PAGE_SIZE), "virtio queue isn't page aligned");
+	ptr.add(MmioOffsets::GuestPageSize.scale32()).write_volatile(PAGE_SIZE as u32);
+	// This is where the device will look for the used ring.
+	ptr.add(MmioOffsets::QueueAlign.scale32()).write_volatile(PAGE_SIZE as u32);
+	// QueuePFN is a physical page number. This is the memory that we
+	// (the OS) and the device have in common for making and receiving
+	// requests.
+	ptr.add(MmioOffsets::QueuePfn.scale32()).write_volatile((queue_ptr as usize / PAGE_SIZE) as u32);
+	Some(Queue { desc:  queue_ptr as *mut Descriptor,
+	             avail: queue_ptr.add(avail_offset) as *mut u16,
+	             used:  queue_ptr.add(used_offset) as *mut u16,
+	             size:  size as u16,
+	             dev:   ptr,
+	             qnum,
+	             free:  0, })
+}
+
 #[repr(usize)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum DeviceTypes {
 	None = 0,
 	Network = 1,
@@ -144,6 +307,39 @@ pub enum DeviceTypes {
 	Gpu = 16,
 	Input = 18,
 	Memory = 24,
+	Sound = 25,
+}
+
+impl DeviceTypes {
+	/// The DeviceID register holds one of these. Anything we don't know
+	/// about comes back as None.
+	pub fn from_id(id: u32) -> Self {
+		match id {
+			1 => DeviceTypes::Network,
+			2 => DeviceTypes::Block,
+			3 => DeviceTypes::Console,
+			4 => DeviceTypes::Entropy,
+			16 => DeviceTypes::Gpu,
+			18 => DeviceTypes::Input,
+			24 => DeviceTypes::Memory,
+			25 => DeviceTypes::Sound,
+			_ => DeviceTypes::None,
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			DeviceTypes::None => "unknown",
+			DeviceTypes::Network => "network",
+			DeviceTypes::Block => "block",
+			DeviceTypes::Console => "console",
+			DeviceTypes::Entropy => "entropy",
+			DeviceTypes::Gpu => "GPU",
+			DeviceTypes::Input => "input",
+			DeviceTypes::Memory => "memory",
+			DeviceTypes::Sound => "sound",
+		}
+	}
 }
 
 // Enumerations in Rust aren't easy to convert back
@@ -218,23 +414,83 @@ pub const MMIO_VIRTIO_MAGIC: u32 = 0x74_72_69_76;
 // to determine what virtio devices are attached to the system. Right now,
 // we're using the 1..=8  linearity of the VirtIO devices on QEMU to help
 // with reducing the data structure itself. Otherwise, we might be forced
-// to use an MMIO pointer.
+// to use an MMIO pointer. Every connected device gets one, even if we
+// couldn't set it up, so that summary() can show it. Only the ready ones
+// get interrupts or show up in find().
 pub struct VirtioDevice {
 	pub devtype: DeviceTypes,
+	pub ready:   bool,
 }
 
 impl VirtioDevice {
 	pub const fn new() -> Self {
-		VirtioDevice { devtype: DeviceTypes::None, }
+		VirtioDevice { devtype: DeviceTypes::None,
+		               ready:   false, }
 	}
 
 	pub const fn new_with(devtype: DeviceTypes) -> Self {
-		VirtioDevice { devtype }
+		VirtioDevice { devtype,
+		               ready: true, }
 	}
 }
 
 static mut VIRTIO_DEVICES: [Option<VirtioDevice>; 8] = [None, None, None, None, None, None, None, None];
 
+/// What we've counted for one device slot since boot. See stats().
+#[derive(Copy, Clone)]
+pub struct DeviceStats {
+	// Interrupts we handed to the device's driver.
+	pub interrupts: usize,
+	// Used ring entries the driver took back, whether it was in an
+	// interrupt or it was waiting for them.
+	pub reclaimed:  usize,
+	// Interrupts for a slot with no working device behind it.
+	pub spurious:   usize,
+}
+
+impl DeviceStats {
+	pub const fn new() -> Self {
+		DeviceStats { interrupts: 0,
+		              reclaimed:  0,
+		              spurious:   0, }
+	}
+}
+
+// These are only touched from interrupts and drivers with the device
+// locked, and we're only taking interrupts on one hart.
+static mut DEVICE_STATS: [DeviceStats; 8] = [DeviceStats::new(); 8];
+
+/// The counters for device number dev (1..=8).
+pub fn device_stats(dev: usize) -> Option<DeviceStats> {
+	if dev == 0 || dev > 8 {
+		return None;
+	}
+	unsafe { Some(DEVICE_STATS[dev - 1]) }
+}
+
+/// Print how busy each device has been. Lots of interrupts for only a few
+/// reclaimed descriptors each means the device is being fed one request
+/// at a time.
+pub fn stats() {
+	println!("  Dev  Type      Interrupts  Reclaimed  Spurious");
+	unsafe {
+		for (idx, vd) in VIRTIO_DEVICES.iter().enumerate() {
+			let st = DEVICE_STATS[idx];
+			if vd.is_none() && st.spurious == 0 {
+				continue;
+			}
+			println!(
+			         "  {:<3}  {:<8}  {:>10}  {:>9}  {:>8}",
+			         idx + 1,
+			         vd.as_ref().map(|vd| vd.devtype.name()).unwrap_or("none"),
+			         st.interrupts,
+			         st.reclaimed,
+			         st.spurious
+			);
+		}
+	}
+}
+
 /// Probe the VirtIO bus for devices that might be
 /// out there.
 pub fn probe() {
@@ -266,73 +522,136 @@ pub fn probe() {
 		// If we get here, we have a connected virtio device. Now we have
 		// to figure out what kind it is so we can do device-specific setup.
 		else {
-			match deviceid {
-				// DeviceID 1 is a network device
-				1 => {
-					print!("network device...");
-					if false == setup_network_device(ptr) {
-						println!("setup failed.");
-					}
-					else {
-						println!("setup succeeded!");
-					}
-				},
-				// DeviceID 2 is a block device
-				2 => {
-					print!("block device...");
-					if false == setup_block_device(ptr) {
-						println!("setup failed.");
-					}
-					else {
-						let idx = (addr - MMIO_VIRTIO_START) >> 12;
-						unsafe {
-							VIRTIO_DEVICES[idx] =
-								Some(VirtioDevice::new_with(DeviceTypes::Block));
-						}
-						println!("setup succeeded!");
-					}
-				},
-				// DeviceID 4 is a random number generator device
-				4 => {
-					print!("entropy device...");
-					if false == setup_entropy_device(ptr) {
-						println!("setup failed.");
-					}
-					else {
-						println!("setup succeeded!");
-					}
-				},
-				// DeviceID 16 is a GPU device
-				16 => {
-					print!("GPU device...");
-					if false == setup_gpu_device(ptr) {
-						println!("setup failed.");
-					}
-					else {
-						let idx = (addr - MMIO_VIRTIO_START) >> 12;
-						unsafe {
-							VIRTIO_DEVICES[idx] =
-								Some(VirtioDevice::new_with(DeviceTypes::Gpu));
-						}
-						println!("setup succeeded!");
-					}
-				},
-				// DeviceID 18 is an input device
-				18 => {
-					print!("input device...");
-					if false == setup_input_device(ptr) {
-						println!("setup failed.");
-					}
-					else {
-						let idx = (addr - MMIO_VIRTIO_START) >> 12;
-						unsafe {
-							VIRTIO_DEVICES[idx] =
-								Some(VirtioDevice::new_with(DeviceTypes::Input));
-						}
-						println!("setup succeeded!");
-					}
-				},
-				_ => println!("unknown device type."),
+			let devtype = DeviceTypes::from_id(deviceid);
+			if devtype == DeviceTypes::None {
+				print!("unknown device type {}...", deviceid);
+			}
+			else {
+				print!("{} device...", devtype.name());
+			}
+			let ready = setup_device(devtype, ptr);
+			if ready {
+				println!("setup succeeded!");
+			}
+			else {
+				println!("setup failed.");
+			}
+			let idx = (addr - MMIO_VIRTIO_START) >> 12;
+			unsafe {
+				VIRTIO_DEVICES[idx] = Some(VirtioDevice { devtype, ready });
+			}
+		}
+	}
+}
+
+/// Run the driver's setup for a device of this type at ptr. This is the
+/// whole initialization sequence, starting with a reset, so it's also how
+/// we bring back a device that needs a reset.
+fn setup_device(devtype: DeviceTypes, ptr: *mut u32) -> bool {
+	match devtype {
+		// DeviceID 1 is a network device
+		DeviceTypes::Network => setup_network_device(ptr),
+		// DeviceID 2 is a block device
+		DeviceTypes::Block => setup_block_device(ptr),
+		// DeviceID 4 is a random number generator device
+		DeviceTypes::Entropy => setup_entropy_device(ptr),
+		// DeviceID 16 is a GPU device
+		DeviceTypes::Gpu => setup_gpu_device(ptr),
+		// DeviceID 18 is an input device
+		DeviceTypes::Input => setup_input_device(ptr),
+		// DeviceID 25 is a sound device
+		DeviceTypes::Sound => setup_sound_device(ptr),
+		_ => false,
+	}
+}
+
+/// Read the status register of device number dev (1..=8). This is 0 if
+/// there's no such device, which is also what a device that has been
+/// reset reads as.
+pub fn device_status(dev: usize) -> u32 {
+	if dev == 0 || dev > 8 {
+		return 0;
+	}
+	let ptr = (MMIO_VIRTIO_START + (dev - 1) * MMIO_VIRTIO_STRIDE) as *mut u32;
+	unsafe { ptr.add(MmioOffsets::Status.scale32()).read_volatile() }
+}
+
+/// If device number dev has set DEVICE_NEEDS_RESET, it has stopped
+/// working and won't start again on its own. Set it up from scratch and
+/// return true. Whatever it was in the middle of is lost: block requests
+/// fail with an I/O error, and queued input events are dropped. The old
+/// queue memory is leaked, since we can't be sure the device is done
+/// with it. Don't call this with the device's lock held.
+pub fn reset_if_needed(dev: usize) -> bool {
+	if !StatusField::needs_reset(device_status(dev)) {
+		return false;
+	}
+	let idx = dev - 1;
+	unsafe {
+		if let Some(vd) = VIRTIO_DEVICES[idx].as_mut() {
+			print!("virtio device {} ({}) needs a reset...", dev, vd.devtype.name());
+			if vd.devtype == DeviceTypes::Block {
+				block::fail_outstanding(idx);
+			}
+			let ptr = (MMIO_VIRTIO_START + idx * MMIO_VIRTIO_STRIDE) as *mut u32;
+			vd.ready = setup_device(vd.devtype, ptr);
+			// The GPU also needs its framebuffer back on the screen.
+			if vd.ready && vd.devtype == DeviceTypes::Gpu {
+				gpu::init_device(dev);
+			}
+			if vd.ready {
+				println!("back up.");
+			}
+			else {
+				println!("setup failed.");
+			}
+		}
+	}
+	true
+}
+
+/// Which device number (1..=8, the same number gpu::init(), block_op(),
+/// and the rest take) is the first working device of this type? Don't
+/// count on any particular order. It's whatever order QEMU was given the
+/// devices on its command line.
+pub fn find(devtype: DeviceTypes) -> Option<usize> {
+	unsafe {
+		VIRTIO_DEVICES.iter()
+		              .position(|vd| match vd {
+			              Some(vd) => vd.ready && vd.devtype == devtype,
+			              None => false,
+		              })
+		              .map(|idx| idx + 1)
+	}
+}
+
+/// Is device number dev (1..=8) a working device of this type?
+pub fn is_ready(dev: usize, devtype: DeviceTypes) -> bool {
+	if dev == 0 || dev > 8 {
+		return false;
+	}
+	unsafe {
+		match VIRTIO_DEVICES[dev - 1].as_ref() {
+			Some(vd) => vd.ready && vd.devtype == devtype,
+			None => false,
+		}
+	}
+}
+
+/// Print everything probe() found.
+pub fn summary() {
+	println!("Virtio devices:");
+	println!("  Dev  Address     Type      Status");
+	unsafe {
+		for (idx, vd) in VIRTIO_DEVICES.iter().enumerate() {
+			if let Some(vd) = vd {
+				println!(
+				         "  {:<3}  0x{:08x}  {:<8}  {}",
+				         idx + 1,
+				         MMIO_VIRTIO_START + idx * MMIO_VIRTIO_STRIDE,
+				         vd.devtype.name(),
+				         if vd.ready { "ready" } else { "failed" }
+				);
 			}
 		}
 	}
@@ -345,11 +664,19 @@ pub fn setup_network_device(_ptr: *mut u32) -> bool {
 // The External pin (PLIC) trap will lead us here if it is
 // determined that interrupts 1..=8 are what caused the interrupt.
 // In here, we try to figure out where to direct the interrupt
-// and then handle it.
-pub fn handle_interrupt(interrupt: u32) {
+// and then handle it. Returns false if there is no device at
+// that slot, so the caller can treat it as spurious.
+pub fn handle_interrupt(interrupt: u32) -> bool {
 	let idx = interrupt as usize - 1;
+	// A device that needs a reset interrupts us to say so. After the
+	// reset, there's nothing left for the driver to handle.
+	if reset_if_needed(interrupt as usize) {
+		return true;
+	}
 	unsafe {
-		if let Some(vd) = &VIRTIO_DEVICES[idx] {
+		// A device we failed to set up has no driver state to hand this to.
+		if let Some(vd) = VIRTIO_DEVICES[idx].as_ref().filter(|vd| vd.ready) {
+			DEVICE_STATS[idx].interrupts += 1;
 			match vd.devtype {
 				DeviceTypes::Block => {
 					block::handle_interrupt(idx);
@@ -360,13 +687,22 @@ pub fn handle_interrupt(interrupt: u32) {
 				DeviceTypes::Input => {
 					input::handle_interrupt(idx);
 				},
+				DeviceTypes::Sound => {
+					sound::handle_interrupt(idx);
+				},
+				// get_random() waits for its own requests, so there's
+				// nothing to do here.
+				DeviceTypes::Entropy => {},
 				_ => {
 					println!("Invalid device generated interrupt!");
 				},
 			}
+			true
 		}
 		else {
-			println!("Spurious interrupt {}", interrupt);
+			// Let the PLIC count this one too.
+			DEVICE_STATS[idx].spurious += 1;
+			false
 		}
 	}
 }
@@ -0,0 +1,10 @@
+#!/usr/bin/env python3
+# Write the file the kernel's Minix read self-test (test::fs_selftest) checks
+# against. Byte i is i % 251. Put it on the disk with ./upload.sh fsfixture.bin
+import sys
+
+SIZE = 40000
+
+out = sys.argv[1] if len(sys.argv) > 1 else "fsfixture.bin"
+with open(out, "wb") as f:
+	f.write(bytes(i % 251 for i in range(SIZE)))
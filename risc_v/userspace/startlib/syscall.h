@@ -15,9 +15,37 @@ extern "C"
 #define syscall_put_char(x)	make_syscall(2, (unsigned long)x)
 #define syscall_yield()		make_syscall(9)
 #define syscall_sleep(x)	make_syscall(10, (unsigned long)x)
-#define syscall_get_fb(x)	make_syscall(1000, (unsigned long)x)
+// The load averages are over 1, 5, and 15 timer ticks, not minutes.
+#define syscall_sysinfo(x)	make_syscall(179, (unsigned long)x)
+// Only root can do these. The block devices are flushed first.
+#define REBOOT_CMD_RESTART	0x01234567UL
+#define REBOOT_CMD_POWER_OFF	0x4321fedcUL
+#define syscall_poweroff()	make_syscall(142, REBOOT_CMD_POWER_OFF)
+#define syscall_reboot()	make_syscall(142, REBOOT_CMD_RESTART)
+// For the framebuffer calls, a device of 0 means the first GPU.
+#define FB_READ_ONLY	1
+#define syscall_get_fb(x)	make_syscall(1000, (unsigned long)x, 0UL)
+#define syscall_get_fb_ro(x)	make_syscall(1000, (unsigned long)x, (unsigned long)FB_READ_ONLY)
 #define syscall_inv_rect(d, x, y, w, h) make_syscall(1001, (unsigned long) d, (unsigned long)x, (unsigned long)y, (unsigned long)w, (unsigned long)h)
 #define syscall_get_key(x, y)	make_syscall(1002, (unsigned long)x, (unsigned long)y)
 #define syscall_get_abs(x, y)	make_syscall(1004, (unsigned long)x, (unsigned long)y)
+#define syscall_play_pcm(s, n, r)	make_syscall(1005, (unsigned long)s, (unsigned long)n, (unsigned long)r)
+#define syscall_set_switch_hz(x)	make_syscall(1006, (unsigned long)x)
+#define syscall_get_rel(x, y)	make_syscall(1007, (unsigned long)x, (unsigned long)y)
+#define syscall_get_pointer()	make_syscall(1008)
+// The timeout is in mtime ticks (10 MHz), or 0 for one 60 Hz frame.
+#define syscall_wait_display(d, t)	make_syscall(1009, (unsigned long)d, (unsigned long)t)
+// Whether the framebuffer is red or blue first in memory. Pixels are 4 bytes
+// either way, with alpha last.
+#define FB_FORMAT_RGBA	0
+#define FB_FORMAT_BGRA	1
+#define syscall_get_fb_format(d)	make_syscall(1010, (unsigned long)d)
+// chmod and chown need uid 0, except that the owner of a file can chmod it.
+#define syscall_chmod(p, m)	make_syscall(1028, (unsigned long)p, (unsigned long)m)
+#define syscall_chown(p, u, g)	make_syscall(1029, (unsigned long)p, (unsigned long)u, (unsigned long)g)
+// Up to a page of random bytes at a time. Returns how many it wrote.
+#define syscall_getrandom(b, l)	make_syscall(278, (unsigned long)b, (unsigned long)l, 0UL)
 #define syscall_get_time()  make_syscall(1062)
 
+// Fills x with argc, argv[], and the strings. l is the size of x in bytes.
+#define syscall_getmainvars(x, l)	make_syscall(2011, (unsigned long)x, (unsigned long)l)